@@ -0,0 +1,77 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use millionaire::{
+    backend::{garbler_backend::construct_circuit, receiver_backend::GarbledCircuitRecv},
+    garbling::{GarbledCircuit, KEY_SIZE},
+};
+
+/// The bit widths of the compared numbers
+const WIDTHS: [usize; 3] = [8, 32, 128];
+
+/// The total size of a garbled circuit's rows in bytes
+fn ciphertext_bytes(circuit: &GarbledCircuit) -> u64 {
+    circuit
+        .gates()
+        .iter()
+        .flat_map(|gate| gate.rows())
+        .map(|row| row.len() as u64)
+        .sum()
+}
+
+/// The keys of the circuit's inputs when every bit of both numbers is set
+fn all_on_keys(circuit: &GarbledCircuit) -> Vec<[u8; KEY_SIZE]> {
+    let input_keys = circuit.input_keys();
+
+    (0..circuit.n()).map(|i| input_keys[&i].on_key()).collect()
+}
+
+/// Garbling throughput, in gates per second
+fn garble(c: &mut Criterion) {
+    let mut group = c.benchmark_group("garble");
+
+    for n in WIDTHS {
+        // The topology is the same for every session, so only garbling it is measured
+        let topology = construct_circuit(n);
+        let circuit: GarbledCircuit = topology.clone().into();
+        group.throughput(Throughput::Elements(circuit.gates().len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &topology, |b, topology| {
+            b.iter(|| GarbledCircuit::<KEY_SIZE>::from(topology.clone()))
+        });
+    }
+
+    group.finish();
+}
+
+/// The conversion of a garbled circuit into the form sent to the receiver, in bytes of ciphertext per second
+fn convert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("convert");
+
+    for n in WIDTHS {
+        let circuit: GarbledCircuit = construct_circuit(n).into();
+        group.throughput(Throughput::Bytes(ciphertext_bytes(&circuit)));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &circuit, |b, circuit| {
+            b.iter(|| GarbledCircuitRecv::from(circuit.clone()))
+        });
+    }
+
+    group.finish();
+}
+
+/// Evaluation throughput, in gates per second
+fn eval(c: &mut Criterion) {
+    let mut group = c.benchmark_group("eval");
+
+    for n in WIDTHS {
+        let circuit: GarbledCircuit = construct_circuit(n).into();
+        let inputs = all_on_keys(&circuit);
+        group.throughput(Throughput::Elements(circuit.gates().len() as u64));
+        let recv_circuit: GarbledCircuitRecv = circuit.into();
+        group.bench_with_input(BenchmarkId::from_parameter(n), &inputs, |b, inputs| {
+            b.iter(|| recv_circuit.eval(inputs))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, garble, convert, eval);
+criterion_main!(benches);
@@ -1,5 +1,7 @@
 fn main() {
+    // The .proto file is parsed in Rust, so building doesn't need protoc installed
     protobuf_codegen::Codegen::new()
+        .pure()
         .cargo_out_dir("protos")
         .include("src")
         .input("src/protos/protos.proto")
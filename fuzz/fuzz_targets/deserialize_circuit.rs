@@ -0,0 +1,32 @@
+//! Feed arbitrary bytes to the receiver's circuit deserialization, the way a malicious garbler could.
+//! Whatever the bytes are, parsing and evaluating the circuit must fail with an error rather than panic
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use millionaire::{
+    backend::receiver_backend::{deserialize_circuit, receive_streamed_circuit, GarbledCircuitRecv},
+    garbling::KEY_SIZE,
+};
+
+/// Circuits with more inputs than this aren't evaluated, since we'd only run out of memory building their inputs
+const MAX_INPUTS: usize = 1 << 12;
+
+fn eval(circuit: &GarbledCircuitRecv) {
+    if circuit.n() <= MAX_INPUTS {
+        let _ = circuit.try_eval(&vec![[0u8; KEY_SIZE]; circuit.n()]);
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    // The circuit in a single message (see `serialize_circuit`)
+    if let Ok(circuit) = deserialize_circuit(data) {
+        eval(&circuit);
+    }
+
+    // The same bytes, as a circuit streamed gate by gate (see `send_garbled_circuit_streaming`)
+    if let Ok(circuit) = receive_streamed_circuit(&mut Cursor::new(data.to_vec())) {
+        eval(&circuit);
+    }
+});
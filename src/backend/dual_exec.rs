@@ -0,0 +1,197 @@
+#[cfg(feature = "sync")]
+use rand::{Rng, SeedableRng};
+#[cfg(feature = "sync")]
+use rand_chacha::ChaCha20Rng;
+use sha2::{Digest, Sha256};
+use std::io;
+#[cfg(feature = "sync")]
+use std::io::{Read, Write};
+
+#[cfg(feature = "sync")]
+use crate::{backend::garbler_backend::protos::OutputComparison, message::MessageStream};
+use crate::garbling::GarbledKeys;
+
+/// The keys of the output wires of a circuit we garbled, for the given output bits.
+/// Returns an error if there isn't a bit per output
+pub fn output_keys<const N: usize>(circuit: &impl GarbledKeys<N>, bits: &[bool]) -> Result<Vec<[u8; N]>, io::Error> {
+    let output_wires = circuit.output_wires();
+
+    if output_wires.len() != bits.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("circuit has {} outputs, but got {} bits", output_wires.len(), bits.len()),
+        ));
+    }
+
+    Ok(output_wires
+        .iter()
+        .zip(bits)
+        .map(|(wire, bit)| if *bit { wire.on_key() } else { wire.off_key() })
+        .collect())
+}
+
+/// The hash the parties compare: the output keys of the first execution's circuit, followed by those of the second's
+pub fn outputs_hash<const N: usize>(first: &[[u8; N]], second: &[[u8; N]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+
+    for key in first.iter().chain(second) {
+        hasher.update(key);
+    }
+
+    hasher.finalize().into()
+}
+
+/// The outputs of the comparison circuit (see `comparison_circuit`) with its inputs swapped.
+/// In the second execution the roles are reversed, so the other party's input comes first
+pub fn swapped_result(result: &[bool]) -> Vec<bool> {
+    match result {
+        [greater, equal] => vec![!greater && !equal, *equal],
+        _ => result.to_vec(),
+    }
+}
+
+/// The commitment the first party sends to its hash
+#[cfg(feature = "sync")]
+fn commitment(hash: &[u8], nonce: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(hash);
+    hasher.update(nonce);
+
+    hasher.finalize().to_vec()
+}
+
+/// Compare our hash of the output keys (see `outputs_hash`) with the other party's, aborting if they don't match.
+/// The `first` party commits to its hash before seeing the other's, and opens the commitment once it has, so neither
+/// party can simply echo the other's hash back.
+///
+/// This is the last step of dual execution (Mohassel and Franklin; Huang, Katz and Evans), which hardens the protocol against
+/// a cheating garbler: both parties garble the circuit and evaluate the other's, so each party ends up with the output keys of
+/// the circuit it evaluated, and knows every key of the circuit it garbled. A party computes the keys of the circuit it garbled
+/// from the result it got by evaluating the other party's, so unless both circuits computed the same result, the hashes differ.
+/// A cheating party can still learn a single bit (whether the hashes match), but it can't make the other party accept a wrong result
+#[cfg(feature = "sync")]
+pub fn compare_outputs(stream: &mut (impl Read + Write), hash: [u8; 32], first: bool) -> Result<(), io::Error> {
+    let mismatch = || io::Error::new(io::ErrorKind::InvalidData, "the outputs of the two executions don't match");
+
+    if first {
+        let nonce: [u8; 32] = ChaCha20Rng::from_entropy().gen();
+        let mut commitment_msg = OutputComparison::new();
+        commitment_msg.hash = commitment(&hash, &nonce);
+
+        MessageStream::<OutputComparison>::send_msg(stream, commitment_msg)?;
+        let theirs = MessageStream::<OutputComparison>::receive_msg(stream)?;
+        // Open the commitment even if the hashes don't match, so that the other party aborts as well
+        let mut opening_msg = OutputComparison::new();
+        opening_msg.hash = hash.to_vec();
+        opening_msg.nonce = nonce.to_vec();
+
+        MessageStream::<OutputComparison>::send_msg(stream, opening_msg)?;
+
+        if theirs.hash != hash {
+            return Err(mismatch());
+        }
+    } else {
+        let commitment_msg = MessageStream::<OutputComparison>::receive_msg(stream)?;
+        let mut hash_msg = OutputComparison::new();
+        hash_msg.hash = hash.to_vec();
+
+        MessageStream::<OutputComparison>::send_msg(stream, hash_msg)?;
+        let opening = MessageStream::<OutputComparison>::receive_msg(stream)?;
+
+        if commitment_msg.hash != commitment(&opening.hash, &opening.nonce) || opening.hash != hash {
+            return Err(mismatch());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::{
+        backend::{
+            garbler_backend::{comparison_circuit, decoding_table_msg},
+            receiver_backend::GarbledCircuitRecv,
+        },
+        circuit::{Circuit, Node},
+        garbling::GarbledCircuit,
+        gates::NOT_LEFT,
+    };
+
+    use super::{output_keys, outputs_hash, swapped_result};
+
+    /// Garble a comparison of `bits`-bit numbers and evaluate it with the given inputs,
+    /// returning the garbled circuit and the receiver's output keys and bits
+    fn execute(circuit: Circuit, first: usize, second: usize, bits: usize) -> (GarbledCircuit, Vec<[u8; 32]>, Vec<bool>) {
+        let garbled: GarbledCircuit = circuit.into();
+        let input_keys = garbled.input_keys();
+        let inputs: Vec<[u8; 32]> = (0..2 * bits)
+            .map(|i| {
+                let value = if i < bits { first >> i } else { second >> (i - bits) };
+
+                if value & 1 == 1 { input_keys[&i].on_key() } else { input_keys[&i].off_key() }
+            })
+            .collect();
+        let mut recv_circuit: GarbledCircuitRecv = garbled.clone().into();
+        recv_circuit.set_decoding_table(decoding_table_msg(&garbled));
+        let outs = recv_circuit.try_eval(&inputs).unwrap();
+        let result = recv_circuit.decode_bits(&outs).unwrap();
+
+        (garbled, outs, result)
+    }
+
+    #[test]
+    fn dual_exec_test() {
+        let (garbler_worth, receiver_worth) = (9, 3);
+        // The receiver evaluates the garbler's circuit, and then the garbler evaluates the receiver's (with the inputs swapped)
+        let (first_circuit, first_keys, first_result) = execute(comparison_circuit(4), garbler_worth, receiver_worth, 4);
+        let (second_circuit, second_keys, second_result) = execute(comparison_circuit(4), receiver_worth, garbler_worth, 4);
+
+        assert_eq!(first_result, vec![true, false]);
+        assert_eq!(swapped_result(&second_result), first_result);
+
+        // Each party computes the keys of the circuit it garbled from the result of the circuit it evaluated
+        let garbler_hash = outputs_hash(&output_keys(&first_circuit, &swapped_result(&second_result)).unwrap(), &second_keys);
+        let receiver_hash = outputs_hash(&first_keys, &output_keys(&second_circuit, &swapped_result(&first_result)).unwrap());
+
+        assert_eq!(garbler_hash, receiver_hash);
+
+        // A receiver that garbles a circuit with its outputs negated makes the garbler believe the wrong result,
+        // but then the hashes don't match
+        let outs = comparison_circuit(4).outs().into_iter().map(Rc::new);
+        let negated = Circuit::with_outputs(outs.map(|out| Node::Gate(NOT_LEFT, out.clone(), out)).collect()).unwrap();
+        let (_, cheated_keys, cheated_result) = execute(negated, receiver_worth, garbler_worth, 4);
+        let garbler_hash = outputs_hash(&output_keys(&first_circuit, &swapped_result(&cheated_result)).unwrap(), &cheated_keys);
+
+        assert_ne!(swapped_result(&cheated_result), first_result);
+        assert_ne!(garbler_hash, receiver_hash);
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn compare_outputs_test() {
+        use std::{
+            net::{TcpListener, TcpStream},
+            thread,
+        };
+
+        use super::compare_outputs;
+
+        // Both parties abort iff their hashes differ
+        for theirs in [[1u8; 32], [2u8; 32]] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let first = thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+
+                compare_outputs(&mut stream, [1u8; 32], true).is_ok()
+            });
+            let second = compare_outputs(&mut TcpStream::connect(addr).unwrap(), theirs, false).is_ok();
+
+            assert_eq!(first.join().unwrap(), theirs == [1u8; 32]);
+            assert_eq!(second, theirs == [1u8; 32]);
+        }
+    }
+}
@@ -1,135 +1,686 @@
-use std::{io, net::TcpStream};
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::cmp::Ordering;
+use std::io;
+#[cfg(feature = "sync")]
+use std::io::{Read, Write};
+use std::rc::Rc;
 
+#[cfg(feature = "async")]
+use crate::message::AsyncMessageStream;
+#[cfg(feature = "sync")]
+use crate::{
+    garbling::{StreamedCircuit, KEY_SIZE},
+    message::{MessageStream, ProtocolStep},
+    ot::{
+        extension::{MsgPair, OtExtensionSender, NUM_BASE_OTS},
+        simplest::SimplestOtReceiver,
+    },
+};
 use crate::{
-    backend::receiver_backend::GarbledNodeRecv,
     circuit::{self, Circuit},
-    garbling::GarbledCircuit,
-    message::MessageStream,
+    garbling::{color, key_commitment, GarbledCircuit, GarbledKeys, GateKind},
+    gates,
+};
+use protobuf::Message;
+use num_bigint::{BigInt, BigUint};
+#[cfg(feature = "sync")]
+use sha2::{Digest, Sha256};
+#[cfg(feature = "sync")]
+use protos::{
+    CircuitOutputs, OtBlindedIdx, OtBlindedIdxBatch, OtEncMessages, OtEncMessagesBatch, OtExtensionColumns, OtPublics,
+    StreamedCircuitHeader,
 };
-use protobuf::MessageField;
-use protos::{GarbledCircuitSend, GarbledNodeSend, GarblerKeys, Gate, Input};
+use protos::{DecodingTable, GarbledCircuitSend, GarblerKeys, Gate, KeyCommitments, OutputDecoding};
 
+#[cfg(feature = "sync")]
+use super::receiver_backend::{to_keys, GarbledNodeRecv};
 use super::receiver_backend::GarbledCircuitRecv;
 
 include!(concat!(env!("OUT_DIR"), "/protos/mod.rs"));
 
-const AND_GATE: u8 = 0b1000u8;
-const OR_GATE: u8 = 0b1110u8;
-const XNOR_GATE: u8 = 0b1001u8;
-/// $x \wedge \neg y$
-/// Truth table (top to bottom):
-/// F F T F
-const MY_GATE: u8 = 0b0100u8;
-
-// Convert a garbled node to the garbled node protobuf
-impl From<GarbledNodeRecv> for GarbledNodeSend {
-    fn from(value: GarbledNodeRecv) -> Self {
-        let mut input_send = GarbledNodeSend::new();
-
-        match value {
-            GarbledNodeRecv::Input(idx) => {
-                // Extract the input index from the message
-                let mut input_msg = Input::new();
-                input_msg.idx = idx as i64;
-                input_send.input = MessageField::some(input_msg);
-
-                input_send
-            }
-            GarbledNodeRecv::Gate(gate) => {
+/// The protobuf value of a gate's kind
+fn kind_msg(kind: GateKind) -> protos::GateKind {
+    match kind {
+        GateKind::Table => protos::GateKind::TABLE,
+        GateKind::FreeXor => protos::GateKind::FREE_XOR,
+        GateKind::HalfGates => protos::GateKind::HALF_GATES,
+    }
+}
+
+// Convert a garbled circuit to the garbled circuit protobuf
+impl From<&GarbledCircuitRecv> for GarbledCircuitSend {
+    fn from(value: &GarbledCircuitRecv) -> Self {
+        let n = value.n;
+        let mut circuit_msg = GarbledCircuitSend::new();
+
+        circuit_msg.gates = value
+            .gates
+            .iter()
+            .map(|gate| {
                 // Extract the gate data
                 let mut gate_msg = Gate::new();
-                gate_msg.c_00 = gate.c_00().unwrap();
-                gate_msg.c_01 = gate.c_01().unwrap();
-                gate_msg.c_10 = gate.c_10().unwrap();
-                gate_msg.c_11 = gate.c_11().unwrap();
-                gate_msg.left =
-                    MessageField::some(GarbledNodeSend::from(gate.left.unwrap().borrow().clone()));
-                gate_msg.right = MessageField::some(GarbledNodeSend::from(
-                    gate.right.unwrap().borrow().clone(),
-                ));
-                input_send.gate = MessageField::some(gate_msg);
-
-                input_send
+                gate_msg.kind = kind_msg(gate.kind()).into();
+                gate_msg.rows = gate.rows().to_vec();
+                gate_msg.inputs = gate.inputs.iter().map(|input| input.to_wire(n)).collect();
+
+                gate_msg
+            })
+            .collect();
+        circuit_msg.outs = value.outs.iter().map(|out| out.to_wire(n)).collect();
+        circuit_msg.n = n as i64;
+        circuit_msg.key_size = value.key_size as i64;
+
+        circuit_msg
+    }
+}
+
+impl From<GarbledCircuitRecv> for GarbledCircuitSend {
+    fn from(value: GarbledCircuitRecv) -> Self {
+        (&value).into()
+    }
+}
+
+/// Build the message containing the keys corresponding to our input (sent by `send_input_keys`): the key of each input wire
+/// in `owned_indices` for the corresponding bit of `values`. Returns an error if there isn't a value per index, or if an index
+/// isn't an input of the circuit or appears twice
+pub fn input_keys_msg<const N: usize>(
+    circuit: &impl GarbledKeys<N>,
+    owned_indices: &[usize],
+    values: &[bool],
+) -> Result<GarblerKeys, io::Error> {
+    if owned_indices.len() != values.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("got {} input indices, but {} values", owned_indices.len(), values.len()),
+        ));
+    }
+
+    let mut keys_msg = GarblerKeys::new();
+
+    for (&idx, &value) in owned_indices.iter().zip(values) {
+        // The receiver would reject the keys anyway, but it's better to catch this before the keys are sent
+        let wire = match circuit.input_wire(idx) {
+            Some(wire) if !keys_msg.indices.contains(&(idx as u64)) => wire,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("input {} isn't an input of the circuit, or is owned twice", idx),
+                ))
             }
-        }
+        };
+
+        keys_msg.keys.push(if value { wire.on_key().to_vec() } else { wire.off_key().to_vec() });
+        keys_msg.indices.push(idx as u64);
+    }
+
+    Ok(keys_msg)
+}
+
+/// The width in bits of the net worths the binaries compare (see `comparison_circuit`)
+pub const NET_WORTH_BITS: usize = 10;
+
+/// Check that a value fits in a given number of bits, so that it isn't silently truncated to its low bits (see `to_bits`)
+pub fn check_width(value: usize, bits: usize) -> Result<(), io::Error> {
+    if bits < usize::BITS as usize && value >> bits != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("value must be < {}, got {}", 1usize << bits, value),
+        ));
+    }
+
+    Ok(())
+}
+
+/// The bits of a number (least significant first), as fed into `comparison_circuit`
+pub fn to_bits(value: usize, len: usize) -> Vec<bool> {
+    (0..len).map(|i| (value & (1 << i)) != 0).collect()
+}
+
+/// The `len` bits of a decimal number of any size (least significant first, like `to_bits`), so that inputs wider than a
+/// `usize` can be compared. Returns an error if the string isn't a number, or if the number doesn't fit in `len` bits
+pub fn parse_bits(value: &str, len: usize) -> Result<Vec<bool>, io::Error> {
+    let number = BigUint::parse_bytes(value.as_bytes(), 10)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} isn't a decimal number", value)))?;
+
+    if number.bits() > len as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("value must be < 2^{}, got {}", len, number),
+        ));
+    }
+
+    Ok((0..len as u64).map(|i| number.bit(i)).collect())
+}
+
+/// Like `parse_bits`, but for a number that may be negative, whose `len` bits are its two's complement
+/// (see `signed_comparison_circuit`). Returns an error if the string isn't a number, or if the number isn't in [-2^(len - 1), 2^(len - 1))
+pub fn parse_signed_bits(value: &str, len: usize) -> Result<Vec<bool>, io::Error> {
+    let number = BigInt::parse_bytes(value.as_bytes(), 10)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} isn't a decimal number", value)))?;
+    let bound = BigInt::from(1) << len.saturating_sub(1);
+
+    if len == 0 || number < -&bound || number >= bound {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("value must be in [-2^{}, 2^{}), got {}", len.saturating_sub(1), len.saturating_sub(1), number),
+        ));
     }
+
+    Ok((0..len as u64).map(|i| number.bit(i)).collect())
+}
+
+/// Build the message committing to both keys of every input wire (sent by `send_input_keys`).
+/// The commitments to each wire's keys are ordered by the keys' colors, which are random, so their order doesn't
+/// reveal which key is the on key
+pub fn key_commitments_msg<const N: usize>(circuit: &impl GarbledKeys<N>) -> KeyCommitments {
+    let mut commitments_msg = KeyCommitments::new();
+
+    commitments_msg.commitments = (0..circuit.n())
+        .flat_map(|idx| {
+            let wire = circuit.input_wire(idx).unwrap();
+            let (off_key, on_key) = (wire.off_key(), wire.on_key());
+            let keys = if color(&off_key) { [on_key, off_key] } else { [off_key, on_key] };
+
+            keys.map(|key| key_commitment(idx, &key).to_vec())
+        })
+        .collect();
+
+    commitments_msg
 }
 
-/// Send the keys corresponding to our input to the receiver
+/// Send the keys corresponding to our input to the receiver: we own the input wires in `owned_indices`, and the i-th of them
+/// carries `values[i]`. The message tells the receiver which wires are ours, and the receiver's inputs are the rest (see `receiver_indices`).
 /// Note that since we don't tell the receiver which keys correspond to which bit value (on/off),
-/// the receiver can't learn anything about our inputs
-pub fn send_input_keys(
-    stream: &mut TcpStream,
-    circuit: &GarbledCircuit,
-    net_worth: usize,
+/// the receiver can't learn anything about our inputs.
+///
+/// The keys are preceded by commitments to both keys of every input wire (ours and the receiver's), which the receiver
+/// checks the keys it gets (from us and from OT) against (see `verify_input_keys`). This alone isn't security against a
+/// malicious garbler, which could still garble the wrong circuit, but it catches a garbler that substitutes keys it hasn't committed to
+#[cfg(feature = "sync")]
+pub fn send_input_keys<const N: usize>(
+    stream: &mut (impl Read + Write),
+    circuit: &impl GarbledKeys<N>,
+    owned_indices: &[usize],
+    values: &[bool],
 ) -> Result<(), io::Error> {
-    // Extract the keys we need to send based on the garbler's net worth
-    let mut keys_msg = GarblerKeys::new();
-    let mut keys = vec![];
-    let key_map = circuit.input_keys();
+    let keys_msg = input_keys_msg(circuit, owned_indices, values)?;
+
+    MessageStream::<KeyCommitments>::send_msg(stream, key_commitments_msg(circuit))?;
+    MessageStream::<GarblerKeys>::send_msg(stream, keys_msg)?;
+
+    Ok(())
+}
+
+/// The async version of `send_input_keys`.
+/// The messages are built before the returned future is first polled, so the future doesn't hold on to the
+/// circuit (which isn't `Send`) and can be spawned onto a multithreaded runtime
+#[cfg(feature = "async")]
+pub fn send_input_keys_async<'a, const N: usize>(
+    stream: &'a mut tokio::net::TcpStream,
+    circuit: &impl GarbledKeys<N>,
+    owned_indices: &[usize],
+    values: &[bool],
+) -> impl Future<Output = Result<(), io::Error>> + Send + 'a {
+    let commitments_msg = key_commitments_msg(circuit);
+    let keys_msg = input_keys_msg(circuit, owned_indices, values);
+
+    async move {
+        let keys_msg = keys_msg?;
+
+        AsyncMessageStream::<KeyCommitments>::send_msg(stream, commitments_msg).await?;
+        AsyncMessageStream::<GarblerKeys>::send_msg(stream, keys_msg).await?;
+
+        Ok(())
+    }
+}
+
+/// Build the message containing the garbled circuit (sent by `send_garbled_circuit`)
+pub fn garbled_circuit_msg<const N: usize>(garbled_circuit: GarbledCircuit<N>) -> GarbledCircuitSend {
+    // "dumb down" the circuit to a form the receiver can understand
+    let recv_circuit: GarbledCircuitRecv = garbled_circuit.into();
+
+    recv_circuit.into()
+}
+
+/// Serialize the garbled circuit (i.e. the message built by `garbled_circuit_msg`) to bytes.
+/// This doesn't depend on any transport, so the host can move the bytes however it likes (e.g. from a browser);
+/// the receiver parses them with `deserialize_circuit`
+pub fn serialize_circuit<const N: usize>(garbled_circuit: GarbledCircuit<N>) -> Result<Vec<u8>, io::Error> {
+    Ok(garbled_circuit_msg(garbled_circuit).write_to_bytes()?)
+}
 
-    for key_idx in 0..circuit.n() / 2 {
-        let wire = key_map.get(&key_idx).unwrap();
+/// Build the message containing the circuit's output decoding table (sent by `send_decoding_table`)
+pub fn decoding_table_msg<const N: usize>(garbled_circuit: &impl GarbledKeys<N>) -> DecodingTable {
+    let mut table_msg = DecodingTable::new();
+    table_msg.outs = garbled_circuit
+        .decoding_table()
+        .into_iter()
+        .map(|(off_hash, on_hash)| {
+            let mut decoding_msg = OutputDecoding::new();
+            decoding_msg.off_hash = off_hash.to_vec();
+            decoding_msg.on_hash = on_hash.to_vec();
 
-        keys.push(
-            // Is the current bit set or not?
-            if (net_worth & (1 << key_idx)) != 0 {
-                wire.on_key().to_vec()
-            } else {
-                wire.off_key().to_vec()
-            },
-        );
+            decoding_msg
+        })
+        .collect();
+
+    table_msg
+}
+
+/// Decode the output keys the receiver got by evaluating our circuit (see `EvalResult`) into its outputs.
+/// The receiver only learns one key of each output wire, so it can't send us the key of an output it didn't get,
+/// which lets us learn the result without trusting the receiver to report it honestly.
+/// Returns an error if there isn't a key per output, or if a key isn't one of its output wire's keys
+pub fn decode_output_keys<const N: usize>(circuit: &impl GarbledKeys<N>, keys: &[Vec<u8>]) -> Result<Vec<bool>, io::Error> {
+    let output_wires = circuit.output_wires();
+
+    if output_wires.len() != keys.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("circuit has {} outputs, but got {} keys", output_wires.len(), keys.len()),
+        ));
     }
 
-    keys_msg.keys = keys;
+    output_wires
+        .iter()
+        .zip(keys)
+        .enumerate()
+        .map(|(idx, (wire, key))| match key.as_slice() {
+            key if key == wire.on_key() => Ok(true),
+            key if key == wire.off_key() => Ok(false),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the key of output {} isn't one of the output's keys", idx),
+            )),
+        })
+        .collect()
+}
 
-    MessageStream::<GarblerKeys>::send_msg(stream, keys_msg)?;
+/// Send the receiver the hashes of the output wires' keys, so that it can decode its output keys into bits.
+/// The output keys are random like all other keys, so the receiver can't tell which bit a key stands for without the table
+#[cfg(feature = "sync")]
+pub fn send_decoding_table<const N: usize>(
+    stream: &mut (impl Read + Write),
+    garbled_circuit: &impl GarbledKeys<N>,
+) -> Result<(), io::Error> {
+    MessageStream::<DecodingTable>::send_msg(stream, decoding_table_msg(garbled_circuit))?;
 
     Ok(())
 }
 
+/// The async version of `send_decoding_table` (see `send_input_keys_async`)
+#[cfg(feature = "async")]
+pub fn send_decoding_table_async<'a, const N: usize>(
+    stream: &'a mut tokio::net::TcpStream,
+    garbled_circuit: &impl GarbledKeys<N>,
+) -> impl Future<Output = Result<(), io::Error>> + Send + 'a {
+    let table_msg = decoding_table_msg(garbled_circuit);
+
+    async move {
+        AsyncMessageStream::<DecodingTable>::send_msg(stream, table_msg).await?;
+
+        Ok(())
+    }
+}
+
 /// Send the garbled circuit to the receiver
-pub fn send_garbled_circuit(
-    stream: &mut TcpStream,
-    garbled_circuit: GarbledCircuit,
+#[cfg(feature = "sync")]
+pub fn send_garbled_circuit<const N: usize>(
+    stream: &mut (impl Read + Write),
+    garbled_circuit: GarbledCircuit<N>,
 ) -> Result<(), io::Error> {
-    let n = garbled_circuit.n();
-    // "dumb down" the circuit to a form the receiver can understand
-    let recv_circuit: GarbledCircuitRecv = garbled_circuit.into();
-    let out_msg: GarbledNodeSend = recv_circuit.out.into();
-    // Send the garbled circuit to the receiver
-    let mut garbled_circuit_msg = GarbledCircuitSend::new();
-    garbled_circuit_msg.n = n as i64;
-    garbled_circuit_msg.out = MessageField::some(out_msg);
-    MessageStream::<GarbledCircuitSend>::send_msg(stream, garbled_circuit_msg)?;
+    MessageStream::<GarbledCircuitSend>::send_msg(stream, garbled_circuit_msg(garbled_circuit))?;
+
+    Ok(())
+}
+
+/// The async version of `send_garbled_circuit` (see `send_input_keys_async`)
+#[cfg(feature = "async")]
+pub fn send_garbled_circuit_async<'a, const N: usize>(
+    stream: &'a mut tokio::net::TcpStream,
+    garbled_circuit: GarbledCircuit<N>,
+) -> impl Future<Output = Result<(), io::Error>> + Send + 'a {
+    let garbled_circuit_msg = garbled_circuit_msg(garbled_circuit);
+
+    async move {
+        AsyncMessageStream::<GarbledCircuitSend>::send_msg(stream, garbled_circuit_msg).await?;
+
+        Ok(())
+    }
+}
+
+/// A SHA-256 of the messages a garbled circuit is streamed in (see `send_garbled_circuit_streaming`), which lets the receiver
+/// detect a circuit that was truncated or corrupted in transfer. It's the same for the messages the receiver parses
+/// as for the ones we send, since they serialize to the same bytes
+#[cfg(feature = "sync")]
+#[derive(Default)]
+pub struct CircuitDigest(Sha256);
+
+#[cfg(feature = "sync")]
+impl CircuitDigest {
+    /// Add the next message of the circuit
+    pub fn update(&mut self, msg: &impl Message) -> Result<(), io::Error> {
+        self.0.update(msg.write_to_bytes()?);
+
+        Ok(())
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        self.0.finalize().to_vec()
+    }
+}
+
+/// Garble the circuit and send it to the receiver gate by gate (see `StreamedCircuit::garble`), so that neither the garbled
+/// circuit nor its message is ever held in memory as a whole. The receiver reads it with `receive_streamed_circuit`.
+/// The returned circuit holds the keys we need for the rest of the protocol
+#[cfg(feature = "sync")]
+pub fn send_garbled_circuit_streaming<const N: usize>(
+    stream: &mut (impl Read + Write),
+    circuit: Circuit,
+) -> Result<StreamedCircuit<N>, io::Error> {
+    let n = circuit.n();
+    let mut header = StreamedCircuitHeader::new();
+    header.n = n as i64;
+    header.key_size = N as i64;
+    header.num_gates = circuit.num_gates() as u64;
+    let mut digest = CircuitDigest::default();
+
+    digest.update(&header)?;
+    // The number of bytes sent so far, size prefixes included
+    let mut sent = MessageStream::<StreamedCircuitHeader>::send_msg(stream, header)?;
+
+    let streamed = StreamedCircuit::garble(circuit, |gate| {
+        let mut gate_msg = Gate::new();
+        gate_msg.kind = kind_msg(gate.kind()).into();
+        gate_msg.rows = gate.rows().to_vec();
+        gate_msg.inputs = gate
+            .inputs
+            .iter()
+            .map(|input| GarbledNodeRecv::from(*input).to_wire(n))
+            .collect();
+
+        digest.update(&gate_msg)?;
+        sent += MessageStream::<Gate>::send_msg(stream, gate_msg)?;
+
+        Ok(())
+    })?;
+    let mut outs_msg = CircuitOutputs::new();
+    outs_msg.outs = streamed
+        .outs()
+        .into_iter()
+        .map(|out| GarbledNodeRecv::from(out).to_wire(n))
+        .collect();
+    digest.update(&outs_msg)?;
+    outs_msg.digest = digest.finalize();
+
+    sent += MessageStream::<CircuitOutputs>::send_msg(stream, outs_msg)?;
+    log::info!("sent a garbled circuit of {} gates in {} bytes", streamed.num_gates(), sent);
+
+    Ok(streamed)
+}
+
+/// Send the receiver one message of each pair in `msgs` using OT extension (see `OtExtensionSender`), so that however many
+/// pairs there are, it only takes `NUM_BASE_OTS` public-key OTs. The receiver (see `receive_by_ot`) acts as the sender of
+/// these base OTs, which are simplest OTs (see `SimplestOtSender`), so it sends the first message.
+/// Returns an error if the receiver's messages are malformed, e.g. if a group element it sent isn't in the group
+#[cfg(feature = "sync")]
+pub fn send_by_ot(stream: &mut (impl Read + Write), msgs: Vec<MsgPair>) -> Result<(), io::Error> {
+    let sender = OtExtensionSender::new(msgs);
+    let choices = sender.base_choices();
+    // The base OTs, in which we're the receiver
+    let publics_msg = MessageStream::<OtPublics>::receive_msg(stream).step("receiving the base OT public values")?;
+
+    if publics_msg.publics.len() != NUM_BASE_OTS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expected {} base OT public values, got {}", NUM_BASE_OTS, publics_msg.publics.len()),
+        ));
+    }
+
+    let base_receivers: Vec<SimplestOtReceiver> = publics_msg
+        .publics
+        .iter()
+        .map(|public| {
+            // A public value outside the group could leak our choice
+            SimplestOtReceiver::new(BigUint::from_bytes_be(public))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "a public value isn't an element of the group"))
+        })
+        .collect::<Result<_, io::Error>>()
+        .step("receiving the base OT public values")?;
+    let mut blinded_idxs = OtBlindedIdxBatch::new();
+    blinded_idxs.idxs = base_receivers
+        .iter()
+        .zip(&choices)
+        .map(|(receiver, s_j)| {
+            let mut blinded_idx = OtBlindedIdx::new();
+            blinded_idx.v = receiver.blind_idx(*s_j as usize).to_bytes_be();
+
+            blinded_idx
+        })
+        .collect();
+
+    MessageStream::<OtBlindedIdxBatch>::send_msg(stream, blinded_idxs).step("sending the base OT blinded indices")?;
+    let seeds_batch = MessageStream::<OtEncMessagesBatch>::receive_msg(stream).step("receiving the base OT replies")?;
+
+    if seeds_batch.msgs.len() != NUM_BASE_OTS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expected {} base OT replies, got {}", NUM_BASE_OTS, seeds_batch.msgs.len()),
+        ));
+    }
+
+    let base_keys = base_receivers
+        .iter()
+        .zip(choices)
+        .zip(seeds_batch.msgs)
+        .map(|((receiver, s_j), m_primes_msg)| {
+            // The masked messages are seeds, so a reply of any other size is malformed
+            let m_primes = to_keys::<KEY_SIZE>(&[m_primes_msg.m_prime_0, m_primes_msg.m_prime_1])?;
+
+            Ok(receiver.derive_msg((m_primes[0], m_primes[1]), s_j as usize))
+        })
+        .collect::<Result<_, io::Error>>()
+        .step("receiving the base OT replies")?;
+    // Extend the base OTs into one OT per message pair
+    let columns = MessageStream::<OtExtensionColumns>::receive_msg(stream).step("receiving the OT extension columns")?;
+    let mut m_primes_batch = OtEncMessagesBatch::new();
+    m_primes_batch.msgs = sender
+        .gen_combined(base_keys, columns.us)
+        .step("receiving the OT extension columns")?
+        .into_iter()
+        .map(|(m_prime_0, m_prime_1)| {
+            let mut m_primes_msg = OtEncMessages::new();
+            m_primes_msg.m_prime_0 = m_prime_0.to_vec();
+            m_primes_msg.m_prime_1 = m_prime_1.to_vec();
+
+            m_primes_msg
+        })
+        .collect();
+
+    MessageStream::<OtEncMessagesBatch>::send_msg(stream, m_primes_batch).step("sending the OT replies")?;
 
     Ok(())
 }
 
+/// Run our (the garbler's) side of the protocol: garble the circuit and stream it to the receiver, send it the decoding table
+/// and our keys, and then send it its keys using OT extension (an extended OT for each of the receiver's input bits, see
+/// `send_by_ot`), which takes two round trips however wide the receiver's input is. Our `input` bits (see `to_bits` and
+/// `parse_bits`) are fed into the first inputs of the circuit, and the rest are the receiver's. The receiver's side is
+/// `run_receiver`. Returns the garbled circuit, which holds its keys.
+/// Returns an error before sending anything if the circuit doesn't have an input for each of our bits
+#[cfg(feature = "sync")]
+pub fn run_garbler(
+    stream: &mut (impl Read + Write),
+    circuit: Circuit,
+    input: &[bool],
+) -> Result<StreamedCircuit<KEY_SIZE>, io::Error> {
+    if input.len() > circuit.n() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("the circuit has {} inputs, so we can't own {} of them", circuit.n(), input.len()),
+        ));
+    }
+
+    // The circuit is garbled as it's sent to the client, so it's never held in memory as a whole
+    let circuit = send_garbled_circuit_streaming::<KEY_SIZE>(stream, circuit).step("sending the garbled circuit")?;
+
+    // Send the receiver the table that decodes its output keys
+    send_decoding_table(stream, &circuit).step("sending the decoding table")?;
+    // Send the receiver our input keys
+    let owned_indices: Vec<usize> = (0..input.len()).collect();
+
+    send_input_keys(stream, &circuit, &owned_indices, input).step("sending our keys")?;
+    log::debug!("sent our keys for {} inputs", owned_indices.len());
+    // Send the receiver its keys using OT, one for each of its input bits
+    let msgs: Vec<MsgPair> = (0..circuit.n())
+        .filter(|i| !owned_indices.contains(i))
+        .map(|i| {
+            let wire = circuit.input_wire(i).unwrap();
+
+            (wire.off_key(), wire.on_key())
+        })
+        .collect();
+    let num_ots = msgs.len();
+
+    send_by_ot(stream, msgs)?;
+    log::info!("sent the receiver its keys using {} extended OTs", num_ots);
+
+    Ok(circuit)
+}
+
 /// Construct a digital comparison circuit 
-/// where each input is of size n bits
-pub fn construct_circuit(n: usize) -> GarbledCircuit {
-    let a_vals: Vec<circuit::Node> = (0..n).map(circuit::Node::Input).collect();
-    let b_vals: Vec<circuit::Node> = (0..n).map(|i| circuit::Node::Input(n + i)).collect();
-    let xs: Vec<circuit::Node> = (0..n).map(|i| circuit::Node::Gate(XNOR_GATE, Box::new(a_vals[i].clone()), Box::new(b_vals[i].clone()))).collect();
+/// where each input is of size n bits.
+/// The circuit has two outputs: whether the first input is greater than the second, and whether the two inputs are equal.
+/// Identical subtrees (such as the XNOR of each pair of bits) are shared, so that they're only garbled once.
+/// The circuit only depends on `n`, so it can be built once and garbled (with fresh wire keys) for every session.
+/// Panics if `n` is 0
+pub fn construct_circuit(n: usize) -> Circuit {
+    construct_comparison(n, n)
+}
+
+/// Like `construct_circuit`, but the first input is `a_bits` bits wide and the second is `b_bits` bits wide
+/// (see `asymmetric_comparison_circuit`). Panics if a width is 0
+pub fn construct_comparison(a_bits: usize, b_bits: usize) -> Circuit {
+    asymmetric_comparison_circuit(a_bits, b_bits).dedup_subexpressions()
+}
+
+/// The (plain) digital comparison circuit, before `construct_circuit` shares its identical subtrees. Panics if `n` is 0
+pub fn comparison_circuit(n: usize) -> Circuit {
+    asymmetric_comparison_circuit(n, n)
+}
+
+/// The comparison circuit of an `a_bits`-bit input (inputs `0..a_bits`) and a `b_bits`-bit input (inputs `a_bits..a_bits + b_bits`).
+/// The shorter input is zero-extended inside the circuit, and the circuit is then optimized (see `Circuit::optimize`),
+/// so that the padding doesn't cost any gates. Panics if a width is 0
+pub fn asymmetric_comparison_circuit(a_bits: usize, b_bits: usize) -> Circuit {
+    comparison(a_bits, b_bits, false)
+}
+
+/// Like `construct_comparison`, but for two's complement inputs (see `signed_comparison_circuit`). Panics if a width is 0
+pub fn construct_signed_comparison(a_bits: usize, b_bits: usize) -> Circuit {
+    signed_comparison_circuit(a_bits, b_bits).dedup_subexpressions()
+}
+
+/// The comparison circuit of two's complement inputs (see `parse_signed_bits`), e.g. for comparing debts. It's the same as
+/// `asymmetric_comparison_circuit` except at the top bit, which is the sign: there, the first input is greater if its bit is
+/// clear and the second's is set. The shorter input is sign-extended rather than zero-extended, which is free too.
+/// Panics if a width is 0
+pub fn signed_comparison_circuit(a_bits: usize, b_bits: usize) -> Circuit {
+    comparison(a_bits, b_bits, true)
+}
+
+/// The comparison circuit of an `a_bits`-bit input and a `b_bits`-bit input, which are unsigned or two's complement if `signed`
+fn comparison(a_bits: usize, b_bits: usize, signed: bool) -> Circuit {
+    assert!(
+        a_bits > 0 && b_bits > 0,
+        "the inputs of a comparison circuit must be at least 1 bit wide, but they're {} and {} bits wide",
+        a_bits,
+        b_bits
+    );
+
+    let n = a_bits.max(b_bits);
+    // A padding bit, which is constant regardless of the input it's computed from
+    let zero = || circuit::Node::Gate(gates::FALSE, Rc::new(circuit::Node::Input(0)), Rc::new(circuit::Node::Input(0)));
+    // Signed inputs are padded with their sign bit
+    let pad = |top: usize| if signed { circuit::Node::Input(top) } else { zero() };
+    let a_vals: Vec<circuit::Node> = (0..n).map(|i| if i < a_bits { circuit::Node::Input(i) } else { pad(a_bits - 1) }).collect();
+    let b_vals: Vec<circuit::Node> =
+        (0..n).map(|i| if i < b_bits { circuit::Node::Input(a_bits + i) } else { pad(a_bits + b_bits - 1) }).collect();
+    let xs: Vec<circuit::Node> = (0..n).map(|i| circuit::Node::Gate(gates::XNOR, Rc::new(a_vals[i].clone()), Rc::new(b_vals[i].clone()))).collect();
     // The AND comparison gates
     let mut out: Option<circuit::Node> = None;
 
     for i in (0..n).rev() {
-        let mut cmp_hat = circuit::Node::Gate(MY_GATE, Box::new(a_vals[i].clone()), Box::new(b_vals[i].clone()));
+        // A set sign bit makes a number smaller, rather than larger
+        let op = if signed && i == n - 1 { gates::NOT_LEFT_AND_RIGHT } else { gates::LEFT_AND_NOT_RIGHT };
+        let mut cmp_hat = circuit::Node::Gate(op, Rc::new(a_vals[i].clone()), Rc::new(b_vals[i].clone()));
 
         for x in xs.iter().take(n).skip(i+1) {
-            cmp_hat = circuit::Node::Gate(AND_GATE, Box::new(cmp_hat.clone()), Box::new(x.clone()));
+            cmp_hat = circuit::Node::Gate(gates::AND, Rc::new(cmp_hat.clone()), Rc::new(x.clone()));
         }
 
         if out.is_some() {
-            out = Some(circuit::Node::Gate(OR_GATE, Box::new(out.unwrap().clone()), Box::new(cmp_hat.clone())));
+            out = Some(circuit::Node::Gate(gates::OR, Rc::new(out.unwrap().clone()), Rc::new(cmp_hat.clone())));
         } else {
             out = Some(cmp_hat);
         }
     }
 
-    let circuit = Circuit::new(out.unwrap());
+    // The inputs are equal iff all of their bits are equal
+    let mut eq = xs[0].clone();
+
+    for x in xs.iter().skip(1) {
+        eq = circuit::Node::Gate(gates::AND, Rc::new(eq), Rc::new(x.clone()));
+    }
+
+    Circuit::with_outputs(vec![out.unwrap(), eq])
+        .expect("the comparison circuit uses all of its inputs")
+        .optimize()
+}
+
+/// The equality circuit of two `n`-bit inputs (inputs `0..n` and `n..2n`), whose single output is whether they're equal.
+/// The XNORs of each pair of bits are ANDed together in a balanced tree, so the circuit's depth only grows logarithmically in `n`.
+/// Panics if `n` is 0
+pub fn equality_circuit(n: usize) -> Circuit {
+    let mut level: Vec<circuit::Node> = (0..n)
+        .map(|i| circuit::Node::Gate(gates::XNOR, Rc::new(circuit::Node::Input(i)), Rc::new(circuit::Node::Input(n + i))))
+        .collect();
+
+    while level.len() > 1 {
+        let mut pairs = level.into_iter();
+        let mut next = vec![];
+
+        while let Some(left) = pairs.next() {
+            next.push(match pairs.next() {
+                Some(right) => circuit::Node::Gate(gates::AND, Rc::new(left), Rc::new(right)),
+                None => left,
+            });
+        }
+
+        level = next;
+    }
+
+    Circuit::with_outputs(level).expect("the equality circuit uses all of its inputs")
+}
+
+/// The comparison circuit (see `comparison_circuit`) with its first output negated, so that it tells whether the first input
+/// is at most the second, i.e. whether the second party is at least as rich. Together with the second output (whether the inputs
+/// are equal), this lets the party whose input comes second compute the comparison from its own perspective
+pub fn negated_comparison_circuit(n: usize) -> Circuit {
+    comparison_circuit(n).negate_output(0).expect("the comparison circuit has two outputs")
+}
 
-    circuit.into()
+/// How the first input of a comparison circuit compares to the second, given the circuit's outputs.
+/// Returns an error if there aren't exactly two outputs (e.g. if the outputs came from some other circuit)
+pub fn comparison_result(outputs: &[bool]) -> Result<Ordering, io::Error> {
+    match outputs {
+        [_, true] => Ok(Ordering::Equal),
+        [true, false] => Ok(Ordering::Greater),
+        [false, false] => Ok(Ordering::Less),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("circuit has {} outputs, expected 2", outputs.len()),
+        )),
+    }
 }
\ No newline at end of file
@@ -0,0 +1,276 @@
+use std::{cmp::Ordering, collections::HashMap, io};
+
+use rand::Rng;
+
+use crate::{
+    backend::{
+        garbler_backend::{
+            check_width, comparison_circuit, comparison_result, construct_circuit, decoding_table_msg, input_keys_msg,
+            key_commitments_msg, serialize_circuit, to_bits,
+        },
+        receiver_backend::{
+            assemble_inputs, deserialize_circuit, receiver_indices, to_keys, verify_input_keys, GarbledCircuitRecv,
+        },
+    },
+    circuit::Circuit,
+    garbling::{GarbledCircuit, KEY_SIZE},
+    ot::{
+        extension::{OtExtensionReceiver, OtExtensionSender},
+        simplest::{SimplestOtReceiver, SimplestOtSender},
+    },
+};
+
+/// Run the whole protocol in memory, without any sockets, comparing the lowest `bits` bits of each party's net worth.
+/// The garbled circuit, its decoding table and the garbler's keys (and commitments to them) are serialized to the same bytes the binaries send over the network,
+/// but instead of running OT, the receiver's keys are taken directly from the garbled circuit.
+/// Returns how the garbler's net worth compares to the receiver's
+pub fn run_millionaire(garbler_worth: usize, receiver_worth: usize, bits: usize) -> Ordering {
+    let circuit: GarbledCircuit = construct_circuit(bits).into();
+    let input_keys = circuit.input_keys();
+    // The garbler's keys, as sent to the receiver
+    let commitments = key_commitments_msg(&circuit);
+    let owned_indices: Vec<usize> = (0..bits).collect();
+    let keys_msg = input_keys_msg(&circuit, &owned_indices, &to_bits(garbler_worth, bits)).unwrap();
+    // The receiver's keys, which it would get using OT
+    let own_keys: Vec<[u8; KEY_SIZE]> = receiver_indices(2 * bits, &keys_msg)
+        .unwrap()
+        .into_iter()
+        .zip(to_bits(receiver_worth, bits))
+        .map(|(idx, bit)| if bit { input_keys[&idx].on_key() } else { input_keys[&idx].off_key() })
+        .collect();
+    let inputs = assemble_inputs(2 * bits, &keys_msg, &own_keys).unwrap();
+    verify_input_keys(&commitments, &inputs).unwrap();
+
+    let table_msg = decoding_table_msg(&circuit);
+    let mut circuit_recv = deserialize_circuit(&serialize_circuit(circuit).unwrap()).unwrap();
+    circuit_recv.set_decoding_table(table_msg);
+    // The first output is whether the garbler is richer, and the second is whether they're tied
+    comparison_result(&circuit_recv.eval_bits(&inputs).unwrap()).unwrap()
+}
+
+/// Garble a circuit, pick the key of each input for its bit in `inputs`, and evaluate the garbled circuit as the receiver would,
+/// returning its first output. This is `Circuit::eval` through garbling, so checking that garbling preserves a circuit's
+/// function only takes comparing the two. Panics if there isn't a bit per input
+pub fn simulate(circuit: &Circuit, inputs: &[bool]) -> bool {
+    assert_eq!(inputs.len(), circuit.n(), "expected a bit per input of the circuit");
+
+    let garbled: GarbledCircuit = circuit.clone().into();
+    let input_keys = garbled.input_keys();
+    let keys: Vec<[u8; KEY_SIZE]> = inputs
+        .iter()
+        .enumerate()
+        .map(|(i, bit)| if *bit { input_keys[&i].on_key() } else { input_keys[&i].off_key() })
+        .collect();
+    let circuit_recv: GarbledCircuitRecv = garbled.into();
+
+    circuit_recv.decode_bits(&circuit_recv.eval(&keys)).unwrap()[0]
+}
+
+/// A party that feeds inputs into a circuit evaluated by `simulate_multiparty`
+pub type PartyId = usize;
+
+/// The party that garbles the circuit in `simulate_multiparty`
+pub const GARBLER: PartyId = 0;
+
+/// Simulate evaluating a circuit on the inputs of any number of parties, with all of them in memory. `owners` maps each input
+/// of the circuit to the party it belongs to, and `inputs` holds each party's bits, in the order of its inputs. The garbler
+/// (`GARBLER`) garbles the circuit and hands out its own keys, every other party gets its keys from the garbler using OT and
+/// hands them to the evaluator, and the evaluator checks every key against the garbler's commitments before evaluating the circuit.
+/// This is only a simulation: the network protocol (`run_garbler` and `run_receiver`) is still between two parties,
+/// where the garbler owns the first inputs and the receiver owns the rest.
+/// Returns the circuit's outputs, or an error if an input has no owner, or if a party doesn't have a bit per input it owns
+pub fn simulate_multiparty(
+    circuit: Circuit,
+    owners: &HashMap<usize, PartyId>,
+    inputs: &HashMap<PartyId, Vec<bool>>,
+) -> Result<Vec<bool>, io::Error> {
+    let n = circuit.n();
+
+    if let Some(idx) = (0..n).find(|idx| !owners.contains_key(idx)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("input {} has no owner", idx)));
+    }
+
+    let owned_by = |party: PartyId| -> Vec<usize> { (0..n).filter(|idx| owners[idx] == party).collect() };
+    let bits_of = |party: PartyId, indices: &[usize]| -> Result<&[bool], io::Error> {
+        let bits = inputs.get(&party).map(Vec::as_slice).unwrap_or(&[]);
+
+        if bits.len() != indices.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("party {} owns {} inputs, but has {} bits", party, indices.len(), bits.len()),
+            ));
+        }
+
+        Ok(bits)
+    };
+
+    let circuit: GarbledCircuit = circuit.into();
+    let commitments = key_commitments_msg(&circuit);
+    // The garbler's keys, as it would send them to the evaluator
+    let garbler_indices = owned_by(GARBLER);
+    let keys_msg = input_keys_msg(&circuit, &garbler_indices, bits_of(GARBLER, &garbler_indices)?)?;
+    let mut keys: HashMap<usize, [u8; KEY_SIZE]> = garbler_indices.into_iter().zip(to_keys(&keys_msg.keys)?).collect();
+    // Every other party gets its keys using OT
+    let mut parties: Vec<PartyId> = owners.values().copied().filter(|party| *party != GARBLER).collect();
+    parties.sort_unstable();
+    parties.dedup();
+
+    for party in parties {
+        let indices = owned_by(party);
+        let own_keys = transfer_keys(&circuit, &indices, bits_of(party, &indices)?)?;
+
+        keys.extend(indices.into_iter().zip(own_keys));
+    }
+
+    let input_keys: Vec<[u8; KEY_SIZE]> = (0..n).map(|idx| keys[&idx]).collect();
+    verify_input_keys(&commitments, &input_keys)?;
+
+    let table_msg = decoding_table_msg(&circuit);
+    let mut circuit_recv = deserialize_circuit(&serialize_circuit(circuit)?)?;
+    circuit_recv.set_decoding_table(table_msg);
+
+    circuit_recv.eval_bits(&input_keys)
+}
+
+/// Get the receiver the keys of the inputs at `indices` for the given bits, using the same OT extension that the binaries
+/// run over the network (see `send_by_ot` and `receive_by_ot`), but with both sides in memory
+fn transfer_keys(circuit: &GarbledCircuit, indices: &[usize], bits: &[bool]) -> Result<Vec<[u8; KEY_SIZE]>, io::Error> {
+    let msgs = indices
+        .iter()
+        .map(|idx| {
+            let wire = circuit.input_wire(*idx).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("input {} isn't an input of the circuit", idx))
+            })?;
+
+            Ok((wire.off_key(), wire.on_key()))
+        })
+        .collect::<Result<_, io::Error>>()?;
+    let sender = OtExtensionSender::new(msgs);
+    let receiver = OtExtensionReceiver::new(bits.to_vec());
+    // The base OTs, with the roles reversed
+    let base_keys = receiver
+        .base_msgs()
+        .into_iter()
+        .zip(sender.base_choices())
+        .map(|(seeds, s_j)| {
+            let base_sender = SimplestOtSender::new(seeds);
+            // Our own public value is always an element of the group, and so is a blinded index
+            let base_receiver = SimplestOtReceiver::new(base_sender.public()).unwrap();
+            let m_primes = base_sender.gen_combined(base_receiver.blind_idx(s_j as usize)).unwrap();
+
+            base_receiver.derive_msg(m_primes, s_j as usize)
+        })
+        .collect();
+
+    Ok(receiver.derive_msgs(sender.gen_combined(base_keys, receiver.gen_us())?))
+}
+
+/// Check the whole pipeline locally, without a second party: for a few pairs of `bits`-bit values (the extremes and a random pair),
+/// garble the comparison circuit, get the receiver's keys using OT, evaluate the circuit as the receiver would,
+/// and check that the result is the same as that of evaluating the plaintext circuit.
+/// Returns an error describing the first mismatch, if there is one
+pub fn self_test(bits: usize) -> Result<(), io::Error> {
+    let max = (1 << bits) - 1;
+    let mut rng = rand::thread_rng();
+    let pairs = [(0, 0), (max, max), (max, 0), (0, max), (rng.gen_range(0..=max), rng.gen_range(0..=max))];
+    let plaintext = comparison_circuit(bits);
+    // Every pair is compared with a freshly garbled copy of the same circuit
+    let topology = construct_circuit(bits);
+
+    for (garbler_worth, receiver_worth) in pairs {
+        check_width(garbler_worth, bits)?;
+        check_width(receiver_worth, bits)?;
+
+        let circuit: GarbledCircuit = topology.clone().into();
+        let commitments = key_commitments_msg(&circuit);
+        let owned_indices: Vec<usize> = (0..bits).collect();
+        let keys_msg = input_keys_msg(&circuit, &owned_indices, &to_bits(garbler_worth, bits))?;
+        let own_keys = transfer_keys(&circuit, &receiver_indices(2 * bits, &keys_msg)?, &to_bits(receiver_worth, bits))?;
+        let inputs = assemble_inputs(2 * bits, &keys_msg, &own_keys)?;
+        verify_input_keys(&commitments, &inputs)?;
+
+        let table_msg = decoding_table_msg(&circuit);
+        let mut circuit_recv = deserialize_circuit(&serialize_circuit(circuit)?)?;
+        circuit_recv.set_decoding_table(table_msg);
+        let garbled = circuit_recv.eval_bits(&inputs)?;
+        let expected = plaintext.eval_outputs(&[to_bits(garbler_worth, bits), to_bits(receiver_worth, bits)].concat());
+
+        if garbled != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "comparing {} with {}: the garbled circuit gave {:?}, but the plaintext circuit gave {:?}",
+                    garbler_worth, receiver_worth, garbled, expected
+                ),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, rc::Rc};
+
+    use crate::{
+        circuit::{Circuit, Node},
+        gates::{AND, XOR},
+    };
+
+    use super::{run_millionaire, self_test, simulate, simulate_multiparty, GARBLER};
+
+    #[test]
+    fn run_millionaire_test() {
+        for garbler_worth in 0..16 {
+            for receiver_worth in 0..16 {
+                assert_eq!(
+                    run_millionaire(garbler_worth, receiver_worth, 4),
+                    garbler_worth.cmp(&receiver_worth)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn simulate_test() {
+        let input = |idx| Rc::new(Node::Input(idx));
+        // A two-input gate, a free one, and a table gate
+        let majority = Node::GateN(vec![false, false, false, true, false, true, true, true], (0..3).map(input).collect());
+        let circuit = Circuit::new(Node::Gate(XOR, Rc::new(Node::Gate(AND, input(0), input(3))), Rc::new(majority))).unwrap();
+
+        for bits in 0..16usize {
+            let bits: Vec<bool> = (0..4).map(|i| bits & (1 << i) != 0).collect();
+
+            assert_eq!(simulate(&circuit, &bits), circuit.eval(&bits));
+        }
+    }
+
+    #[test]
+    fn self_test_test() {
+        self_test(4).unwrap();
+    }
+
+    #[test]
+    fn simulate_multiparty_test() {
+        // Three parties with a bit each: the majority of the bits, and their parity
+        let input = |idx| Rc::new(Node::Input(idx));
+        let majority = Node::GateN(vec![false, false, false, true, false, true, true, true], (0..3).map(input).collect());
+        let parity = Node::Gate(XOR, Rc::new(Node::Gate(XOR, input(0), input(1))), input(2));
+        let circuit = || Circuit::with_outputs(vec![majority.clone(), parity.clone()]).unwrap();
+        let owners = HashMap::from([(0, GARBLER), (1, 1), (2, 2)]);
+
+        for bits in 0..8usize {
+            let bits: Vec<bool> = (0..3).map(|i| bits & (1 << i) != 0).collect();
+            let inputs = HashMap::from([(GARBLER, vec![bits[0]]), (1, vec![bits[1]]), (2, vec![bits[2]])]);
+
+            assert_eq!(simulate_multiparty(circuit(), &owners, &inputs).unwrap(), circuit().eval_outputs(&bits));
+        }
+
+        // Every input must have an owner, and every party a bit per input it owns
+        let inputs = HashMap::from([(GARBLER, vec![true]), (1, vec![true]), (2, vec![true])]);
+        assert!(simulate_multiparty(circuit(), &HashMap::from([(0, GARBLER), (1, 1)]), &inputs).is_err());
+        let inputs = HashMap::from([(GARBLER, vec![true]), (1, vec![true, false]), (2, vec![true])]);
+        assert!(simulate_multiparty(circuit(), &owners, &inputs).is_err());
+    }
+}
@@ -1,2 +1,6 @@
+pub mod dual_exec;
 pub mod garbler_backend;
+pub mod in_process;
+pub mod offline;
 pub mod receiver_backend;
+pub mod session;
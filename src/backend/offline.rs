@@ -0,0 +1,138 @@
+use std::io;
+
+use protobuf::{Message, MessageField};
+
+use crate::{
+    backend::{
+        garbler_backend::{
+            decoding_table_msg, garbled_circuit_msg, input_keys_msg, key_commitments_msg,
+            protos::{GarbledCircuitFile, WireKeys},
+        },
+        receiver_backend::{assemble_inputs, receiver_indices, to_keys, verify_input_keys, ConversionError, GarbledCircuitRecv},
+    },
+    garbling::GarbledCircuit,
+};
+
+/// The version of the `GarbledCircuitFile` format written by `serialize_circuit_file`
+pub const CIRCUIT_FILE_VERSION: u32 = 1;
+
+/// Serialize a garbled circuit, along with its decoding table and the garbler's keys for its `input` bits (which are fed into
+/// the first inputs of the circuit, like in `run_garbler`), into a file that `eval_circuit_file` evaluates offline.
+/// The receiver's keys are written as they are rather than sent using OT, so the file must only be used for testing.
+/// Returns an error if the circuit doesn't have an input for each of our bits
+pub fn serialize_circuit_file<const N: usize>(circuit: GarbledCircuit<N>, input: &[bool]) -> Result<Vec<u8>, io::Error> {
+    let owned_indices: Vec<usize> = (0..input.len()).collect();
+    let garbler_keys = input_keys_msg(&circuit, &owned_indices, input)?;
+    let input_keys = circuit.input_keys();
+    let mut file = GarbledCircuitFile::new();
+
+    file.version = CIRCUIT_FILE_VERSION;
+    file.decoding_table = MessageField::some(decoding_table_msg(&circuit));
+    file.commitments = MessageField::some(key_commitments_msg(&circuit));
+    file.receiver_keys = (input.len()..circuit.n())
+        .map(|idx| {
+            let mut keys = WireKeys::new();
+            keys.off_key = input_keys[&idx].off_key().to_vec();
+            keys.on_key = input_keys[&idx].on_key().to_vec();
+
+            keys
+        })
+        .collect();
+    file.garbler_keys = MessageField::some(garbler_keys);
+    file.circuit = MessageField::some(garbled_circuit_msg(circuit));
+
+    Ok(file.write_to_bytes()?)
+}
+
+/// Evaluate a circuit serialized with `serialize_circuit_file` as the receiver, on our `input` bits (zero-extended to the
+/// inputs the garbler left us, like in `run_receiver`). The keys are checked against the garbler's commitments, just like
+/// when the circuit is received over the network. Returns the bits the circuit's outputs decode to.
+/// Returns an error if the file is malformed (e.g. missing one of its messages) or of another version,
+/// or if our input doesn't fit in our inputs
+pub fn eval_circuit_file<const N: usize>(bytes: &[u8], input: &[bool]) -> Result<Vec<bool>, io::Error> {
+    let file = GarbledCircuitFile::parse_from_bytes(bytes)?;
+
+    if file.version != CIRCUIT_FILE_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("the file is of version {}, expected {}", file.version, CIRCUIT_FILE_VERSION),
+        ));
+    }
+
+    let mut circuit: GarbledCircuitRecv = required(file.circuit, "circuit")?.try_into()?;
+    circuit.set_decoding_table(required(file.decoding_table, "decoding table")?);
+    let n = circuit.n();
+    let garbler_keys = required(file.garbler_keys, "garbler keys")?;
+    let our_indices = receiver_indices(n, &garbler_keys)?;
+
+    if file.receiver_keys.len() != our_indices.len() || input.iter().skip(our_indices.len()).any(|bit| *bit) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "the file has keys for {} of our inputs, but the garbler left us {}, which our input must fit in",
+                file.receiver_keys.len(),
+                our_indices.len()
+            ),
+        ));
+    }
+
+    // Pick the key of each of our inputs, like OT would have
+    let own_keys: Vec<Vec<u8>> = file
+        .receiver_keys
+        .into_iter()
+        .enumerate()
+        .map(|(i, keys)| if input.get(i).copied().unwrap_or(false) { keys.on_key } else { keys.off_key })
+        .collect();
+    let inputs = assemble_inputs::<N>(n, &garbler_keys, &to_keys(&own_keys)?)?;
+    verify_input_keys(&required(file.commitments, "key commitments")?, &inputs)?;
+
+    circuit.eval_bits(&inputs)
+}
+
+/// A message of the file, which `serialize_circuit_file` always sets
+fn required<M>(field: MessageField<M>, name: &'static str) -> Result<M, ConversionError> {
+    field.into_option().ok_or(ConversionError::MissingField(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use protobuf::Message;
+
+    use crate::{
+        backend::garbler_backend::{comparison_result, construct_circuit, protos::GarbledCircuitFile, to_bits},
+        garbling::{GarbledCircuit, KEY_SIZE},
+    };
+
+    use super::{eval_circuit_file, serialize_circuit_file};
+
+    #[test]
+    fn circuit_file_test() {
+        let circuit: GarbledCircuit = construct_circuit(4).into();
+        let bytes = serialize_circuit_file(circuit, &to_bits(9, 4)).unwrap();
+
+        // The same file can be evaluated on any of the receiver's inputs
+        for receiver_worth in 0..16 {
+            let outputs = eval_circuit_file::<KEY_SIZE>(&bytes, &to_bits(receiver_worth, 4)).unwrap();
+
+            assert_eq!(comparison_result(&outputs).unwrap(), 9.cmp(&receiver_worth));
+        }
+
+        // Our input must fit in our inputs
+        assert!(eval_circuit_file::<KEY_SIZE>(&bytes, &to_bits(16, 5)).is_err());
+
+        // A file of another version, or with a substituted key, is rejected
+        let mut file = GarbledCircuitFile::parse_from_bytes(&bytes).unwrap();
+        file.version += 1;
+        assert!(eval_circuit_file::<KEY_SIZE>(&file.write_to_bytes().unwrap(), &[]).is_err());
+
+        let mut file = GarbledCircuitFile::parse_from_bytes(&bytes).unwrap();
+        file.receiver_keys[0].off_key[0] ^= 1;
+        assert!(eval_circuit_file::<KEY_SIZE>(&file.write_to_bytes().unwrap(), &to_bits(0, 4)).is_err());
+
+        // So is a file missing one of its messages, rather than evaluating an empty circuit
+        let mut file = GarbledCircuitFile::parse_from_bytes(&bytes).unwrap();
+        file.circuit.clear();
+        let err = eval_circuit_file::<KEY_SIZE>(&file.write_to_bytes().unwrap(), &to_bits(0, 4)).unwrap_err();
+        assert!(err.to_string().contains("missing its circuit"), "{}", err);
+    }
+}
@@ -1,132 +1,1042 @@
-use protobuf::MessageField;
-use std::{cell::RefCell, rc::Rc};
+#[cfg(feature = "sync")]
+use num_bigint::BigUint;
+use protobuf::Message;
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Read},
+};
+#[cfg(feature = "sync")]
+use std::io::Write;
+use sha2::{Digest, Sha256};
+use subtle::{Choice, ConditionallySelectable, ConstantTimeEq};
+#[cfg(feature = "sync")]
+use zeroize::Zeroizing;
 
+#[cfg(feature = "sync")]
 use crate::{
-    backend::garbler_backend::protos::{GarbledCircuitSend, GarbledNodeSend},
-    crypto::aes_ctr::AesCtr,
-    garbling::{GarbledCircuit, GarbledNode},
+    backend::garbler_backend::CircuitDigest,
+    backend::garbler_backend::protos::{OtBlindedIdxBatch, OtEncMessages, OtEncMessagesBatch, OtExtensionColumns, OtPublics},
+    message::{MessageStream, ProtocolStep},
+    ot::{
+        extension::{OtExtensionReceiver, NUM_BASE_OTS},
+        simplest::SimplestOtSender,
+    },
+};
+use crate::{
+    backend::garbler_backend::protos::{
+        self, CircuitOutputs, DecodingTable, GarbledCircuitSend, GarblerKeys, Gate, KeyCommitments, StreamedCircuitHeader,
+    },
+    circuit::{Circuit, CircuitVisitor, Node, MAX_GATE_INPUTS},
+    crypto::{fixed_key::FixedKeyAes, GarbleCipher},
+    garbling::{
+        anf, color, gate_tweak, key_commitment, label_hash, output_hash, row_key, row_pad, xor, GarbledCircuit, GarbledNode,
+        GateKind, KEY_SIZE, TAG_SIZE,
+    },
 };
 
-const KEY_SIZE: usize = 32;
-
-/// From the receiver's POV, a gate is defined by its ciphertexts and its children
+/// From the receiver's POV, a gate is defined by its ciphertexts and its children.
+/// How many rows a gate has depends on its kind (see `GateKind`)
 #[derive(Clone)]
 pub struct GarbledGateRecv {
-    c_00: Option<Vec<u8>>,
-    c_01: Option<Vec<u8>>,
-    c_10: Option<Vec<u8>>,
-    c_11: Option<Vec<u8>>,
-    pub left: Option<Rc<RefCell<GarbledNodeRecv>>>,
-    pub right: Option<Rc<RefCell<GarbledNodeRecv>>>,
+    kind: GateKind,
+    rows: Vec<Vec<u8>>,
+    pub inputs: Vec<GarbledNodeRecv>,
 }
 
-/// A node in the circuit can be either an input or a gate (like `Circuit` and `GarbledCircuit`)
-#[derive(Clone)]
+/// A node in the circuit can be either an input or a gate (like `Circuit` and `GarbledCircuit`).
+/// Gates are referred to by their index in the circuit's gate arena
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum GarbledNodeRecv {
     Input(usize),
-    Gate(GarbledGateRecv),
+    Gate(usize),
 }
 
-/// A garbled circuit from the receiver's POV 
+/// The key of every input and gate of an evaluated circuit (see `GarbledCircuitRecv::eval_trace`)
+pub type EvalTrace<const N: usize = KEY_SIZE> = HashMap<GarbledNodeRecv, [u8; N]>;
+
+/// A garbled circuit from the receiver's POV.
+/// The gates are stored in topological order, and a gate's index is its id (see `gate_tweak`)
 pub struct GarbledCircuitRecv {
-    pub(crate) out: GarbledNodeRecv,
+    pub(crate) gates: Vec<GarbledGateRecv>,
+    pub(crate) outs: Vec<GarbledNodeRecv>,
     pub(crate) n: usize,
+    /// The size of the circuit's wire keys in bytes
+    pub(crate) key_size: usize,
+    /// The hashes of the off and on keys of each output wire, which map output keys to bits
+    pub(crate) decoding: Vec<([u8; 32], [u8; 32])>,
 }
 
 impl GarbledGateRecv {
-    pub fn c_00(&self) -> Option<Vec<u8>> {
-        self.c_00.clone()
+    /// A gate of kind `kind` with the given rows, fed by `inputs` (in order), e.g. for tests
+    pub fn new(kind: GateKind, rows: Vec<Vec<u8>>, inputs: Vec<GarbledNodeRecv>) -> GarbledGateRecv {
+        GarbledGateRecv { kind, rows, inputs }
+    }
+
+    /// The gate's rows (see `GateKind`)
+    pub fn rows(&self) -> &[Vec<u8>] {
+        &self.rows
+    }
+
+    pub fn kind(&self) -> GateKind {
+        self.kind
+    }
+
+    /// Parse a gate from its protobuf, given the number of inputs to its circuit (see
+    /// `GarbledNodeRecv::from_wire`)
+    fn from_msg(gate: Gate, n: usize) -> GarbledGateRecv {
+        // Like any proto3 enum, an unknown kind is read as the default one
+        let kind = match gate.kind.enum_value_or_default() {
+            protos::GateKind::TABLE => GateKind::Table,
+            protos::GateKind::FREE_XOR => GateKind::FreeXor,
+            protos::GateKind::HALF_GATES => GateKind::HalfGates,
+        };
+
+        GarbledGateRecv {
+            kind,
+            rows: gate.rows,
+            inputs: gate.inputs.into_iter().map(|input| GarbledNodeRecv::from_wire(input, n)).collect(),
+        }
+    }
+
+    /// Evaluate this gate (whose id is `id`) given the keys coming from its children (in order).
+    /// Returns `None` if none of the rows of a `Table` gate authenticated under our keys
+    fn eval<H: GarbleCipher, const N: usize>(&self, id: u64, input_keys: &[[u8; N]]) -> Option<[u8; N]> {
+        match self.kind {
+            GateKind::Table => self.eval_table::<H, N>(id, input_keys),
+            GateKind::FreeXor => Some(input_keys.iter().fold([0u8; N], |out, key| xor(&out, key))),
+            GateKind::HalfGates => {
+                let (x, y) = (&input_keys[0], &input_keys[1]);
+                let row = |idx: usize| -> [u8; N] { self.rows[idx].as_slice().try_into().unwrap() };
+                let scale = |bit: bool, key: &[u8; N]| if bit { *key } else { [0u8; N] };
+                // W_G = H(X) ^ s_x * T_G and W_E = H(Y) ^ s_y * (T_E ^ X) (see `half_gate`)
+                let w_g = xor(&label_hash::<H, N>(gate_tweak(id, 0), x), &scale(color(x), &row(0)));
+                let w_e = xor(&label_hash::<H, N>(gate_tweak(id, 1), y), &scale(color(y), &xor(&row(1), x)));
+
+                Some(xor(&w_g, &w_e))
+            }
+        }
     }
 
-    pub fn c_01(&self) -> Option<Vec<u8>> {
-        self.c_01.clone()
+    /// Evaluate a `Table` gate, by decrypting the row encrypted under our keys
+    fn eval_table<H: GarbleCipher, const N: usize>(&self, id: u64, input_keys: &[[u8; N]]) -> Option<[u8; N]> {
+        let key = row_key(input_keys);
+
+        // Decrypt each of this gate's rows; only the tag of the row encrypted
+        // under our keys matches, and the row contains this gate's output key
+        let decryptions = (0..self.rows.len()).map(|pos| self.row_decryption::<H, N>(&key, id, pos));
+
+        select_row(decryptions)
     }
 
-    pub fn c_10(&self) -> Option<Vec<u8>> {
-        self.c_10.clone()
+    /// Decrypt row `pos` of a `Table` gate (whose id is `id`) under the row key of its input keys
+    /// (see `row_key`), returning the plaintext and whether the row's tag authenticated
+    fn row_decryption<H: GarbleCipher, const N: usize>(&self, key: &[u8; 32], id: u64, pos: usize) -> (Vec<u8>, Choice) {
+        let row = &self.rows[pos];
+        let (ciphertext, row_tag) = match row.len() {
+            // If the gate is row-reduced, the first row is only its tag, with a zero ciphertext
+            TAG_SIZE if pos == 0 => (&[0u8; N][..], &row[..]),
+            len if len == N + TAG_SIZE => row.split_at(N),
+            // The length of a row is public, so we can skip a row of the wrong length
+            _ => return (vec![], Choice::from(0)),
+        };
+        let (pad, tag) = row_pad::<H, N>(key, id, pos);
+        let plaintext = ciphertext.iter().zip(pad).map(|(c, p)| c ^ p).collect();
+
+        (plaintext, row_tag.ct_eq(&tag))
     }
 
-    pub fn c_11(&self) -> Option<Vec<u8>> {
-        self.c_11.clone()
+    /// Decrypt row `pos` of a `Table` gate (whose id is `id`) under its children's keys, for
+    /// testing. Unlike evaluating the gate, this isn't constant-time. Returns `None` if there's no
+    /// such row, or if it didn't authenticate
+    pub fn decrypt_row<const N: usize>(&self, id: u64, pos: usize, input_keys: &[[u8; N]]) -> Option<[u8; N]> {
+        self.decrypt_row_with::<FixedKeyAes, N>(id, pos, input_keys)
+    }
+
+    /// Same as `decrypt_row`, for a row encrypted with `H`
+    pub fn decrypt_row_with<H: GarbleCipher, const N: usize>(
+        &self,
+        id: u64,
+        pos: usize,
+        input_keys: &[[u8; N]],
+    ) -> Option<[u8; N]> {
+        if pos >= self.rows.len() {
+            return None;
+        }
+
+        let (plaintext, valid) = self.row_decryption::<H, N>(&row_key(input_keys), id, pos);
+
+        bool::from(valid).then(|| plaintext.try_into().ok()).flatten()
     }
 }
 
 impl GarbledNodeRecv {
-    /// Evaluate the garbled circuit based on a vector of input keys
-    pub fn eval(&self, inputs: &Vec<[u8; KEY_SIZE]>) -> [u8; KEY_SIZE] {
+    /// The node referred to by a wire index in the circuit protobuf: indices below `n` are inputs,
+    /// and index `n + i` is the i-th gate
+    pub(crate) fn from_wire(wire: u64, n: usize) -> GarbledNodeRecv {
+        match wire.checked_sub(n as u64) {
+            Some(gate) => GarbledNodeRecv::Gate(gate as usize),
+            None => GarbledNodeRecv::Input(wire as usize),
+        }
+    }
+
+    /// The inverse of `from_wire`
+    pub(crate) fn to_wire(self, n: usize) -> u64 {
         match self {
-            Self::Input(idx) => inputs[*idx],
-            Self::Gate(gate) => {
-                // Construct ciphers based on the keys coming from our left and right children
-                // (this is done by recursively calling `eval` on our children)
-                let left_out = gate.left.as_ref().unwrap().borrow().eval(inputs);
-                let right_out = gate.right.as_ref().unwrap().borrow().eval(inputs);
-                let left_cipher = AesCtr::new(&left_out);
-                let right_cipher = AesCtr::new(&right_out);
-                // The correct key is appended with 32 zeros
-                let suffix = [0u8; KEY_SIZE];
-                // Decrypt each of this gate's ciphertexts based on the two ciphers we constructed
-                // Only one decryption will be valid
-                let d_00 =
-                    right_cipher.decrypt(&left_cipher.decrypt(gate.c_00.as_ref().unwrap(), 0), 0);
-                let d_01 =
-                    right_cipher.decrypt(&left_cipher.decrypt(gate.c_01.as_ref().unwrap(), 0), 0);
-                let d_10 =
-                    right_cipher.decrypt(&left_cipher.decrypt(gate.c_10.as_ref().unwrap(), 0), 0);
-                let d_11 =
-                    right_cipher.decrypt(&left_cipher.decrypt(gate.c_11.as_ref().unwrap(), 0), 0);
-
-                // Get this gate's output key by checking which decryption ends with the correct suffix
-                if d_00.ends_with(&suffix) {
-                    d_00[0..KEY_SIZE].try_into().unwrap()
-                } else if d_01.ends_with(&suffix) {
-                    d_01[0..KEY_SIZE].try_into().unwrap()
-                } else if d_10.ends_with(&suffix) {
-                    d_10[0..KEY_SIZE].try_into().unwrap()
-                } else {
-                    d_11[0..KEY_SIZE].try_into().unwrap()
-                }
-            }
+            GarbledNodeRecv::Input(idx) => idx as u64,
+            GarbledNodeRecv::Gate(idx) => (n + idx) as u64,
+        }
+    }
+}
+
+/// Convert the keys received over the network into arrays of `N` bytes. Returns an error if any of
+/// the keys isn't exactly `N` bytes long (see `ConversionError::BadKeyLength`)
+pub fn to_keys<const N: usize>(keys: &[Vec<u8>]) -> Result<Vec<[u8; N]>, io::Error> {
+    keys.iter()
+        .enumerate()
+        .map(|(i, key)| {
+            key.as_slice().try_into().map_err(|_| ConversionError::BadKeyLength { key: i, len: key.len(), expected: N }.into())
+        })
+        .collect()
+}
+
+/// The input wires the garbler's keys are for (see `send_input_keys`), in a circuit with `n`
+/// inputs. Returns an error if an index isn't an input of the circuit or appears twice, or if there
+/// isn't an index per key
+fn garbler_indices(n: usize, keys_msg: &GarblerKeys) -> Result<Vec<usize>, io::Error> {
+    // A garbler that doesn't say which inputs are its own owns the first ones
+    if keys_msg.indices.is_empty() {
+        return Ok((0..keys_msg.keys.len().min(n)).collect());
+    }
+
+    if keys_msg.indices.len() != keys_msg.keys.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("got {} keys, but {} input indices", keys_msg.keys.len(), keys_msg.indices.len()),
+        ));
+    }
+
+    let mut indices = vec![];
+
+    for &idx in &keys_msg.indices {
+        if idx as usize >= n || indices.contains(&(idx as usize)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the garbler sent a key for input {}, which isn't an input or was already sent", idx),
+            ));
+        }
+
+        indices.push(idx as usize);
+    }
+
+    Ok(indices)
+}
+
+/// Our input wires in a circuit with `n` inputs: every input the garbler didn't send a key for, in
+/// order. Returns an error if the garbler's indices are invalid (see `garbler_indices`)
+pub fn receiver_indices(n: usize, keys_msg: &GarblerKeys) -> Result<Vec<usize>, io::Error> {
+    let garbler = garbler_indices(n, keys_msg)?;
+
+    Ok((0..n).filter(|idx| !garbler.contains(idx)).collect())
+}
+
+/// Put together the keys of all of a circuit's `n` inputs (in order), from the garbler's keys and
+/// our keys (for the inputs given by `receiver_indices`, in order). Returns an error if the
+/// garbler's keys or indices are invalid, or if we don't have a key for each of our inputs
+pub fn assemble_inputs<const N: usize>(n: usize, keys_msg: &GarblerKeys, own_keys: &[[u8; N]]) -> Result<Vec<[u8; N]>, io::Error> {
+    let garbler_keys: Vec<[u8; N]> = to_keys(&keys_msg.keys)?;
+    let garbler = garbler_indices(n, keys_msg)?;
+    let ours = receiver_indices(n, keys_msg)?;
+
+    if garbler_keys.len() != garbler.len() || own_keys.len() != ours.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expected keys for {} inputs, got {}", n, garbler_keys.len() + own_keys.len()),
+        ));
+    }
+
+    let mut inputs = vec![[0u8; N]; n];
+
+    for (idx, key) in garbler.into_iter().zip(garbler_keys).chain(ours.into_iter().zip(own_keys.iter().copied())) {
+        inputs[idx] = key;
+    }
+
+    Ok(inputs)
+}
+
+/// Check the keys of all of the circuit's input wires (in order) against the garbler's commitments
+/// (see `send_input_keys`). Returns an error if there isn't a pair of commitments per key, or if a
+/// key isn't one of the two its wire's commitments open to
+pub fn verify_input_keys<const N: usize>(commitments: &KeyCommitments, keys: &[[u8; N]]) -> Result<(), io::Error> {
+    if commitments.commitments.len() != 2 * keys.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expected {} key commitments, got {}", 2 * keys.len(), commitments.commitments.len()),
+        ));
+    }
+
+    for (idx, key) in keys.iter().enumerate() {
+        // The commitments to each wire's keys are ordered by color
+        if commitments.commitments[2 * idx + color(key) as usize] != key_commitment(idx, key) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the key of input {} doesn't match the garbler's commitment", idx),
+            ));
         }
     }
+
+    Ok(())
+}
+
+/// Select the output key from the decryptions of a gate's rows, given whether each one
+/// authenticated. Every row is examined and selected in constant time, so our timing doesn't reveal
+/// which row decrypted. Returns `None` if no row authenticated
+pub(crate) fn select_row<const N: usize>(rows: impl IntoIterator<Item = (Vec<u8>, Choice)>) -> Option<[u8; N]> {
+    let mut out = [0u8; N];
+    let mut found = Choice::from(0);
+
+    for (plaintext, valid) in rows {
+        // The length of a row is public, so a row of the wrong length can simply be skipped
+        if plaintext.len() != N {
+            continue;
+        }
+
+        for (out_byte, row_byte) in out.iter_mut().zip(&plaintext) {
+            out_byte.conditional_assign(row_byte, valid);
+        }
+
+        found |= valid;
+    }
+
+    bool::from(found).then_some(out)
+}
+
+/// The error for a gate (given its id) none of whose rows authenticated under our keys
+fn undecryptable(id: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("no row of garbled gate {} authenticated under our keys", id),
+    )
 }
 
 impl GarbledCircuitRecv {
-    pub fn eval(&self, inputs: &Vec<[u8; KEY_SIZE]>) -> [u8; KEY_SIZE] {
-        self.out.eval(inputs)
+    /// Evaluate the garbled circuit, returning the key of every output wire. `N` must match the key
+    /// size the circuit was garbled with (see `key_size`). The gates are evaluated in order, each
+    /// one once however many gates use it. Panics if none of a gate's rows authenticate (which
+    /// `try_eval` reports as an error instead)
+    pub fn eval<const N: usize>(&self, inputs: &[[u8; N]]) -> Vec<[u8; N]> {
+        self.eval_gates::<FixedKeyAes, N>(inputs, &mut Vec::with_capacity(self.gates.len()))
+            .unwrap_or_else(|id| panic!("no row of garbled gate {} authenticated", id))
+    }
+
+    /// Evaluate the gates in order (see `eval`) with `H`, pushing each gate's key to `gate_outs`.
+    /// Returns the id of the first gate that didn't decrypt, if any
+    fn eval_gates<H: GarbleCipher, const N: usize>(
+        &self,
+        inputs: &[[u8; N]],
+        gate_outs: &mut Vec<[u8; N]>,
+    ) -> Result<Vec<[u8; N]>, usize> {
+        let key = |node: GarbledNodeRecv, gate_outs: &[[u8; N]]| match node {
+            GarbledNodeRecv::Input(idx) => inputs[idx],
+            GarbledNodeRecv::Gate(idx) => gate_outs[idx],
+        };
+
+        for (id, gate) in self.gates.iter().enumerate() {
+            let input_keys: Vec<[u8; N]> = gate.inputs.iter().map(|input| key(*input, gate_outs)).collect();
+            let out = gate.eval::<H, N>(id as u64, &input_keys).ok_or(id)?;
+            gate_outs.push(out);
+        }
+
+        Ok(self.outs.iter().map(|out| key(*out, gate_outs)).collect())
+    }
+
+    /// Same as `eval`, but first checks that there's a key per input, that every gate only refers
+    /// to inputs and earlier gates, and that it has as many rows as its kind calls for.
+    /// Undecryptable gates are errors too, so no circuit a garbler sends can make us panic
+    pub fn try_eval<const N: usize>(&self, inputs: &[[u8; N]]) -> Result<Vec<[u8; N]>, io::Error> {
+        self.try_eval_with::<FixedKeyAes, N>(inputs)
+    }
+
+    /// Same as `try_eval`, for a circuit garbled with `H` (see `GarbledCircuit::from_with_cipher`)
+    pub fn try_eval_with<H: GarbleCipher, const N: usize>(&self, inputs: &[[u8; N]]) -> Result<Vec<[u8; N]>, io::Error> {
+        self.validate(inputs)?;
+
+        self.eval_gates::<H, N>(inputs, &mut Vec::with_capacity(self.gates.len())).map_err(undecryptable)
+    }
+
+    /// Evaluate the circuit like `try_eval` on each of several assignments of keys to its inputs,
+    /// validating it once for all of them. Returns an error naming the first assignment that fails
+    pub fn eval_many<const N: usize>(&self, inputs: &[Vec<[u8; N]>]) -> Result<Vec<Vec<[u8; N]>>, io::Error> {
+        self.eval_many_with::<FixedKeyAes, N>(inputs)
+    }
+
+    /// Same as `eval_many`, for a circuit whose rows were encrypted with `H`
+    pub fn eval_many_with<H: GarbleCipher, const N: usize>(
+        &self,
+        inputs: &[Vec<[u8; N]>],
+    ) -> Result<Vec<Vec<[u8; N]>>, io::Error> {
+        match inputs.first() {
+            Some(first) => self.validate(first)?,
+            None => return Ok(vec![]),
+        }
+
+        let mut gate_outs = Vec::with_capacity(self.gates.len());
+
+        inputs
+            .iter()
+            .enumerate()
+            .map(|(i, keys)| {
+                let assignment_error = |e: io::Error| io::Error::new(e.kind(), format!("assignment {}: {}", i, e));
+
+                // The circuit was validated against the first assignment, whose keys are as long
+                if keys.len() != self.n {
+                    return Err(assignment_error(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("circuit has {} inputs, but got {} keys", self.n, keys.len()),
+                    )));
+                }
+
+                gate_outs.clear();
+                self.eval_gates::<H, N>(keys, &mut gate_outs).map_err(|id| assignment_error(undecryptable(id)))
+            })
+            .collect()
+    }
+
+    /// Evaluate the circuit like `try_eval`, also returning the key of every input and gate, for
+    /// debugging. If a gate can't be decrypted, the keys up to that gate are still returned
+    pub fn eval_trace<const N: usize>(&self, inputs: &[[u8; N]]) -> (Result<Vec<[u8; N]>, io::Error>, EvalTrace<N>) {
+        self.eval_trace_with::<FixedKeyAes, N>(inputs)
+    }
+
+    /// Same as `eval_trace`, for a circuit whose rows were encrypted with `H`
+    pub fn eval_trace_with<H: GarbleCipher, const N: usize>(
+        &self,
+        inputs: &[[u8; N]],
+    ) -> (Result<Vec<[u8; N]>, io::Error>, EvalTrace<N>) {
+        let mut trace: EvalTrace<N> =
+            inputs.iter().enumerate().map(|(idx, key)| (GarbledNodeRecv::Input(idx), *key)).collect();
+
+        if let Err(e) = self.validate(inputs) {
+            return (Err(e), trace);
+        }
+
+        let mut gate_outs = Vec::with_capacity(self.gates.len());
+        let result = self.eval_gates::<H, N>(inputs, &mut gate_outs).map_err(undecryptable);
+        trace.extend(gate_outs.into_iter().enumerate().map(|(idx, key)| (GarbledNodeRecv::Gate(idx), key)));
+
+        (result, trace)
+    }
+
+    /// Check that the circuit can be evaluated on `inputs` without going out of bounds
+    fn validate<const N: usize>(&self, inputs: &[[u8; N]]) -> Result<(), io::Error> {
+        if inputs.len() != self.n {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("circuit has {} inputs, but got {} keys", self.n, inputs.len()),
+            ));
+        }
+
+        // Children must be evaluated before their parents, so gate i can only refer to gates 0..i
+        let valid = |node: &GarbledNodeRecv, num_gates: usize| match node {
+            GarbledNodeRecv::Input(idx) => *idx < self.n,
+            GarbledNodeRecv::Gate(idx) => *idx < num_gates,
+        };
+
+        for (id, gate) in self.gates.iter().enumerate() {
+            if !gate.inputs.iter().all(|input| valid(input, id)) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("gate {} refers to a wire that isn't an input or an earlier gate", id),
+                ));
+            }
+
+            let well_formed = match gate.kind {
+                GateKind::Table => {
+                    !gate.inputs.is_empty()
+                        && gate.inputs.len() <= MAX_GATE_INPUTS
+                        && gate.rows.len() == 1 << gate.inputs.len()
+                }
+                GateKind::FreeXor => gate.inputs.len() <= 2 && gate.rows.is_empty(),
+                GateKind::HalfGates => gate.inputs.len() == 2 && gate.rows.len() == 2 && gate.rows.iter().all(|row| row.len() == N),
+            };
+
+            if !well_formed {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{:?} gate {} has {} inputs, but {} rows", gate.kind, id, gate.inputs.len(), gate.rows.len()),
+                ));
+            }
+        }
+
+        if !self.outs.iter().all(|out| valid(out, self.gates.len())) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "an output refers to a wire that isn't an input or a gate",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Same as `try_eval`, with the key of each input given by its index, for when some keys may be
+    /// missing. Returns an error naming the first input without a key
+    pub fn try_eval_map<const N: usize>(&self, inputs: &HashMap<usize, [u8; N]>) -> Result<Vec<[u8; N]>, io::Error> {
+        if let Some(extra) = inputs.keys().filter(|idx| **idx >= self.n).min() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("circuit has {} inputs, but got a key for input {}", self.n, extra),
+            ));
+        }
+
+        let inputs = (0..self.n)
+            .map(|idx| {
+                inputs.get(&idx).copied().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("no key for input {}", idx))
+                })
+            })
+            .collect::<Result<Vec<[u8; N]>, io::Error>>()?;
+
+        self.try_eval(&inputs)
+    }
+
+    /// Evaluate the circuit like `try_eval`, and decode the output keys into bits with the decoding
+    /// table. Returns an error if an output key isn't in the table
+    pub fn eval_bits<const N: usize>(&self, inputs: &[[u8; N]]) -> Result<Vec<bool>, io::Error> {
+        self.decode_bits(&self.try_eval(inputs)?)
+    }
+
+    /// Decode the keys of the circuit's outputs into bits with the decoding table (see `eval_bits`)
+    pub fn decode_bits<const N: usize>(&self, outs: &[[u8; N]]) -> Result<Vec<bool>, io::Error> {
+        if self.decoding.len() != outs.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("circuit has {} outputs, but the decoding table has {} entries", outs.len(), self.decoding.len()),
+            ));
+        }
+
+        outs.iter()
+            .zip(&self.decoding)
+            .enumerate()
+            .map(|(idx, (key, (off_hash, on_hash)))| match output_hash(idx, key) {
+                hash if hash == *on_hash => Ok(true),
+                hash if hash == *off_hash => Ok(false),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("the key of output {} isn't in the decoding table", idx),
+                )),
+            })
+            .collect()
+    }
+
+    /// Set the output decoding table, which the garbler sends after the garbled circuit
+    pub fn set_decoding_table(&mut self, table: DecodingTable) {
+        // Hashes of the wrong length can't match any key, so they're replaced with zeros
+        let hash = |bytes: Vec<u8>| bytes.try_into().unwrap_or([0u8; 32]);
+
+        self.decoding = table
+            .outs
+            .into_iter()
+            .map(|entry| (hash(entry.off_hash), hash(entry.on_hash)))
+            .collect();
+    }
+
+    /// Start building a circuit that's streamed gate by gate (see
+    /// `send_garbled_circuit_streaming`). The gates are then added with `push_gate`, and the
+    /// outputs set with `set_outputs`. Returns an error if the header's sizes are out of range
+    pub fn from_header(header: &StreamedCircuitHeader) -> Result<GarbledCircuitRecv, ConversionError> {
+        Ok(GarbledCircuitRecv {
+            gates: vec![],
+            outs: vec![],
+            n: size_field(header.n, "number of inputs")?,
+            key_size: key_size_field(header.key_size)?,
+            decoding: vec![],
+        })
+    }
+
+    /// Add the next gate of a streamed circuit
+    pub fn push_gate(&mut self, gate: Gate) {
+        self.gates.push(GarbledGateRecv::from_msg(gate, self.n));
+    }
+
+    /// Set the outputs of a streamed circuit, which come after all of its gates
+    pub fn set_outputs(&mut self, outs: CircuitOutputs) {
+        self.outs = outs.outs.into_iter().map(|out| GarbledNodeRecv::from_wire(out, self.n)).collect();
+    }
+
+    /// Same as `eval_bits`, for circuits with a single output
+    pub fn eval_bool<const N: usize>(&self, inputs: &[[u8; N]]) -> Result<bool, io::Error> {
+        match self.eval_bits(inputs)?[..] {
+            [bit] => Ok(bit),
+            ref bits => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("circuit has {} outputs, expected 1", bits.len()),
+            )),
+        }
+    }
+
+    /// The number of (non-empty) rows of the circuit's gates (see `StreamedCircuit::num_rows`)
+    pub fn num_rows(&self) -> usize {
+        self.gates.iter().flat_map(|gate| &gate.rows).filter(|row| !row.is_empty()).count()
+    }
+
+    /// A hash of everything garbling doesn't hide about the circuit: its number of inputs, the
+    /// kind, children and number of rows of each gate, and its outputs (see `topology_hash`)
+    pub fn structure_hash(&self) -> [u8; 32] {
+        let gates = self.gates.iter().map(|gate| {
+            (gate.kind, gate.inputs.iter().map(|input| input.to_wire(self.n)).collect(), gate.rows.len())
+        });
+
+        hash_structure(self.n, gates, self.outs.iter().map(|out| out.to_wire(self.n)))
+    }
+
+    /// The size in bytes of the message `send_garbled_circuit` sends for this circuit (without its
+    /// size prefix)
+    pub fn serialized_size(&self) -> usize {
+        GarbledCircuitSend::from(self).compute_size() as usize
+    }
+
+    /// Write the circuit and its decoding table without protobuf, to be read with `from_reader`.
+    /// Every number is a big-endian u64, and every list (and row) is prefixed with its length
+    pub fn to_writer(&self, mut w: impl io::Write) -> Result<(), io::Error> {
+        write_u64(&mut w, self.n as u64)?;
+        write_u64(&mut w, self.key_size as u64)?;
+        write_u64(&mut w, self.gates.len() as u64)?;
+
+        for gate in &self.gates {
+            let kind = match gate.kind {
+                GateKind::Table => 0,
+                GateKind::FreeXor => 1,
+                GateKind::HalfGates => 2,
+            };
+            write_u64(&mut w, kind as u64)?;
+            write_u64(&mut w, gate.inputs.len() as u64)?;
+
+            for input in &gate.inputs {
+                write_u64(&mut w, input.to_wire(self.n))?;
+            }
+
+            write_u64(&mut w, gate.rows.len() as u64)?;
+
+            for row in &gate.rows {
+                write_u64(&mut w, row.len() as u64)?;
+                w.write_all(row)?;
+            }
+        }
+
+        write_u64(&mut w, self.outs.len() as u64)?;
+
+        for out in &self.outs {
+            write_u64(&mut w, out.to_wire(self.n))?;
+        }
+
+        write_u64(&mut w, self.decoding.len() as u64)?;
+
+        for (off_hash, on_hash) in &self.decoding {
+            w.write_all(off_hash)?;
+            w.write_all(on_hash)?;
+        }
+
+        Ok(())
+    }
+
+    /// Read a circuit written with `to_writer`, which is only checked when it's evaluated (see
+    /// `try_eval`). Returns an error if the input ends early or has an unknown gate kind
+    pub fn from_reader(mut r: impl io::Read) -> Result<GarbledCircuitRecv, io::Error> {
+        let n = read_len(&mut r)?;
+        let key_size = read_len(&mut r)?;
+        // The lengths aren't trusted, so nothing is allocated up front: the input has to actually
+        // hold that many items
+        let mut gates = vec![];
+
+        for _ in 0..read_u64(&mut r)? {
+            let kind = match read_u64(&mut r)? {
+                0 => GateKind::Table,
+                1 => GateKind::FreeXor,
+                2 => GateKind::HalfGates,
+                kind => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown gate kind {}", kind))),
+            };
+            let inputs = (0..read_u64(&mut r)?)
+                .map(|_| Ok(GarbledNodeRecv::from_wire(read_u64(&mut r)?, n)))
+                .collect::<Result<_, io::Error>>()?;
+            let rows = (0..read_u64(&mut r)?)
+                .map(|_| {
+                    let len = read_u64(&mut r)?;
+                    let mut row = vec![];
+                    (&mut r).take(len).read_to_end(&mut row)?;
+
+                    match row.len() as u64 == len {
+                        true => Ok(row),
+                        false => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                    }
+                })
+                .collect::<Result<_, io::Error>>()?;
+
+            gates.push(GarbledGateRecv { kind, rows, inputs });
+        }
+
+        let outs = (0..read_u64(&mut r)?)
+            .map(|_| Ok(GarbledNodeRecv::from_wire(read_u64(&mut r)?, n)))
+            .collect::<Result<_, io::Error>>()?;
+        let decoding = (0..read_u64(&mut r)?)
+            .map(|_| {
+                let (mut off_hash, mut on_hash) = ([0u8; 32], [0u8; 32]);
+                r.read_exact(&mut off_hash)?;
+                r.read_exact(&mut on_hash)?;
+
+                Ok((off_hash, on_hash))
+            })
+            .collect::<Result<_, io::Error>>()?;
+
+        Ok(GarbledCircuitRecv {
+            gates,
+            outs,
+            n,
+            key_size,
+            decoding,
+        })
     }
 
     pub fn n(&self) -> usize {
         self.n
     }
+
+    pub fn key_size(&self) -> usize {
+        self.key_size
+    }
 }
 
-// Convert from the node protobuf sent to us over the network to a `GarbledInputRecv`
-impl From<GarbledNodeSend> for GarbledNodeRecv {
-    fn from(value: GarbledNodeSend) -> Self {
-        if let MessageField(Some(input)) = value.input {
-            GarbledNodeRecv::Input(input.idx as usize)
-        } else {
-            let gate = value.gate.unwrap();
+/// Write a number in the format of `GarbledCircuitRecv::to_writer`
+fn write_u64(w: &mut impl io::Write, value: u64) -> Result<(), io::Error> {
+    w.write_all(&value.to_be_bytes())
+}
 
-            GarbledNodeRecv::Gate(GarbledGateRecv {
-                c_00: Some(gate.c_00),
-                c_01: Some(gate.c_01),
-                c_10: Some(gate.c_10),
-                c_11: Some(gate.c_11),
-                left: Some(Rc::new(RefCell::new(gate.left.unwrap().into()))),
-                right: Some(Rc::new(RefCell::new(gate.right.unwrap().into()))),
-            })
+/// Read a big-endian u64 written by `GarbledCircuitRecv::to_writer`
+fn read_u64(r: &mut impl io::Read) -> Result<u64, io::Error> {
+    let mut bytes = [0u8; 8];
+    r.read_exact(&mut bytes)?;
+
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// Read a size written by `GarbledCircuitRecv::to_writer`, which must fit in a usize
+fn read_len(r: &mut impl io::Read) -> Result<usize, io::Error> {
+    let len = read_u64(r)?;
+
+    usize::try_from(len).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("size {} is too large", len)))
+}
+
+/// The kind of a garbled gate, its children's wire indices and its number of rows
+type GateShape = (GateKind, Vec<u64>, usize);
+
+/// Hash the structure of a circuit (see `GarbledCircuitRecv::structure_hash`)
+fn hash_structure(n: usize, gates: impl Iterator<Item = GateShape>, outs: impl Iterator<Item = u64>) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update((n as u64).to_be_bytes());
+
+    for (kind, inputs, rows) in gates {
+        hasher.update([kind as u8]);
+        hasher.update((inputs.len() as u64).to_be_bytes());
+
+        for input in inputs {
+            hasher.update(input.to_be_bytes());
+        }
+
+        hasher.update((rows as u64).to_be_bytes());
+    }
+
+    for out in outs {
+        hasher.update(out.to_be_bytes());
+    }
+
+    hasher.finalize().into()
+}
+
+/// Collects the shape each gate of a plain circuit is garbled into, in the garbler's arena order.
+/// Each node's value is its wire index
+struct StructureVisitor {
+    n: usize,
+    gates: Vec<GateShape>,
+}
+
+impl CircuitVisitor for StructureVisitor {
+    type Output = u64;
+
+    fn visit_input(&mut self, idx: usize) -> u64 {
+        idx as u64
+    }
+
+    fn visit_gate(&mut self, gate: &Node, children: &[u64]) -> u64 {
+        let shape = match gate {
+            // Like in `half_gate`, a linear gate is free, and only depends on the children whose
+            // coefficients are set
+            Node::Gate(op, _, _) => match anf(*op) {
+                [_, c_1, c_2, false] => {
+                    let inputs = children.iter().zip([c_1, c_2]).filter(|(_, c)| *c).map(|(child, _)| *child).collect();
+
+                    (GateKind::FreeXor, inputs, 0)
+                }
+                _ => (GateKind::HalfGates, children.to_vec(), 2),
+            },
+            // A table gate has a row per entry of its truth table
+            Node::GateN(table, _) => (GateKind::Table, children.to_vec(), table.len()),
+            Node::Input(_) => unreachable!("inputs are visited with visit_input"),
+        };
+        self.gates.push(shape);
+
+        (self.n + self.gates.len() - 1) as u64
+    }
+}
+
+/// The structure hash (see `GarbledCircuitRecv::structure_hash`) of any garbling of `circuit`,
+/// computed without garbling it
+pub fn topology_hash(circuit: &Circuit) -> [u8; 32] {
+    let mut visitor = StructureVisitor { n: circuit.n(), gates: vec![] };
+    let outs = circuit.visit(&mut visitor);
+
+    hash_structure(circuit.n(), visitor.gates.into_iter(), outs.into_iter())
+}
+
+/// Parse a garbled circuit serialized with `serialize_circuit`. Like a circuit received over the
+/// network, its decoding table still has to be set with `set_decoding_table`
+pub fn deserialize_circuit(bytes: &[u8]) -> Result<GarbledCircuitRecv, io::Error> {
+    Ok(GarbledCircuitSend::parse_from_bytes(bytes)?.try_into()?)
+}
+
+/// Receive a garbled circuit sent with `send_garbled_circuit_streaming`, one gate at a time.
+/// Returns an error if the circuit doesn't match the digest the garbler sent after it (see
+/// `CircuitDigest`)
+#[cfg(feature = "sync")]
+pub fn receive_streamed_circuit(stream: &mut (impl Read + Write)) -> Result<GarbledCircuitRecv, io::Error> {
+    let header = MessageStream::<StreamedCircuitHeader>::receive_msg(stream)?;
+    let mut circuit = GarbledCircuitRecv::from_header(&header)?;
+    let mut digest = CircuitDigest::default();
+    digest.update(&header)?;
+
+    for _ in 0..header.num_gates {
+        let gate = MessageStream::<Gate>::receive_msg(stream)?;
+        digest.update(&gate)?;
+        circuit.push_gate(gate);
+    }
+
+    let mut outs = MessageStream::<CircuitOutputs>::receive_msg(stream)?;
+    let expected = std::mem::take(&mut outs.digest);
+    digest.update(&outs)?;
+
+    if digest.finalize() != expected {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the garbled circuit doesn't match its digest, so it was corrupted in transfer",
+        ));
+    }
+
+    circuit.set_outputs(outs);
+    log::debug!("received a garbled circuit of {} gates ({} rows)", circuit.gates.len(), circuit.num_rows());
+
+    Ok(circuit)
+}
+
+/// Get the messages `choices` picks from the pairs the garbler sends with `send_by_ot`, using OT
+/// extension. Returns an error if the garbler's messages are malformed
+#[cfg(feature = "sync")]
+pub fn receive_by_ot(stream: &mut (impl Read + Write), choices: &[bool]) -> Result<Vec<[u8; KEY_SIZE]>, io::Error> {
+    let receiver = OtExtensionReceiver::new(choices.to_vec());
+    // The base OTs, in which we send the garbler one seed of each pair
+    let base_senders: Vec<SimplestOtSender> = receiver.base_msgs().into_iter().map(SimplestOtSender::new).collect();
+    let mut publics_msg = OtPublics::new();
+    publics_msg.publics = base_senders.iter().map(|sender| sender.public().to_bytes_be()).collect();
+
+    MessageStream::<OtPublics>::send_msg(stream, publics_msg).step("sending the base OT public values")?;
+    let blinded_idxs = MessageStream::<OtBlindedIdxBatch>::receive_msg(stream).step("receiving the base OT blinded indices")?;
+
+    if blinded_idxs.idxs.len() != NUM_BASE_OTS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expected {} base OT blinded indices, got {}", NUM_BASE_OTS, blinded_idxs.idxs.len()),
+        ));
+    }
+
+    let mut seeds_batch = OtEncMessagesBatch::new();
+    seeds_batch.msgs = base_senders
+        .iter()
+        .zip(blinded_idxs.idxs)
+        .map(|(sender, blinded_idx)| {
+            // A blinded index outside the group could leak our secret exponent
+            let m_primes = sender.gen_combined(BigUint::from_bytes_be(&blinded_idx.v)).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "a blinded index isn't an element of the group")
+            })?;
+            let mut m_primes_msg = OtEncMessages::new();
+            m_primes_msg.m_prime_0 = m_primes.0.to_vec();
+            m_primes_msg.m_prime_1 = m_primes.1.to_vec();
+
+            Ok(m_primes_msg)
+        })
+        .collect::<Result<_, io::Error>>()
+        .step("answering the base OT blinded indices")?;
+
+    MessageStream::<OtEncMessagesBatch>::send_msg(stream, seeds_batch).step("sending the base OT replies")?;
+    // Extend the base OTs into one OT per choice
+    let mut columns_msg = OtExtensionColumns::new();
+    columns_msg.us = receiver.gen_us();
+
+    MessageStream::<OtExtensionColumns>::send_msg(stream, columns_msg).step("sending the OT extension columns")?;
+    let m_primes_batch = MessageStream::<OtEncMessagesBatch>::receive_msg(stream).step("receiving the OT replies")?;
+
+    if m_primes_batch.msgs.len() != choices.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expected {} pairs of m_primes, got {}", choices.len(), m_primes_batch.msgs.len()),
+        ));
+    }
+
+    let m_primes = m_primes_batch
+        .msgs
+        .into_iter()
+        .map(|m_primes_msg| {
+            // The masked messages are keys, so a reply of any other size is malformed
+            let m_primes = to_keys::<KEY_SIZE>(&[m_primes_msg.m_prime_0, m_primes_msg.m_prime_1])?;
+
+            Ok((m_primes[0], m_primes[1]))
+        })
+        .collect::<Result<_, io::Error>>()
+        .step("receiving the OT replies")?;
+
+    Ok(receiver.derive_msgs(m_primes))
+}
+
+/// Run our (the receiver's) side of the protocol (see `run_garbler`) on the `circuit` we agreed on,
+/// returning the keys of its outputs and the bits they decode to. Returns an error if our `input`
+/// doesn't fit in the inputs the garbler left us
+#[cfg(feature = "sync")]
+pub fn run_receiver(
+    stream: &mut (impl Read + Write),
+    circuit: &Circuit,
+    input: &[bool],
+) -> Result<(Vec<[u8; KEY_SIZE]>, Vec<bool>), io::Error> {
+    // The garbler should have sent us the garbled circuit
+    let mut circuit_recv = receive_streamed_circuit(stream).step("receiving the garbled circuit")?;
+
+    // The circuit is public, so a garbler that sent a different one (e.g. for another width) is
+    // caught before we run any OTs
+    if circuit_recv.structure_hash() != topology_hash(circuit) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the garbled circuit isn't the circuit we agreed on",
+        ));
+    }
+    // Followed by the table that decodes our output keys
+    let decoding_table = MessageStream::<DecodingTable>::receive_msg(stream).step("receiving the decoding table")?;
+    circuit_recv.set_decoding_table(decoding_table);
+
+    // We can only evaluate circuits garbled with keys of the size we were built with
+    if circuit_recv.key_size() != KEY_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "circuit uses {}-byte keys, expected {}",
+                circuit_recv.key_size(),
+                KEY_SIZE
+            ),
+        ));
+    }
+
+    // The garbler commits to the keys of every input wire before opening any of them
+    let commitments = MessageStream::<KeyCommitments>::receive_msg(stream).step("receiving the key commitments")?;
+    // What are the garbler's keys in the circuit?
+    let keys_msg = MessageStream::<GarblerKeys>::receive_msg(stream).step("receiving the garbler's keys")?;
+    let n = circuit_recv.n();
+
+    // Our inputs are the ones the garbler didn't send keys for, and we get the keys for all of them
+    // in a single batch of extended OTs
+    let our_indices = receiver_indices(n, &keys_msg).step("receiving the garbler's keys")?;
+    if input.iter().skip(our_indices.len()).any(|bit| *bit) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("our input doesn't fit in the {} inputs the garbler left us", our_indices.len()),
+        ));
+    }
+
+    let bits: Vec<bool> = (0..our_indices.len()).map(|i| input.get(i).copied().unwrap_or(false)).collect();
+
+    // Using OT, get our (the receiver's) keys, which are zeroed once we're done with them (like the
+    // garbler's wires)
+    let ot_keys = Zeroizing::new(receive_by_ot(stream, &bits)?);
+    log::debug!("got our keys for {} inputs using OT", ot_keys.len());
+    let circuit_inputs = Zeroizing::new(assemble_inputs(n, &keys_msg, &ot_keys).step("receiving the garbler's keys")?);
+    // Make sure the garbler didn't substitute any of the keys (its own or the ones we got using OT)
+    verify_input_keys(&commitments, &circuit_inputs).step("verifying the input keys")?;
+
+    // Evaluate the garbled circuit
+    let outs = circuit_recv.try_eval(&circuit_inputs).step("evaluating the garbled circuit")?;
+    let bits = circuit_recv.decode_bits(&outs).step("evaluating the garbled circuit")?;
+    log::info!("evaluated the garbled circuit to {:?}", bits);
+
+    Ok((outs, bits))
+}
+
+/// Why a message received from the garbler couldn't be converted into the receiver's view of it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConversionError {
+    /// A field that must be set wasn't (to proto3, a key size of 0 is unset)
+    MissingField(&'static str),
+    /// A size field that's negative or too large
+    OutOfRange { field: &'static str, value: i64 },
+    /// A key (by its index in its message) that isn't the size of the circuit's keys
+    BadKeyLength { key: usize, len: usize, expected: usize },
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConversionError::MissingField(field) => write!(f, "the message is missing its {}", field),
+            ConversionError::OutOfRange { field, value } => write!(f, "the circuit's {} is out of range: {}", field, value),
+            ConversionError::BadKeyLength { key, len, expected } => {
+                write!(f, "key {} is {} bytes long, expected {}", key, len, expected)
+            }
         }
     }
 }
 
-impl From<GarbledCircuitSend> for GarbledCircuitRecv {
-    fn from(value: GarbledCircuitSend) -> Self {
-        let n = value.n as usize;
-        let out = value.out.unwrap().into();
+impl std::error::Error for ConversionError {}
+
+/// Malformed messages are invalid data, like any other message the protocol can't make sense of
+impl From<ConversionError> for io::Error {
+    fn from(value: ConversionError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, value)
+    }
+}
+
+/// A size sent in a circuit's protobuf (whose fields are signed), naming `field` if it's negative
+fn size_field(value: i64, field: &'static str) -> Result<usize, ConversionError> {
+    usize::try_from(value).map_err(|_| ConversionError::OutOfRange { field, value })
+}
 
-        GarbledCircuitRecv { out, n }
+/// The key size in a circuit's protobuf, which must be set, and at most 32 bytes (see `row_pad`)
+fn key_size_field(value: i64) -> Result<usize, ConversionError> {
+    match size_field(value, "key size")? {
+        0 => Err(ConversionError::MissingField("key size")),
+        key_size if key_size > 32 => Err(ConversionError::OutOfRange { field: "key size", value }),
+        key_size => Ok(key_size),
+    }
+}
+
+/// Returns an error if the message's sizes are out of range, rather than wrapping them around
+impl TryFrom<GarbledCircuitSend> for GarbledCircuitRecv {
+    type Error = ConversionError;
+
+    fn try_from(value: GarbledCircuitSend) -> Result<Self, ConversionError> {
+        let n = size_field(value.n, "number of inputs")?;
+        let key_size = key_size_field(value.key_size)?;
+        let gates = value.gates.into_iter().map(|gate| GarbledGateRecv::from_msg(gate, n)).collect();
+        let outs = value.outs.into_iter().map(|out| GarbledNodeRecv::from_wire(out, n)).collect();
+
+        // The decoding table is sent separately (see `set_decoding_table`)
+        Ok(GarbledCircuitRecv {
+            gates,
+            outs,
+            n,
+            key_size,
+            decoding: vec![],
+        })
     }
 }
 
@@ -135,31 +1045,926 @@ impl From<GarbledNode> for GarbledNodeRecv {
     fn from(value: GarbledNode) -> Self {
         match value {
             GarbledNode::Input(idx) => GarbledNodeRecv::Input(idx),
-            GarbledNode::Gate(gate) => {
-                let gate = gate.borrow().clone();
-
-                GarbledNodeRecv::Gate(GarbledGateRecv {
-                    c_00: Some(gate.c_00()),
-                    c_01: Some(gate.c_01()),
-                    c_10: Some(gate.c_10()),
-                    c_11: Some(gate.c_11()),
-                    left: Some(Rc::new(RefCell::new(
-                        gate.left.clone().unwrap().borrow().clone().into(),
-                    ))),
-                    right: Some(Rc::new(RefCell::new(
-                        gate.right.clone().unwrap().borrow().clone().into(),
-                    ))),
-                })
-            }
+            GarbledNode::Gate(idx) => GarbledNodeRecv::Gate(idx),
         }
     }
 }
 
-impl From<GarbledCircuit> for GarbledCircuitRecv {
-    fn from(value: GarbledCircuit) -> Self {
+impl<const N: usize> From<GarbledCircuit<N>> for GarbledCircuitRecv {
+    /// Panics if the circuit was garbled in privacy-free mode (see `PrivacyFreeCircuitRecv`)
+    fn from(value: GarbledCircuit<N>) -> Self {
+        assert!(!value.is_privacy_free(), "privacy-free circuits must be evaluated with PrivacyFreeCircuitRecv");
+
+        // The arena carries over as is, minus the wire keys and operations
+        let gates = value
+            .gates()
+            .iter()
+            .map(|gate| GarbledGateRecv {
+                kind: gate.kind(),
+                rows: gate.rows().to_vec(),
+                inputs: gate.inputs.iter().map(|input| (*input).into()).collect(),
+            })
+            .collect();
+
         GarbledCircuitRecv {
-            out: value.out().into(),
+            gates,
+            outs: value.outs().into_iter().map(|out| out.into()).collect(),
+            n: value.n(),
+            key_size: N,
+            decoding: value.decoding_table(),
+        }
+    }
+}
+
+/// A gate of a privacy-free garbled circuit, whose operation isn't hidden
+pub struct PrivacyFreeGateRecv<const N: usize = KEY_SIZE> {
+    op: u8,
+    /// The gate's single ciphertext, or `None` if the gate is linear (and hence free)
+    ciphertext: Option<[u8; N]>,
+    pub left: GarbledNodeRecv,
+    pub right: GarbledNodeRecv,
+}
+
+/// A circuit garbled with `GarbledCircuit::from_privacy_free`, from the receiver's POV. Its output
+/// keys prove to the garbler that we evaluated it honestly (see `GarbledCircuit::decode_outputs`)
+pub struct PrivacyFreeCircuitRecv<const N: usize = KEY_SIZE> {
+    gates: Vec<PrivacyFreeGateRecv<N>>,
+    outs: Vec<GarbledNodeRecv>,
+    n: usize,
+}
+
+impl<const N: usize> PrivacyFreeCircuitRecv<N> {
+    /// Evaluate the circuit given the value of each input and its key, returning the value and key
+    /// of every output
+    pub fn eval(&self, inputs: &[(bool, [u8; N])]) -> Vec<(bool, [u8; N])> {
+        self.eval_with::<FixedKeyAes>(inputs)
+    }
+
+    /// Same as `eval`, for a circuit garbled with `H` (see
+    /// `GarbledCircuit::from_privacy_free_with_cipher`)
+    pub fn eval_with<H: GarbleCipher>(&self, inputs: &[(bool, [u8; N])]) -> Vec<(bool, [u8; N])> {
+        let mut gate_outs: Vec<(bool, [u8; N])> = Vec::with_capacity(self.gates.len());
+        let wire = |node: GarbledNodeRecv, gate_outs: &[(bool, [u8; N])]| match node {
+            GarbledNodeRecv::Input(idx) => inputs[idx],
+            GarbledNodeRecv::Gate(idx) => gate_outs[idx],
+        };
+
+        for (id, gate) in self.gates.iter().enumerate() {
+            let ((left_val, left_key), (right_val, right_key)) = (wire(gate.left, &gate_outs), wire(gate.right, &gate_outs));
+            let [_, c_1, c_2, _] = anf(gate.op);
+            let val = (gate.op >> (2 * left_val as usize + right_val as usize)) & 1 == 1;
+            let scale = |bit: bool, key: &[u8; N]| if bit { *key } else { [0u8; N] };
+
+            let key = match gate.ciphertext {
+                // If a ^ c_2 = 0, the output key is H(A); otherwise, it's H(A) ^ T ^ B
+                Some(t) => {
+                    let hash = label_hash::<H, N>(gate_tweak(id as u64, 0), &left_key);
+
+                    if left_val ^ c_2 { xor(&xor(&hash, &t), &right_key) } else { hash }
+                }
+                // Linear gates are free
+                None => xor(&scale(c_1, &left_key), &scale(c_2, &right_key)),
+            };
+
+            gate_outs.push((val, key));
+        }
+
+        self.outs.iter().map(|out| wire(*out, &gate_outs)).collect()
+    }
+
+    pub fn n(&self) -> usize {
+        self.n
+    }
+}
+
+impl<const N: usize> From<GarbledCircuit<N>> for PrivacyFreeCircuitRecv<N> {
+    /// Panics if the circuit wasn't garbled in privacy-free mode
+    fn from(value: GarbledCircuit<N>) -> Self {
+        assert!(value.is_privacy_free(), "the circuit wasn't garbled in privacy-free mode");
+
+        let gates = value
+            .gates()
+            .iter()
+            .map(|gate| PrivacyFreeGateRecv {
+                op: gate.op(),
+                ciphertext: gate.privacy_free_ciphertext(),
+                left: gate.inputs[0].into(),
+                right: gate.inputs[1].into(),
+            })
+            .collect();
+
+        PrivacyFreeCircuitRecv {
+            gates,
+            outs: value.outs().into_iter().map(|out| out.into()).collect(),
             n: value.n(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, io, rc::Rc};
+
+    use protobuf::Message;
+    use subtle::Choice;
+
+    use crate::{
+        backend::garbler_backend::{
+            asymmetric_comparison_circuit, check_width, comparison_circuit, construct_circuit, construct_comparison,
+            construct_signed_comparison, decode_output_keys, decoding_table_msg, garbled_circuit_msg, input_keys_msg,
+            key_commitments_msg, parse_bits, parse_signed_bits, protos::{GarbledCircuitSend, GarblerKeys}, serialize_circuit,
+            to_bits,
+        },
+        circuit::{Circuit, Node},
+        crypto::aes_ctr::AesCtr,
+        garbling::{GarbledCircuit, GateKind},
+        gates,
+    };
+
+    use super::{
+        assemble_inputs, deserialize_circuit, receiver_indices, select_row, to_keys, topology_hash, verify_input_keys,
+        ConversionError, EvalTrace, GarbledCircuitRecv, GarbledGateRecv, GarbledNodeRecv, PrivacyFreeCircuitRecv,
+    };
+
+    #[test]
+    fn try_eval_key_count_test() {
+        let circuit: GarbledCircuit = construct_circuit(2).into();
+        let input_keys = circuit.input_keys();
+        let keys_msg = input_keys_msg(&circuit, &[0, 1], &to_bits(0b10, 2)).unwrap();
+        let recv_circuit: GarbledCircuitRecv = circuit.into();
+        // Our keys for the input 0b01
+        let ot_keys = vec![input_keys[&2].on_key(), input_keys[&3].off_key()];
+
+        let mut inputs = to_keys::<32>(&keys_msg.keys).unwrap();
+        inputs.extend(&ot_keys);
+        let result = recv_circuit.try_eval(&inputs).unwrap();
+        // The garbler is richer, and we aren't tied
+        assert_eq!(result.len(), 2);
+        assert_eq!(recv_circuit.eval_bits(&inputs).unwrap(), vec![true, false]);
+
+        // A garbler that sends too few keys is caught before evaluation
+        let mut short_keys_msg = GarblerKeys::new();
+        short_keys_msg.keys = keys_msg.keys[..1].to_vec();
+        let mut inputs = to_keys::<32>(&short_keys_msg.keys).unwrap();
+        inputs.extend(&ot_keys);
+
+        assert!(recv_circuit.try_eval(&inputs).is_err());
+
+        // Keys given by index: the missing input is named, rather than indexed out of bounds
+        let mut keyed: HashMap<usize, [u8; 32]> = to_keys::<32>(&keys_msg.keys).unwrap().into_iter().enumerate().collect();
+        keyed.insert(3, ot_keys[1]);
+        let err = recv_circuit.try_eval_map(&keyed).unwrap_err();
+
+        assert!(err.to_string().contains("no key for input 2"));
+        keyed.insert(2, ot_keys[0]);
+        assert_eq!(recv_circuit.try_eval_map(&keyed).unwrap(), result);
+        keyed.insert(4, ot_keys[0]);
+        assert!(recv_circuit.try_eval_map(&keyed).is_err());
+    }
+
+    #[test]
+    fn check_width_test() {
+        // 2000 doesn't fit in 10 bits; truncating it would compare 976 instead
+        assert!(check_width(1023, 10).is_ok());
+        assert!(check_width(2000, 10).unwrap_err().to_string().contains("value must be < 1024"));
+        assert!(check_width(usize::MAX, usize::BITS as usize).is_ok());
+    }
+
+    #[test]
+    fn parse_signed_bits_test() {
+        assert_eq!(parse_signed_bits("5", 4).unwrap(), to_bits(5, 4));
+        // -3 is 2^4 - 3 in two's complement
+        assert_eq!(parse_signed_bits("-3", 4).unwrap(), to_bits(13, 4));
+        assert_eq!(parse_signed_bits("-8", 4).unwrap(), to_bits(8, 4));
+        assert!(parse_signed_bits("8", 4).unwrap_err().to_string().contains("value must be in [-2^3, 2^3)"));
+        assert!(parse_signed_bits("-9", 4).is_err());
+        assert!(parse_signed_bits("0", 0).is_err());
+    }
+
+    #[test]
+    fn parse_bits_test() {
+        assert_eq!(parse_bits("6", 4).unwrap(), to_bits(6, 4));
+        assert!(parse_bits("16", 4).unwrap_err().to_string().contains("value must be < 2^4"));
+        assert!(parse_bits("-1", 4).is_err());
+        assert!(parse_bits("six", 4).is_err());
+
+        // Numbers wider than a usize are parsed in full
+        let bits = parse_bits("36893488147419103232", 70).unwrap();
+        assert_eq!(bits.iter().position(|bit| *bit), Some(65));
+        assert_eq!(bits.iter().filter(|bit| **bit).count(), 1);
+    }
+
+    #[test]
+    fn input_layout_test() {
+        let circuit: GarbledCircuit = construct_circuit(2).into();
+        let input_keys = circuit.input_keys();
+        let key = |i: usize, bit: bool| if bit { input_keys[&i].on_key() } else { input_keys[&i].off_key() };
+        // The garbler owns the second number (and sends its bits out of order), so we own the first
+        let keys_msg = input_keys_msg(&circuit, &[3, 2], &[true, false]).unwrap();
+        let recv_circuit: GarbledCircuitRecv = circuit.clone().into();
+
+        assert_eq!(receiver_indices(4, &keys_msg).unwrap(), vec![0, 1]);
+
+        // 0b11 > 0b10
+        let inputs = assemble_inputs(4, &keys_msg, &[key(0, true), key(1, true)]).unwrap();
+        assert_eq!(inputs, vec![key(0, true), key(1, true), key(2, false), key(3, true)]);
+        assert_eq!(recv_circuit.eval_bits(&inputs).unwrap(), vec![true, false]);
+        // We need a key for each of our inputs
+        assert!(assemble_inputs(4, &keys_msg, &[key(0, true)]).is_err());
+
+        // A garbler that doesn't send indices owns the first inputs
+        let mut legacy_msg = keys_msg.clone();
+        legacy_msg.indices.clear();
+        assert_eq!(receiver_indices(4, &legacy_msg).unwrap(), vec![2, 3]);
+
+        // Indices that aren't inputs, or appear twice, are rejected by both parties
+        let mut bad_msg = keys_msg.clone();
+        bad_msg.indices = vec![3, 3];
+        assert!(receiver_indices(4, &bad_msg).is_err());
+        bad_msg.indices = vec![3, 4];
+        assert!(receiver_indices(4, &bad_msg).is_err());
+        assert!(input_keys_msg(&circuit, &[3, 3], &[true, false]).is_err());
+        assert!(input_keys_msg(&circuit, &[3, 4], &[true, false]).is_err());
+        assert!(input_keys_msg(&circuit, &[3], &[true, false]).is_err());
+    }
+
+    #[test]
+    fn verify_input_keys_test() {
+        let circuit: GarbledCircuit = construct_circuit(2).into();
+        let input_keys = circuit.input_keys();
+        let commitments = key_commitments_msg(&circuit);
+
+        // Either key of every wire opens its commitment
+        for bits in 0..16 {
+            let keys: Vec<[u8; 32]> = (0..4)
+                .map(|i| if bits & (1 << i) != 0 { input_keys[&i].on_key() } else { input_keys[&i].off_key() })
+                .collect();
+
+            assert!(verify_input_keys(&commitments, &keys).is_ok());
+        }
+
+        let keys: Vec<[u8; 32]> = (0..4).map(|i| input_keys[&i].off_key()).collect();
+        // A substituted key, or another wire's key, doesn't
+        let mut substituted = keys.clone();
+        substituted[1][5] ^= 1;
+        assert!(verify_input_keys(&commitments, &substituted).is_err());
+        substituted = keys.clone();
+        substituted.swap(0, 1);
+        assert!(verify_input_keys(&commitments, &substituted).is_err());
+        // Neither do commitments to a different number of wires
+        assert!(verify_input_keys(&commitments, &keys[..3]).is_err());
+    }
+
+    #[test]
+    fn try_eval_malformed_circuit_test() {
+        let circuit: GarbledCircuit = construct_circuit(2).into();
+        let input_keys = circuit.input_keys();
+        let inputs: Vec<[u8; 32]> = (0..4).map(|i| input_keys[&i].off_key()).collect();
+        let mut circuit_msg = garbled_circuit_msg(circuit);
+
+        // A gate that refers to itself (or any later gate) can't be evaluated in order
+        let first_gate = circuit_msg.n as u64;
+        circuit_msg.gates[0].inputs[0] = first_gate;
+        let recv_circuit: GarbledCircuitRecv = circuit_msg.clone().try_into().unwrap();
+        assert!(recv_circuit.try_eval(&inputs).is_err());
+
+        // Neither can an output that refers to a nonexistent gate
+        circuit_msg.gates[0].inputs[0] = 0;
+        let outs = circuit_msg.outs.clone();
+        circuit_msg.outs[0] = first_gate + circuit_msg.gates.len() as u64;
+        let recv_circuit: GarbledCircuitRecv = circuit_msg.clone().try_into().unwrap();
+        assert!(recv_circuit.try_eval(&inputs).is_err());
+
+        // Nor a gate that's missing a row
+        circuit_msg.outs = outs;
+        circuit_msg.gates[0].rows.pop();
+        let recv_circuit: GarbledCircuitRecv = circuit_msg.try_into().unwrap();
+        assert!(recv_circuit.try_eval(&inputs).is_err());
+
+        // Nor a table gate none of whose rows decrypt under our keys, which is reported instead
+        let majority = Node::GateN(
+            vec![false, false, false, true, false, true, true, true],
+            (0..3).map(|i| Rc::new(Node::Input(i))).collect(),
+        );
+        let circuit: GarbledCircuit = Circuit::new(majority).unwrap().into();
+        let input_keys = circuit.input_keys();
+        let inputs: Vec<[u8; 32]> = (0..3).map(|i| input_keys[&i].on_key()).collect();
+        let mut circuit_msg = garbled_circuit_msg(circuit);
+        // Every row's tag ends its row
+        for row in circuit_msg.gates[0].rows.iter_mut() {
+            *row.last_mut().unwrap() ^= 1;
+        }
+        let recv_circuit: GarbledCircuitRecv = circuit_msg.try_into().unwrap();
+        assert!(recv_circuit.try_eval(&inputs).is_err());
+    }
+
+    #[test]
+    fn decode_output_keys_test() {
+        let circuit: GarbledCircuit = construct_circuit(2).into();
+        let input_keys = circuit.input_keys();
+        // 0b10 vs. 0b01
+        let inputs = vec![input_keys[&0].off_key(), input_keys[&1].on_key(), input_keys[&2].on_key(), input_keys[&3].off_key()];
+        let recv_circuit: GarbledCircuitRecv = circuit.clone().into();
+        let mut keys: Vec<Vec<u8>> = recv_circuit.eval(&inputs).iter().map(|key| key.to_vec()).collect();
+
+        // The garbler decodes the receiver's keys into the same result the receiver got
+        assert_eq!(decode_output_keys(&circuit, &keys).unwrap(), recv_circuit.eval_bits(&inputs).unwrap());
+        assert_eq!(decode_output_keys(&circuit, &keys).unwrap(), vec![true, false]);
+
+        // A receiver that reports a different result can't come up with its key
+        assert!(decode_output_keys(&circuit, &keys[..1]).is_err());
+        keys[1][0] ^= 1;
+        assert!(decode_output_keys(&circuit, &keys).is_err());
+    }
+
+    #[test]
+    fn eval_many_test() {
+        let circuit: GarbledCircuit = construct_circuit(2).into();
+        let input_keys = circuit.input_keys();
+        let recv_circuit: GarbledCircuitRecv = circuit.clone().into();
+        // Every assignment of the four inputs
+        let assignments: Vec<Vec<[u8; 32]>> = (0..16usize)
+            .map(|x| {
+                (0..4)
+                    .map(|i| if (x >> i) & 1 == 1 { input_keys[&i].on_key() } else { input_keys[&i].off_key() })
+                    .collect()
+            })
+            .collect();
+
+        let outs = recv_circuit.eval_many(&assignments).unwrap();
+        assert_eq!(outs.len(), assignments.len());
+        for (keys, out) in assignments.iter().zip(&outs) {
+            assert_eq!(*out, recv_circuit.try_eval(keys).unwrap());
+        }
+        assert!(recv_circuit.eval_many::<32>(&[]).unwrap().is_empty());
+
+        // An assignment that's missing a key fails the whole batch, naming it
+        let mut short = assignments.clone();
+        short[5].pop();
+        let e = recv_circuit.eval_many(&short).unwrap_err();
+        assert!(e.to_string().starts_with("assignment 5"), "{}", e);
+
+        // A circuit garbled with another cipher is evaluated with it, and the decoded results match
+        // the plain circuit
+        let circuit = GarbledCircuit::<32>::from_with_cipher::<AesCtr>(construct_circuit(2));
+        let input_keys = circuit.input_keys();
+        let mut recv_circuit: GarbledCircuitRecv = circuit.clone().into();
+        recv_circuit.set_decoding_table(decoding_table_msg(&circuit));
+        let assignments: Vec<Vec<[u8; 32]>> = (0..16usize)
+            .map(|x| {
+                (0..4)
+                    .map(|i| if (x >> i) & 1 == 1 { input_keys[&i].on_key() } else { input_keys[&i].off_key() })
+                    .collect()
+            })
+            .collect();
+        let outs = recv_circuit.eval_many_with::<AesCtr, 32>(&assignments).unwrap();
+
+        for (x, out) in outs.iter().enumerate() {
+            let bits: Vec<bool> = (0..4).map(|i| (x >> i) & 1 == 1).collect();
+            assert_eq!(recv_circuit.decode_bits(out).unwrap(), construct_circuit(2).eval_outputs(&bits));
+        }
+
+        // Evaluated with the default cipher, its output keys don't decode
+        let outs = recv_circuit.eval_many(&assignments).unwrap();
+        assert!(outs.iter().all(|out| recv_circuit.decode_bits(out).is_err()));
+    }
+
+    #[test]
+    fn eval_trace_test() {
+        let circuit: GarbledCircuit = construct_circuit(2).into();
+        let wires: Vec<_> = circuit.gates().iter().map(|gate| gate.out_wire()).collect();
+        let input_keys = circuit.input_keys();
+        let inputs: Vec<[u8; 32]> = (0..4).map(|i| input_keys[&i].on_key()).collect();
+        let mut circuit_msg = garbled_circuit_msg(circuit);
+        let recv_circuit: GarbledCircuitRecv = circuit_msg.clone().try_into().unwrap();
+        let (result, trace) = recv_circuit.eval_trace(&inputs);
+
+        assert_eq!(result.unwrap(), recv_circuit.eval(&inputs));
+        assert_eq!(trace.len(), inputs.len() + wires.len());
+        assert_eq!(trace[&GarbledNodeRecv::Input(1)], inputs[1]);
+        // Every gate's key is one of the keys of its output wire
+        let keys_match = |trace: &EvalTrace, id: usize| {
+            [wires[id].off_key(), wires[id].on_key()].contains(&trace[&GarbledNodeRecv::Gate(id)])
+        };
+        assert!((0..wires.len()).all(|id| keys_match(&trace, id)));
+
+        // Miswire a gate: the garbler can tell it's the first gate with a wrong key
+        let n = circuit_msg.n as u64;
+        let miswired = circuit_msg.gates.iter().position(|gate| gate.inputs[0] < n).unwrap();
+        let input = &mut circuit_msg.gates[miswired].inputs[0];
+        *input = (*input + 1) % n;
+        let recv_circuit: GarbledCircuitRecv = circuit_msg.try_into().unwrap();
+        let (_, trace) = recv_circuit.eval_trace(&inputs);
+
+        assert_eq!((0..wires.len()).position(|id| !keys_match(&trace, id)), Some(miswired));
+    }
+
+    #[test]
+    fn serialize_circuit_test() {
+        let circuit: GarbledCircuit = construct_circuit(4).into();
+        let input_keys = circuit.input_keys();
+        let table_msg = decoding_table_msg(&circuit);
+        let bytes = serialize_circuit(circuit).unwrap();
+        let mut recv_circuit = deserialize_circuit(&bytes).unwrap();
+        recv_circuit.set_decoding_table(table_msg);
+        assert_eq!(recv_circuit.serialized_size(), bytes.len());
+
+        // 9 vs. 3
+        let bits = 9 | (3 << 4);
+        let inputs: Vec<[u8; 32]> = (0..8)
+            .map(|i| {
+                let wire = &input_keys[&i];
+
+                if bits & (1 << i) != 0 { wire.on_key() } else { wire.off_key() }
+            })
+            .collect();
+        assert_eq!(recv_circuit.eval_bits(&inputs).unwrap(), vec![true, false]);
+
+        // Garbage doesn't parse, and neither does a circuit with a negative size
+        assert!(deserialize_circuit(&[0xff; 4]).is_err());
+        let mut circuit_msg = GarbledCircuitSend::parse_from_bytes(&bytes).unwrap();
+        circuit_msg.n = -1;
+        assert!(deserialize_circuit(&circuit_msg.write_to_bytes().unwrap()).is_err());
+
+        // Each malformed size is reported as such
+        let circuit_msg = GarbledCircuitSend::parse_from_bytes(&bytes).unwrap();
+        let convert = |n: i64, key_size: i64| {
+            GarbledCircuitRecv::try_from(GarbledCircuitSend { n, key_size, ..circuit_msg.clone() }).err()
+        };
+        assert_eq!(convert(-1, 32), Some(ConversionError::OutOfRange { field: "number of inputs", value: -1 }));
+        assert_eq!(convert(4, 0), Some(ConversionError::MissingField("key size")));
+        assert_eq!(convert(4, 33), Some(ConversionError::OutOfRange { field: "key size", value: 33 }));
+        assert_eq!(convert(4, 16), None);
+    }
+
+    #[test]
+    fn shared_gate_test() {
+        // x ^ y feeds both (x ^ y) & z and (x ^ y) | z
+        let input = |idx| Rc::new(Node::Input(idx));
+        let xor = Rc::new(Node::Gate(gates::XOR, input(0), input(1)));
+        let and = Node::Gate(gates::AND, xor.clone(), input(2));
+        let or = Node::Gate(gates::OR, xor, input(2));
+        let circuit: GarbledCircuit = Circuit::with_outputs(vec![and, or]).unwrap().into();
+        let input_keys = circuit.input_keys();
+        let table_msg = decoding_table_msg(&circuit);
+        let mut recv_circuit = deserialize_circuit(&serialize_circuit(circuit).unwrap()).unwrap();
+        recv_circuit.set_decoding_table(table_msg);
+
+        // The shared gate is sent and stored once, and both of its parents refer to it by its id
+        assert_eq!(recv_circuit.gates.len(), 3);
+        let parents: Vec<GarbledNodeRecv> = recv_circuit
+            .outs
+            .iter()
+            .map(|out| match out {
+                GarbledNodeRecv::Gate(idx) => recv_circuit.gates[*idx].inputs[0],
+                GarbledNodeRecv::Input(_) => panic!("the outputs should be gates"),
+            })
+            .collect();
+        assert_eq!(parents[0], parents[1]);
+        assert!(matches!(parents[0], GarbledNodeRecv::Gate(_)));
+
+        for bits in 0..8usize {
+            let inputs: Vec<[u8; 32]> = (0..3)
+                .map(|i| if bits & (1 << i) != 0 { input_keys[&i].on_key() } else { input_keys[&i].off_key() })
+                .collect();
+            let (xor, z) = ((bits & 1) ^ ((bits >> 1) & 1) == 1, bits & 4 != 0);
+
+            assert_eq!(recv_circuit.eval_bits(&inputs).unwrap(), vec![xor && z, xor || z]);
+        }
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn streamed_circuit_test() {
+        use std::{
+            net::{TcpListener, TcpStream},
+            thread,
+        };
+
+        use crate::{
+            backend::garbler_backend::{protos::DecodingTable, send_decoding_table, send_garbled_circuit_streaming},
+            garbling::StreamedCircuit,
+            message::MessageStream,
+        };
+
+        use super::receive_streamed_circuit;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let garbler = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let circuit: StreamedCircuit =
+                send_garbled_circuit_streaming(&mut stream, comparison_circuit(4).dedup_subexpressions()).unwrap();
+            send_decoding_table(&mut stream, &circuit).unwrap();
+            // The garbler's keys for 9
+            MessageStream::<GarblerKeys>::send_msg(&mut stream, input_keys_msg(&circuit, &[0, 1, 2, 3], &to_bits(9, 4)).unwrap())
+                .unwrap();
+
+            circuit.input_keys()
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut recv_circuit = receive_streamed_circuit(&mut stream).unwrap();
+        recv_circuit.set_decoding_table(MessageStream::<DecodingTable>::receive_msg(&mut stream).unwrap());
+        let keys_msg = MessageStream::<GarblerKeys>::receive_msg(&mut stream).unwrap();
+        let input_keys = garbler.join().unwrap();
+
+        // The receiver's keys for 3
+        let mut inputs: Vec<[u8; 32]> = to_keys(&keys_msg.keys).unwrap();
+        inputs.extend((4..8).map(|i| {
+            let wire = &input_keys[&i];
+
+            if 3 & (1 << (i - 4)) != 0 { wire.on_key() } else { wire.off_key() }
+        }));
+
+        assert_eq!(recv_circuit.n(), 8);
+        assert_eq!(recv_circuit.eval_bits(&inputs).unwrap(), vec![true, false]);
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn ot_test() {
+        use std::{
+            net::{TcpListener, TcpStream},
+            thread,
+        };
+
+        use crate::{
+            backend::garbler_backend::{
+                protos::{OtBlindedIdxBatch, OtPublics},
+                send_by_ot,
+            },
+            message::MessageStream,
+        };
+
+        use super::receive_by_ot;
+
+        let msgs: Vec<([u8; 32], [u8; 32])> = (0..10u8).map(|i| ([i; 32], [i + 100; 32])).collect();
+        let choices: Vec<bool> = (0..10).map(|i| i % 3 == 0).collect();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let sent = msgs.clone();
+        let garbler = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            send_by_ot(&mut stream, sent).unwrap();
+
+            // A garbler that doesn't run all of the base OTs
+            let (mut stream, _) = listener.accept().unwrap();
+            MessageStream::<OtPublics>::receive_msg(&mut stream).unwrap();
+            MessageStream::<OtBlindedIdxBatch>::send_msg(&mut stream, OtBlindedIdxBatch::new()).unwrap();
+        });
+        let keys = receive_by_ot(&mut TcpStream::connect(addr).unwrap(), &choices).unwrap();
+
+        for ((key, (m_0, m_1)), choice) in keys.iter().zip(&msgs).zip(&choices) {
+            assert_eq!(key, if *choice { m_1 } else { m_0 });
+        }
+
+        let err = receive_by_ot(&mut TcpStream::connect(addr).unwrap(), &choices).unwrap_err();
+        assert!(err.to_string().contains("expected 128 base OT blinded indices"), "{}", err);
+        garbler.join().unwrap();
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn corrupted_streamed_circuit_test() {
+        use std::io::Cursor;
+
+        use crate::{backend::garbler_backend::send_garbled_circuit_streaming, garbling::StreamedCircuit};
+
+        use super::receive_streamed_circuit;
+
+        let mut sent = Cursor::new(vec![]);
+        let _: StreamedCircuit = send_garbled_circuit_streaming(&mut sent, comparison_circuit(4)).unwrap();
+        let sent = sent.into_inner();
+        let circuit = receive_streamed_circuit(&mut Cursor::new(sent.clone())).unwrap();
+
+        // Flip a bit of one of the rows, which still parses as a circuit
+        let row = &circuit.gates[0].rows[1];
+        let offset = sent.windows(row.len()).position(|window| window == &row[..]).unwrap();
+        let mut corrupted = sent.clone();
+        corrupted[offset] ^= 1;
+
+        match receive_streamed_circuit(&mut Cursor::new(corrupted)) {
+            Err(e) => assert!(e.to_string().contains("digest"), "{}", e),
+            Ok(_) => panic!("a corrupted circuit was accepted"),
+        }
+    }
+
+    #[test]
+    fn privacy_free_test() {
+        let circuit: GarbledCircuit = GarbledCircuit::from_privacy_free(comparison_circuit(4)).unwrap();
+        let input_keys = circuit.input_keys();
+        let recv_circuit: PrivacyFreeCircuitRecv = circuit.clone().into();
+
+        for (a, b) in [(0usize, 0usize), (9, 3), (3, 9), (5, 5), (15, 14)] {
+            let bits = a | (b << 4);
+            let inputs: Vec<(bool, [u8; 32])> = (0..8)
+                .map(|i| {
+                    let (bit, wire) = (bits & (1 << i) != 0, &input_keys[&i]);
+
+                    (bit, if bit { wire.on_key() } else { wire.off_key() })
+                })
+                .collect();
+            let outs = recv_circuit.eval(&inputs);
+            let keys: Vec<[u8; 32]> = outs.iter().map(|(_, key)| *key).collect();
+
+            // The receiver computes the result, and the garbler verifies it from the output keys
+            assert_eq!(outs.iter().map(|(val, _)| *val).collect::<Vec<_>>(), vec![a > b, a == b]);
+            assert_eq!(circuit.decode_outputs(&keys), Some(vec![a > b, a == b]));
+
+            // Keys that don't belong to the output wires are rejected
+            let mut forged = keys.clone();
+            forged[0][0] ^= 1;
+            assert_eq!(circuit.decode_outputs(&forged), None);
+        }
+
+        // Only the non-linear gates have a ciphertext
+        let xnor_gates = recv_circuit.gates.iter().filter(|gate| gate.op == gates::XNOR).count();
+        let free_gates = recv_circuit.gates.iter().filter(|gate| gate.ciphertext.is_none()).count();
+        assert_eq!(free_gates, xnor_gates);
+
+        // A circuit garbled with another cipher is evaluated with that cipher
+        let circuit: GarbledCircuit = GarbledCircuit::from_privacy_free_with_cipher::<AesCtr>(comparison_circuit(4)).unwrap();
+        let input_keys = circuit.input_keys();
+        let recv_circuit: PrivacyFreeCircuitRecv = circuit.clone().into();
+        // 9 vs. 3
+        let inputs: Vec<(bool, [u8; 32])> = to_bits(9 | (3 << 4), 8)
+            .into_iter()
+            .enumerate()
+            .map(|(i, bit)| (bit, if bit { input_keys[&i].on_key() } else { input_keys[&i].off_key() }))
+            .collect();
+        let keys: Vec<[u8; 32]> = recv_circuit.eval_with::<AesCtr>(&inputs).iter().map(|(_, key)| *key).collect();
+        assert_eq!(circuit.decode_outputs(&keys), Some(vec![true, false]));
+        let keys: Vec<[u8; 32]> = recv_circuit.eval(&inputs).iter().map(|(_, key)| *key).collect();
+        assert_eq!(circuit.decode_outputs(&keys), None);
+    }
+
+    #[test]
+    fn privacy_free_wide_gate_test() {
+        let inputs = (0..3).map(|i| Rc::new(Node::Input(i))).collect();
+        let circuit = Circuit::new(Node::GateN(vec![false, true, true, false, true, false, false, true], inputs)).unwrap();
+
+        // Only two-input gates can be garbled in privacy-free mode
+        let err = GarbledCircuit::<32>::from_privacy_free(circuit).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("3 inputs"), "{}", err);
+    }
+
+    #[test]
+    fn eval_bits_test() {
+        let circuit: GarbledCircuit = construct_circuit(2).into();
+        let input_keys = circuit.input_keys();
+        // The garbler has 0b10 and we have 0b01
+        let inputs: Vec<[u8; 32]> = vec![
+            input_keys[&0].off_key(),
+            input_keys[&1].on_key(),
+            input_keys[&2].on_key(),
+            input_keys[&3].off_key(),
+        ];
+        let mut table_msg = decoding_table_msg(&circuit);
+        let mut recv_circuit: GarbledCircuitRecv = garbled_circuit_msg(circuit).try_into().unwrap();
+
+        // The output keys can't be decoded until we get the decoding table
+        assert!(recv_circuit.eval_bits(&inputs).is_err());
+
+        recv_circuit.set_decoding_table(table_msg.clone());
+        assert_eq!(recv_circuit.eval_bits(&inputs).unwrap(), vec![true, false]);
+        // There's more than one output
+        assert!(recv_circuit.eval_bool(&inputs).is_err());
+
+        // Output keys that aren't in the decoding table are rejected
+        table_msg.outs[1].off_hash[0] ^= 1;
+        recv_circuit.set_decoding_table(table_msg);
+        assert!(recv_circuit.eval_bits(&inputs).is_err());
+
+        // A single-output circuit
+        let circuit: GarbledCircuit = Circuit::new(Node::Gate(gates::AND, Rc::new(Node::Input(0)), Rc::new(Node::Input(1)))).unwrap().into();
+        let input_keys = circuit.input_keys();
+        let recv_circuit: GarbledCircuitRecv = circuit.into();
+
+        assert!(recv_circuit.eval_bool(&[input_keys[&0].on_key(), input_keys[&1].on_key()]).unwrap());
+        assert!(!recv_circuit.eval_bool(&[input_keys[&0].on_key(), input_keys[&1].off_key()]).unwrap());
+    }
+
+    #[test]
+    fn to_keys_test() {
+        assert_eq!(to_keys::<4>(&[vec![1u8; 4], vec![2u8; 4]]).unwrap(), vec![[1u8; 4], [2u8; 4]]);
+        // Truncated and oversized keys are rejected
+        assert!(to_keys::<4>(&[vec![1u8; 4], vec![2u8; 3]]).is_err());
+        assert!(to_keys::<4>(&[vec![1u8; 5]]).is_err());
+
+        // The error names the key
+        let err = to_keys::<4>(&[vec![1u8; 4], vec![2u8; 3]]).unwrap_err();
+        let expected = ConversionError::BadKeyLength { key: 1, len: 3, expected: 4 };
+        assert_eq!(err.get_ref().and_then(|err| err.downcast_ref::<ConversionError>()), Some(&expected));
+    }
+
+    #[test]
+    fn decrypt_row_test() {
+        // A 3-input majority gate, garbled as a table with a row per combination of its inputs
+        let majority = Node::GateN(
+            vec![false, false, false, true, false, true, true, true],
+            (0..3).map(|i| Rc::new(Node::Input(i))).collect(),
+        );
+        let circuit: GarbledCircuit = GarbledCircuit::from_seeded(Circuit::new(majority).unwrap(), [3u8; 32]);
+        let (input_keys, out) = (circuit.input_keys(), circuit.output_wires()[0].clone());
+        let recv_circuit: GarbledCircuitRecv = circuit.into();
+        let gate = &recv_circuit.gates[0];
+        let gate = GarbledGateRecv::new(gate.kind(), gate.rows().to_vec(), gate.inputs.clone());
+
+        assert_eq!(gate.kind(), GateKind::Table);
+        assert_eq!(gate.rows().len(), 8);
+
+        for bits in 0..8usize {
+            let keys: Vec<[u8; 32]> = (0..3)
+                .map(|i| if bits & (1 << i) != 0 { input_keys[&i].on_key() } else { input_keys[&i].off_key() })
+                .collect();
+            let expected = if bits.count_ones() >= 2 { out.on_key() } else { out.off_key() };
+            let decrypted: Vec<[u8; 32]> = (0..8).filter_map(|pos| gate.decrypt_row(0, pos, &keys)).collect();
+
+            // Exactly one row is encrypted under each combination of the input keys, and it holds
+            // the right output key
+            assert_eq!(decrypted, vec![expected]);
+            // The row is tied to the gate's id
+            assert!((0..8).all(|pos| gate.decrypt_row(1, pos, &keys).is_none()));
+        }
+
+        assert!(gate.decrypt_row::<32>(0, 8, &[[0u8; 32]; 3]).is_none());
+    }
+
+    #[test]
+    fn select_row_test() {
+        // Whichever position the valid row is in, its plaintext is selected
+        for valid_pos in 0..4 {
+            let rows = (0..4).map(|pos| (vec![pos as u8 + 1; 16], Choice::from((pos == valid_pos) as u8)));
+
+            assert_eq!(select_row::<16>(rows), Some([valid_pos as u8 + 1; 16]));
+        }
+
+        // A row of the wrong length is skipped, even if it authenticates
+        let rows = vec![(vec![1u8; 8], Choice::from(1)), (vec![2u8; 16], Choice::from(1))];
+        assert_eq!(select_row::<16>(rows), Some([2u8; 16]));
+
+        // No row is selected if none authenticates
+        let rows = vec![(vec![1u8; 16], Choice::from(0)), (vec![2u8; 16], Choice::from(0))];
+
+        assert_eq!(select_row::<16>(rows), None);
+    }
+
+    #[test]
+    fn reused_topology_test() {
+        // Garbling the same circuit twice gives each session its own wire keys, and both garblings
+        // evaluate correctly
+        let topology = construct_circuit(2);
+        let first: GarbledCircuit = topology.clone().into();
+        let second: GarbledCircuit = topology.into();
+        assert_ne!(first.input_keys()[&0].off_key(), second.input_keys()[&0].off_key());
+
+        for circuit in [first, second] {
+            let input_keys = circuit.input_keys();
+            // 0b10 vs. 0b01
+            let inputs = vec![input_keys[&0].off_key(), input_keys[&1].on_key(), input_keys[&2].on_key(), input_keys[&3].off_key()];
+            let recv_circuit: GarbledCircuitRecv = circuit.into();
+
+            assert_eq!(recv_circuit.eval_bits(&inputs).unwrap(), vec![true, false]);
+        }
+    }
+
+    #[test]
+    fn topology_hash_test() {
+        let expected = topology_hash(&construct_comparison(2, 3));
+        // Any garbling of the agreed circuit has its structure, whatever its keys
+        let circuit: GarbledCircuit = construct_comparison(2, 3).into();
+        let recv_circuit: GarbledCircuitRecv = circuit.into();
+        assert_eq!(recv_circuit.structure_hash(), expected);
+
+        // A circuit for other widths doesn't
+        for (a_bits, b_bits) in [(3, 2), (2, 4), (3, 3)] {
+            assert_ne!(topology_hash(&construct_comparison(a_bits, b_bits)), expected);
+        }
+
+        // The hash of the plaintext circuit matches that of its garblings for every kind of gate:
+        // linear gates that only depend on some of their inputs, table gates, shared nodes and
+        // outputs that are inputs
+        let input = |i| Rc::new(Node::Input(i));
+        let and = Rc::new(Node::Gate(gates::AND, input(0), input(1)));
+        let majority = Rc::new(Node::GateN(vec![false, false, false, true, false, true, true, true], (0..3).map(input).collect()));
+        let circuits = [
+            construct_comparison(3, 2).dedup_subexpressions(),
+            construct_signed_comparison(2, 2),
+            Circuit::with_outputs(vec![
+                Node::Gate(gates::XOR, and.clone(), majority.clone()),
+                Node::Gate(gates::NOT_LEFT, and.clone(), input(2)),
+                Node::Gate(gates::RIGHT, input(1), majority),
+                Node::Gate(gates::TRUE, input(0), and),
+                Node::Input(2),
+            ])
+            .unwrap(),
+        ];
+
+        for circuit in circuits {
+            let expected = topology_hash(&circuit);
+            let recv_circuit: GarbledCircuitRecv = GarbledCircuit::<32>::from(circuit.clone()).into();
+            assert_eq!(recv_circuit.structure_hash(), expected);
+
+            let recv_circuit: GarbledCircuitRecv = GarbledCircuit::<32>::from_with_cipher::<AesCtr>(circuit).into();
+            assert_eq!(recv_circuit.structure_hash(), expected);
+        }
+    }
+
+    #[test]
+    fn writer_reader_test() {
+        let circuit: GarbledCircuit = construct_circuit(2).into();
+        let input_keys = circuit.input_keys();
+        let recv_circuit: GarbledCircuitRecv = circuit.into();
+        let mut bytes = vec![];
+        recv_circuit.to_writer(&mut bytes).unwrap();
+
+        let read_circuit = GarbledCircuitRecv::from_reader(bytes.as_slice()).unwrap();
+        assert_eq!(read_circuit.n(), recv_circuit.n());
+        assert_eq!(read_circuit.key_size(), recv_circuit.key_size());
+        assert_eq!(read_circuit.num_rows(), recv_circuit.num_rows());
+
+        for a in 0..4 {
+            for b in 0..4 {
+                let bits = [a >> 1 & 1 == 1, a & 1 == 1, b >> 1 & 1 == 1, b & 1 == 1];
+                let inputs: Vec<_> = bits
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &bit)| if bit { input_keys[&i].on_key() } else { input_keys[&i].off_key() })
+                    .collect();
+
+                assert_eq!(read_circuit.eval_bits(&inputs).unwrap(), recv_circuit.eval_bits(&inputs).unwrap());
+            }
+        }
+
+        // A truncated circuit is rejected rather than read partially
+        assert!(GarbledCircuitRecv::from_reader(&bytes[..bytes.len() - 1]).is_err());
+        // The kind of the first gate comes right after the header
+        bytes[31] = 3;
+        assert!(GarbledCircuitRecv::from_reader(bytes.as_slice()).is_err());
+    }
+
+    #[test]
+    fn asymmetric_comparison_test() {
+        for (a_bits, b_bits) in [(2, 4), (4, 2), (3, 3)] {
+            let circuit: GarbledCircuit = construct_comparison(a_bits, b_bits).into();
+            let input_keys = circuit.input_keys();
+            let recv_circuit: GarbledCircuitRecv = circuit.into();
+
+            // The shorter input is zero-extended, for free
+            assert_eq!(recv_circuit.n(), a_bits + b_bits);
+            let symmetric = comparison_circuit(a_bits.max(b_bits)).num_gates();
+            assert!(asymmetric_comparison_circuit(a_bits, b_bits).num_gates() <= symmetric);
+
+            for a in 0..1 << a_bits {
+                for b in 0..1 << b_bits {
+                    let bits = [to_bits(a, a_bits), to_bits(b, b_bits)].concat();
+                    let keys: Vec<[u8; 32]> = bits
+                        .iter()
+                        .enumerate()
+                        .map(|(i, bit)| if *bit { input_keys[&i].on_key() } else { input_keys[&i].off_key() })
+                        .collect();
+
+                    assert_eq!(recv_circuit.eval_bits(&keys).unwrap(), vec![a > b, a == b]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "at least 1 bit wide")]
+    fn zero_width_comparison_test() {
+        asymmetric_comparison_circuit(4, 0);
+    }
+
+    #[test]
+    fn signed_comparison_test() {
+        let eval = |a: &str, b: &str, a_bits: usize, b_bits: usize| {
+            let circuit: GarbledCircuit = construct_signed_comparison(a_bits, b_bits).into();
+            let input_keys = circuit.input_keys();
+            let bits = [parse_signed_bits(a, a_bits).unwrap(), parse_signed_bits(b, b_bits).unwrap()].concat();
+            let keys: Vec<[u8; 32]> = bits
+                .iter()
+                .enumerate()
+                .map(|(i, bit)| if *bit { input_keys[&i].on_key() } else { input_keys[&i].off_key() })
+                .collect();
+            let recv_circuit: GarbledCircuitRecv = circuit.into();
+
+            recv_circuit.eval_bits(&keys).unwrap()
+        };
+
+        assert_eq!(eval("-3", "5", 4, 4), vec![false, false]);
+        assert_eq!(eval("5", "-3", 4, 4), vec![true, false]);
+
+        // The shorter input is sign-extended
+        for (a_bits, b_bits) in [(3, 3), (2, 4), (4, 2)] {
+            let range = |bits: usize| -(1i64 << (bits - 1))..1 << (bits - 1);
+
+            for a in range(a_bits) {
+                for b in range(b_bits) {
+                    assert_eq!(eval(&a.to_string(), &b.to_string(), a_bits, b_bits), vec![a > b, a == b], "{} vs {}", a, b);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use std::{fmt, io};
+#[cfg(feature = "sync")]
+use std::io::{Read, Write};
+
+use crate::backend::garbler_backend::protos::Hello;
+#[cfg(feature = "sync")]
+use crate::message::MessageStream;
+
+/// The version of the protocol the parties speak, which they exchange first (see `exchange_hello`).
+/// It must be bumped whenever the messages of a session change
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// The parameters of a session, which both parties must agree on before the garbler sends its circuit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionParams {
+    /// The width of the garbler's net worth in bits
+    pub garbler_bits: usize,
+    /// The width of the receiver's net worth in bits
+    pub receiver_bits: usize,
+    /// Whether the net worths are two's complement (see `signed_comparison_circuit`)
+    pub signed: bool,
+    /// Whether only the receiver learns the result, i.e. doesn't send an `EvalResult` back
+    pub receiver_only: bool,
+    /// Whether the receiver garbles the circuit as well (see `compare_outputs`)
+    pub dual_exec: bool,
+}
+
+impl SessionParams {
+    /// The `Hello` message announcing these parameters
+    pub fn hello_msg(&self) -> Hello {
+        let mut msg = Hello::new();
+
+        msg.version = PROTOCOL_VERSION;
+        msg.garbler_bits = self.garbler_bits as u64;
+        msg.receiver_bits = self.receiver_bits as u64;
+        msg.signed = self.signed;
+        msg.receiver_only = self.receiver_only;
+        msg.dual_exec = self.dual_exec;
+
+        msg
+    }
+
+    /// Check that the other party's `Hello` (`peer` names it in the error) announces these parameters.
+    /// Returns an error if it speaks another version of the protocol, or runs the session with other parameters
+    pub fn check_hello(&self, msg: &Hello, peer: &str) -> Result<(), io::Error> {
+        if msg.version != PROTOCOL_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the {} speaks version {} of the protocol, but we speak version {}", peer, msg.version, PROTOCOL_VERSION),
+            ));
+        }
+
+        let theirs = SessionParams {
+            garbler_bits: msg.garbler_bits as usize,
+            receiver_bits: msg.receiver_bits as usize,
+            signed: msg.signed,
+            receiver_only: msg.receiver_only,
+            dual_exec: msg.dual_exec,
+        };
+
+        if theirs != *self {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the {} runs a session of {}, but we run a session of {}", peer, theirs, self),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for SessionParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-bit garbler and {}-bit receiver {} net worths",
+            self.garbler_bits,
+            self.receiver_bits,
+            if self.signed { "signed" } else { "unsigned" }
+        )?;
+
+        match (self.dual_exec, self.receiver_only) {
+            (true, _) => write!(f, " in dual execution mode"),
+            (false, true) => write!(f, " where only the receiver learns the result"),
+            (false, false) => Ok(()),
+        }
+    }
+}
+
+/// Exchange `Hello` messages with the other party before anything else, aborting if it speaks another version of the protocol
+/// or runs the session with other parameters (see `SessionParams::check_hello`). The garbler sends its `Hello` first.
+/// Both parties send theirs before checking the other's, so that both of them abort cleanly on a mismatch
+#[cfg(feature = "sync")]
+pub fn exchange_hello(stream: &mut (impl Read + Write), params: &SessionParams, garbler: bool) -> Result<(), io::Error> {
+    let peer = if garbler { "receiver" } else { "garbler" };
+
+    if garbler {
+        MessageStream::<Hello>::send_msg(stream, params.hello_msg())?;
+    }
+
+    let msg = MessageStream::<Hello>::receive_msg(stream)?;
+
+    if !garbler {
+        MessageStream::<Hello>::send_msg(stream, params.hello_msg())?;
+    }
+
+    params.check_hello(&msg, peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SessionParams, PROTOCOL_VERSION};
+
+    fn params() -> SessionParams {
+        SessionParams { garbler_bits: 32, receiver_bits: 16, signed: false, receiver_only: false, dual_exec: false }
+    }
+
+    #[test]
+    fn check_hello_test() {
+        let ours = params();
+
+        assert!(ours.check_hello(&ours.hello_msg(), "garbler").is_ok());
+
+        // Another version of the protocol, whatever its parameters
+        let mut msg = ours.hello_msg();
+        msg.version = PROTOCOL_VERSION + 1;
+        let err = ours.check_hello(&msg, "garbler").unwrap_err();
+        assert!(err.to_string().contains("version"), "{}", err);
+
+        // Each parameter has to match
+        let mismatches = [
+            SessionParams { receiver_bits: 32, ..ours },
+            SessionParams { signed: true, ..ours },
+            SessionParams { receiver_only: true, ..ours },
+            SessionParams { dual_exec: true, ..ours },
+        ];
+
+        for theirs in mismatches {
+            let err = ours.check_hello(&theirs.hello_msg(), "receiver").unwrap_err();
+
+            assert!(err.to_string().starts_with("the receiver runs a session of"), "{}", err);
+        }
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn exchange_hello_test() {
+        use std::{
+            net::{TcpListener, TcpStream},
+            thread,
+        };
+
+        use super::exchange_hello;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let garbler = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            exchange_hello(&mut stream, &params(), true)
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let receiver = exchange_hello(&mut stream, &SessionParams { signed: true, ..params() }, false);
+
+        // Both parties abort, each naming the other
+        assert!(receiver.unwrap_err().to_string().contains("the garbler runs a session of 32-bit garbler and 16-bit receiver unsigned"));
+        assert!(garbler.join().unwrap().unwrap_err().to_string().contains("the receiver runs a session of"));
+    }
+}
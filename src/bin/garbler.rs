@@ -1,94 +1,387 @@
+use clap::{builder::RangedU64ValueParser, value_parser, Arg, ArgAction, ArgGroup, ArgMatches, Command};
 use millionaire::{
-    backend::garbler_backend::{
-        construct_circuit, protos::{EvalResult, OtBlindedIdx, OtEncMessages, RsaPubkey, Xs}, send_garbled_circuit, send_input_keys
+    backend::{
+        dual_exec::{compare_outputs, output_keys, outputs_hash, swapped_result},
+        in_process::self_test,
+        offline::serialize_circuit_file,
+        garbler_backend::{
+            comparison_result, construct_comparison, construct_signed_comparison, decode_output_keys, parse_bits,
+            parse_signed_bits, protos::EvalResult, run_garbler, NET_WORTH_BITS,
+        },
+        receiver_backend::run_receiver,
+        session::{exchange_hello, SessionParams},
     },
-    crypto::rsa::Keypair,
-    message::MessageStream,
-    ot::ObTransferSender,
+    circuit::Circuit,
+    garbling::GarbledCircuit,
+    message::{MessageStream, ProtocolStep},
 };
-use num_bigint::BigUint;
+#[cfg(feature = "tls")]
+use millionaire::tls;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
 use std::{
-    env,
-    io::{self, stdin, stdout, Write},
-    net::TcpListener,
+    cmp::Ordering,
+    fs,
+    io::{self, stdin, stdout, Read, Write},
+    net::{TcpListener, TcpStream},
+    process,
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::Duration,
 };
 
-fn get_net_worth() -> usize {
+/// Read our net worth as the bits of our input, exiting if it isn't a (decimal) number that fits in our `bits` inputs of the
+/// circuit (rather than comparing its low bits). The number may be wider than a `usize`, and if it's `signed`, it may be negative
+fn get_net_worth(bits: usize, signed: bool) -> Vec<bool> {
     let mut input = String::new();
 
     print!("How much $ do you have? (in millions): ");
     stdout().flush().unwrap();
     stdin().read_line(&mut input).expect("Failed to read line");
-    input = input.trim().to_lowercase();
 
-    input.parse::<usize>().unwrap()
+    let net_worth = match signed {
+        true => parse_signed_bits(input.trim(), bits),
+        false => parse_bits(input.trim(), bits),
+    };
+
+    match net_worth {
+        Ok(net_worth) => net_worth,
+        Err(e) => {
+            eprintln!("Invalid net worth: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Our net worth (as the bits of our input), the widths (in bits) of our input and of the other party's,
+/// and whether the net worths are signed
+#[derive(Clone)]
+struct Input {
+    net_worth: Vec<bool>,
+    bits: usize,
+    peer_bits: usize,
+    signed: bool,
+}
+
+impl Input {
+    /// The circuit comparing an `a_bits`-bit input to a `b_bits`-bit input, as signed numbers if the net worths are signed
+    fn comparison(&self, a_bits: usize, b_bits: usize) -> Circuit {
+        match self.signed {
+            true => construct_signed_comparison(a_bits, b_bits),
+            false => construct_comparison(a_bits, b_bits),
+        }
+    }
+}
+
+/// The command line interface. The receiver must be run with the same `--bits` and `--peer-bits` (swapped), `--signed`,
+/// result mode and `--tls` flags (see `exchange_hello`)
+fn cli() -> Command {
+    // Inputs are parsed into bits (see `parse_bits`), so they can be wider than a `usize`
+    let width = |name: &'static str, help: &'static str| {
+        Arg::new(name).long(name).value_name("BITS").value_parser(RangedU64ValueParser::<usize>::new().range(1..)).help(help)
+    };
+    let flag = |name: &'static str, help: &'static str| Arg::new(name).long(name).action(ArgAction::SetTrue).help(help);
+    let path = |name: &'static str, help: &'static str| Arg::new(name).long(name).value_name("PATH").help(help);
+
+    Command::new("garbler")
+        .about("Compares our net worth with that of every receiver that connects, by garbling a comparison circuit for it")
+        .arg(Arg::new("host").long("host").value_name("HOST").default_value("127.0.0.1").help("The address to listen on"))
+        .arg(
+            Arg::new("port")
+                .long("port")
+                .value_name("PORT")
+                .value_parser(value_parser!(u16))
+                .required_unless_present_any(["self-test", "emit"])
+                .help("The port to listen on (0 picks a free one)"),
+        )
+        .arg(width("bits", "The width of our net worth [default: 10]"))
+        .arg(width("peer-bits", "The width of the receiver's net worth [default: 10]"))
+        .arg(flag("signed", "Net worths are two's complement, so they can be negative"))
+        .arg(flag("dual-exec", "The receiver garbles the circuit as well, and we evaluate it"))
+        .arg(flag("verify-result", "Decode the receiver's output keys rather than trusting the result it reports"))
+        .arg(flag("receiver-only", "Only the receiver learns the result"))
+        .group(ArgGroup::new("mode").args(["dual-exec", "verify-result", "receiver-only"]))
+        .arg(
+            Arg::new("timeout")
+                .long("timeout")
+                .value_name("SECS")
+                .value_parser(value_parser!(u64))
+                .help("The read/write timeout [default: 30]"),
+        )
+        .arg(flag("tls", "Serve receivers over TLS").requires_all(["tls-cert", "tls-key"]))
+        .arg(path("tls-cert", "Our certificate chain (PEM), which receivers can pin with --tls-pin"))
+        .arg(path("tls-key", "Our private key (PEM)"))
+        .arg(path("emit", "Write the garbled circuit to a file instead of serving receivers"))
+        .arg(flag("self-test", "Check the whole pipeline locally instead of running a session"))
+}
+
+/// Default read/write timeout in seconds
+const DEFAULT_TIMEOUT: u64 = 30;
+
+/// How we learn the result once the receiver has evaluated our circuit
+#[derive(Clone, Copy)]
+enum Mode {
+    /// The receiver reports the result, which we trust
+    Reported,
+    /// The receiver sends us its output keys, which we decode ourselves (see `decode_output_keys`)
+    Verified,
+    /// The receiver garbles the circuit as well, and we evaluate it (see `play`)
+    DualExec,
+    /// We don't learn the result at all: the receiver doesn't send it back, so the session ends once it has its keys.
+    /// Other than the receiver's input, the result is all we'd learn in the other modes, so in this mode the session
+    /// tells us nothing about the receiver's net worth. The receiver still learns the result (and so something about ours)
+    ReceiverOnly,
+}
+
+/// The TLS configuration we serve receivers with. TLS is only available when we're built with the `tls` feature;
+/// otherwise there's never a configuration
+#[cfg(feature = "tls")]
+type TlsConfig = Arc<rustls::ServerConfig>;
+#[cfg(not(feature = "tls"))]
+#[derive(Clone)]
+enum TlsConfig {}
+
+/// The TLS configuration given by `--tls`, which requires our certificate chain (`--tls-cert`) and private key (`--tls-key`)
+/// as PEM files
+#[cfg(feature = "tls")]
+fn tls_config(matches: &ArgMatches) -> Result<Option<TlsConfig>, io::Error> {
+    if !matches.get_flag("tls") {
+        return Ok(None);
+    }
+
+    let path = |name: &str| matches.get_one::<String>(name).unwrap();
+    let (cert_pem, key_pem) = (fs::read(path("tls-cert"))?, fs::read(path("tls-key"))?);
+
+    tls::server_config(&cert_pem, &key_pem).map(Some)
+}
+
+#[cfg(not(feature = "tls"))]
+fn tls_config(matches: &ArgMatches) -> Result<Option<TlsConfig>, io::Error> {
+    match matches.get_flag("tls") {
+        true => Err(io::Error::new(io::ErrorKind::Unsupported, "--tls requires building with the `tls` feature")),
+        false => Ok(None),
+    }
+}
+
+/// Run the protocol with a single receiver, over TLS if we have a configuration
+fn serve(
+    mut stream: TcpStream,
+    input: Input,
+    timeout: Duration,
+    mode: Mode,
+    tls: Option<TlsConfig>,
+) -> Result<Option<Ordering>, io::Error> {
+    // Don't block forever if the receiver crashes mid-protocol
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    match tls {
+        #[cfg(feature = "tls")]
+        Some(config) => play(&mut tls::accept(config, stream).step("the TLS handshake")?, input, mode),
+        #[cfg(not(feature = "tls"))]
+        Some(never) => match never {},
+        None => play(&mut stream, input, mode),
+    }
+}
+
+/// Run the protocol over an established stream, and learn the result as `mode` says. In dual execution mode, the receiver then
+/// garbles the circuit and we evaluate it, and the result is only returned once both parties have checked that the two
+/// executions agree (see `compare_outputs`). Returns how our net worth compares to the receiver's, unless only the receiver
+/// learns it
+fn play(stream: &mut (impl Read + Write), input: Input, mode: Mode) -> Result<Option<Ordering>, io::Error> {
+    // The circuit and the messages after the evaluation depend on the session's parameters, so we check the receiver agrees
+    let params = SessionParams {
+        garbler_bits: input.bits,
+        receiver_bits: input.peer_bits,
+        signed: input.signed,
+        receiver_only: matches!(mode, Mode::ReceiverOnly),
+        dual_exec: matches!(mode, Mode::DualExec),
+    };
+    exchange_hello(stream, &params, true).step("the handshake")?;
+
+    // Every receiver gets a freshly garbled circuit with its own wire keys:
+    // reusing a garbled circuit (and thus its keys) across receivers would leak our input
+    let circuit = input.comparison(input.bits, input.peer_bits);
+    let circuit = run_garbler(stream, circuit, &input.net_worth)?;
+
+    println!(
+        "Sent a garbled circuit of {} gates ({} rows, {} bytes of ciphertexts)",
+        circuit.num_gates(),
+        circuit.num_rows(),
+        circuit.ciphertext_bytes()
+    );
+    // The receiver's keys are all sent with OT extension, so there are two round trips however wide its input is
+    println!("Sent the receiver its keys using {} extended OTs", circuit.n() - input.net_worth.len());
+
+    let result = match mode {
+        Mode::Reported => {
+            let result = MessageStream::<EvalResult>::receive_msg(stream).step("receiving the result")?;
+
+            vec![result.result, result.equal]
+        }
+        Mode::Verified => {
+            let result = MessageStream::<EvalResult>::receive_msg(stream).step("receiving the result")?;
+
+            decode_output_keys(&circuit, &result.output_keys).step("verifying the result")?
+        }
+        Mode::DualExec => {
+            // The receiver's input comes first in the circuit it garbles
+            let (keys, result) = run_receiver(stream, &input.comparison(input.peer_bits, input.bits), &input.net_worth)?;
+            let result = swapped_result(&result);
+            let hash = outputs_hash(&output_keys(&circuit, &result)?, &keys);
+
+            compare_outputs(stream, hash, true).step("comparing the outputs")?;
+
+            result
+        }
+        Mode::ReceiverOnly => return Ok(None),
+    };
+
+    comparison_result(&result).map(Some)
 }
 
-fn listen(net_worth: usize, params: (String, u16)) -> Result<bool, io::Error> {
-    let listener = TcpListener::bind(format!("{}:{}", params.0, params.1)).unwrap();
-    let circuit = construct_circuit(10);
-    let input_keys = circuit.input_keys();
-    let keypair = Keypair::new(None, None);
-
-    println!("Keypair generated");
-
-    if let Some(stream) = listener.incoming().next() {
-        let mut stream = stream.unwrap();
-        // Send the client the circuit
-        send_garbled_circuit(&mut stream, circuit.clone())?;
-        // Send the receiver our input keys
-        send_input_keys(&mut stream, &circuit, net_worth)?;
-        // Send the receiver our RSA public key
-        let mut pubkey_msg = RsaPubkey::new();
-        pubkey_msg.e = keypair.public.e.to_bytes_be();
-        pubkey_msg.n = keypair.public.n.to_bytes_be();
-
-        MessageStream::<RsaPubkey>::send_msg(&mut stream, pubkey_msg)?;
-        // Proceed with n/2 rounds of OT to send the receiver its keys
-        for i in circuit.n() / 2..circuit.n() {
-            let wire = input_keys.get(&i).unwrap();
-            let msgs = (
-                BigUint::from_bytes_be(&wire.off_key()),
-                BigUint::from_bytes_be(&wire.on_key()),
-            );
-            let sender = ObTransferSender::new(msgs, keypair.clone());
-            // Send the x values
-            let mut xs = Xs::new();
-            let xs_bigints = sender.xs();
-            xs.x_0 = xs_bigints.0.to_bytes_be();
-            xs.x_1 = xs_bigints.1.to_bytes_be();
-
-            MessageStream::<Xs>::send_msg(&mut stream, xs)?;
-            // Receive the blinded index from the message
-            let blinded_idx = MessageStream::<OtBlindedIdx>::receive_msg(&mut stream)?;
-            // Respond with the m_primes
-            let m_primes = sender.gen_combined(BigUint::from_bytes_be(&blinded_idx.v));
-            let mut m_primes_msg = OtEncMessages::new();
-            m_primes_msg.m_prime_0 = m_primes.0.to_bytes_be();
-            m_primes_msg.m_prime_1 = m_primes.1.to_bytes_be();
-
-            MessageStream::<OtEncMessages>::send_msg(&mut stream, m_primes_msg)?;
+/// The line we print for the result of a session (if we learned it)
+fn result_line(result: Option<Ordering>) -> &'static str {
+    match result {
+        Some(Ordering::Equal) => "You have the same amount!",
+        Some(Ordering::Greater) => "The garbler is richer!",
+        Some(Ordering::Less) => "The receiver is richer!",
+        None => "Only the receiver learned the result",
+    }
+}
+
+/// The address of a receiver we served, and how our net worth compares to the receiver's (or why the session was aborted)
+type SessionResult = (String, Result<Option<Ordering>, io::Error>);
+
+/// Serve receivers in the background, returning the results of the sessions as they end
+fn listen(
+    input: Input,
+    params: (String, u16),
+    timeout: Duration,
+    mode: Mode,
+    tls: Option<TlsConfig>,
+) -> Result<Receiver<SessionResult>, io::Error> {
+    let listener = TcpListener::bind(format!("{}:{}", params.0, params.1))?;
+    // The port may have been picked by the OS (if we were given port 0)
+    println!("Listening on {}", listener.local_addr()?);
+
+    let (results, received) = mpsc::channel();
+
+    // Serve each receiver on its own thread, so that the listener stays alive
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Failed to accept a connection: {}", e);
+                    continue;
+                }
+            };
+            let (input, tls, results) = (input.clone(), tls.clone(), results.clone());
+
+            thread::spawn(move || {
+                let peer = stream
+                    .peer_addr()
+                    .map(|addr| addr.to_string())
+                    .unwrap_or_else(|_| "an unknown peer".to_string());
+                let _ = results.send((peer, serve(stream, input, timeout, mode, tls)));
+            });
         }
+    });
 
-        let result = MessageStream::<EvalResult>::receive_msg(&mut stream)?;
+    Ok(received)
+}
 
-        if result.result {
-            println!("The garbler is richer!");
-        } else {
-            println!("The receiver is richer!");
+/// Check the whole pipeline locally (see `self_test`) instead of running a session, exiting non-zero if anything fails
+fn run_self_test() -> ! {
+    match self_test(NET_WORTH_BITS) {
+        Ok(()) => {
+            println!("Self-test passed");
+            process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("Self-test failed: {}", e);
+            process::exit(1);
         }
     }
+}
+
+/// Garble the circuit for our input and write it to `path` (see `serialize_circuit_file`) instead of serving receivers,
+/// so that a receiver can evaluate it offline with `--eval`
+fn emit_circuit(path: &str, input: &Input) -> Result<(), io::Error> {
+    let circuit: GarbledCircuit = input.comparison(input.bits, input.peer_bits).into();
+    let num_gates = circuit.gates().len();
+
+    fs::write(path, serialize_circuit_file(circuit, &input.net_worth)?)?;
+    println!("Wrote a garbled circuit of {} gates to {}", num_gates, path);
 
-    Ok(true)
+    Ok(())
 }
 
 fn main() {
-    let net_worth = get_net_worth();
-    let args: Vec<String> = env::args().collect();
-    let (ip, port) = (
-        args.get(1).unwrap(),
-        args.get(2).unwrap().parse::<u16>().unwrap(),
-    );
+    let matches = cli().get_matches();
+
+    // With `--self-test`, no session is run (so there's no net worth or port)
+    if matches.get_flag("self-test") {
+        run_self_test();
+    }
+
+    // The widths of our net worth and the receiver's, which it must agree with
+    let width = |name: &str| matches.get_one::<usize>(name).copied().unwrap_or(NET_WORTH_BITS);
+    let (bits, peer_bits) = (width("bits"), width("peer-bits"));
+    // With `--signed`, net worths are two's complement, so they can be negative (the other party must be run with `--signed` too)
+    let signed = matches.get_flag("signed");
+    let net_worth = get_net_worth(bits, signed);
+
+    // With `--emit`, the garbled circuit is written to a file for testing, and no session is run (so there's no port).
+    // The file holds the receiver's keys for both of its values, so it reveals our net worth to whoever reads it
+    if let Some(path) = matches.get_one::<String>("emit") {
+        match emit_circuit(path, &Input { net_worth, bits, peer_bits, signed }) {
+            Ok(()) => process::exit(0),
+            Err(e) => {
+                eprintln!("Aborting: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    // The port is required unless we're self-testing or emitting the circuit
+    let (host, port) = (matches.get_one::<String>("host").unwrap(), *matches.get_one::<u16>("port").unwrap());
+    let timeout = matches.get_one::<u64>("timeout").copied().unwrap_or(DEFAULT_TIMEOUT);
+    // In dual execution mode, the receiver garbles the circuit as well (it must be run with `--dual-exec` too).
+    // With `--verify-result`, we decode the receiver's output keys rather than trusting the result it reports.
+    // With `--receiver-only`, only the receiver learns the result (it must be run with `--receiver-only` too).
+    // At most one of them is given
+    let mode = if matches.get_flag("dual-exec") {
+        Mode::DualExec
+    } else if matches.get_flag("verify-result") {
+        Mode::Verified
+    } else if matches.get_flag("receiver-only") {
+        Mode::ReceiverOnly
+    } else {
+        Mode::Reported
+    };
+
+    // With `--tls`, receivers connect over TLS (and must be run with `--tls` too)
+    let tls = tls_config(&matches).unwrap_or_else(|e| {
+        eprintln!("Aborting: {}", e);
+        process::exit(1);
+    });
+
     // Start the garbling server
-    listen(net_worth, (ip.to_string(), port)).unwrap();
+    let params = (host.to_string(), port);
+    let timeout = Duration::from_secs(timeout);
+    let input = Input { net_worth, bits, peer_bits, signed };
+
+    let results = listen(input, params, timeout, mode, tls).unwrap_or_else(|e| {
+        eprintln!("Aborting: {}", e);
+        process::exit(1);
+    });
+
+    for (peer, result) in results {
+        match result {
+            Ok(result) => println!("{}", result_line(result)),
+            Err(e) => eprintln!("Aborted the protocol with {}: {}", peer, e),
+        }
+    }
 }
@@ -1,109 +1,315 @@
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use clap::{builder::RangedU64ValueParser, value_parser, Arg, ArgAction, ArgGroup, ArgMatches, Command};
 use std::{
-    env,
-    io::{self, stdin, stdout, Write},
+    cmp::Ordering,
+    fs,
+    io::{self, stdin, stdout, Read, Write},
     net::TcpStream,
+    process,
+    time::Duration,
 };
 
 use millionaire::{
-    backend::{garbler_backend::protos::{
-        EvalResult, GarbledCircuitSend, GarblerKeys, OtBlindedIdx, OtEncMessages, RsaPubkey, Xs
-    }, receiver_backend::GarbledCircuitRecv},
-    crypto::rsa::PublicKey,
-    message::MessageStream,
-    ot::ObTransferReceiver,
+    backend::{
+        dual_exec::{compare_outputs, output_keys, outputs_hash, swapped_result},
+        in_process::self_test,
+        offline::eval_circuit_file,
+        garbler_backend::{
+            comparison_result, construct_comparison, construct_signed_comparison, parse_bits, parse_signed_bits,
+            protos::EvalResult, run_garbler, NET_WORTH_BITS,
+        },
+        receiver_backend::run_receiver,
+        session::{exchange_hello, SessionParams},
+    },
+    circuit::Circuit,
+    garbling::KEY_SIZE,
+    message::{MessageStream, ProtocolStep},
 };
-use num_bigint::BigUint;
+#[cfg(feature = "tls")]
+use millionaire::tls;
 
-fn get_net_worth() -> usize {
+/// Read our net worth as the bits of our input, exiting if it isn't a (decimal) number that fits in our `bits` inputs of the
+/// circuit (rather than comparing its low bits). The number may be wider than a `usize`, and if it's `signed`, it may be negative
+fn get_net_worth(bits: usize, signed: bool) -> Vec<bool> {
     let mut input = String::new();
 
     print!("How much $ do you have? (in millions): ");
     stdout().flush().unwrap();
     stdin().read_line(&mut input).expect("Failed to read line");
-    input = input.trim().to_lowercase();
 
-    input.parse::<usize>().unwrap()
+    let net_worth = match signed {
+        true => parse_signed_bits(input.trim(), bits),
+        false => parse_bits(input.trim(), bits),
+    };
+
+    match net_worth {
+        Ok(net_worth) => net_worth,
+        Err(e) => {
+            eprintln!("Invalid net worth: {}", e);
+            process::exit(1);
+        }
+    }
 }
 
-fn connect(net_worth: usize, params: (String, u16)) -> Result<bool, io::Error> {
-    let mut stream = TcpStream::connect(format!("{}:{}", params.0, params.1))?;
-    // The garbler should have sent us the garbled circuit
-    let circuit = MessageStream::<GarbledCircuitSend>::receive_msg(&mut stream)?;
-    let circuit_recv: GarbledCircuitRecv = circuit.into();
-    // What are the garbler's keys in the circuit?
-    let keys_msg = MessageStream::<GarblerKeys>::receive_msg(&mut stream)?;
-    let mut circuit_inputs = keys_msg.keys;
-    // Using OT, get our (the receiver's) keys
-    // First, the garbler should have sent us their RSA public key
-    let garbler_pubkey = MessageStream::<RsaPubkey>::receive_msg(&mut stream)?;
-    let pubkey = PublicKey {
-        e: BigUint::from_bytes_be(&garbler_pubkey.e),
-        n: BigUint::from_bytes_be(&garbler_pubkey.n),
+/// Our net worth (as the bits of our input), the widths (in bits) of our input and of the other party's,
+/// and whether the net worths are signed
+#[derive(Clone)]
+struct Input {
+    net_worth: Vec<bool>,
+    bits: usize,
+    peer_bits: usize,
+    signed: bool,
+}
+
+impl Input {
+    /// The circuit comparing an `a_bits`-bit input to a `b_bits`-bit input, as signed numbers if the net worths are signed
+    fn comparison(&self, a_bits: usize, b_bits: usize) -> Circuit {
+        match self.signed {
+            true => construct_signed_comparison(a_bits, b_bits),
+            false => construct_comparison(a_bits, b_bits),
+        }
+    }
+}
+
+/// The command line interface. The garbler must be run with the same `--bits` and `--peer-bits` (swapped), `--signed`,
+/// result mode and `--tls` flags (see `exchange_hello`)
+fn cli() -> Command {
+    // Inputs are parsed into bits (see `parse_bits`), so they can be wider than a `usize`
+    let width = |name: &'static str, help: &'static str| {
+        Arg::new(name).long(name).value_name("BITS").value_parser(RangedU64ValueParser::<usize>::new().range(1..)).help(help)
     };
-    let n = circuit_recv.n();
-
-    // We have n / 2 inputs
-    for i in 0..n / 2 {
-        let curr_bit = ((net_worth & (1 << i)) != 0) as usize;
-
-        let xs = MessageStream::<Xs>::receive_msg(&mut stream)?;
-        let (x_0, x_1) = (
-            BigUint::from_bytes_be(&xs.x_0),
-            BigUint::from_bytes_be(&xs.x_1),
-        ); 
-        let receiver = ObTransferReceiver::new(pubkey.clone(), (x_0, x_1));
-        // Blind the index we want & send it to the garbler
-        let v = receiver.blind_idx(curr_bit);
-        let mut blinded_idx = OtBlindedIdx::new();
-        blinded_idx.v = v.to_bytes_be();
-
-        MessageStream::<OtBlindedIdx>::send_msg(&mut stream, blinded_idx)?;
-        // We should now get the encrypted messages
-        let m_primes_msg = MessageStream::<OtEncMessages>::receive_msg(&mut stream)?;
-        let (m_prime_0, m_prime_1) = (
-            BigUint::from_bytes_be(&m_primes_msg.m_prime_0),
-            BigUint::from_bytes_be(&m_primes_msg.m_prime_1),
-        );
-        // Get our key
-        circuit_inputs.push(
-            receiver
-                .derive_msg((m_prime_0, m_prime_1), curr_bit)
-                .to_bytes_be(),
-        );
+    let flag = |name: &'static str, help: &'static str| Arg::new(name).long(name).action(ArgAction::SetTrue).help(help);
+    let path = |name: &'static str, help: &'static str| Arg::new(name).long(name).value_name("PATH").help(help);
+
+    Command::new("receiver")
+        .about("Compares our net worth with the garbler's, by evaluating the comparison circuit it garbles")
+        .arg(Arg::new("host").long("host").value_name("HOST").default_value("127.0.0.1").help("The address of the garbler"))
+        .arg(
+            Arg::new("port")
+                .long("port")
+                .value_name("PORT")
+                .value_parser(value_parser!(u16))
+                .required_unless_present_any(["self-test", "eval"])
+                .help("The port the garbler listens on"),
+        )
+        .arg(width("bits", "The width of our net worth [default: 10]"))
+        .arg(width("peer-bits", "The width of the garbler's net worth [default: 10]"))
+        .arg(flag("signed", "Net worths are two's complement, so they can be negative"))
+        .arg(flag("dual-exec", "Garble the circuit as well, and have the garbler evaluate it"))
+        .arg(flag("receiver-only", "Don't send the result back, so only we learn it").conflicts_with("dual-exec"))
+        .arg(
+            Arg::new("timeout")
+                .long("timeout")
+                .value_name("SECS")
+                .value_parser(value_parser!(u64))
+                .help("The read/write timeout [default: 30]"),
+        )
+        .arg(flag("tls", "Connect to the garbler over TLS").requires("tls-trust"))
+        .arg(path("tls-ca", "The certificate of the CA that issued the garbler's certificate (PEM)"))
+        .arg(path("tls-pin", "Only accept the garbler's certificate in this file, i.e. its --tls-cert (PEM)"))
+        .group(ArgGroup::new("tls-trust").args(["tls-ca", "tls-pin"]).multiple(true))
+        .arg(
+            Arg::new("tls-name")
+                .long("tls-name")
+                .value_name("NAME")
+                .help("The name the garbler's certificate must be valid for [default: --host]"),
+        )
+        .arg(path("eval", "Evaluate a circuit the garbler wrote with --emit instead of connecting to it"))
+        .arg(flag("self-test", "Check the whole pipeline locally instead of running a session"))
+}
+
+/// Default read/write timeout in seconds
+const DEFAULT_TIMEOUT: u64 = 30;
+
+/// The TLS configuration we connect to the garbler with, along with the name its certificate must be valid for.
+/// TLS is only available when we're built with the `tls` feature; otherwise there's never a configuration
+#[cfg(feature = "tls")]
+type TlsConfig = (Arc<rustls::ClientConfig>, String);
+#[cfg(not(feature = "tls"))]
+enum TlsConfig {}
+
+/// The TLS configuration given by `--tls`, which requires the (PEM) certificate of the CA we trust to have issued the garbler's
+/// certificate (`--tls-ca`), the garbler's own certificate to pin (`--tls-pin`, see `pinned_client_config`), or both.
+/// A CA-issued certificate must be valid for `--tls-name`, which defaults to the address we connect to
+#[cfg(feature = "tls")]
+fn tls_config(matches: &ArgMatches, host: &str) -> Result<Option<TlsConfig>, io::Error> {
+    if !matches.get_flag("tls") {
+        return Ok(None);
     }
 
-    // Evaluate the garbled circuit
-    let circuit_inputs: Vec<[u8; 32]> = circuit_inputs
-        .iter()
-        .map(|x| x.as_slice().try_into().unwrap())
-        .collect();
+    let ca_pem = matches.get_one::<String>("tls-ca").map(fs::read).transpose()?;
+    let name = matches.get_one::<String>("tls-name").cloned().unwrap_or_else(|| host.to_string());
+    let config = match matches.get_one::<String>("tls-pin") {
+        Some(pin_path) => tls::pinned_client_config(&fs::read(pin_path)?, ca_pem.as_deref())?,
+        None => tls::client_config(ca_pem.as_deref().unwrap())?,
+    };
 
-    let result = circuit_recv.eval(&circuit_inputs);
+    Ok(Some((config, name)))
+}
 
-    // Send the result to the garbler
-    let mut msg = EvalResult::new();
+#[cfg(not(feature = "tls"))]
+fn tls_config(matches: &ArgMatches, _host: &str) -> Result<Option<TlsConfig>, io::Error> {
+    match matches.get_flag("tls") {
+        true => Err(io::Error::new(io::ErrorKind::Unsupported, "--tls requires building with the `tls` feature")),
+        false => Ok(None),
+    }
+}
 
-    msg.result = result[0] != 0;
+/// Connect to the garbler (over TLS if we have a configuration) and run the protocol with it,
+/// returning how the garbler's net worth compares to ours
+fn connect(
+    input: Input,
+    params: (String, u16),
+    timeout: Duration,
+    dual_exec: bool,
+    receiver_only: bool,
+    tls: Option<TlsConfig>,
+) -> Result<Ordering, io::Error> {
+    let mut stream = TcpStream::connect(format!("{}:{}", params.0, params.1)).step("connecting to the garbler")?;
+    // Don't block forever if the garbler crashes mid-protocol
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    match tls {
+        #[cfg(feature = "tls")]
+        Some((config, name)) => {
+            play(&mut tls::connect(config, &name, stream).step("the TLS handshake")?, input, dual_exec, receiver_only)
+        }
+        #[cfg(not(feature = "tls"))]
+        Some(never) => match never {},
+        None => play(&mut stream, input, dual_exec, receiver_only),
+    }
+}
+
+/// Run the protocol over an established stream. In dual execution mode, we then garble
+/// the circuit and the garbler evaluates it, and the results are only revealed once both parties have checked that the two
+/// executions agree. If `receiver_only`, we don't send the result back, so the garbler doesn't learn it
+fn play(
+    stream: &mut (impl Read + Write),
+    input: Input,
+    dual_exec: bool,
+    receiver_only: bool,
+) -> Result<Ordering, io::Error> {
+    let params = SessionParams {
+        garbler_bits: input.peer_bits,
+        receiver_bits: input.bits,
+        signed: input.signed,
+        receiver_only,
+        dual_exec,
+    };
+    exchange_hello(stream, &params, false).step("the handshake")?;
 
-    MessageStream::<EvalResult>::send_msg(&mut stream, msg)?;
+    // The garbler's input comes first in the circuit it garbles
+    let circuit = input.comparison(input.peer_bits, input.bits);
+    let (keys, result) = run_receiver(stream, &circuit, &input.net_worth)?;
+    // Our keys all come from a single batch of OT extension, however wide our input is
+    println!("Received our keys using {} extended OTs", circuit.n() - input.peer_bits);
+    // The first output is whether the garbler is richer, and the second is whether we're tied
+    let comparison = comparison_result(&result)?;
 
-    // Print the result
-    if result[0] != 0    {
-        println!("The garbler is richer!");
-    } else {
-        println!("The receiver is richer!");
+    if dual_exec {
+        // Our input comes first in the circuit we garble
+        let circuit = input.comparison(input.bits, input.peer_bits);
+        let circuit = run_garbler(stream, circuit, &input.net_worth)?;
+        let hash = outputs_hash(&keys, &output_keys(&circuit, &swapped_result(&result))?);
+
+        compare_outputs(stream, hash, false).step("comparing the outputs")?;
+    } else if !receiver_only {
+        // Send the result to the garbler, along with our output keys, so that it can check the result itself.
+        // The keys only tell the garbler the outputs, which the result tells it anyway
+        let mut msg = EvalResult::new();
+
+        msg.result = result[0];
+        msg.equal = result[1];
+        msg.output_keys = keys.iter().map(|key| key.to_vec()).collect();
+
+        MessageStream::<EvalResult>::send_msg(stream, msg).step("sending the result")?;
     }
 
-    Ok(true)
+    Ok(comparison)
+}
+
+/// The line we print for the result of the session
+fn result_line(result: Ordering) -> &'static str {
+    match result {
+        Ordering::Equal => "You have the same amount!",
+        Ordering::Greater => "The garbler is richer!",
+        Ordering::Less => "The receiver is richer!",
+    }
+}
+
+/// Check the whole pipeline locally (see `self_test`) instead of running a session, exiting non-zero if anything fails
+fn run_self_test() -> ! {
+    match self_test(NET_WORTH_BITS) {
+        Ok(()) => {
+            println!("Self-test passed");
+            process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("Self-test failed: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Evaluate a circuit the garbler wrote to `path` with `--emit` (see `eval_circuit_file`) on our input, instead of
+/// connecting to the garbler. Returns how the garbler's net worth compares to ours
+fn eval_file(path: &str, input: &Input) -> Result<Ordering, io::Error> {
+    comparison_result(&eval_circuit_file::<KEY_SIZE>(&fs::read(path)?, &input.net_worth)?)
 }
 
 fn main() {
-    let net_worth = get_net_worth();
-    let args: Vec<String> = env::args().collect();
-    let (ip, port) = (
-        args.get(1).unwrap(),
-        args.get(2).unwrap().parse::<u16>().unwrap(),
-    );
-
-    connect(net_worth, (ip.to_string(), port)).unwrap();
+    let matches = cli().get_matches();
+
+    // With `--self-test`, no session is run (so there's no net worth or port)
+    if matches.get_flag("self-test") {
+        run_self_test();
+    }
+
+    // The widths of our net worth and the garbler's, which it must agree with
+    let width = |name: &str| matches.get_one::<usize>(name).copied().unwrap_or(NET_WORTH_BITS);
+    let (bits, peer_bits) = (width("bits"), width("peer-bits"));
+    // With `--signed`, net worths are two's complement, so they can be negative (the other party must be run with `--signed` too)
+    let signed = matches.get_flag("signed");
+    let net_worth = get_net_worth(bits, signed);
+
+    // With `--eval`, we evaluate a circuit the garbler wrote to a file, so there's no port
+    if let Some(path) = matches.get_one::<String>("eval") {
+        match eval_file(path, &Input { net_worth, bits, peer_bits, signed }) {
+            Ok(result) => {
+                println!("{}", result_line(result));
+                process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Aborting: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    // The port is required unless we're self-testing or evaluating a file
+    let (host, port) = (matches.get_one::<String>("host").unwrap(), *matches.get_one::<u16>("port").unwrap());
+    let timeout = matches.get_one::<u64>("timeout").copied().unwrap_or(DEFAULT_TIMEOUT);
+    // In dual execution mode, we garble the circuit as well (the garbler must be run with `--dual-exec` too)
+    let dual_exec = matches.get_flag("dual-exec");
+    // With `--receiver-only`, we don't send the result back, so only we learn it (the garbler must be run with `--receiver-only`
+    // too). In dual execution mode both parties learn the result, so it can't be combined with `--dual-exec`
+    let receiver_only = matches.get_flag("receiver-only");
+
+    // With `--tls`, we connect to the garbler over TLS (it must be run with `--tls` too)
+    let tls = tls_config(&matches, host).unwrap_or_else(|e| {
+        eprintln!("Aborting: {}", e);
+        process::exit(1);
+    });
+
+    let input = Input { net_worth, bits, peer_bits, signed };
+
+    match connect(input, (host.to_string(), port), Duration::from_secs(timeout), dual_exec, receiver_only, tls) {
+        Ok(result) => println!("{}", result_line(result)),
+        Err(e) => {
+            eprintln!("Aborting: {}", e);
+            process::exit(1);
+        }
+    }
 }
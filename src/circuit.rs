@@ -1,3 +1,11 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, io,
+    rc::Rc,
+};
+
+use crate::gates::{self, op_name};
+
 /// A node in the circuit
 #[derive(Debug, Clone)]
 pub enum Node {
@@ -5,18 +13,50 @@ pub enum Node {
     Input(usize),
     /// A logic gate represented with a 4-bit integer -- since the truth table has 4 rows, we can
     /// save for each gate the output column read as a 4-bit integer (frop top to botoom). For example, OR is represented as 0111
-    /// We also save the boxed left and right inputs to this gate
-    Gate(u8, Box<Node>, Box<Node>),
+    /// We also save the left and right inputs to this gate. These are reference counted, so a subtree can feed several gates
+    Gate(u8, Rc<Node>, Rc<Node>),
+    /// A logic gate with any number k of inputs (up to `MAX_GATE_INPUTS`), represented by its truth table of 2^k entries.
+    /// The entry for the inputs (x_1, ..., x_k) is at the index whose binary representation is x_1...x_k, so the first input
+    /// is the most significant bit (like the left input of a `Gate`). Two-input gates should use `Gate`, which is cheaper to handle
+    GateN(Vec<bool>, Vec<Rc<Node>>),
 }
 
-/// The circuit is represented as a binary tree
+/// The maximum number of inputs of a `Node::GateN`, whose garbled table has 2^8 = 256 rows.
+/// Each row takes two hashes (its pad and tag), so a gate's tweaks stay below the 2^10 that
+/// `gate_tweak` leaves for them
+pub const MAX_GATE_INPUTS: usize = 8;
+
+/// The largest number of inputs `Circuit::truth_table` evaluates every combination of (2^20 evaluations)
+pub const MAX_TRUTH_TABLE_INPUTS: usize = 20;
+
+/// The circuit is represented as a binary tree per output wire. Subtrees may be shared between gates (and outputs),
+/// in which case the circuit is a DAG, and shared gates are only garbled once.
+/// Cloning a circuit is cheap, since the clone shares its subtrees with the original
+#[derive(Clone)]
 pub struct Circuit {
-    out: Node,
+    outs: Vec<Node>,
     /// Number of inputs to the circuit
     n: usize,
 }
 
 impl Node {
+    /// Parse a boolean expression such as `in0 & (in1 ^ in2)` into a node. Inputs are written `inN`, where N is the input's id.
+    /// The operators are `!` (NOT), `&` (AND), `^` (XOR) and `|` (OR), from the tightest binding to the loosest; binary operators
+    /// are left-associative, and parentheses group as usual. `NOT` and the names of the gate operations (see `gates::op_name`)
+    /// are accepted as well, so rendered expressions can be parsed back, except for constants (`true` and `false`) and wide gates
+    /// (e.g. `[0110](in0, in1)`), which the parser rejects. NOT is applied like `negate`, so negating a gate flips its truth table
+    /// rather than adding a gate.
+    /// Returns an error pointing at the first unexpected character or token
+    pub fn parse(expr: &str) -> Result<Node, io::Error> {
+        let mut parser = Parser { tokens: tokenize(expr)?, pos: 0 };
+        let node = parser.expr(0)?;
+
+        match parser.tokens.get(parser.pos) {
+            None => Ok(node),
+            Some((token, offset)) => Err(unexpected(token, *offset)),
+        }
+    }
+
     pub fn eval(&self, input: &Vec<bool>) -> bool {
         match self {
             Node::Input(idx) => input[*idx],
@@ -26,6 +66,12 @@ impl Node {
 
                 (op & (1 << (2 * left_val as usize + right_val as usize))) != 0
             }
+            Node::GateN(table, inputs) => {
+                // Index into the truth table based on the inputs
+                let row = inputs.iter().fold(0, |row, child| 2 * row + child.eval(input) as usize);
+
+                table[row]
+            }
         }
     }
 
@@ -43,34 +89,792 @@ impl Node {
 
                 inputs
             }
+            Node::GateN(_, inputs) => inputs.iter().flat_map(|input| input.inputs()).collect(),
         }
     }
 
     pub fn n_inputs(&self) -> usize {
-        let mut inputs = self.inputs();
+        count_inputs(self.inputs())
+    }
 
-        // We may have repetitions (in case some inputs are connected to multiple gates)
-        // in which case we have to dedup them
-        inputs.sort();
-        inputs.dedup();
+    /// Some input this node depends on (its leftmost leaf)
+    fn first_input(&self) -> usize {
+        match self {
+            Node::Input(idx) => *idx,
+            Node::Gate(_, left, _) => left.first_input(),
+            Node::GateN(_, inputs) => inputs[0].first_input(),
+        }
+    }
+
+    /// There's no constant node, so a constant is represented as a gate whose operation ignores both of its inputs
+    /// (0000 or 1111). Its inputs are set to some input of the subtree it replaces, so the circuit keeps the same inputs
+    fn constant(val: bool, input: usize) -> Node {
+        let op = if val { gates::TRUE } else { gates::FALSE };
+
+        Node::Gate(op, Rc::new(Node::Input(input)), Rc::new(Node::Input(input)))
+    }
+
+    /// If this node is a constant, return its value
+    fn as_constant(&self) -> Option<bool> {
+        match self {
+            Node::Gate(gates::FALSE, _, _) => Some(false),
+            Node::Gate(gates::TRUE, _, _) => Some(true),
+            _ => None,
+        }
+    }
+
+    /// A multiplexer, which outputs `a` if `sel` is set and `b` otherwise: (sel AND a) OR ((NOT sel) AND b).
+    /// The negation of `sel` is folded into the second AND gate, so the multiplexer takes three gates, and `sel` is shared between them
+    pub fn mux(sel: Rc<Node>, a: Rc<Node>, b: Rc<Node>) -> Node {
+        let selected_a = Node::Gate(gates::AND, sel.clone(), a);
+        let selected_b = Node::Gate(gates::NOT_LEFT_AND_RIGHT, sel, b);
+
+        Node::Gate(gates::OR, Rc::new(selected_a), Rc::new(selected_b))
+    }
+
+    /// Negate a node: gates are negated by flipping their truth table, and inputs are fed into a NOT gate
+    fn negate(self) -> Node {
+        match self {
+            Node::Input(idx) => Node::Gate(gates::NOT_LEFT, Rc::new(Node::Input(idx)), Rc::new(Node::Input(idx))),
+            Node::Gate(op, left, right) => Node::Gate(!op & 0b1111, left, right),
+            Node::GateN(table, inputs) => Node::GateN(table.into_iter().map(|bit| !bit).collect(), inputs),
+        }
+    }
+
+    /// The number of inputs and the size of the truth table of some `GateN` in this node that doesn't have a table entry
+    /// per combination of its inputs (or has too many inputs), if there is one
+    fn malformed_gate(&self) -> Option<(usize, usize)> {
+        match self {
+            Node::Input(_) => None,
+            Node::Gate(_, left, right) => left.malformed_gate().or_else(|| right.malformed_gate()),
+            Node::GateN(table, inputs) => {
+                if inputs.is_empty() || inputs.len() > MAX_GATE_INPUTS || table.len() != 1 << inputs.len() {
+                    return Some((inputs.len(), table.len()));
+                }
+
+                inputs.iter().find_map(|input| input.malformed_gate())
+            }
+        }
+    }
+
+    /// The operation of some `Gate` in this node that has bits set above its 4-bit truth table, if there is one
+    fn invalid_op(&self) -> Option<u8> {
+        match self {
+            Node::Input(_) => None,
+            Node::Gate(op, _, _) if *op > 0b1111 => Some(*op),
+            Node::Gate(_, left, right) => left.invalid_op().or_else(|| right.invalid_op()),
+            Node::GateN(_, inputs) => inputs.iter().find_map(|input| input.invalid_op()),
+        }
+    }
+
+    /// Run `visitor` over this node's subtree (see `Circuit::visit`). The value of every node visited so far is memoized
+    /// in `visited` by address, so a shared node is only visited once
+    fn accept<V: CircuitVisitor>(&self, visitor: &mut V, visited: &mut HashMap<*const Node, V::Output>) -> V::Output {
+        if let Some(value) = visited.get(&(self as *const Node)) {
+            return value.clone();
+        }
+
+        let value = match self {
+            Node::Input(idx) => visitor.visit_input(*idx),
+            Node::Gate(_, left, right) => {
+                let children = [left.accept(visitor, visited), right.accept(visitor, visited)];
+
+                visitor.visit_gate(self, &children)
+            }
+            Node::GateN(_, inputs) => {
+                let children: Vec<V::Output> = inputs.iter().map(|input| input.accept(visitor, visited)).collect();
+
+                visitor.visit_gate(self, &children)
+            }
+        };
+        visited.insert(self as *const Node, value.clone());
+
+        value
+    }
+
+    /// Append this node's gates to `order` in topological order (see `Circuit::iter_topological`), skipping the nodes in `seen`
+    fn visit_topological<'a>(&'a self, seen: &mut HashSet<*const Node>, order: &mut Vec<&'a Node>) {
+        if !seen.insert(self as *const Node) {
+            return;
+        }
 
-        inputs.len()
+        match self {
+            Node::Input(_) => return,
+            Node::Gate(_, left, right) => {
+                left.visit_topological(seen, order);
+                right.visit_topological(seen, order);
+            }
+            Node::GateN(_, inputs) => inputs.iter().for_each(|input| input.visit_topological(seen, order)),
+        }
+
+        order.push(self);
+    }
+
+    /// Return the shared copy of this node, creating it if no identical node was seen yet (see `Circuit::dedup_subexpressions`)
+    fn dedup(&self, seen: &mut Subexpressions) -> Rc<Node> {
+        match self {
+            Node::Input(idx) => seen.inputs.entry(*idx).or_insert_with(|| Rc::new(Node::Input(*idx))).clone(),
+            Node::Gate(op, left, right) => {
+                // Once the children are deduplicated, identical subtrees are the same shared node,
+                // so two gates are identical iff they have the same operation and point to the same children
+                let (left, right) = (left.dedup(seen), right.dedup(seen));
+                let key = (*op, Rc::as_ptr(&left), Rc::as_ptr(&right));
+
+                seen.gates.entry(key).or_insert_with(|| Rc::new(Node::Gate(*op, left, right))).clone()
+            }
+            Node::GateN(table, inputs) => {
+                let inputs: Vec<Rc<Node>> = inputs.iter().map(|input| input.dedup(seen)).collect();
+                let key = (table.clone(), inputs.iter().map(Rc::as_ptr).collect());
+
+                seen.wide_gates.entry(key).or_insert_with(|| Rc::new(Node::GateN(table.clone(), inputs))).clone()
+            }
+        }
+    }
+
+    /// Rebuild this node with each input i replaced by `wires[i]` (see `Circuit::compose`).
+    /// Rebuilt nodes are memoized by address, so subtrees shared in this node stay shared
+    fn substitute(&self, wires: &[Rc<Node>], rebuilt: &mut HashMap<*const Node, Rc<Node>>) -> Rc<Node> {
+        if let Some(node) = rebuilt.get(&(self as *const Node)) {
+            return node.clone();
+        }
+
+        let node = match self {
+            Node::Input(idx) => wires[*idx].clone(),
+            Node::Gate(op, left, right) => Rc::new(Node::Gate(*op, left.substitute(wires, rebuilt), right.substitute(wires, rebuilt))),
+            Node::GateN(table, inputs) => {
+                Rc::new(Node::GateN(table.clone(), inputs.iter().map(|input| input.substitute(wires, rebuilt)).collect()))
+            }
+        };
+        rebuilt.insert(self as *const Node, node.clone());
+
+        node
+    }
+
+    /// Fold constants and drop the subtrees whose values don't affect the output (see `Circuit::optimize`).
+    /// Gates with more than two inputs are kept as they are, but their children are still optimized.
+    /// Like `substitute`, optimized nodes are memoized by address, so shared subtrees are only optimized once and stay shared
+    fn optimize(&self, rebuilt: &mut HashMap<*const Node, Rc<Node>>) -> Rc<Node> {
+        if let Some(node) = rebuilt.get(&(self as *const Node)) {
+            return node.clone();
+        }
+
+        let node = match self {
+            Node::Input(idx) => Rc::new(Node::Input(*idx)),
+            Node::GateN(table, inputs) => {
+                Rc::new(Node::GateN(table.clone(), inputs.iter().map(|input| input.optimize(rebuilt)).collect()))
+            }
+            Node::Gate(op, left, right) => {
+                Node::optimize_gate(*op, left.optimize(rebuilt), right.optimize(rebuilt), left.first_input())
+            }
+        };
+        rebuilt.insert(self as *const Node, node.clone());
+
+        node
+    }
+
+    /// Fold a gate whose children are already optimized. `input` is some input of the gate's subtree, which a constant
+    /// the gate folds into is fed from (see `constant`)
+    fn optimize_gate(mut op: u8, left: Rc<Node>, right: Rc<Node>, input: usize) -> Rc<Node> {
+        let bit = |op: u8, left_val: usize, right_val: usize| (op >> (2 * left_val + right_val)) & 1;
+
+        // Substitute constant children into the truth table, so it no longer depends on them
+        if let Some(val) = left.as_constant() {
+            op = (0..4).map(|row| bit(op, val as usize, row & 1) << row).sum();
+        }
+        if let Some(val) = right.as_constant() {
+            op = (0..4).map(|row| bit(op, row >> 1, val as usize) << row).sum();
+        }
+
+        let depends_on_left = (op & 0b0011) != (op >> 2);
+        let depends_on_right = (op & 0b0101) != ((op >> 1) & 0b0101);
+
+        match (depends_on_left, depends_on_right) {
+            // e.g. x AND false, x OR true, or a gate with two constant inputs
+            (false, false) => Rc::new(Node::constant(op != 0, input)),
+            // The gate is either the identity or the negation of the child it depends on
+            (false, true) if op == gates::RIGHT => right,
+            (false, true) => Rc::new(Rc::unwrap_or_clone(right).negate()),
+            (true, false) if op == gates::LEFT => left,
+            (true, false) => Rc::new(Rc::unwrap_or_clone(left).negate()),
+            (true, true) => Rc::new(Node::Gate(op, left, right)),
+        }
+    }
+
+    /// Rebuild this node out of NAND gates only (see `Circuit::to_nand`).
+    /// Like `substitute`, rebuilt nodes are memoized by address, so shared subtrees stay shared
+    fn to_nand(&self, rebuilt: &mut HashMap<*const Node, Rc<Node>>) -> Rc<Node> {
+        if let Some(node) = rebuilt.get(&(self as *const Node)) {
+            return node.clone();
+        }
+
+        let node = match self {
+            Node::Input(idx) => Rc::new(Node::Input(*idx)),
+            Node::Gate(gates::NAND, left, right) => nand(left.to_nand(rebuilt), right.to_nand(rebuilt)),
+            Node::Gate(op, left, right) => {
+                // The operation's bit 2l + r is the entry for the inputs (l, r), just like the truth table of a `GateN`
+                let table: Vec<bool> = (0..4).map(|row| (op >> row) & 1 == 1).collect();
+
+                nand_sum_of_products(&table, &[left.to_nand(rebuilt), right.to_nand(rebuilt)])
+            }
+            Node::GateN(table, inputs) => {
+                let inputs: Vec<Rc<Node>> = inputs.iter().map(|input| input.to_nand(rebuilt)).collect();
+
+                nand_sum_of_products(table, &inputs)
+            }
+        };
+        rebuilt.insert(self as *const Node, node.clone());
+
+        node
+    }
+}
+
+/// A NAND gate of two nodes
+fn nand(left: Rc<Node>, right: Rc<Node>) -> Rc<Node> {
+    Rc::new(Node::Gate(gates::NAND, left, right))
+}
+
+/// The NAND of all of `nodes`, built out of two-input NAND gates: NAND(x_1, ..., x_k) = NAND(AND(x_1, ..., x_k-1), x_k),
+/// where the AND is the negation of a smaller NAND. A single node is negated by feeding it into both inputs of a NAND
+fn nand_all(nodes: &[Rc<Node>]) -> Rc<Node> {
+    match nodes {
+        [node] => nand(node.clone(), node.clone()),
+        [rest @ .., last] => {
+            let rest = nand_all(rest);
+
+            nand(nand(rest.clone(), rest), last.clone())
+        }
+        [] => unreachable!("a NAND has at least one input"),
+    }
+}
+
+/// A NAND-only node computing the truth table `table` of `inputs` (indexed like the table of a `GateN`), as a sum of products:
+/// the OR of the rows that are set is the NAND of the negations of the rows, and each negated row is the NAND of its literals
+fn nand_sum_of_products(table: &[bool], inputs: &[Rc<Node>]) -> Rc<Node> {
+    let negated: Vec<Rc<Node>> = inputs.iter().map(|input| nand(input.clone(), input.clone())).collect();
+    // x NAND (NOT x) is always true
+    let always = || nand(inputs[0].clone(), negated[0].clone());
+
+    if table.iter().all(|&bit| bit) {
+        return always();
+    }
+
+    let rows: Vec<Rc<Node>> = (0..table.len())
+        .filter(|&row| table[row])
+        .map(|row| {
+            let literals: Vec<Rc<Node>> = (0..inputs.len())
+                .map(|i| match (row >> (inputs.len() - 1 - i)) & 1 {
+                    1 => inputs[i].clone(),
+                    _ => negated[i].clone(),
+                })
+                .collect();
+
+            nand_all(&literals)
+        })
+        .collect();
+
+    if rows.is_empty() {
+        // NOT true
+        let always = always();
+
+        return nand(always.clone(), always);
+    }
+
+    nand_all(&rows)
+}
+
+/// A pass over the nodes of a circuit, driven by `Circuit::visit`. Every node is visited after its children,
+/// and the value the visitor returns for it is handed to its parents. Like garbling, a shared node is only visited once,
+/// and all of its parents get the value it was visited with
+pub trait CircuitVisitor {
+    /// The value computed for each node
+    type Output: Clone;
+
+    /// Visit input `idx`
+    fn visit_input(&mut self, idx: usize) -> Self::Output;
+
+    /// Visit a `Gate` or `GateN`, given the values of its children (in order)
+    fn visit_gate(&mut self, gate: &Node, children: &[Self::Output]) -> Self::Output;
+}
+
+/// Size metrics of a circuit, gathered by visiting it. The value of each node is its depth
+#[derive(Default)]
+struct Metrics {
+    /// The number of gates with each operation
+    ops: HashMap<u8, usize>,
+    /// The number of gates with more than two inputs (which don't have a 4-bit operation)
+    wide_gates: usize,
+}
+
+impl CircuitVisitor for Metrics {
+    type Output = usize;
+
+    fn visit_input(&mut self, _idx: usize) -> usize {
+        0
+    }
+
+    fn visit_gate(&mut self, gate: &Node, children: &[usize]) -> usize {
+        match gate {
+            Node::Gate(op, _, _) => *self.ops.entry(*op).or_default() += 1,
+            _ => self.wide_gates += 1,
+        }
+
+        children.iter().max().unwrap_or(&0) + 1
+    }
+}
+
+/// The distinct subexpressions seen so far by `Node::dedup`. Gates are keyed by their operation and the addresses
+/// of their (already shared) children; the children are kept alive by the map, so the addresses stay unique
+#[derive(Default)]
+struct Subexpressions {
+    inputs: HashMap<usize, Rc<Node>>,
+    gates: HashMap<(u8, *const Node, *const Node), Rc<Node>>,
+    wide_gates: HashMap<(Vec<bool>, Vec<*const Node>), Rc<Node>>,
+}
+
+/// Count the distinct input indices in `inputs`
+fn count_inputs(mut inputs: Vec<usize>) -> usize {
+    // We may have repetitions (in case some inputs are connected to multiple gates)
+    // in which case we have to dedup them
+    inputs.sort();
+    inputs.dedup();
+
+    inputs.len()
+}
+
+/// A token of an expression parsed by `Node::parse`
+#[derive(Debug, PartialEq)]
+enum Token {
+    Input(usize),
+    Not,
+    /// A binary gate, with its operation and precedence (higher binds tighter)
+    Binary(u8, usize),
+    Open,
+    Close,
+}
+
+/// The operation and precedence of a binary operator, given as a symbol or by its name (see `gates::op_name`)
+fn binary_operator(name: &str) -> Option<(u8, usize)> {
+    let op = match name {
+        "&" => gates::AND,
+        "^" => gates::XOR,
+        "|" => gates::OR,
+        _ => gates::op_by_name(name)?,
+    };
+    let precedence = match op {
+        gates::AND | gates::NAND => 3,
+        gates::XOR | gates::XNOR => 2,
+        _ => 1,
+    };
+
+    Some((op, precedence))
+}
+
+/// Split an expression into tokens, along with the offset of each token in the expression
+fn tokenize(expr: &str) -> Result<Vec<(Token, usize)>, io::Error> {
+    let mut tokens = vec![];
+    let mut chars = expr.char_indices().peekable();
+
+    while let Some((offset, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '!' => Token::Not,
+            '&' | '^' | '|' => {
+                let (op, precedence) = binary_operator(&c.to_string()).unwrap();
+
+                Token::Binary(op, precedence)
+            }
+            c if c.is_ascii_alphanumeric() => {
+                let mut word = c.to_string();
+
+                while let Some((_, c)) = chars.next_if(|(_, c)| c.is_ascii_alphanumeric()) {
+                    word.push(c);
+                }
+
+                match (word.as_str(), word.strip_prefix("in").map(str::parse::<usize>)) {
+                    (_, Some(Ok(idx))) => Token::Input(idx),
+                    ("NOT", _) => Token::Not,
+                    (word, _) => match binary_operator(word) {
+                        Some((op, precedence)) => Token::Binary(op, precedence),
+                        None => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                format!("unknown identifier {:?} at offset {}", word, offset),
+                            ))
+                        }
+                    },
+                }
+            }
+            c => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unexpected character {:?} at offset {}", c, offset),
+                ))
+            }
+        };
+
+        tokens.push((token, offset));
+    }
+
+    Ok(tokens)
+}
+
+/// The error for a token that can't appear where it was found
+fn unexpected(token: &Token, offset: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("unexpected {:?} at offset {}", token, offset),
+    )
+}
+
+/// A precedence climbing parser over the tokens of an expression (see `Node::parse`)
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    /// The index of the next token
+    pos: usize,
+}
+
+impl Parser {
+    /// Parse an expression whose binary operators all have a precedence of at least `min_precedence`
+    fn expr(&mut self, min_precedence: usize) -> Result<Node, io::Error> {
+        let mut left = self.operand()?;
+
+        while let Some((Token::Binary(op, precedence), _)) = self.tokens.get(self.pos) {
+            let (op, precedence) = (*op, *precedence);
+
+            if precedence < min_precedence {
+                break;
+            }
+
+            self.pos += 1;
+            // The right operand only takes operators that bind tighter, so operators of the same precedence associate to the left
+            let right = self.expr(precedence + 1)?;
+            left = Node::Gate(op, Rc::new(left), Rc::new(right));
+        }
+
+        Ok(left)
+    }
+
+    /// Parse an input, a negated operand, or a parenthesized expression
+    fn operand(&mut self) -> Result<Node, io::Error> {
+        let Some((token, offset)) = self.tokens.get(self.pos) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "unexpected end of expression"));
+        };
+        self.pos += 1;
+
+        match token {
+            Token::Input(idx) => Ok(Node::Input(*idx)),
+            Token::Not => Ok(self.operand()?.negate()),
+            Token::Open => {
+                let node = self.expr(0)?;
+
+                match self.tokens.get(self.pos) {
+                    Some((Token::Close, _)) => {
+                        self.pos += 1;
+
+                        Ok(node)
+                    }
+                    Some((token, offset)) => Err(unexpected(token, *offset)),
+                    None => Err(io::Error::new(io::ErrorKind::InvalidInput, "unclosed parenthesis")),
+                }
+            }
+            token => Err(unexpected(token, *offset)),
+        }
+    }
+}
+
+/// Render the node as an infix expression, e.g. `(in0 AND (in1 XOR in2))`. Constants are rendered as `true` and `false`,
+/// gates that negate one of their inputs as `(NOT x)`, and other operations by their name (see `gates::op_name`).
+/// Wide gates are rendered as their truth table applied to their inputs, e.g. `[01101001](in0, in1, in2)`.
+/// Shared subtrees are rendered each time they're used
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Node::Input(idx) => write!(f, "in{}", idx),
+            Node::Gate(gates::FALSE, _, _) => write!(f, "false"),
+            Node::Gate(gates::TRUE, _, _) => write!(f, "true"),
+            Node::Gate(gates::NOT_LEFT, left, _) => write!(f, "(NOT {})", left),
+            Node::Gate(gates::NOT_RIGHT, _, right) => write!(f, "(NOT {})", right),
+            Node::Gate(op, left, right) => write!(f, "({} {} {})", left, op_name(*op), right),
+            Node::GateN(table, inputs) => {
+                let table: String = table.iter().map(|bit| if *bit { '1' } else { '0' }).collect();
+                let inputs: Vec<String> = inputs.iter().map(|input| input.to_string()).collect();
+
+                write!(f, "[{}]({})", table, inputs.join(", "))
+            }
+        }
+    }
+}
+
+/// Render the circuit's input count followed by a line per output, e.g. `out = (in0 AND in1)`.
+/// If there are several outputs, each is named by its index (`out0`, `out1`, ...)
+impl fmt::Display for Circuit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "circuit with {} inputs", self.n)?;
+
+        for (idx, out) in self.outs.iter().enumerate() {
+            if self.outs.len() == 1 {
+                write!(f, "\nout = {}", out)?;
+            } else {
+                write!(f, "\nout{} = {}", idx, out)?;
+            }
+        }
+
+        Ok(())
     }
 }
 
 impl Circuit {
-    pub fn new(out: Node) -> Circuit {
-        let n = out.n_inputs();
+    /// Construct a circuit with a single output wire (see `with_outputs`)
+    pub fn new(out: Node) -> Result<Circuit, io::Error> {
+        Circuit::with_outputs(vec![out])
+    }
+
+    /// Construct a circuit with multiple output wires. The inputs are shared between all outputs.
+    /// The inputs are indexed by their ids, so the ids must be exactly 0..n; returns an error listing the missing ids otherwise.
+    /// Also returns an error if a gate's truth table doesn't have an entry for each combination of its inputs,
+    /// or if a two-input gate's operation has bits set above the 4 bits of its truth table (which would otherwise be ignored)
+    pub fn with_outputs(outs: Vec<Node>) -> Result<Circuit, io::Error> {
+        if let Some(op) = outs.iter().find_map(Node::invalid_op) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("gate operations are 4-bit truth tables, but a gate has the operation {:#b}", op),
+            ));
+        }
+
+        if let Some(bad) = outs.iter().find_map(Node::malformed_gate) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "gates must have 1 to {} inputs and a truth table of 2^k entries, but a gate has {} inputs and {} entries",
+                    MAX_GATE_INPUTS, bad.0, bad.1
+                ),
+            ));
+        }
+
+        let mut inputs: Vec<usize> = outs.iter().flat_map(|out| out.inputs()).collect();
+        inputs.sort();
+        inputs.dedup();
+        let n = inputs.last().map_or(0, |max| max + 1);
+
+        if inputs.len() != n {
+            let missing: Vec<usize> = (0..n).filter(|idx| inputs.binary_search(idx).is_err()).collect();
 
-        Circuit { out, n }
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("circuit inputs must be numbered 0..{}, but inputs {:?} are missing", n, missing),
+            ));
+        }
+
+        Ok(Circuit { outs, n })
     }
 
+    /// Evaluate the circuit's first output
     pub fn eval(&self, input: &Vec<bool>) -> bool {
-        self.out.eval(input)
+        self.outs[0].eval(input)
+    }
+
+    /// Evaluate all of the circuit's outputs
+    pub fn eval_outputs(&self, input: &Vec<bool>) -> Vec<bool> {
+        self.outs.iter().map(|out| out.eval(input)).collect()
+    }
+
+    /// Evaluate the circuit's first output on two `bits`-bit numbers, in the input order of the comparison circuits
+    /// (see `construct_circuit`): inputs `0..bits` are the bits of `a` and inputs `bits..2 * bits` are the bits of `b`,
+    /// least significant first. Higher bits of `a` and `b` are ignored
+    pub fn eval_int(&self, a: u64, b: u64, bits: usize) -> bool {
+        let to_bits = |value: u64| (0..bits).map(move |i| i < u64::BITS as usize && (value >> i) & 1 == 1);
+
+        self.eval(&to_bits(a).chain(to_bits(b)).collect())
+    }
+
+    /// Evaluate the circuit on all 2^n combinations of its inputs. The outputs for the combination whose binary representation
+    /// is x come at index x * (number of outputs), where input i is the i-th bit of x (so input 0 is the least significant).
+    /// Returns an error if the circuit has more than `MAX_TRUTH_TABLE_INPUTS` inputs, rather than taking forever
+    pub fn truth_table(&self) -> Result<Vec<bool>, io::Error> {
+        if self.n > MAX_TRUTH_TABLE_INPUTS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "circuit has {} inputs, but truth tables are limited to {} inputs",
+                    self.n, MAX_TRUTH_TABLE_INPUTS
+                ),
+            ));
+        }
+
+        Ok((0..1usize << self.n)
+            .flat_map(|x| self.eval_outputs(&(0..self.n).map(|i| (x >> i) & 1 == 1).collect()))
+            .collect())
+    }
+
+    /// Whether two circuits compute the same function, i.e. have the same number of inputs and outputs and the same truth table.
+    /// Returns an error if either has too many inputs to compute its truth table (see `truth_table`)
+    pub fn equivalent_to(&self, other: &Circuit) -> Result<bool, io::Error> {
+        if self.n != other.n || self.outs.len() != other.outs.len() {
+            return Ok(false);
+        }
+
+        Ok(self.truth_table()? == other.truth_table()?)
+    }
+
+    /// Chain two circuits, feeding some of this circuit's outputs into the inputs of `other`: each `(out, input)` pair
+    /// in `mapping` connects output `out` of this circuit to input `input` of `other`. An output may feed several inputs.
+    /// The composed circuit's inputs are this circuit's inputs, followed by the inputs of `other` that aren't connected
+    /// (in order). Its outputs are this circuit's outputs that aren't connected (in order), followed by the outputs of `other`.
+    /// The connected outputs are shared rather than copied, so they're only garbled once.
+    /// Returns an error if a pair refers to a wire that doesn't exist, or if an input of `other` is connected twice
+    pub fn compose(self, other: Circuit, mapping: &[(usize, usize)]) -> Result<Circuit, io::Error> {
+        let mut sources: Vec<Option<usize>> = vec![None; other.n];
+
+        for &(out, input) in mapping {
+            if out >= self.outs.len() || input >= other.n {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "can't connect output {} to input {}: the circuits have {} outputs and {} inputs",
+                        out,
+                        input,
+                        self.outs.len(),
+                        other.n
+                    ),
+                ));
+            }
+
+            if sources[input].replace(out).is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("input {} is connected more than once", input),
+                ));
+            }
+        }
+
+        let outs: Vec<Rc<Node>> = self.outs.into_iter().map(Rc::new).collect();
+        // The unconnected inputs of `other` are renumbered to come after our own
+        let mut n = self.n;
+        let wires: Vec<Rc<Node>> = sources
+            .iter()
+            .map(|source| match source {
+                Some(out) => outs[*out].clone(),
+                None => {
+                    n += 1;
+                    Rc::new(Node::Input(n - 1))
+                }
+            })
+            .collect();
+        let mut rebuilt = HashMap::new();
+        let other_outs: Vec<Node> = other
+            .outs
+            .iter()
+            .map(|out| Rc::unwrap_or_clone(out.substitute(&wires, &mut rebuilt)))
+            .collect();
+        let outs = outs
+            .into_iter()
+            .enumerate()
+            .filter(|(idx, _)| !mapping.iter().any(|(out, _)| out == idx))
+            .map(|(_, out)| Rc::unwrap_or_clone(out))
+            .chain(other_outs)
+            .collect();
+
+        Ok(Circuit { outs, n })
+    }
+
+    /// Negate one of the circuit's outputs. Rather than adding a NOT gate, the output gate's truth table is flipped,
+    /// so the negated circuit has the same number of gates (unless the output is an input, which is fed into a NOT gate).
+    /// Returns an error if there's no output `idx`
+    pub fn negate_output(mut self, idx: usize) -> Result<Circuit, io::Error> {
+        if idx >= self.outs.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("circuit has {} outputs, can't negate output {}", self.outs.len(), idx),
+            ));
+        }
+
+        self.outs[idx] = self.outs[idx].clone().negate();
+
+        Ok(self)
+    }
+
+    /// Simplify the circuit before garbling it, so that fewer garbled gates have to be sent:
+    /// gates with two constant inputs are folded into constants, gates such as `x AND false` and `x OR true` become constants,
+    /// and gates whose output only depends on one of their inputs are replaced by that input (or its negation),
+    /// which drops the subtree of the other input. The optimized circuit has the same inputs and evaluates the same as the original.
+    /// Shared subtrees are optimized once and stay shared, so optimizing a DAG takes time linear in its number of gates
+    pub fn optimize(self) -> Circuit {
+        let mut rebuilt = HashMap::new();
+        let outs = self.outs.iter().map(|out| Rc::unwrap_or_clone(out.optimize(&mut rebuilt))).collect();
+
+        Circuit { outs, n: self.n }
+    }
+
+    /// Merge structurally identical subtrees into shared nodes, turning the circuit into a DAG.
+    /// Shared nodes are only garbled once, so the garbled circuit has fewer gates. `optimize` keeps shared subtrees shared,
+    /// but it can create identical subtrees of its own (e.g. two negations of the same node), so it's best run before deduplicating
+    pub fn dedup_subexpressions(self) -> Circuit {
+        let mut seen = Subexpressions::default();
+        let outs = self.outs.iter().map(|out| Rc::unwrap_or_clone(out.dedup(&mut seen))).collect();
+
+        Circuit { outs, n: self.n }
+    }
+
+    /// Rewrite every gate into an equivalent tree of NAND gates, so the circuit only uses a single operation.
+    /// Each gate becomes a sum of products of its (possibly negated) inputs, so the lowered circuit has many more gates;
+    /// running `dedup_subexpressions` afterwards merges the negations that are repeated across gates.
+    /// The lowered circuit has the same inputs and outputs and evaluates the same as the original
+    pub fn to_nand(self) -> Circuit {
+        let mut rebuilt = HashMap::new();
+        let outs = self.outs.iter().map(|out| Rc::unwrap_or_clone(out.to_nand(&mut rebuilt))).collect();
+
+        Circuit { outs, n: self.n }
     }
 
-    pub fn out(&self) -> Node {
-        self.out.clone()
+    /// Measure the whole circuit in a single traversal, returning its depth and the metrics of its gates
+    fn metrics(&self) -> (usize, Metrics) {
+        let mut metrics = Metrics::default();
+        let depth = self.visit(&mut metrics).into_iter().max().unwrap_or(0);
+
+        (depth, metrics)
+    }
+
+    /// Walk the circuit once with `visitor` (see `CircuitVisitor`), returning the value of each output (in order)
+    pub fn visit<V: CircuitVisitor>(&self, visitor: &mut V) -> Vec<V::Output> {
+        let mut visited = HashMap::new();
+
+        self.outs.iter().map(|out| out.accept(visitor, &mut visited)).collect()
+    }
+
+    /// The circuit's gates in topological order, i.e. every gate comes after the gates feeding it (the outputs' gates are
+    /// visited in order). Like `num_gates`, a shared gate is only yielded once; inputs aren't yielded
+    pub fn iter_topological(&self) -> impl Iterator<Item = &Node> {
+        let (mut seen, mut order) = (HashSet::new(), vec![]);
+
+        for out in &self.outs {
+            out.visit_topological(&mut seen, &mut order);
+        }
+
+        order.into_iter()
+    }
+
+    /// The number of gates in the circuit, i.e. the number of gates in its garbled version
+    /// (shared nodes are counted once)
+    pub fn num_gates(&self) -> usize {
+        let (_, metrics) = self.metrics();
+
+        metrics.ops.values().sum::<usize>() + metrics.wide_gates
+    }
+
+    /// The length of the longest path from an input to an output, in gates
+    pub fn depth(&self) -> usize {
+        self.metrics().0
+    }
+
+    /// The number of two-input gates with each operation
+    pub fn gate_counts(&self) -> HashMap<u8, usize> {
+        self.metrics().1.ops
+    }
+
+    pub fn outs(&self) -> Vec<Node> {
+        self.outs.clone()
     }
 
     pub fn n(&self) -> usize {
@@ -80,50 +884,98 @@ impl Circuit {
 
 #[cfg(test)]
 mod tests {
+    use std::rc::Rc;
+
     use super::{Circuit, Node};
+    use crate::{
+        backend::garbler_backend::{equality_circuit, negated_comparison_circuit},
+        gates::{AND, FALSE, LEFT_AND_NOT_RIGHT, NAND, NOR, NOT_LEFT_AND_RIGHT, OR, TRUE, XNOR, XOR},
+    };
 
-    // Some useful gates
-    const AND_GATE: u8 = 0b1000u8;
-    const OR_GATE: u8 = 0b1110u8;
-    const XOR_GATE: u8 = 0b0110u8;
+    fn count_gates(node: &Node) -> usize {
+        match node {
+            Node::Input(_) => 0,
+            Node::Gate(_, left, right) => 1 + count_gates(left) + count_gates(right),
+            Node::GateN(_, inputs) => 1 + inputs.iter().map(|input| count_gates(input)).sum::<usize>(),
+        }
+    }
 
     #[test]
     pub fn and_gate_test() {
         let x = Node::Input(0);
         let y = Node::Input(1);
-        let out = Node::Gate(AND_GATE, Box::new(x), Box::new(y));
-        let circuit = Circuit::new(out);
+        let out = Node::Gate(AND, Rc::new(x), Rc::new(y));
+        let circuit = Circuit::new(out).unwrap();
 
-        assert_eq!(circuit.eval(&vec![false, false]), false);
-        assert_eq!(circuit.eval(&vec![false, true]), false);
-        assert_eq!(circuit.eval(&vec![true, false]), false);
-        assert_eq!(circuit.eval(&vec![true, true]), true);
+        assert!(!circuit.eval(&vec![false, false]));
+        assert!(!circuit.eval(&vec![false, true]));
+        assert!(!circuit.eval(&vec![true, false]));
+        assert!(circuit.eval(&vec![true, true]));
     }
 
     #[test]
     pub fn or_gate_test() {
         let x = Node::Input(0);
         let y = Node::Input(1);
-        let out = Node::Gate(OR_GATE, Box::new(x), Box::new(y));
-        let circuit = Circuit::new(out);
+        let out = Node::Gate(OR, Rc::new(x), Rc::new(y));
+        let circuit = Circuit::new(out).unwrap();
 
-        assert_eq!(circuit.eval(&vec![false, false]), false);
-        assert_eq!(circuit.eval(&vec![false, true]), true);
-        assert_eq!(circuit.eval(&vec![true, false]), true);
-        assert_eq!(circuit.eval(&vec![true, true]), true);
+        assert!(!circuit.eval(&vec![false, false]));
+        assert!(circuit.eval(&vec![false, true]));
+        assert!(circuit.eval(&vec![true, false]));
+        assert!(circuit.eval(&vec![true, true]));
     }
 
     #[test]
     pub fn xor_gate_test() {
         let x = Node::Input(0);
         let y = Node::Input(1);
-        let out = Node::Gate(XOR_GATE, Box::new(x), Box::new(y));
-        let circuit = Circuit::new(out);
+        let out = Node::Gate(XOR, Rc::new(x), Rc::new(y));
+        let circuit = Circuit::new(out).unwrap();
+
+        assert!(!circuit.eval(&vec![false, false]));
+        assert!(circuit.eval(&vec![false, true]));
+        assert!(circuit.eval(&vec![true, false]));
+        assert!(!circuit.eval(&vec![true, true]));
+    }
+
+    /// The outputs of a two-input gate with operation `op` for the inputs 00, 01, 10 and 11
+    fn gate_outputs(op: u8) -> Vec<bool> {
+        let circuit = Circuit::new(Node::Gate(op, Rc::new(Node::Input(0)), Rc::new(Node::Input(1)))).unwrap();
+
+        [(false, false), (false, true), (true, false), (true, true)]
+            .into_iter()
+            .map(|(x, y)| circuit.eval(&vec![x, y]))
+            .collect()
+    }
 
-        assert_eq!(circuit.eval(&vec![false, false]), false);
-        assert_eq!(circuit.eval(&vec![false, true]), true);
-        assert_eq!(circuit.eval(&vec![true, false]), true);
-        assert_eq!(circuit.eval(&vec![true, true]), false);
+    #[test]
+    pub fn nand_nor_xnor_gate_test() {
+        assert_eq!(gate_outputs(NAND), vec![true, true, true, false]);
+        assert_eq!(gate_outputs(NOR), vec![true, false, false, false]);
+        assert_eq!(gate_outputs(XNOR), vec![true, false, false, true]);
+    }
+
+    #[test]
+    pub fn left_and_not_right_gate_test() {
+        // Only true when the left input is set and the right one isn't, i.e. when the left bit is greater
+        assert_eq!(gate_outputs(LEFT_AND_NOT_RIGHT), vec![false, false, true, false]);
+        assert_eq!(gate_outputs(NOT_LEFT_AND_RIGHT), vec![false, true, false, false]);
+    }
+
+    #[test]
+    pub fn mux_test() {
+        let out = Node::mux(Rc::new(Node::Input(0)), Rc::new(Node::Input(1)), Rc::new(Node::Input(2)));
+        let circuit = Circuit::new(out).unwrap();
+
+        for bits in 0..8usize {
+            let input: Vec<bool> = (0..3).map(|i| bits & (1 << i) != 0).collect();
+            let (sel, a, b) = (input[0], input[1], input[2]);
+
+            assert_eq!(circuit.eval(&input), if sel { a } else { b });
+        }
+
+        assert_eq!(circuit.num_gates(), 3);
     }
 
     #[test]
@@ -132,18 +984,447 @@ mod tests {
         let x = Node::Input(0);
         let y = Node::Input(1);
         let z = Node::Input(2);
-        let or = Node::Gate(OR_GATE, Box::new(x.clone()), Box::new(y));
-        let xor = Node::Gate(XOR_GATE, Box::new(or), Box::new(z));
-        let out = Node::Gate(AND_GATE, Box::new(x), Box::new(xor));
-        let circuit = Circuit::new(out);
-
-        assert_eq!(circuit.eval(&vec![false, false, false]), false);
-        assert_eq!(circuit.eval(&vec![false, false, true]), false);
-        assert_eq!(circuit.eval(&vec![false, true, false]), false);
-        assert_eq!(circuit.eval(&vec![false, true, true]), false);
-        assert_eq!(circuit.eval(&vec![true, false, false]), true);
-        assert_eq!(circuit.eval(&vec![true, false, true]), false);
-        assert_eq!(circuit.eval(&vec![true, true, false]), true);
-        assert_eq!(circuit.eval(&vec![true, true, true]), false);
+        let or = Node::Gate(OR, Rc::new(x.clone()), Rc::new(y));
+        let xor = Node::Gate(XOR, Rc::new(or), Rc::new(z));
+        let out = Node::Gate(AND, Rc::new(x), Rc::new(xor));
+        let circuit = Circuit::new(out).unwrap();
+
+        assert!(!circuit.eval(&vec![false, false, false]));
+        assert!(!circuit.eval(&vec![false, false, true]));
+        assert!(!circuit.eval(&vec![false, true, false]));
+        assert!(!circuit.eval(&vec![false, true, true]));
+        assert!(circuit.eval(&vec![true, false, false]));
+        assert!(!circuit.eval(&vec![true, false, true]));
+        assert!(circuit.eval(&vec![true, true, false]));
+        assert!(!circuit.eval(&vec![true, true, true]));
+    }
+
+    #[test]
+    pub fn iter_topological_test() {
+        use std::collections::HashMap;
+
+        use crate::backend::garbler_backend::comparison_circuit;
+
+        // x & ((x | y) ^ z), whose input x is shared, and the comparison circuit, whose XNORs are shared
+        let x = Rc::new(Node::Input(0));
+        let or = Node::Gate(OR, x.clone(), Rc::new(Node::Input(1)));
+        let xor = Node::Gate(XOR, Rc::new(or), Rc::new(Node::Input(2)));
+        let complex = Circuit::new(Node::Gate(AND, x, Rc::new(xor))).unwrap();
+
+        for circuit in [complex, comparison_circuit(3).dedup_subexpressions()] {
+            let order: Vec<&Node> = circuit.iter_topological().collect();
+            let positions: HashMap<*const Node, usize> =
+                order.iter().enumerate().map(|(pos, node)| (*node as *const Node, pos)).collect();
+
+            // Every gate is yielded exactly once
+            assert_eq!(order.len(), circuit.num_gates());
+            assert_eq!(positions.len(), order.len());
+
+            // Each gate comes after the gates feeding it
+            for (pos, node) in order.iter().enumerate() {
+                let children: Vec<&Rc<Node>> = match node {
+                    Node::Input(_) => panic!("inputs aren't yielded"),
+                    Node::Gate(_, left, right) => vec![left, right],
+                    Node::GateN(_, inputs) => inputs.iter().collect(),
+                };
+
+                for child in children.into_iter().filter(|child| !matches!(***child, Node::Input(_))) {
+                    assert!(positions[&Rc::as_ptr(child)] < pos);
+                }
+            }
+        }
+    }
+
+    #[test]
+    pub fn multi_output_test() {
+        // Half adder: the sum is x ^ y and the carry is x & y
+        let x = Node::Input(0);
+        let y = Node::Input(1);
+        let sum = Node::Gate(XOR, Rc::new(x.clone()), Rc::new(y.clone()));
+        let carry = Node::Gate(AND, Rc::new(x), Rc::new(y));
+        let circuit = Circuit::with_outputs(vec![sum, carry]).unwrap();
+
+        assert_eq!(circuit.n(), 2);
+        assert_eq!(circuit.eval_outputs(&vec![false, false]), vec![false, false]);
+        assert_eq!(circuit.eval_outputs(&vec![false, true]), vec![true, false]);
+        assert_eq!(circuit.eval_outputs(&vec![true, false]), vec![true, false]);
+        assert_eq!(circuit.eval_outputs(&vec![true, true]), vec![false, true]);
+    }
+
+    #[test]
+    pub fn optimize_test() {
+        let x = || Rc::new(Node::Input(0));
+        let y = || Rc::new(Node::Input(1));
+        let z = || Rc::new(Node::Input(2));
+        let constant = |op| Rc::new(Node::Gate(op, x(), y()));
+        // (x | y) & false, which is constant
+        let dead = Node::Gate(AND, Rc::new(Node::Gate(OR, x(), y())), constant(FALSE));
+        // (true ^ z) | ((x & y) & true), i.e. !z | (x & y)
+        let negated = Node::Gate(XOR, constant(TRUE), z());
+        let folded = Node::Gate(AND, Rc::new(Node::Gate(AND, x(), y())), constant(TRUE));
+        let out = Node::Gate(OR, Rc::new(negated), Rc::new(folded));
+        // true & true, which has two constant inputs
+        let constant_out = Node::Gate(AND, constant(TRUE), constant(TRUE));
+        let outs = vec![dead, out, constant_out];
+        let gates: usize = outs.iter().map(count_gates).sum();
+        let circuit = Circuit::with_outputs(outs.clone()).unwrap();
+        let optimized = Circuit::with_outputs(outs).unwrap().optimize();
+
+        assert_eq!(optimized.n(), circuit.n());
+        assert!(optimized.outs().iter().map(count_gates).sum::<usize>() < gates);
+        // Each constant output is a single gate, and !z | (x & y) takes three gates
+        assert_eq!(optimized.outs().iter().map(count_gates).collect::<Vec<_>>(), vec![1, 3, 1]);
+
+        for x in 0..8 {
+            let input: Vec<bool> = (0..3).map(|i| x & (1 << i) != 0).collect();
+
+            assert_eq!(optimized.eval_outputs(&input), circuit.eval_outputs(&input));
+        }
+    }
+
+    #[test]
+    pub fn optimize_dag_test() {
+        // 16 layers, each of which uses the previous one twice, which would take 2^16 gates as a tree
+        let mut node = Rc::new(Node::Gate(XOR, Rc::new(Node::Input(0)), Rc::new(Node::Input(1))));
+
+        for _ in 0..16 {
+            node = Rc::new(Node::Gate(AND, node.clone(), node));
+        }
+
+        let optimized = Circuit::new(Rc::unwrap_or_clone(node)).unwrap().optimize();
+
+        // The layers stay shared
+        assert_eq!(optimized.num_gates(), 17);
+
+        for x in 0..4 {
+            let input: Vec<bool> = (0..2).map(|i| x & (1 << i) != 0).collect();
+
+            assert_eq!(optimized.eval(&input), input[0] ^ input[1]);
+        }
+    }
+
+    #[test]
+    pub fn dedup_subexpressions_test() {
+        // (x ^ y) & (x ^ y), built as a tree with two copies of x ^ y
+        let xor = || Rc::new(Node::Gate(XOR, Rc::new(Node::Input(0)), Rc::new(Node::Input(1))));
+        let circuit = Circuit::new(Node::Gate(AND, xor(), xor())).unwrap().dedup_subexpressions();
+        let Node::Gate(_, left, right) = &circuit.outs()[0] else {
+            panic!("the output should be a gate");
+        };
+
+        // Both copies are merged into a single shared node
+        assert!(Rc::ptr_eq(left, right));
+
+        for x in 0..4 {
+            let input: Vec<bool> = (0..2).map(|i| x & (1 << i) != 0).collect();
+
+            assert_eq!(circuit.eval(&input), input[0] ^ input[1]);
+        }
+    }
+
+    #[test]
+    pub fn metrics_test() {
+        // x & ((x | y) ^ z), plus x & y as a second output
+        let x = Rc::new(Node::Input(0));
+        let y = Rc::new(Node::Input(1));
+        let or = Rc::new(Node::Gate(OR, x.clone(), y.clone()));
+        let xor = Rc::new(Node::Gate(XOR, or, Rc::new(Node::Input(2))));
+        let circuit = Circuit::with_outputs(vec![Node::Gate(AND, x.clone(), xor), Node::Gate(AND, x, y)]).unwrap();
+
+        assert_eq!(circuit.num_gates(), 4);
+        assert_eq!(circuit.depth(), 3);
+        assert_eq!(circuit.gate_counts(), [(AND, 2), (OR, 1), (XOR, 1)].into());
+
+        // A subtree shared by both inputs of a gate is only counted once
+        let and = Rc::new(Node::Gate(AND, Rc::new(Node::Input(0)), Rc::new(Node::Input(1))));
+        let circuit = Circuit::new(Node::Gate(OR, and.clone(), and)).unwrap();
+
+        assert_eq!(circuit.num_gates(), 2);
+        assert_eq!(circuit.depth(), 2);
+    }
+
+    #[test]
+    pub fn visitor_test() {
+        use super::CircuitVisitor;
+
+        /// Evaluates the circuit on `input`, counting the nodes it visits
+        struct Evaluator {
+            input: Vec<bool>,
+            visits: usize,
+        }
+
+        impl CircuitVisitor for Evaluator {
+            type Output = bool;
+
+            fn visit_input(&mut self, idx: usize) -> bool {
+                self.visits += 1;
+                self.input[idx]
+            }
+
+            fn visit_gate(&mut self, gate: &Node, children: &[bool]) -> bool {
+                self.visits += 1;
+
+                match gate {
+                    Node::Gate(op, _, _) => (op >> (2 * children[0] as usize + children[1] as usize)) & 1 == 1,
+                    Node::GateN(table, _) => table[children.iter().fold(0, |row, bit| 2 * row + *bit as usize)],
+                    Node::Input(_) => unreachable!(),
+                }
+            }
+        }
+
+        // The full adder's x ^ y is shared between both outputs
+        let circuit = full_adder();
+
+        for x in 0..8usize {
+            let input: Vec<bool> = (0..3).map(|i| (x >> i) & 1 == 1).collect();
+            let mut evaluator = Evaluator { input: input.clone(), visits: 0 };
+
+            assert_eq!(circuit.visit(&mut evaluator), circuit.eval_outputs(&input));
+            // Each of the 6 input nodes is visited, and the shared gate is only visited once
+            assert_eq!(evaluator.visits, 6 + circuit.num_gates());
+        }
+    }
+
+    #[test]
+    pub fn truth_table_test() {
+        use crate::backend::garbler_backend::comparison_circuit;
+
+        let and = Circuit::new(Node::Gate(AND, Rc::new(Node::Input(0)), Rc::new(Node::Input(1)))).unwrap();
+        let or = Circuit::new(Node::Gate(OR, Rc::new(Node::Input(0)), Rc::new(Node::Input(1)))).unwrap();
+
+        assert_eq!(and.truth_table().unwrap(), vec![false, false, false, true]);
+        assert!(!and.equivalent_to(&or).unwrap());
+        // Circuits with a different number of inputs or outputs are never equivalent
+        assert!(!and.equivalent_to(&Circuit::new(Node::Input(0)).unwrap()).unwrap());
+        assert!(!and.equivalent_to(&Circuit::with_outputs(vec![and.outs()[0].clone(), Node::Input(1)]).unwrap()).unwrap());
+
+        // Optimizing and deduplicating a circuit doesn't change what it computes
+        assert!(comparison_circuit(3).equivalent_to(&comparison_circuit(3).optimize().dedup_subexpressions()).unwrap());
+        assert_eq!(comparison_circuit(3).truth_table().unwrap().len(), 2 * 64);
+
+        // Large circuits are rejected rather than evaluated 2^n times
+        assert!(comparison_circuit(11).truth_table().is_err());
+        assert!(comparison_circuit(11).equivalent_to(&comparison_circuit(11)).is_err());
+    }
+
+    /// A 1-bit full adder of inputs 0 and 1 with carry-in 2, whose outputs are the sum and the carry-out
+    fn full_adder() -> Circuit {
+        let input = |idx| Rc::new(Node::Input(idx));
+        let half_sum = Rc::new(Node::Gate(XOR, input(0), input(1)));
+        let sum = Node::Gate(XOR, half_sum.clone(), input(2));
+        let carry = Node::Gate(
+            OR,
+            Rc::new(Node::Gate(AND, input(0), input(1))),
+            Rc::new(Node::Gate(AND, half_sum, input(2))),
+        );
+
+        Circuit::with_outputs(vec![sum, carry]).unwrap()
+    }
+
+    #[test]
+    pub fn compose_test() {
+        // Feed the first adder's carry-out into the second's carry-in. The inputs are a_0, b_0, c_0, a_1, b_1,
+        // and the outputs are the sum bits s_0, s_1 and the carry-out
+        let adder = full_adder().compose(full_adder(), &[(1, 2)]).unwrap();
+        assert_eq!(adder.n(), 5);
+        assert_eq!(adder.num_gates(), 10);
+
+        for x in 0..32usize {
+            let input: Vec<bool> = (0..5).map(|i| (x >> i) & 1 == 1).collect();
+            let (a, b, carry) = (x & 1 | ((x >> 3) & 1) << 1, (x >> 1) & 1 | ((x >> 4) & 1) << 1, (x >> 2) & 1);
+            let sum = a + b + carry;
+
+            assert_eq!(adder.eval_outputs(&input), (0..3).map(|i| (sum >> i) & 1 == 1).collect::<Vec<bool>>());
+        }
+
+        // Connections to wires that don't exist, or to the same input twice
+        assert!(full_adder().compose(full_adder(), &[(2, 0)]).is_err());
+        assert!(full_adder().compose(full_adder(), &[(0, 3)]).is_err());
+        assert!(full_adder().compose(full_adder(), &[(0, 1), (1, 1)]).is_err());
+    }
+
+    #[test]
+    pub fn negate_output_test() {
+        let out = Node::Gate(AND, Rc::new(Node::Input(0)), Rc::new(Node::Input(1)));
+        let circuit = Circuit::with_outputs(vec![out, Node::Input(1)]).unwrap();
+        let negated = Circuit::with_outputs(circuit.outs()).unwrap().negate_output(0).unwrap().negate_output(1).unwrap();
+
+        for x in 0..4usize {
+            let input = vec![x & 1 == 1, x & 2 == 2];
+            let outs = circuit.eval_outputs(&input);
+
+            assert_eq!(negated.eval_outputs(&input), vec![!outs[0], !outs[1]]);
+        }
+        assert_eq!(negated.num_gates(), circuit.num_gates() + 1);
+        assert!(negated.negate_output(2).is_err());
+
+        // The negated comparison circuit tells whether the first input is at most the second
+        let cmp = negated_comparison_circuit(3);
+        for (a, b) in [(5, 2), (2, 5), (4, 4)] {
+            let input: Vec<bool> = (0..6).map(|i| ((a | b << 3) >> i) & 1 == 1).collect();
+
+            assert_eq!(cmp.eval_outputs(&input), vec![a <= b, a == b]);
+        }
+    }
+
+    #[test]
+    pub fn eval_int_test() {
+        use crate::backend::garbler_backend::comparison_circuit;
+
+        let cmp = comparison_circuit(3);
+
+        for a in 0..8 {
+            for b in 0..8 {
+                assert_eq!(cmp.eval_int(a, b, 3), a > b);
+            }
+        }
+    }
+
+    #[test]
+    pub fn equality_circuit_test() {
+        for n in 1..=3 {
+            let eq = equality_circuit(n);
+
+            for a in 0..1 << n {
+                for b in 0..1 << n {
+                    let input: Vec<bool> = (0..2 * n).map(|i| ((a | b << n) >> i) & 1 == 1).collect();
+
+                    assert_eq!(eq.eval_outputs(&input), vec![a == b]);
+                }
+            }
+        }
+
+        // One XNOR per pair of bits, ANDed together in a tree
+        assert_eq!(equality_circuit(8).num_gates(), 15);
+        assert_eq!(equality_circuit(8).depth(), 4);
+    }
+
+    #[test]
+    pub fn parse_test() {
+        // The circuit of `complex_circuit_test`
+        let x = Rc::new(Node::Input(0));
+        let or = Node::Gate(OR, x.clone(), Rc::new(Node::Input(1)));
+        let xor = Node::Gate(XOR, Rc::new(or), Rc::new(Node::Input(2)));
+        let expected = Circuit::new(Node::Gate(AND, x, Rc::new(xor))).unwrap();
+        let parsed = Node::parse("in0 & ((in0 | in1) ^ in2)").unwrap();
+
+        assert!(Circuit::new(parsed.clone()).unwrap().equivalent_to(&expected).unwrap());
+        assert_eq!(parsed.to_string(), "(in0 AND ((in0 OR in1) XOR in2))");
+
+        // & binds tighter than ^, which binds tighter than |, and NOT binds tightest
+        let parsed = Node::parse("!in0 | in1 ^ in2 & in3").unwrap();
+        assert_eq!(parsed.to_string(), "((NOT in0) OR (in1 XOR (in2 AND in3)))");
+        // Rendered expressions parse back to the same expression, and operators associate to the left
+        let parsed = Node::parse("in0 NAND in1 XNOR in2 NOR (NOT in3) ^ in4 ^ in5").unwrap();
+        assert_eq!(Node::parse(&parsed.to_string()).unwrap().to_string(), parsed.to_string());
+        assert_eq!(parsed.to_string(), "(((in0 NAND in1) XNOR in2) NOR (((NOT in3) XOR in4) XOR in5))");
+
+        for bad in ["", "in0 &", "(in0 | in1", "in0 in1", "in0 + in1", "x & in1", "in0 & )"] {
+            assert!(Node::parse(bad).is_err(), "{:?} should fail to parse", bad);
+        }
+
+        // Constants and wide gates are rendered, but can't be parsed back
+        for rendered in ["true", "in0 & false", "[0110](in0, in1)"] {
+            assert!(Node::parse(rendered).is_err(), "{:?} should fail to parse", rendered);
+        }
+    }
+
+    #[test]
+    pub fn display_test() {
+        let input = |idx| Rc::new(Node::Input(idx));
+        let xor = Node::Gate(XOR, input(1), input(2));
+        let out = Node::Gate(AND, input(0), Rc::new(xor));
+
+        assert_eq!(out.to_string(), "(in0 AND (in1 XOR in2))");
+        assert_eq!(Circuit::new(out).unwrap().to_string(), "circuit with 3 inputs\nout = (in0 AND (in1 XOR in2))");
+
+        // Non-standard operations, negations, constants and wide gates
+        let outs = vec![
+            Node::Gate(0b0100, input(0), input(1)),
+            Node::Gate(0b0011, input(1), input(1)),
+            Node::Gate(FALSE, input(0), input(0)),
+            Node::GateN(vec![false, true, true, false], vec![input(0), input(1)]),
+        ];
+
+        assert_eq!(
+            Circuit::with_outputs(outs).unwrap().to_string(),
+            "circuit with 2 inputs\nout0 = (in0 NIMPLY in1)\nout1 = (NOT in1)\nout2 = false\nout3 = [0110](in0, in1)"
+        );
+    }
+
+    #[test]
+    pub fn gate_op_range_test() {
+        // 0b10110 would otherwise alias XOR
+        let gate = |op| Node::Gate(op, Rc::new(Node::Input(0)), Rc::new(Node::Input(1)));
+        let nested = Node::Gate(AND, Rc::new(gate(0b10110)), Rc::new(Node::Input(2)));
+
+        assert!(Circuit::new(gate(0b10110)).err().unwrap().to_string().contains("0b10110"));
+        assert!(Circuit::new(nested).is_err());
+        assert!(Circuit::new(gate(0b1111)).is_ok());
+    }
+
+    #[test]
+    pub fn input_gaps_test() {
+        // Inputs 1 and 3 are never used
+        let out = Node::Gate(
+            AND,
+            Rc::new(Node::Input(0)),
+            Rc::new(Node::Gate(OR, Rc::new(Node::Input(2)), Rc::new(Node::Input(4)))),
+        );
+        let err = Circuit::new(out).err().unwrap();
+
+        assert!(err.to_string().contains("[1, 3]"));
+        assert!(Circuit::with_outputs(vec![Node::Input(1)]).is_err());
+        assert_eq!(Circuit::with_outputs(vec![Node::Input(1), Node::Input(0)]).unwrap().n(), 2);
+    }
+
+    #[test]
+    pub fn multi_input_gate_test() {
+        // A 3-input majority gate, negated
+        let majority = Node::GateN(
+            vec![false, false, false, true, false, true, true, true],
+            (0..3).map(|i| Rc::new(Node::Input(i))).collect(),
+        );
+        let circuit = Circuit::with_outputs(vec![majority.clone(), majority.negate()]).unwrap();
+
+        for bits in 0..8usize {
+            let input: Vec<bool> = (0..3).map(|i| bits & (1 << i) != 0).collect();
+            let majority = bits.count_ones() >= 2;
+
+            assert_eq!(circuit.eval_outputs(&input), vec![majority, !majority]);
+        }
+
+        assert_eq!(circuit.num_gates(), 2);
+        assert_eq!(circuit.depth(), 1);
+        assert!(circuit.gate_counts().is_empty());
+
+        // The truth table must have an entry per combination of the gate's inputs
+        let bad = Node::GateN(vec![false, true], (0..3).map(|i| Rc::new(Node::Input(i))).collect());
+        assert!(Circuit::new(bad).is_err());
+    }
+
+    #[test]
+    pub fn to_nand_test() {
+        use crate::backend::garbler_backend::comparison_circuit;
+
+        // Every two-input operation (including the constants), a full adder, a comparison, and a 3-input majority gate
+        let gates = (0..16).map(|op| Circuit::new(Node::Gate(op, Rc::new(Node::Input(0)), Rc::new(Node::Input(1)))).unwrap());
+        let majority = Node::GateN(
+            vec![false, false, false, true, false, true, true, true],
+            (0..3).map(|i| Rc::new(Node::Input(i))).collect(),
+        );
+        let circuits = gates.chain([full_adder(), comparison_circuit(3), Circuit::new(majority).unwrap()]);
+
+        for circuit in circuits {
+            let lowered = circuit.clone().to_nand();
+
+            assert!(lowered.equivalent_to(&circuit).unwrap());
+            assert_eq!(lowered.gate_counts().keys().collect::<Vec<_>>(), vec![&NAND]);
+            assert_eq!(lowered.num_gates(), lowered.gate_counts()[&NAND]);
+        }
+
+        // NAND gates are kept as they are
+        let nand = Circuit::new(Node::Gate(NAND, Rc::new(Node::Input(0)), Rc::new(Node::Input(1)))).unwrap();
+        assert_eq!(nand.to_nand().num_gates(), 1);
     }
 }
@@ -3,7 +3,11 @@ use aes::{
     Aes256,
 };
 
+use super::GarbleCipher;
+
 pub const AES_BLOCK_SIZE: usize = 16;
+/// Size of a nonce in bytes. Each counter block is the nonce followed by a 32-bit block counter
+pub const NONCE_SIZE: usize = 12;
 
 #[derive(Clone)]
 pub struct AesCtr {
@@ -19,36 +23,53 @@ impl AesCtr {
         AesCtr { cipher }
     }
 
-    pub fn encrypt(&self, msg: &[u8], nonce: usize) -> Vec<u8> {
-        // AES-CTR encrypts using a running counter, where we XOR each byte of the msg
-        // with a byte from a running keystream
+    /// Encrypt `msg` starting from block `counter` of the keystream of `nonce`.
+    /// A (key, nonce) pair must never encrypt two different messages, which would share a keystream
+    pub fn encrypt(&self, msg: &[u8], nonce: &[u8; NONCE_SIZE], counter: u32) -> Vec<u8> {
+        let mut ciphertext = vec![0u8; msg.len()];
+        self.encrypt_into(&mut ciphertext, msg, nonce, counter);
+
+        ciphertext
+    }
 
-        let num_blocks = msg.len().div_ceil(AES_BLOCK_SIZE);
-        let mut msg_bytes = msg.chunks(AES_BLOCK_SIZE);
-        let mut ciphertext = vec![];
+    /// Like `encrypt`, but writes the ciphertext into `dst` rather than allocating it.
+    /// Panics if `dst` isn't as long as `src`
+    pub fn encrypt_into(&self, dst: &mut [u8], src: &[u8], nonce: &[u8; NONCE_SIZE], counter: u32) {
+        assert_eq!(dst.len(), src.len(), "the destination must be as long as the message");
 
-        for i in nonce..nonce + num_blocks {
-            // Pad it to the block size
-            let mut i_slice = vec![0u8; AES_BLOCK_SIZE - 8];
-            i_slice.extend(&i.to_be_bytes());
-            let i_slice: [u8; AES_BLOCK_SIZE] = i_slice.try_into().unwrap();
-            let mut key_block = GenericArray::from(i_slice);
+        // AES-CTR encrypts using a running counter, where we XOR each byte of the msg
+        // with a byte from a running keystream
+        for (i, (dst_block, src_block)) in dst.chunks_mut(AES_BLOCK_SIZE).zip(src.chunks(AES_BLOCK_SIZE)).enumerate() {
+            // The counter block is the nonce followed by the (big-endian) block counter
+            let mut counter_block = [0u8; AES_BLOCK_SIZE];
+            counter_block[..NONCE_SIZE].copy_from_slice(nonce);
+            counter_block[NONCE_SIZE..].copy_from_slice(&counter.wrapping_add(i as u32).to_be_bytes());
+            let mut key_block = GenericArray::from(counter_block);
             self.cipher.encrypt_block(&mut key_block);
-            // # Of bytes to encrypt in this block
-            let msg_block = msg_bytes.next().unwrap();
-            let to_encrypt = msg_block.len().min(AES_BLOCK_SIZE);
 
-            for j in 0..to_encrypt {
-                ciphertext.push(key_block.get(j).unwrap() ^ msg_block.get(j).unwrap());
+            for ((dst_byte, key_byte), msg_byte) in dst_block.iter_mut().zip(key_block.iter()).zip(src_block) {
+                *dst_byte = key_byte ^ msg_byte;
             }
         }
-
-        ciphertext
     }
 
-    pub fn decrypt(&self, msg: &[u8], nonce: usize) -> Vec<u8> {
+    pub fn decrypt(&self, msg: &[u8], nonce: &[u8; NONCE_SIZE], counter: u32) -> Vec<u8> {
         // Encryption is the same as decryption in CTR mode
-        self.encrypt(msg, nonce)
+        self.encrypt(msg, nonce, counter)
+    }
+}
+
+/// Hashing with AES-CTR keyed by the key itself: the hash is the first 32 bytes of the keystream
+/// whose nonce is the tweak. It's slower than `FixedKeyAes`, with a key schedule per hash, but
+/// doesn't rely on AES under a fixed key behaving like a random permutation
+impl GarbleCipher for AesCtr {
+    fn hash(key: &[u8; 32], tweak: u64) -> [u8; 32] {
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce[..8].copy_from_slice(&tweak.to_be_bytes());
+        let mut out = [0u8; 32];
+        AesCtr::new(key).encrypt_into(&mut out, &[0u8; 32], &nonce, 0);
+
+        out
     }
 }
 
@@ -64,9 +85,36 @@ mod tests {
         let key = sha256::digest("0");
         let key = BigUint::from_str_radix(&key, 16).unwrap().to_bytes_be();
         let cipher = AesCtr::new(&key);
-        let ciphertext = cipher.encrypt(b"ATTACK AT DAWN HELLO", 0);
-        let plaintext = cipher.decrypt(&ciphertext, 0);
+        let ciphertext = cipher.encrypt(b"ATTACK AT DAWN HELLO", &[0u8; 12], 0);
+        let plaintext = cipher.decrypt(&ciphertext, &[0u8; 12], 0);
 
         assert_eq!(plaintext, b"ATTACK AT DAWN HELLO");
     }
+
+    #[test]
+    fn aes_ctr_nonce_test() {
+        let cipher = AesCtr::new(&[1u8; 32]);
+        let msg = [0u8; 40];
+        let (nonce_0, nonce_1) = ([0u8; 12], [1u8; 12]);
+
+        // The same plaintext under different nonces yields different ciphertexts
+        assert_ne!(cipher.encrypt(&msg, &nonce_0, 0), cipher.encrypt(&msg, &nonce_1, 0));
+        // Each block of the keystream is determined by the nonce and the block counter
+        assert_eq!(cipher.encrypt(&msg, &nonce_0, 0)[16..], cipher.encrypt(&msg[16..], &nonce_0, 1));
+        assert_eq!(cipher.decrypt(&cipher.encrypt(&msg, &nonce_1, 7), &nonce_1, 7), msg);
+    }
+
+    #[test]
+    fn encrypt_into_test() {
+        let cipher = AesCtr::new(&[2u8; 32]);
+        let msg: Vec<u8> = (0..40).collect();
+        let nonce = [3u8; 12];
+
+        // Writing into a buffer gives the same ciphertext as allocating it, and can reuse the buffer
+        let mut buf = [0xffu8; 40];
+        cipher.encrypt_into(&mut buf, &msg, &nonce, 5);
+        assert_eq!(buf.to_vec(), cipher.encrypt(&msg, &nonce, 5));
+        cipher.encrypt_into(&mut buf[..16], &msg[24..], &nonce, 6);
+        assert_eq!(buf[..16], cipher.encrypt(&msg[24..], &nonce, 6));
+    }
 }
@@ -0,0 +1,128 @@
+use num_bigint::BigUint;
+use once_cell::sync::Lazy;
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+/// Size of a group element in bytes
+pub const ELEM_SIZE: usize = 256;
+/// Size of a secret exponent in bytes. Following RFC 3526, exponents twice the security level
+/// of the group are sufficient, which are much cheaper than exponents the size of the group order
+const SECRET_SIZE: usize = 32;
+
+/// The 2048-bit MODP group from RFC 3526 (group 14). p is a safe prime, i.e. p = 2q + 1 for a prime q
+pub static P: Lazy<BigUint> = Lazy::new(|| {
+    BigUint::parse_bytes(
+        b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DD\
+EF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED\
+EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF0598DA48361C55D39A69163FA8FD24CF5F\
+83655D23DCA3AD961C62F356208552BB9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B\
+E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF6955817183995497CEA956AE515D2261898FA0510\
+15728E5A8AACAA68FFFFFFFFFFFFFFFF",
+        16,
+    )
+    .unwrap()
+});
+/// The order of the subgroup generated by G: q = (p - 1) / 2
+pub static Q: Lazy<BigUint> = Lazy::new(|| (&*P - 1u64) >> 1);
+/// The generator. 2 is a quadratic residue mod p, so it generates the subgroup of order q
+pub static G: Lazy<BigUint> = Lazy::new(|| BigUint::from(2u64));
+
+/// Generate a random secret exponent
+pub fn gen_secret() -> BigUint {
+    let mut rng = ChaCha20Rng::from_entropy();
+    let mut bytes = [0u8; SECRET_SIZE];
+    rng.fill_bytes(&mut bytes);
+
+    BigUint::from_bytes_be(&bytes)
+}
+
+/// Check that x is an element of the order-q subgroup. Elements received from a peer must be checked,
+/// since elements of small order can leak information about the exponents they are raised to
+pub fn is_group_element(x: &BigUint) -> bool {
+    // Since p is a safe prime, the subgroup of order q is exactly the quadratic residues mod p,
+    // which we can check with the Jacobi symbol (much cheaper than computing x^q)
+    *x > BigUint::from(1u64) && *x < *P && jacobi(x, &P) == 1
+}
+
+/// Compute the Jacobi symbol (a / n) for an odd n
+fn jacobi(a: &BigUint, n: &BigUint) -> i8 {
+    let mut a = a % n;
+    let mut n = n.clone();
+    let mut t = 1;
+
+    while a != BigUint::from(0u64) {
+        // Pull out factors of two: (2 / n) = -1 iff n = 3, 5 (mod 8)
+        let zeros = a.trailing_zeros().unwrap();
+        a >>= zeros;
+        let n_mod_8 = (&n % 8u64).to_u64_digits().first().copied().unwrap_or(0);
+
+        if zeros % 2 == 1 && (n_mod_8 == 3 || n_mod_8 == 5) {
+            t = -t;
+        }
+
+        // Quadratic reciprocity
+        std::mem::swap(&mut a, &mut n);
+
+        if &a % 4u64 == BigUint::from(3u64) && &n % 4u64 == BigUint::from(3u64) {
+            t = -t;
+        }
+
+        a %= &n;
+    }
+
+    if n == BigUint::from(1u64) {
+        t
+    } else {
+        0
+    }
+}
+
+/// Encode a group element as a fixed-size big-endian byte string
+pub fn to_fixed_bytes(x: &BigUint) -> [u8; ELEM_SIZE] {
+    let bytes = x.to_bytes_be();
+    let mut out = [0u8; ELEM_SIZE];
+    out[ELEM_SIZE - bytes.len()..].copy_from_slice(&bytes);
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigUint;
+
+    use super::{gen_secret, is_group_element, jacobi, to_fixed_bytes, G, P, Q};
+
+    #[test]
+    fn group_element_test() {
+        let x = G.modpow(&gen_secret(), &P);
+
+        assert!(is_group_element(&G));
+        assert!(is_group_element(&x));
+        // -1 has order 2, so it's not in the subgroup
+        assert!(!is_group_element(&(&*P - 1u64)));
+        assert!(!is_group_element(&BigUint::from(1u64)));
+        assert!(!is_group_element(&P));
+        assert_eq!(G.modpow(&Q, &P), BigUint::from(1u64));
+    }
+
+    #[test]
+    fn jacobi_test() {
+        // Squares mod 11 are 1, 3, 4, 5, 9
+        let symbols: Vec<i8> = (1..11u64)
+            .map(|a| jacobi(&a.into(), &11u64.into()))
+            .collect();
+
+        assert_eq!(symbols, vec![1, -1, 1, 1, 1, -1, -1, -1, 1, -1]);
+        // (2 / 15) = (2 / 3) * (2 / 5) = 1, yet 2 is not a square mod 15
+        assert_eq!(jacobi(&2u64.into(), &15u64.into()), 1);
+        assert_eq!(jacobi(&5u64.into(), &15u64.into()), 0);
+    }
+
+    #[test]
+    fn fixed_bytes_test() {
+        let bytes = to_fixed_bytes(&BigUint::from(0x1234u64));
+
+        assert_eq!(bytes[..bytes.len() - 2], [0u8; 254]);
+        assert_eq!(bytes[bytes.len() - 2..], [0x12, 0x34]);
+    }
+}
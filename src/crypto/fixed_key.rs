@@ -0,0 +1,93 @@
+use aes::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Aes128,
+};
+use once_cell::sync::Lazy;
+
+use super::{aes_ctr::AES_BLOCK_SIZE, GarbleCipher};
+
+/// The key of the fixed permutation. It's public: the hash relies on AES under a fixed key behaving like a random permutation,
+/// not on the key being secret (the digits of pi, so there's nothing up our sleeve)
+const FIXED_KEY: [u8; AES_BLOCK_SIZE] = [
+    0x24, 0x3f, 0x6a, 0x88, 0x85, 0xa3, 0x08, 0xd3, 0x13, 0x19, 0x8a, 0x2e, 0x03, 0x70, 0x73, 0x44,
+];
+
+/// AES under the fixed key. Its key schedule is computed once, rather than once per hash
+static PERMUTATION: Lazy<Aes128> = Lazy::new(|| Aes128::new(&GenericArray::from(FIXED_KEY)));
+
+/// The fixed permutation pi
+fn permute(block: u128) -> u128 {
+    let mut block = GenericArray::from(block.to_be_bytes());
+    PERMUTATION.encrypt_block(&mut block);
+
+    u128::from_be_bytes(block.into())
+}
+
+/// The linear orthomorphism sigma(x_l || x_r) = (x_l ^ x_r) || x_l, which makes the hash below
+/// circular correlation robust (Guo, Katz, Wang and Yu), as free XOR requires
+fn sigma(block: u128) -> u128 {
+    let (left, right) = (block >> 64, block & u64::MAX as u128);
+
+    ((left ^ right) << 64) | left
+}
+
+/// Davies-Meyer style compression of a block with a tweak: pi(y) ^ y, where y = sigma(x) ^ tweak
+fn compress(block: u128, tweak: u128) -> u128 {
+    let y = sigma(block) ^ tweak;
+
+    permute(y) ^ y
+}
+
+/// Hash a 32-byte key with a tweak, using AES under a fixed key (so no key schedule is computed per hash).
+/// Each 16-byte block of the output XORs together the compressions of both halves of the key, each with its own tweak block,
+/// so every block of the output depends on the whole key. The tweak block is `tweak` followed by the index of the half
+/// and of the output block, so different tweaks never share a tweak block
+pub fn hash(key: &[u8; 32], tweak: u64) -> [u8; 32] {
+    let halves = [0, 1].map(|half| u128::from_be_bytes(key[half * AES_BLOCK_SIZE..][..AES_BLOCK_SIZE].try_into().unwrap()));
+    let mut out = [0u8; 32];
+
+    for (block_idx, out_block) in out.chunks_mut(AES_BLOCK_SIZE).enumerate() {
+        let block = halves
+            .iter()
+            .enumerate()
+            .map(|(half, key_half)| compress(*key_half, ((tweak as u128) << 64) | (2 * block_idx + half) as u128))
+            .fold(0, |acc, compressed| acc ^ compressed);
+
+        out_block.copy_from_slice(&block.to_be_bytes());
+    }
+
+    out
+}
+
+/// The default `GarbleCipher`: the fixed-key AES hash above
+pub struct FixedKeyAes;
+
+impl GarbleCipher for FixedKeyAes {
+    fn hash(key: &[u8; 32], tweak: u64) -> [u8; 32] {
+        hash(key, tweak)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::hash;
+
+    #[test]
+    fn fixed_key_hash_test() {
+        let key = [7u8; 32];
+
+        // The hash is deterministic, and changes with the tweak and with either half of the key
+        assert_eq!(hash(&key, 0), hash(&key, 0));
+        assert_ne!(hash(&key, 0), hash(&key, 1));
+
+        for idx in [0, 31] {
+            let mut other = key;
+            other[idx] ^= 1;
+            let (out, other_out) = (hash(&key, 0), hash(&other, 0));
+
+            // Every block of the output depends on the whole key
+            assert_ne!(out[..16], other_out[..16]);
+            assert_ne!(out[16..], other_out[16..]);
+        }
+    }
+}
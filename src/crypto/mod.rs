@@ -1,2 +1,10 @@
 pub mod aes_ctr;
+pub mod dh;
+pub mod fixed_key;
 pub mod rsa;
+
+/// The hash that garbling encrypts rows with, keyed by a 32-byte key and tweaked by the row's position (see `gate_tweak`).
+/// A circuit has to be evaluated with the same hash it was garbled with. `FixedKeyAes` is the default, and `AesCtr` implements it too
+pub trait GarbleCipher {
+    fn hash(key: &[u8; 32], tweak: u64) -> [u8; 32];
+}
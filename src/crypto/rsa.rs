@@ -1,10 +1,18 @@
 use num_bigint::{BigUint, RandBigInt};
-use rand::{self, thread_rng, RngCore, SeedableRng};
+use rand::{self, thread_rng, Rng, SeedableRng};
 use rand_chacha::ChaCha20Rng;
+use sha2::{Digest, Sha256};
+use std::io;
+
+use crate::garbling::KEY_SIZE;
 
 const RSA_EXP: u64 = 65537u64;
 /// N size in bytes
 pub const N_SIZE: usize = 256;
+/// Output size of the hash used by OAEP (SHA-256) in bytes
+const HASH_SIZE: usize = 32;
+/// The smallest modulus (in bytes) that can carry a `KEY_SIZE`-byte message plus OAEP padding
+pub const MIN_N_SIZE: usize = KEY_SIZE + 2 * HASH_SIZE + 2;
 
 /// RSA Public Key
 #[derive(Clone, Debug)]
@@ -49,6 +57,102 @@ impl Keypair {
         Keypair { public, private }
     }
 
+    /// Generate a keypair with a modulus of exactly `bits` bits (e.g. 2048, 3072 or 4096).
+    /// Returns an error if `bits` isn't a multiple of 16, or if the modulus would be too small
+    /// to carry a `KEY_SIZE`-byte message plus padding
+    pub fn with_bits(bits: usize) -> Result<Keypair, io::Error> {
+        if !bits.is_multiple_of(16) || bits < MIN_N_SIZE * 8 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "invalid RSA modulus size {}: must be a multiple of 16 and at least {} bits",
+                    bits,
+                    MIN_N_SIZE * 8
+                ),
+            ));
+        }
+
+        let e = BigUint::from(RSA_EXP);
+
+        // e must be invertible mod phi(n), so retry in the (unlikely) case it isn't
+        loop {
+            let (p, q) = (gen_prime_bits(bits / 2), gen_prime_bits(bits / 2));
+            let phi_n = (&p - 1u64) * (&q - 1u64);
+
+            if let Some(d) = e.modinv(&phi_n) {
+                let public = PublicKey { e: e.clone(), n: &p * &q };
+                let private = PrivateKey { p, q, d };
+
+                return Ok(Keypair { public, private });
+            }
+        }
+    }
+
+    /// Serialize the keypair, so that it can be stored and reused across runs (see `from_bytes`). The keypair is given by
+    /// its primes p and q (everything else is derived from them), each encoded as its 4-byte big-endian length followed by
+    /// its big-endian bytes.
+    ///
+    /// These bytes are the private key: a receiver who learns them can decrypt both messages of every OT the keypair is used
+    /// in, and hence learn both keys of its input wires, which reveals the garbler's input once it evaluates the circuit.
+    /// They should be stored where only the garbler can read them. Reusing a keypair is otherwise as safe as using it for
+    /// several receivers in the same run, and it lets receivers pin the garbler's public key
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+
+        for prime in [&self.private.p, &self.private.q] {
+            let prime_bytes = prime.to_bytes_be();
+            bytes.extend((prime_bytes.len() as u32).to_be_bytes());
+            bytes.extend(prime_bytes);
+        }
+
+        bytes
+    }
+
+    /// Parse a keypair serialized with `to_bytes`. Returns an error if the bytes are malformed, if p or q isn't prime,
+    /// or if the modulus is too small (see `with_bits`)
+    pub fn from_bytes(bytes: &[u8]) -> Result<Keypair, io::Error> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("invalid RSA keypair: {}", msg));
+        let mut rest = bytes;
+        let mut primes = vec![];
+
+        for _ in 0..2 {
+            let (len_bytes, tail) = rest.split_first_chunk::<4>().ok_or_else(|| invalid("truncated length"))?;
+            let len = u32::from_be_bytes(*len_bytes) as usize;
+
+            if tail.len() < len {
+                return Err(invalid("truncated prime"));
+            }
+
+            primes.push(BigUint::from_bytes_be(&tail[..len]));
+            rest = &tail[len..];
+        }
+
+        if !rest.is_empty() {
+            return Err(invalid("trailing bytes"));
+        }
+
+        let (p, q) = (primes[0].clone(), primes[1].clone());
+
+        if p == q || [&p, &q].iter().any(|prime| **prime < BigUint::from(5u64) || !miller_rabin_test(prime, 40)) {
+            return Err(invalid("p and q must be distinct primes"));
+        }
+
+        let n = &p * &q;
+        let phi_n = (&p - 1u64) * (&q - 1u64);
+        let e = BigUint::from(RSA_EXP);
+
+        if modulus_size(&n) < MIN_N_SIZE {
+            return Err(invalid(&format!("the modulus must be at least {} bits", MIN_N_SIZE * 8)));
+        }
+
+        let d = e.modinv(&phi_n).ok_or_else(|| invalid("e isn't invertible mod phi(n)"))?;
+
+        Ok(Keypair {
+            public: PublicKey { e, n },
+            private: PrivateKey { p, q, d },
+        })
+    }
+
     /// Validate a signature on a message
     pub fn validate(&self, m: &BigUint, s: &BigUint) -> bool {
         s.modpow(&self.public.e, &self.public.n) == *m
@@ -60,19 +164,108 @@ impl Keypair {
     }
 }
 
+/// MGF1 mask generation function (RFC 8017, B.2.1) over SHA-256
+fn mgf1(seed: &[u8], len: usize) -> Vec<u8> {
+    let mut mask = vec![];
+
+    for counter in 0..len.div_ceil(HASH_SIZE) as u32 {
+        let mut hasher = Sha256::new();
+        hasher.update(seed);
+        hasher.update(counter.to_be_bytes());
+        mask.extend(hasher.finalize());
+    }
+
+    mask.truncate(len);
+
+    mask
+}
+
+fn xor_in_place(x: &mut [u8], mask: &[u8]) {
+    x.iter_mut().zip(mask).for_each(|(x_byte, mask_byte)| *x_byte ^= mask_byte);
+}
+
+/// Size of a modulus in bytes
+fn modulus_size(n: &BigUint) -> usize {
+    n.bits().div_ceil(8) as usize
+}
+
 impl PublicKey {
-    /// Encrypt a message under this public key
-    /// the message is padded with OAEP padding (todo)
+    /// Encrypt a message under this public key, without any padding ("textbook" RSA).
+    /// Textbook RSA is deterministic and malleable, so unless the message is uniformly random, use `encrypt_oaep` instead
     pub fn encrypt(&self, m: &BigUint) -> BigUint {
         m.modpow(&self.e, &self.n)
     }
+
+    /// Encrypt a message padded with OAEP (RFC 8017, 7.1.1), using SHA-256 and MGF1.
+    /// Returns `None` if the message is too long for the modulus
+    pub fn encrypt_oaep(&self, msg: &[u8], label: &[u8]) -> Option<BigUint> {
+        let k = modulus_size(&self.n);
+
+        if msg.len() + 2 * HASH_SIZE + 2 > k {
+            return None;
+        }
+
+        // DB = H(label) || 0x00 ... 0x00 || 0x01 || msg
+        let mut db: Vec<u8> = Sha256::digest(label).to_vec();
+        db.resize(k - msg.len() - HASH_SIZE - 2, 0);
+        db.push(1);
+        db.extend(msg);
+        // Mask DB with a random seed, and then mask the seed with the masked DB
+        let mut seed: [u8; HASH_SIZE] = ChaCha20Rng::from_entropy().gen();
+        xor_in_place(&mut db, &mgf1(&seed, k - HASH_SIZE - 1));
+        xor_in_place(&mut seed, &mgf1(&db, HASH_SIZE));
+        // EM = 0x00 || masked seed || masked DB
+        let mut em = vec![0u8];
+        em.extend(seed);
+        em.extend(db);
+
+        Some(self.encrypt(&BigUint::from_bytes_be(&em)))
+    }
 }
 
 impl PrivateKey {
-    /// Decrypt a message under this private key
+    /// Decrypt a message under this private key, without any padding
     pub fn decrypt(&self, c: &BigUint) -> BigUint {
         c.modpow(&self.d, &(&self.p * &self.q))
     }
+
+    /// Decrypt a message encrypted with `PublicKey::encrypt_oaep`.
+    /// Returns `None` if the ciphertext isn't a valid OAEP encryption under the given label
+    pub fn decrypt_oaep(&self, c: &BigUint, label: &[u8]) -> Option<Vec<u8>> {
+        let n = &self.p * &self.q;
+        let k = modulus_size(&n);
+
+        if c >= &n || k < 2 * HASH_SIZE + 2 {
+            return None;
+        }
+
+        // Restore the leading zeros of EM that BigUint drops
+        let em_bytes = self.decrypt(c).to_bytes_be();
+        let mut em = vec![0u8; k.saturating_sub(em_bytes.len())];
+        em.extend(em_bytes);
+
+        if em.len() != k {
+            return None;
+        }
+
+        // Unmask the seed, and then DB
+        let (y, rest) = em.split_at(1);
+        let (masked_seed, masked_db) = rest.split_at(HASH_SIZE);
+        let mut seed = masked_seed.to_vec();
+        xor_in_place(&mut seed, &mgf1(masked_db, HASH_SIZE));
+        let mut db = masked_db.to_vec();
+        xor_in_place(&mut db, &mgf1(&seed, k - HASH_SIZE - 1));
+
+        // DB = H(label) || 0x00 ... 0x00 || 0x01 || msg
+        let (l_hash, padded_msg) = db.split_at(HASH_SIZE);
+        let msg_start = padded_msg.iter().position(|byte| *byte != 0)?;
+
+        if y[0] != 0 || l_hash != Sha256::digest(label).as_slice() || padded_msg[msg_start] != 1 {
+            return None;
+        }
+
+        Some(padded_msg[msg_start + 1..].to_vec())
+    }
 }
 
 /// Factor n into the form n = 2^{s} * d, where d is odd
@@ -123,8 +316,13 @@ fn miller_rabin_test(n: &BigUint, k: usize) -> bool {
     true
 }
 
-/// Generate a random prime with specified number of bits
+/// Generate a random prime of `N_SIZE / 2` bytes
 pub fn gen_prime() -> BigUint {
+    gen_prime_bits(N_SIZE * 4)
+}
+
+/// Generate a random prime with specified number of bits
+pub fn gen_prime_bits(bits: usize) -> BigUint {
     let mut rng = ChaCha20Rng::from_entropy();
 
     // Primes are pretty common: The prime-counting function (number of primes smaller than some real number x)
@@ -132,9 +330,10 @@ pub fn gen_prime() -> BigUint {
     // Therefore, the method we use to generate prime numbers is to generate random numbers with the specified number of bits
     // until we hit a prime number.
     loop {
-        let mut bytes = [0u8; N_SIZE / 2];
-        rng.fill_bytes(&mut bytes);
-        let candidate = BigUint::from_bytes_be(&bytes);
+        let mut candidate = rng.gen_biguint(bits as u64);
+        // Set the top two bits, so that the product of two such primes has exactly 2 * bits bits
+        candidate.set_bit(bits as u64 - 1, true);
+        candidate.set_bit(bits as u64 - 2, true);
 
         if miller_rabin_test(&candidate, 12) {
             return candidate;
@@ -155,19 +354,19 @@ mod tests {
         // Carmichael number
         let carmichael = BigUint::from(41041u64);
 
-        assert_eq!(miller_rabin_test(&my_prime, 13), true);
-        assert_eq!(miller_rabin_test(&carmichael, 13), false);
-        assert_eq!(miller_rabin_test(&my_composite, 13), false);
+        assert!(miller_rabin_test(&my_prime, 13));
+        assert!(!miller_rabin_test(&carmichael, 13));
+        assert!(!miller_rabin_test(&my_composite, 13));
     }
 
     #[test]
     fn factor_test() {
         // 12524 = 2^2 * 3131
-        let number = BigUint::from(12524 as usize);
+        let number = BigUint::from(12524_usize);
 
         assert_eq!(
             factor(&number),
-            (BigUint::from(2 as usize), BigUint::from(3131 as usize))
+            (BigUint::from(2_usize), BigUint::from(3131_usize))
         );
     }
 
@@ -177,7 +376,7 @@ mod tests {
 
         println!("The prime is {}", p);
 
-        assert_eq!(miller_rabin_test(&p, 40), true);
+        assert!(miller_rabin_test(&p, 40));
     }
 
     #[test]
@@ -191,4 +390,47 @@ mod tests {
 
         assert_eq!(decrypted_ciphertext_string, "ATTACK AT DAWN");
     }
+
+    #[test]
+    fn with_bits_test() {
+        let keypair = Keypair::with_bits(1024).unwrap();
+
+        assert_eq!(keypair.public.n.bits(), 1024);
+        assert!(keypair.validate(&BigUint::from(42u64), &keypair.sign(&BigUint::from(42u64))));
+        // Too small to carry a key, or not a multiple of 16
+        assert!(Keypair::with_bits(512).is_err());
+        assert!(Keypair::with_bits(2050).is_err());
+    }
+
+    #[test]
+    fn keypair_bytes_test() {
+        let keypair = Keypair::with_bits(1024).unwrap();
+        let parsed = Keypair::from_bytes(&keypair.to_bytes()).unwrap();
+
+        assert_eq!(parsed.public.n, keypair.public.n);
+        assert_eq!(parsed.private.decrypt(&keypair.public.encrypt(&BigUint::from(42u64))), BigUint::from(42u64));
+
+        // Truncated, padded, or with a composite "prime"
+        let bytes = keypair.to_bytes();
+        assert!(Keypair::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Keypair::from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+        let mut composite = bytes.clone();
+        *composite.last_mut().unwrap() ^= 1;
+        assert!(Keypair::from_bytes(&composite).is_err());
+    }
+
+    #[test]
+    fn oaep_test() {
+        let keypair = Keypair::new(None, None);
+        let ciphertext = keypair.public.encrypt_oaep(&[7u8; 32], b"label").unwrap();
+
+        assert_eq!(keypair.private.decrypt_oaep(&ciphertext, b"label"), Some(vec![7u8; 32]));
+        // OAEP is randomized
+        assert_ne!(keypair.public.encrypt_oaep(&[7u8; 32], b"label").unwrap(), ciphertext);
+        // The wrong label, or a single flipped ciphertext bit, fails to decrypt
+        assert_eq!(keypair.private.decrypt_oaep(&ciphertext, b"other"), None);
+        assert_eq!(keypair.private.decrypt_oaep(&(&ciphertext ^ BigUint::from(1u64)), b"label"), None);
+        // Messages that are too long for the modulus are rejected
+        assert!(keypair.public.encrypt_oaep(&[0u8; N_SIZE], b"").is_none());
+    }
 }
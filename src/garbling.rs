@@ -1,59 +1,93 @@
-use rand::{Rng, SeedableRng};
+use rand::{seq::SliceRandom, Rng, SeedableRng};
 use rand_chacha::ChaCha20Rng;
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, io, marker::PhantomData, rc::Rc};
+use zeroize::{Zeroize, ZeroizeOnDrop};
 
 use crate::{
     circuit::{Circuit, Node},
-    crypto::aes_ctr::AesCtr,
+    crypto::{fixed_key::FixedKeyAes, GarbleCipher},
 };
 
-const KEY_SIZE: usize = 32;
+/// The default size of a wire key in bytes. The garbling types are generic over the key size `N`
+/// (up to 32 bytes), so smaller keys can be used for speed (at the cost of security)
+pub const KEY_SIZE: usize = 32;
+/// The size of the tag that lets the receiver tell which row of a `Table` gate it can decrypt
+pub const TAG_SIZE: usize = 16;
 
-#[derive(Clone, Debug)]
-pub struct GarbledWire {
-    on_key: [u8; KEY_SIZE],
-    off_key: [u8; KEY_SIZE],
+/// A wire's keys. Anyone holding both of them can tell what the wire's value is from its key, so
+/// they're zeroed when the wire is dropped (clones hold their own copy, which is zeroed separately)
+#[derive(Clone, Debug, Zeroize, ZeroizeOnDrop)]
+pub struct GarbledWire<const N: usize = KEY_SIZE> {
+    on_key: [u8; N],
+    off_key: [u8; N],
+}
+
+/// How a gate is garbled. Every wire uses free XOR (its on key is its off key XORed with a global
+/// offset delta), so the receiver learns which of these a gate is, but not the gate's truth table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GateKind {
+    /// A row per combination of the gate's inputs, stored in a random order. Gates with more than
+    /// two inputs are garbled this way. The first row is reduced (GRR3): its ciphertext is all
+    /// zeros, so only its tag is sent
+    Table,
+    /// A two-input gate whose output is linear in its inputs (e.g. XOR, XNOR and NOT). It has no
+    /// rows: its output key is the XOR of the keys of its inputs
+    FreeXor,
+    /// Any other two-input gate (e.g. AND and OR), which is garbled with two rows (see `half_gate`)
+    HalfGates,
 }
 
 #[derive(Debug, Clone)]
 /// A garbled gate (from the garbler's POV, i.e. we know the gate's keys and operation unlike the receiver)
-pub struct GarbledGate {
-    c_00: Option<Vec<u8>>,
-    c_01: Option<Vec<u8>>,
-    c_10: Option<Vec<u8>>,
-    c_11: Option<Vec<u8>>,
-    pub left: Option<Rc<RefCell<GarbledNode>>>,
-    pub right: Option<Rc<RefCell<GarbledNode>>>,
-    left_wire: Option<GarbledWire>,
-    right_wire: Option<GarbledWire>,
-    parent_wire: Option<GarbledWire>,
-    op: Option<u8>,
+pub struct GarbledGate<const N: usize = KEY_SIZE> {
+    kind: GateKind,
+    rows: Vec<Vec<u8>>,
+    /// The gate's children (a two-input gate's left child comes first).
+    /// A `FreeXor` gate only has the children its output depends on
+    pub inputs: Vec<GarbledNode>,
+    /// The gate's output wire
+    out_wire: GarbledWire<N>,
+    /// The gate's truth table (see `Node::GateN`)
+    table: Vec<bool>,
 }
 
-#[derive(Debug, Clone)]
-/// Possible nodes in a GarbledCircuit (analogous to `Node` in a regular Circuit)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Possible nodes in a GarbledCircuit (analogous to `Node` in a regular Circuit).
+/// Gates are referred to by their index in the circuit's gate arena
 pub enum GarbledNode {
     Input(usize),
-    Gate(Rc<RefCell<GarbledGate>>),
+    Gate(usize),
 }
 
-/// A garbled circuit from the garbler's POV
+/// A garbled circuit from the garbler's POV.
+/// The gates are stored in an arena in topological order (every gate comes after its children),
+/// and a gate's index in the arena is its id, from which the nonces of its rows are derived
 #[derive(Debug, Clone)]
-pub struct GarbledCircuit {
-    out: GarbledNode,
-    input_wires: HashMap<usize, GarbledWire>,
+pub struct GarbledCircuit<const N: usize = KEY_SIZE> {
+    gates: Vec<GarbledGate<N>>,
+    outs: Vec<GarbledNode>,
+    input_wires: HashMap<usize, GarbledWire<N>>,
     n: usize,
+    /// Whether the circuit was garbled with `from_privacy_free`
+    privacy_free: bool,
 }
 
-impl GarbledWire {
+impl<const N: usize> GarbledWire<N> {
     /// Generate a new wire with random on and off keys
-    fn new() -> GarbledWire {
-        let mut rng = ChaCha20Rng::from_entropy();
-        let mut on_key = [0u8; KEY_SIZE];
-        let mut off_key = [0u8; KEY_SIZE];
+    fn new() -> GarbledWire<N> {
+        GarbledWire::random(&mut ChaCha20Rng::from_entropy())
+    }
+
+    /// Generate a new wire with on and off keys taken from `rng`
+    fn random<R: Rng>(rng: &mut R) -> GarbledWire<N> {
+        let mut on_key = [0u8; N];
+        let mut off_key = [0u8; N];
 
-        rng.fill(&mut on_key);
-        rng.fill(&mut off_key);
+        rng.fill(&mut on_key[..]);
+        rng.fill(&mut off_key[..]);
 
         GarbledWire {
             on_key,
@@ -61,200 +95,1522 @@ impl GarbledWire {
         }
     }
 
-    /// We have to generate the out wire in a manner that allows the receiver
-    /// to detect whether the gate output true or false. To do this,
-    /// we set the on key to only 1s, and the off key to only 0s
-    fn out_wire() -> Self {
+    /// The key standing for the value `val`
+    fn key(&self, val: bool) -> [u8; N] {
+        if val { self.on_key } else { self.off_key }
+    }
+
+    /// A wire whose on key is its off key XORed with `delta` (for free XOR)
+    fn with_offset(off_key: [u8; N], delta: &[u8; N]) -> GarbledWire<N> {
         GarbledWire {
-            on_key: [1u8; KEY_SIZE],
-            off_key: [0u8; KEY_SIZE],
+            on_key: xor(&off_key, delta),
+            off_key,
         }
     }
 
-    pub fn off_key(&self) -> [u8; KEY_SIZE] {
+    pub fn off_key(&self) -> [u8; N] {
         self.off_key
     }
 
-    pub fn on_key(&self) -> [u8; KEY_SIZE] {
+    pub fn on_key(&self) -> [u8; N] {
         self.on_key
     }
 }
 
-impl Default for GarbledWire {
+impl<const N: usize> Default for GarbledWire<N> {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl GarbledGate {
-    /// Generate a new gate from the gate's parent, and the new gate's operation
-    fn new(parent_wire: Option<GarbledWire>, op: u8) -> Self {
+/// Derive the key that encrypts a row of a gate from the keys of the row's inputs (in order).
+/// The row key is always 32 bytes, whatever the size of the wire keys
+pub(crate) fn row_key<const N: usize>(keys: &[[u8; N]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+
+    for key in keys {
+        hasher.update(key);
+    }
+
+    hasher.finalize().into()
+}
+
+/// The value of each of the `k` inputs of a gate in the given row of its truth table
+pub(crate) fn row_values(row: usize, k: usize) -> impl Iterator<Item = bool> {
+    (0..k).map(move |i| (row >> (k - 1 - i)) & 1 == 1)
+}
+
+/// The truth table of a two-input gate's operation
+fn op_table(op: u8) -> Vec<bool> {
+    (0..4).map(|row| (op >> row) & 1 == 1).collect()
+}
+
+/// The tweak of the `j`-th hash (see `GarbleCipher`) within the gate with the given id. The id
+/// takes the high bits, so gates that share input keys still hash with different tweaks
+pub(crate) fn gate_tweak(gate_id: u64, j: usize) -> u64 {
+    (gate_id << 10) | j as u64
+}
+
+/// The pad that encrypts the output key in the row at position `pos` of a `Table` gate, and the tag
+/// that tells the receiver it decrypted the right row, given the row's key (see `row_key`)
+pub(crate) fn row_pad<H: GarbleCipher, const N: usize>(
+    row_key: &[u8; 32],
+    gate_id: u64,
+    pos: usize,
+) -> ([u8; N], [u8; TAG_SIZE]) {
+    let pad = H::hash(row_key, gate_tweak(gate_id, 2 * pos));
+    let tag = H::hash(row_key, gate_tweak(gate_id, 2 * pos + 1));
+
+    (pad[..N].try_into().unwrap(), tag[..TAG_SIZE].try_into().unwrap())
+}
+
+/// Hash the key of output `idx` for the decoding table, so that the table doesn't reveal the key
+pub(crate) fn output_hash(idx: usize, key: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update((idx as u64).to_be_bytes());
+    hasher.update(key);
+
+    hasher.finalize().into()
+}
+
+/// Commit to the key of input `idx`. Keys are random, so the hash hides them until they're opened.
+/// Domain-separated from `output_hash`
+pub(crate) fn key_commitment(idx: usize, key: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"input key");
+    hasher.update((idx as u64).to_be_bytes());
+    hasher.update(key);
+
+    hasher.finalize().into()
+}
+
+impl<const N: usize> GarbledGate<N> {
+    /// Generate a new gate from its children, its output wire, and its truth table
+    fn new(kind: GateKind, inputs: Vec<GarbledNode>, out_wire: GarbledWire<N>, table: Vec<bool>) -> Self {
         GarbledGate {
-            c_00: None,
-            c_01: None,
-            c_10: None,
-            c_11: None,
-            left: None,
-            right: None,
-            left_wire: None,
-            right_wire: None,
-            parent_wire,
-            op: Some(op),
+            kind,
+            rows: vec![],
+            inputs,
+            out_wire,
+            table,
         }
     }
 
-    /// Assign ciphertexts to this gate based on its encrypted inputs
-    fn assign_ciphertexts(&mut self) {
-        let op = self.op.unwrap();
-        // Get the bits of the operation
-        let vals = ((op & 1) != 0, (op & 2) != 0, (op & 4) != 0, (op & 8) != 0);
-        // Encrypt the output wire's keys
-        let out_on_key = self.parent_wire.as_ref().unwrap().on_key;
-        let out_off_key = self.parent_wire.as_ref().unwrap().off_key;
-        // Each bit in the operation determines whether we encrypt the output wire's on key or off key
-        let (out_00, out_01, out_10, out_11) = (
-            if vals.0 { out_on_key } else { out_off_key },
-            if vals.1 { out_on_key } else { out_off_key },
-            if vals.2 { out_on_key } else { out_off_key },
-            if vals.3 { out_on_key } else { out_off_key },
-        );
-        let left_off_cipher = AesCtr::new(&self.left_wire.as_ref().unwrap().off_key);
-        let left_on_cipher = AesCtr::new(&self.left_wire.as_ref().unwrap().on_key);
-        let right_off_cipher = AesCtr::new(&self.right_wire.as_ref().unwrap().off_key);
-        let right_on_cipher = AesCtr::new(&self.right_wire.as_ref().unwrap().on_key);
-        // We append zeros to the ciphertexts so that the receiver will be able
-        // to distinguish between valid decryptions and gibberish
-        // (since the decrypted keys are, by definition, random sequences of bytes, indistinguishable from gibberish)
-        let zeros = [0u8; KEY_SIZE];
-        self.c_00 = Some(left_off_cipher.encrypt(
-            &right_off_cipher.encrypt([out_00, zeros].as_flattened(), 0),
-            0,
-        ));
-        self.c_01 = Some(left_off_cipher.encrypt(
-            &right_on_cipher.encrypt([out_01, zeros].as_flattened(), 0),
-            0,
-        ));
-        self.c_10 = Some(left_on_cipher.encrypt(
-            &right_off_cipher.encrypt([out_10, zeros].as_flattened(), 0),
-            0,
-        ));
-        self.c_11 = Some(left_on_cipher.encrypt(
-            &right_on_cipher.encrypt([out_11, zeros].as_flattened(), 0),
-            0,
-        ));
+    /// Encrypt the rows of a `Table` gate (whose id is `id`) given the wires of its inputs.
+    /// The rows are returned in the given order (a permutation of the rows of the truth table)
+    fn ciphertexts<H: GarbleCipher>(&self, id: u64, input_wires: &[&GarbledWire<N>], order: &[usize]) -> Vec<Vec<u8>> {
+        // Each row encrypts the output wire's key under the keys of the row's inputs. Each entry in
+        // the truth table determines whether we encrypt the output wire's on key or off key
+        let mut rows: Vec<[u8; 32]> = vec![];
+        let mut out_keys: Vec<[u8; N]> = vec![];
+
+        for (row, bit) in self.table.iter().enumerate() {
+            let keys: Vec<[u8; N]> = row_values(row, input_wires.len())
+                .zip(input_wires)
+                .map(|(val, wire)| wire.key(val))
+                .collect();
+
+            rows.push(row_key(&keys));
+            out_keys.push(self.out_wire.key(*bit));
+        }
+
+        // Each row is the output key XORed with a pad, followed by a tag. Only the holder of the
+        // row's key can compute the tag, so the receiver doesn't need any redundancy in the key to
+        // tell its row from gibberish. Both are tweaked with the row's position
+        let mut ciphertexts: Vec<Vec<u8>> = order
+            .iter()
+            .enumerate()
+            .map(|(pos, &row)| {
+                let (pad, tag) = row_pad::<H, N>(&rows[row], id, pos);
+                let mut ciphertext = xor(&out_keys[row], &pad).to_vec();
+                ciphertext.extend(tag);
+
+                ciphertext
+            })
+            .collect();
+
+        // The first row's ciphertext is all zeros, so only its tag needs to be sent
+        ciphertexts[0].drain(..N);
+
+        ciphertexts
+    }
+
+    pub fn out_wire(&self) -> GarbledWire<N> {
+        self.out_wire.clone()
     }
 
-    pub fn c_00(&self) -> Vec<u8> {
-        self.c_00.as_ref().unwrap().clone()
+    pub fn kind(&self) -> GateKind {
+        self.kind
     }
 
-    pub fn c_01(&self) -> Vec<u8> {
-        self.c_01.as_ref().unwrap().clone()
+    /// The operation of a two-input gate, as a 4-bit integer (see `Node::Gate`)
+    pub(crate) fn op(&self) -> u8 {
+        self.table.iter().enumerate().map(|(row, bit)| (*bit as u8) << row).sum()
     }
 
-    pub fn c_10(&self) -> Vec<u8> {
-        self.c_10.as_ref().unwrap().clone()
+    /// The ciphertext of a gate garbled in privacy-free mode, if it has one
+    pub(crate) fn privacy_free_ciphertext(&self) -> Option<[u8; N]> {
+        self.rows.first().map(|c| c.as_slice().try_into().unwrap())
     }
 
-    pub fn c_11(&self) -> Vec<u8> {
-        self.c_11.as_ref().unwrap().clone()
+    /// The gate's rows (see `GateKind`)
+    pub fn rows(&self) -> &[Vec<u8>] {
+        &self.rows
+    }
+}
+
+/// The wire coming out of a node, given the input wires and the gates garbled so far
+fn wire<'a, const N: usize>(
+    input_wires: &'a HashMap<usize, GarbledWire<N>>,
+    gates: &'a [GarbledGate<N>],
+    node: GarbledNode,
+) -> &'a GarbledWire<N> {
+    match node {
+        GarbledNode::Input(idx) => &input_wires[&idx],
+        GarbledNode::Gate(idx) => &gates[idx].out_wire,
+    }
+}
+
+/// Pick a random row order for a `Table` gate (whose id is `id`), so the row the receiver decrypts
+/// doesn't reveal its inputs. Also returns the gate's output wire, whose key in the first row is
+/// that row's pad, so the row encrypts to all zeros
+fn shuffled_wire<H: GarbleCipher, const N: usize, R: Rng>(
+    rng: &mut R,
+    delta: &[u8; N],
+    id: u64,
+    table: &[bool],
+    input_wires: &[&GarbledWire<N>],
+) -> (Vec<usize>, GarbledWire<N>) {
+    let mut order: Vec<usize> = (0..table.len()).collect();
+    order.shuffle(rng);
+
+    let keys: Vec<[u8; N]> = row_values(order[0], input_wires.len())
+        .zip(input_wires)
+        .map(|(val, wire)| wire.key(val))
+        .collect();
+    let (reduced_key, _) = row_pad::<H, N>(&row_key(&keys), id, 0);
+    let off_key = if table[order[0]] { xor(&reduced_key, delta) } else { reduced_key };
+
+    (order, GarbledWire::with_offset(off_key, delta))
+}
+
+/// The point-and-permute bit of a key. The offset delta has this bit set, so a wire's two keys
+/// always have different colors
+pub(crate) fn color<const N: usize>(key: &[u8; N]) -> bool {
+    key[0] & 1 == 1
+}
+
+/// Multiply an offset by a bit
+fn scale<const N: usize>(bit: bool, delta: &[u8; N]) -> [u8; N] {
+    if bit { *delta } else { [0u8; N] }
+}
+
+/// Garble a two-input gate (whose id is `id`), returning its kind, the children it depends on, its
+/// rows and its output wire.
+///
+/// A gate that's linear in its inputs (see `anf`) is free: its off key is c_1 * A_0 ^ c_2 * B_0 ^
+/// c_0 * delta. Every other gate computes ((a ^ alpha) & (b ^ beta)) ^ gamma, and is garbled with
+/// half gates (Zahur, Rosulek and Evans) into the rows:
+/// T_G = H(X_0) ^ H(X_1) ^ p_b * delta
+/// T_E = H(Y_0) ^ H(Y_1) ^ X_0
+fn half_gate<H: GarbleCipher, const N: usize>(
+    id: u64,
+    delta: &[u8; N],
+    op: u8,
+    inputs: [GarbledNode; 2],
+    wires: [&GarbledWire<N>; 2],
+) -> (GateKind, Vec<GarbledNode>, Vec<Vec<u8>>, GarbledWire<N>) {
+    let [c_0, c_1, c_2, c_3] = anf(op);
+    let (a_0, b_0) = (wires[0].off_key, wires[1].off_key);
+
+    if !c_3 {
+        let inputs = inputs.into_iter().zip([c_1, c_2]).filter(|(_, c)| *c).map(|(input, _)| input).collect();
+        let off_key = xor(&xor(&scale(c_1, &a_0), &scale(c_2, &b_0)), &scale(c_0, delta));
+
+        return (GateKind::FreeXor, inputs, vec![], GarbledWire::with_offset(off_key, delta));
     }
+
+    // f(a, b) = ((a ^ c_2) & (b ^ c_1)) ^ (c_0 ^ (c_1 & c_2))
+    let (x_0, y_0) = (xor(&a_0, &scale(c_2, delta)), xor(&b_0, &scale(c_1, delta)));
+    let (p_x, p_y) = (color(&x_0), color(&y_0));
+    let hash = |j: usize, key: &[u8; N]| label_hash::<H, N>(gate_tweak(id, j), key);
+    let (h_x_0, h_x_1) = (hash(0, &x_0), hash(0, &xor(&x_0, delta)));
+    let (h_y_0, h_y_1) = (hash(1, &y_0), hash(1, &xor(&y_0, delta)));
+    let t_g = xor(&xor(&h_x_0, &h_x_1), &scale(p_y, delta));
+    let t_e = xor(&xor(&h_y_0, &h_y_1), &x_0);
+    // The keys the receiver computes for each half gate when x = 0
+    let w_g = xor(&h_x_0, &scale(p_x, &t_g));
+    let w_e = xor(&h_y_0, &scale(p_y, &xor(&t_e, &x_0)));
+    let off_key = xor(&xor(&w_g, &w_e), &scale(c_0 ^ (c_1 & c_2), delta));
+
+    (
+        GateKind::HalfGates,
+        inputs.to_vec(),
+        vec![t_g.to_vec(), t_e.to_vec()],
+        GarbledWire::with_offset(off_key, delta),
+    )
+}
+
+/// Generate the global offset delta and the input wires of a circuit with `n` inputs
+fn free_xor_wires<const N: usize, R: Rng>(rng: &mut R, n: usize) -> ([u8; N], HashMap<usize, GarbledWire<N>>) {
+    let mut delta = [0u8; N];
+    rng.fill(&mut delta[..]);
+    // The two keys of every wire must have different colors
+    delta[0] |= 1;
+
+    let input_wires = (0..n)
+        .map(|i| {
+            let mut off_key = [0u8; N];
+            rng.fill(&mut off_key[..]);
+
+            (i, GarbledWire::with_offset(off_key, &delta))
+        })
+        .collect();
+
+    (delta, input_wires)
+}
+
+/// Builds the gate arena of a circuit, taking all wire keys and row orders from an RNG, and
+/// encrypting rows with `H`
+struct Garbler<'a, const N: usize, R: Rng, H> {
+    input_wires: &'a HashMap<usize, GarbledWire<N>>,
+    rng: &'a mut R,
+    /// The global offset between the off and on keys of every wire
+    delta: [u8; N],
+    gates: Vec<GarbledGate<N>>,
+    /// The (random) order of the rows of each `Table` gate (and an empty order for other gates)
+    orders: Vec<Vec<usize>>,
+    /// The nodes garbled so far, by address
+    garbled: HashMap<*const Node, GarbledNode>,
+    /// Called with the number of completed gates each time a gate's rows are completed
+    progress: &'a mut dyn FnMut(usize),
+    /// The number of gates whose rows are complete
+    finished: usize,
+    cipher: PhantomData<H>,
 }
 
-impl GarbledNode {
-    /// Recursively garble a circuit
-    fn garble(
-        node: Node,
-        parent_wire: Option<GarbledWire>,
-        input_wires: &HashMap<usize, GarbledWire>,
-    ) -> Option<Rc<RefCell<GarbledNode>>> {
+impl<const N: usize, R: Rng, H: GarbleCipher> Garbler<'_, N, R, H> {
+    /// Recursively add a node and its children to the arena in postorder, so that every gate comes
+    /// after its children
+    fn garble(&mut self, node: &Node) -> GarbledNode {
         match node {
-            // If this node is an input node, just transform it to a `GarbledInput::Input`
+            // If this node is an input node, just transform it to a `GarbledNode::Input`
             // with the same input index
-            Node::Input(idx) => Some(Rc::new(RefCell::new(GarbledNode::Input(idx)))),
+            Node::Input(idx) => GarbledNode::Input(*idx),
             Node::Gate(op, left, right) => {
-                // Construct the gate we'll output
-                let out_node = Rc::new(RefCell::new(GarbledGate::new(parent_wire, op)));
-                // If our left child is an Input node, get the wire connecting us to the left child
-                // by looking up the input node's index in the input wires
-                // Otherwise, create a new wire
-                let left_wire = if let Node::Input(idx) = *left {
-                    input_wires.get(&idx).unwrap().clone()
-                } else {
-                    GarbledWire::new()
-                };
-                // Same goes for the right child
-                let right_wire = if let Node::Input(idx) = *right {
-                    input_wires.get(&idx).unwrap().clone()
-                } else {
-                    GarbledWire::new()
-                };
-                // Call recursively on our children; the left and right children's parent wires are
-                // left_wire and right_wire, respectively
-                let left_child = GarbledNode::garble(*left, Some(left_wire.clone()), input_wires);
-                let right_child = GarbledNode::garble(*right, Some(right_wire.clone()), input_wires);
-        
-                // Set our children to the left and right children we just created
-                if let Some(ref left_c) = left_child {
-                    out_node.borrow_mut().left = Some(left_c.clone());
-                    out_node.borrow_mut().left_wire = Some(left_wire);
-                }
-                if let Some(ref right_c) = right_child {
-                    out_node.borrow_mut().right = Some(right_c.clone());
-                    out_node.borrow_mut().right_wire = Some(right_wire);
-                }
-        
-                // Create the ciphertexts for this node
-                out_node.borrow_mut().assign_ciphertexts();
-        
-                Some(Rc::new(RefCell::new(GarbledNode::Gate(out_node))))
+                let inputs = [self.garble_shared(left), self.garble_shared(right)];
+                let wires = inputs.map(|input| wire(self.input_wires, &self.gates, input));
+                // A half gate's rows determine its output wire, so they're computed right away
+                let (kind, inputs, rows, out_wire) = half_gate::<H, N>(self.gates.len() as u64, &self.delta, *op, inputs, wires);
+                let mut gate = GarbledGate::new(kind, inputs, out_wire, op_table(*op));
+
+                gate.rows = rows;
+                self.gates.push(gate);
+                self.orders.push(vec![]);
+                self.finished += 1;
+                (self.progress)(self.finished);
+
+                GarbledNode::Gate(self.gates.len() - 1)
+            }
+            Node::GateN(table, inputs) => {
+                let inputs = inputs.iter().map(|input| self.garble_shared(input)).collect();
+
+                self.garble_table(inputs, table.clone())
+            }
+        }
+    }
+
+    /// Add a `Table` gate to the arena, given its (already garbled) children
+    fn garble_table(&mut self, inputs: Vec<GarbledNode>, table: Vec<bool>) -> GarbledNode {
+        let wires: Vec<&GarbledWire<N>> = inputs.iter().map(|input| wire(self.input_wires, &self.gates, *input)).collect();
+        let (order, out_wire) = shuffled_wire::<H, N, R>(self.rng, &self.delta, self.gates.len() as u64, &table, &wires);
+
+        // The ciphertexts for this gate are created once the whole circuit is built
+        self.gates.push(GarbledGate::new(GateKind::Table, inputs, out_wire, table));
+        self.orders.push(order);
+
+        GarbledNode::Gate(self.gates.len() - 1)
+    }
+
+    /// Garble a child node. A shared node is only garbled the first time we get to it
+    fn garble_shared(&mut self, node: &Rc<Node>) -> GarbledNode {
+        if let Some(garbled) = self.garbled.get(&Rc::as_ptr(node)) {
+            return *garbled;
+        }
+
+        let garbled = self.garble(node);
+        self.garbled.insert(Rc::as_ptr(node), garbled);
+
+        garbled
+    }
+
+    /// Assign the ciphertexts of every `Table` gate, concurrently if `parallel` is set (and the
+    /// `parallel` feature is enabled)
+    #[cfg_attr(not(feature = "parallel"), allow(unused_variables))]
+    fn assign_ciphertexts(&mut self, parallel: bool) {
+        let (input_wires, all_gates) = (self.input_wires, &self.gates);
+        let encrypt = |(id, (gate, order)): (usize, (&GarbledGate<N>, &Vec<usize>))| {
+            let wires: Vec<&GarbledWire<N>> = gate.inputs.iter().map(|input| wire(input_wires, all_gates, *input)).collect();
+
+            (gate.kind == GateKind::Table).then(|| gate.ciphertexts::<H>(id as u64, &wires, order))
+        };
+        let gates = self.gates.iter().zip(&self.orders).enumerate();
+
+        #[cfg(feature = "parallel")]
+        let ciphertexts: Vec<Option<Vec<Vec<u8>>>> = if parallel {
+            gates.collect::<Vec<_>>().into_par_iter().map(encrypt).collect()
+        } else {
+            gates.map(encrypt).collect()
+        };
+        #[cfg(not(feature = "parallel"))]
+        let ciphertexts: Vec<Option<Vec<Vec<u8>>>> = gates.map(encrypt).collect();
+
+        for (gate, ciphertexts) in self.gates.iter_mut().zip(ciphertexts) {
+            if let Some(ciphertexts) = ciphertexts {
+                gate.rows = ciphertexts;
+                self.finished += 1;
+                (self.progress)(self.finished);
             }
         }
     }
 }
 
-impl From<Circuit> for GarbledCircuit {
-    /// Garble a circuit
+/// Garbles a circuit like `Garbler` (in the same order, with the same randomness), but hands each
+/// gate to `emit` as soon as it's ready (see `StreamedCircuit`). A wire is dropped once all of its
+/// parents are garbled
+struct StreamingGarbler<'a, const N: usize, R: Rng, H, F> {
+    input_wires: &'a HashMap<usize, GarbledWire<N>>,
+    rng: &'a mut R,
+    /// The global offset between the off and on keys of every wire
+    delta: [u8; N],
+    emit: F,
+    /// The number of gates garbled so far, which is also the id of the next gate
+    num_gates: usize,
+    /// The number of rows of the gates garbled so far, not counting empty ones
+    num_rows: usize,
+    /// The total size of the rows of the gates garbled so far, in bytes
+    ciphertext_bytes: usize,
+    /// The shared nodes garbled so far that still have parents left to garble, by address,
+    /// along with their wire and how many parents are left
+    shared: HashMap<*const Node, (GarbledNode, GarbledWire<N>, usize)>,
+    cipher: PhantomData<H>,
+}
+
+impl<const N: usize, R: Rng, H: GarbleCipher, F: FnMut(&GarbledGate<N>) -> Result<(), io::Error>> StreamingGarbler<'_, N, R, H, F> {
+    /// Recursively garble a node and its children in postorder, returning the node and its wire
+    fn garble(&mut self, node: &Node) -> Result<(GarbledNode, GarbledWire<N>), io::Error> {
+        match node {
+            Node::Input(idx) => Ok((GarbledNode::Input(*idx), self.input_wires[idx].clone())),
+            Node::Gate(op, left, right) => {
+                let ((left, left_wire), (right, right_wire)) = (self.garble_shared(left)?, self.garble_shared(right)?);
+                let (kind, inputs, rows, out_wire) = half_gate::<H, N>(
+                    self.num_gates as u64,
+                    &self.delta,
+                    *op,
+                    [left, right],
+                    [&left_wire, &right_wire],
+                );
+                let mut gate = GarbledGate::new(kind, inputs, out_wire, op_table(*op));
+
+                gate.rows = rows;
+                self.emit(gate)
+            }
+            Node::GateN(table, inputs) => {
+                let inputs: Vec<_> = inputs.iter().map(|input| self.garble_shared(input)).collect::<Result<_, _>>()?;
+                let id = self.num_gates as u64;
+                let wires: Vec<&GarbledWire<N>> = inputs.iter().map(|(_, wire)| wire).collect();
+                let (order, out_wire) = shuffled_wire::<H, N, R>(self.rng, &self.delta, id, table, &wires);
+                let mut gate = GarbledGate::new(GateKind::Table, inputs.iter().map(|(input, _)| *input).collect(), out_wire, table.clone());
+
+                gate.rows = gate.ciphertexts::<H>(id, &wires, &order);
+                self.emit(gate)
+            }
+        }
+    }
+
+    /// Emit a garbled gate, returning it as a node along with its wire
+    fn emit(&mut self, gate: GarbledGate<N>) -> Result<(GarbledNode, GarbledWire<N>), io::Error> {
+        let id = self.num_gates;
+
+        (self.emit)(&gate)?;
+        self.num_gates += 1;
+        self.num_rows += gate.rows.iter().filter(|row| !row.is_empty()).count();
+        self.ciphertext_bytes += gate.rows.iter().map(|row| row.len()).sum::<usize>();
+
+        Ok((GarbledNode::Gate(id), gate.out_wire))
+    }
+
+    /// Garble a child node the first time we get to it, keeping its wire until its last parent gets
+    /// to it. Once the circuit is dropped, a node's reference count is its number of parents
+    fn garble_shared(&mut self, node: &Rc<Node>) -> Result<(GarbledNode, GarbledWire<N>), io::Error> {
+        let ptr = Rc::as_ptr(node);
+
+        if let Some((garbled, wire, parents_left)) = self.shared.get_mut(&ptr) {
+            let garbled = (*garbled, wire.clone());
+            *parents_left -= 1;
+
+            if *parents_left == 0 {
+                self.shared.remove(&ptr);
+            }
+
+            return Ok(garbled);
+        }
+
+        let garbled = self.garble(node)?;
+
+        if Rc::strong_count(node) > 1 {
+            self.shared.insert(ptr, (garbled.0, garbled.1.clone(), Rc::strong_count(node) - 1));
+        }
+
+        Ok(garbled)
+    }
+}
+
+/// A circuit garbled by `StreamedCircuit::garble`. Only the input wires (for sending keys and OT)
+/// and the output wires (for the decoding table) are kept
+pub struct StreamedCircuit<const N: usize = KEY_SIZE> {
+    input_wires: HashMap<usize, GarbledWire<N>>,
+    outs: Vec<GarbledNode>,
+    output_wires: Vec<GarbledWire<N>>,
+    n: usize,
+    num_gates: usize,
+    num_rows: usize,
+    ciphertext_bytes: usize,
+}
+
+/// The keys the garbler needs once the gates are sent (see `GarbledCircuit` and `StreamedCircuit`)
+pub trait GarbledKeys<const N: usize> {
+    fn input_keys(&self) -> HashMap<usize, GarbledWire<N>>;
+
+    fn input_wire(&self, idx: usize) -> Option<&GarbledWire<N>>;
+
+    fn n(&self) -> usize;
+
+    fn decoding_table(&self) -> Vec<([u8; 32], [u8; 32])>;
+
+    fn output_wires(&self) -> Vec<GarbledWire<N>>;
+}
+
+/// The decoding table entries of the given output wires (see `output_hash`)
+fn decoding_entries<'a, const N: usize>(
+    output_wires: impl Iterator<Item = &'a GarbledWire<N>>,
+) -> Vec<([u8; 32], [u8; 32])> {
+    output_wires
+        .enumerate()
+        .map(|(idx, out_wire)| (output_hash(idx, &out_wire.off_key), output_hash(idx, &out_wire.on_key)))
+        .collect()
+}
+
+/// Garbles a circuit in privacy-free mode (Frederiksen, Nielsen and Orlandi), for a receiver that
+/// knows the values on every wire. Wires use free XOR, so linear gates need no ciphertexts. Every
+/// other gate computes ((a ^ alpha) & (b ^ beta)) ^ gamma, and needs a single ciphertext:
+/// T = H(A_0) ^ H(A_1) ^ B_0
+/// where A_x and B_x are the keys of a ^ alpha and b ^ beta, and H is `label_hash`.
+/// Its off key is H(A_0)
+struct PrivacyFreeGarbler<'a, const N: usize, H> {
+    input_wires: &'a HashMap<usize, GarbledWire<N>>,
+    /// The global offset between the off and on keys of every wire
+    delta: [u8; N],
+    gates: Vec<GarbledGate<N>>,
+    /// The nodes garbled so far, by address
+    garbled: HashMap<*const Node, GarbledNode>,
+    cipher: PhantomData<H>,
+}
+
+/// The coefficients of an operation's algebraic normal form:
+/// f(a, b) = c_0 ^ (c_1 & a) ^ (c_2 & b) ^ (c_3 & a & b)
+pub(crate) fn anf(op: u8) -> [bool; 4] {
+    let f = |left_val: u8, right_val: u8| (op >> (2 * left_val + right_val)) & 1 == 1;
+
+    [
+        f(0, 0),
+        f(0, 0) ^ f(1, 0),
+        f(0, 0) ^ f(0, 1),
+        f(0, 0) ^ f(0, 1) ^ f(1, 0) ^ f(1, 1),
+    ]
+}
+
+/// The hash used by privacy-free gates and half gates (see `gate_tweak`). Keys shorter than 32
+/// bytes are zero-padded, and the hash is truncated to the size of the key
+pub(crate) fn label_hash<H: GarbleCipher, const N: usize>(tweak: u64, key: &[u8; N]) -> [u8; N] {
+    let mut padded = [0u8; 32];
+    padded[..N].copy_from_slice(key);
+
+    H::hash(&padded, tweak)[..N].try_into().unwrap()
+}
+
+pub(crate) fn xor<const N: usize>(x: &[u8; N], y: &[u8; N]) -> [u8; N] {
+    let mut out = [0u8; N];
+
+    for (out_byte, (x_byte, y_byte)) in out.iter_mut().zip(x.iter().zip(y)) {
+        *out_byte = x_byte ^ y_byte;
+    }
+
+    out
+}
+
+impl<const N: usize, H: GarbleCipher> PrivacyFreeGarbler<'_, N, H> {
+    /// Recursively add a node and its children to the arena in postorder. Unlike `Garbler`, each
+    /// gate's ciphertext is computed right away, since it determines the gate's output wire.
+    /// Returns an error if the node has a `Node::GateN`
+    fn garble(&mut self, node: &Node) -> Result<GarbledNode, io::Error> {
+        match node {
+            Node::Input(idx) => Ok(GarbledNode::Input(*idx)),
+            Node::Gate(op, left, right) => {
+                let (left, right) = (self.garble_shared(left)?, self.garble_shared(right)?);
+                let out_wire = self.out_wire(left, right, *op);
+                // Privacy-free gates are evaluated by `PrivacyFreeCircuitRecv`, which doesn't look
+                // at their kind
+                let mut gate = GarbledGate::new(GateKind::Table, vec![left, right], out_wire, op_table(*op));
+
+                gate.rows = self.ciphertext(left, right, *op).into_iter().map(|t| t.to_vec()).collect();
+                self.gates.push(gate);
+
+                Ok(GarbledNode::Gate(self.gates.len() - 1))
+            }
+            Node::GateN(_, inputs) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("privacy-free garbling only supports two-input gates, but the circuit has a gate with {} inputs", inputs.len()),
+            )),
+        }
+    }
+
+    /// Multiply the offset by a bit
+    fn offset(&self, bit: bool) -> [u8; N] {
+        if bit { self.delta } else { [0u8; N] }
+    }
+
+    /// The keys of a ^ alpha and b ^ beta (see above) for the next gate in the arena, with the
+    /// given children and operation
+    fn and_inputs(&self, left: GarbledNode, right: GarbledNode, op: u8) -> ([u8; N], [u8; N]) {
+        let [_, c_1, c_2, _] = anf(op);
+        let left_off = wire(self.input_wires, &self.gates, left).off_key;
+        let right_off = wire(self.input_wires, &self.gates, right).off_key;
+
+        // f(a, b) = ((a ^ c_2) & (b ^ c_1)) ^ (c_0 ^ (c_1 & c_2))
+        (xor(&left_off, &self.offset(c_2)), xor(&right_off, &self.offset(c_1)))
+    }
+
+    /// The output wire of the next gate in the arena
+    fn out_wire(&self, left: GarbledNode, right: GarbledNode, op: u8) -> GarbledWire<N> {
+        let [c_0, c_1, c_2, c_3] = anf(op);
+        let id = self.gates.len() as u64;
+
+        let off_key = if c_3 {
+            let (a_0, _) = self.and_inputs(left, right, op);
+
+            xor(&label_hash::<H, N>(gate_tweak(id, 0), &a_0), &self.offset(c_0 ^ (c_1 & c_2)))
+        } else {
+            // A linear gate: the output key is c_1 * A ^ c_2 * B (and the constant c_0 shifts the
+            // off key by delta)
+            let scale = |bit: bool, node: GarbledNode| {
+                if bit { wire(self.input_wires, &self.gates, node).off_key } else { [0u8; N] }
+            };
+
+            xor(&xor(&scale(c_1, left), &scale(c_2, right)), &self.offset(c_0))
+        };
+
+        GarbledWire::with_offset(off_key, &self.delta)
+    }
+
+    /// The ciphertext T of the next gate in the arena, or `None` if it's linear
+    fn ciphertext(&self, left: GarbledNode, right: GarbledNode, op: u8) -> Option<[u8; N]> {
+        let id = self.gates.len() as u64;
+        let (a_0, b_0) = self.and_inputs(left, right, op);
+
+        let hash = |key: &[u8; N]| label_hash::<H, N>(gate_tweak(id, 0), key);
+
+        anf(op)[3].then(|| xor(&xor(&hash(&a_0), &hash(&xor(&a_0, &self.delta))), &b_0))
+    }
+
+    /// Garble a child node. A shared node is only garbled the first time we get to it
+    fn garble_shared(&mut self, node: &Rc<Node>) -> Result<GarbledNode, io::Error> {
+        if let Some(garbled) = self.garbled.get(&Rc::as_ptr(node)) {
+            return Ok(*garbled);
+        }
+
+        let garbled = self.garble(node)?;
+        self.garbled.insert(Rc::as_ptr(node), garbled);
+
+        Ok(garbled)
+    }
+}
+
+impl<const N: usize> From<Circuit> for GarbledCircuit<N> {
+    /// Garble a circuit with `FixedKeyAes` (see `from_with_cipher` for other ciphers)
     fn from(value: Circuit) -> Self {
+        GarbledCircuit::from_with_cipher::<FixedKeyAes>(value)
+    }
+}
+
+impl<const N: usize> GarbledCircuit<N> {
+    /// Garble a circuit deterministically from `seed`, for debugging and tests (actual use should
+    /// go through `From<Circuit>`)
+    pub fn from_seeded(circuit: Circuit, seed: [u8; 32]) -> GarbledCircuit<N> {
+        GarbledCircuit::garble_with_rng::<FixedKeyAes, _>(circuit, &mut ChaCha20Rng::from_seed(seed), cfg!(feature = "parallel"))
+    }
+
+    /// Garble a circuit like `From<Circuit>`, calling `progress` with the number of gates garbled
+    /// so far and the total. `Node::GateN` gates are only encrypted after the traversal, so they're
+    /// reported last
+    pub fn from_with_progress(circuit: Circuit, mut progress: impl FnMut(usize, usize)) -> GarbledCircuit<N> {
+        let total = circuit.num_gates();
+
+        GarbledCircuit::garble_with_progress::<FixedKeyAes, _>(
+            circuit,
+            &mut ChaCha20Rng::from_entropy(),
+            cfg!(feature = "parallel"),
+            &mut |done| progress(done, total),
+        )
+    }
+
+    /// Garble a circuit with `H`, taking all of its randomness from `rng` (whatever `parallel` is)
+    pub(crate) fn garble_with_rng<H: GarbleCipher, R: Rng>(value: Circuit, rng: &mut R, parallel: bool) -> GarbledCircuit<N> {
+        GarbledCircuit::garble_with_progress::<H, _>(value, rng, parallel, &mut |_| {})
+    }
+
+    /// Garble a circuit like `From<Circuit>`, encrypting its rows with `H` (e.g. `AesCtr`). The
+    /// receiver has to evaluate it with the same cipher (see `GarbledCircuitRecv::try_eval_with`)
+    pub fn from_with_cipher<H: GarbleCipher>(circuit: Circuit) -> GarbledCircuit<N> {
+        GarbledCircuit::garble_with_rng::<H, _>(circuit, &mut ChaCha20Rng::from_entropy(), cfg!(feature = "parallel"))
+    }
+
+    /// Same as `garble_with_rng`, encrypting rows with `H` and calling `progress` with the number
+    /// of gates garbled so far (see `from_with_progress`)
+    fn garble_with_progress<H: GarbleCipher, R: Rng>(
+        value: Circuit,
+        rng: &mut R,
+        parallel: bool,
+        progress: &mut dyn FnMut(usize),
+    ) -> GarbledCircuit<N> {
         // Generate the input wire keys
         let n = value.n();
+        let (delta, input_wires) = free_xor_wires(rng, n);
+
+        // Garble the output nodes (this garbles the entire circuit)
+        let mut garbler = Garbler {
+            input_wires: &input_wires,
+            rng,
+            delta,
+            gates: vec![],
+            orders: vec![],
+            garbled: HashMap::new(),
+            progress,
+            finished: 0,
+            cipher: PhantomData::<H>,
+        };
+        let outs = value
+            .outs()
+            .into_iter()
+            .map(|out| garbler.garble(&out))
+            .collect();
+
+        garbler.assign_ciphertexts(parallel);
+        let gates = garbler.gates;
+        log::debug!("garbled a circuit of {} gates with {} inputs", gates.len(), n);
+
+        GarbledCircuit {
+            gates,
+            outs,
+            input_wires,
+            n,
+            privacy_free: false,
+        }
+    }
+
+    /// Garble a circuit without hiding its wire values (see `PrivacyFreeGarbler`), to be evaluated
+    /// by `PrivacyFreeCircuitRecv`. `N` must be at most 32. Returns an error on a `Node::GateN`
+    pub fn from_privacy_free(circuit: Circuit) -> Result<GarbledCircuit<N>, io::Error> {
+        GarbledCircuit::from_privacy_free_with_cipher::<FixedKeyAes>(circuit)
+    }
+
+    /// Same as `from_privacy_free`, hashing keys with `H` instead of `FixedKeyAes`. The receiver
+    /// has to evaluate it with the same cipher (see `PrivacyFreeCircuitRecv::eval_with`)
+    pub fn from_privacy_free_with_cipher<H: GarbleCipher>(circuit: Circuit) -> Result<GarbledCircuit<N>, io::Error> {
+        let mut rng = ChaCha20Rng::from_entropy();
+        let mut delta = [0u8; N];
+        rng.fill(&mut delta[..]);
+
+        // With free XOR, the on key of every wire is its off key XORed with the global offset delta
+        let n = circuit.n();
         let mut input_wires = HashMap::new();
 
         for i in 0..n {
-            input_wires.insert(i, GarbledWire::new());
+            let mut off_key = [0u8; N];
+            rng.fill(&mut off_key[..]);
+
+            input_wires.insert(i, GarbledWire::with_offset(off_key, &delta));
+        }
+
+        let mut garbler = PrivacyFreeGarbler {
+            input_wires: &input_wires,
+            delta,
+            gates: vec![],
+            garbled: HashMap::new(),
+            cipher: PhantomData::<H>,
+        };
+        let outs = circuit.outs().into_iter().map(|out| garbler.garble(&out)).collect::<Result<_, _>>()?;
+        let gates = garbler.gates;
+
+        Ok(GarbledCircuit {
+            gates,
+            outs,
+            input_wires,
+            n,
+            privacy_free: true,
+        })
+    }
+
+    /// Decode the output keys of a privacy-free circuit, which verifies the result since the
+    /// receiver can't forge keys. Returns `None` if any of the keys isn't a key of its output wire
+    pub fn decode_outputs(&self, keys: &[[u8; N]]) -> Option<Vec<bool>> {
+        if keys.len() != self.outs.len() {
+            return None;
         }
 
-        // Garble the output node (this garbled the entire circuit)
-        let garbled_out =
-            GarbledNode::garble(value.out(), Some(GarbledWire::out_wire()), &input_wires);
-        let garbled_out = garbled_out.as_ref().unwrap().borrow();
+        self.outs
+            .iter()
+            .zip(keys)
+            .map(|(out, key)| {
+                let out_wire = wire(&self.input_wires, &self.gates, *out);
+
+                match key {
+                    key if *key == out_wire.on_key => Some(true),
+                    key if *key == out_wire.off_key => Some(false),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    pub fn is_privacy_free(&self) -> bool {
+        self.privacy_free
+    }
+
+    /// The decoding table: the hashes of each output wire's off and on keys (see `output_hash`)
+    pub fn decoding_table(&self) -> Vec<([u8; 32], [u8; 32])> {
+        decoding_entries(self.outs.iter().map(|out| wire(&self.input_wires, &self.gates, *out)))
+    }
+
+    pub fn input_keys(&self) -> HashMap<usize, GarbledWire<N>> {
+        self.input_wires.clone()
+    }
+
+    /// The wire of input `idx`, without cloning the rest of the input wires (like `input_keys`
+    /// does). Returns `None` if the circuit has no input `idx`
+    pub fn input_wire(&self, idx: usize) -> Option<&GarbledWire<N>> {
+        self.input_wires.get(&idx)
+    }
+
+    /// The wires coming out of the circuit's outputs (in order)
+    pub fn output_wires(&self) -> Vec<GarbledWire<N>> {
+        self.outs.iter().map(|out| wire(&self.input_wires, &self.gates, *out).clone()).collect()
+    }
+
+    /// The circuit's gates, in topological order
+    pub fn gates(&self) -> &[GarbledGate<N>] {
+        &self.gates
+    }
+
+    pub fn outs(&self) -> Vec<GarbledNode> {
+        self.outs.clone()
+    }
 
-        GarbledCircuit::new(garbled_out.clone(), input_wires, n)
+    pub fn n(&self) -> usize {
+        self.n
     }
 }
 
-impl GarbledCircuit {
-    pub fn new(
-        out: GarbledNode,
-        input_wires: HashMap<usize, GarbledWire>,
-        n: usize,
-    ) -> GarbledCircuit {
-        GarbledCircuit {
-            out,
+impl<const N: usize> GarbledKeys<N> for GarbledCircuit<N> {
+    fn input_keys(&self) -> HashMap<usize, GarbledWire<N>> {
+        GarbledCircuit::input_keys(self)
+    }
+
+    fn input_wire(&self, idx: usize) -> Option<&GarbledWire<N>> {
+        GarbledCircuit::input_wire(self, idx)
+    }
+
+    fn n(&self) -> usize {
+        GarbledCircuit::n(self)
+    }
+
+    fn decoding_table(&self) -> Vec<([u8; 32], [u8; 32])> {
+        GarbledCircuit::decoding_table(self)
+    }
+
+    fn output_wires(&self) -> Vec<GarbledWire<N>> {
+        GarbledCircuit::output_wires(self)
+    }
+}
+
+impl<const N: usize> StreamedCircuit<N> {
+    /// Garble a circuit gate by gate, calling `emit` with each gate (in topological order) without
+    /// keeping it. Gate i is the i-th gate emitted, like in `GarbledCircuit::outs`. Stops at the
+    /// first error `emit` returns
+    pub fn garble(
+        circuit: Circuit,
+        emit: impl FnMut(&GarbledGate<N>) -> Result<(), io::Error>,
+    ) -> Result<StreamedCircuit<N>, io::Error> {
+        StreamedCircuit::garble_with_cipher::<FixedKeyAes>(circuit, emit)
+    }
+
+    /// Same as `garble`, encrypting rows with `H` (see `GarbledCircuit::from_with_cipher`)
+    pub fn garble_with_cipher<H: GarbleCipher>(
+        circuit: Circuit,
+        emit: impl FnMut(&GarbledGate<N>) -> Result<(), io::Error>,
+    ) -> Result<StreamedCircuit<N>, io::Error> {
+        StreamedCircuit::garble_with_rng::<H, _>(circuit, &mut ChaCha20Rng::from_entropy(), emit)
+    }
+
+    /// Same as `garble_with_cipher`, taking all of the randomness from `rng`. Given the same RNG
+    /// state, the emitted gates are exactly the gates of `GarbledCircuit::garble_with_rng`
+    pub(crate) fn garble_with_rng<H: GarbleCipher, R: Rng>(
+        circuit: Circuit,
+        rng: &mut R,
+        emit: impl FnMut(&GarbledGate<N>) -> Result<(), io::Error>,
+    ) -> Result<StreamedCircuit<N>, io::Error> {
+        let (n, outs) = (circuit.n(), circuit.outs());
+        let (delta, input_wires) = free_xor_wires(rng, n);
+
+        // The circuit's own references to its nodes would throw off the parent counts (see
+        // `StreamingGarbler::garble_shared`)
+        drop(circuit);
+
+        let mut garbler = StreamingGarbler {
+            input_wires: &input_wires,
+            rng,
+            delta,
+            emit,
+            num_gates: 0,
+            num_rows: 0,
+            ciphertext_bytes: 0,
+            shared: HashMap::new(),
+            cipher: PhantomData::<H>,
+        };
+        let (outs, output_wires) = outs
+            .iter()
+            .map(|out| garbler.garble(out))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .unzip();
+        let (num_gates, num_rows, ciphertext_bytes) = (garbler.num_gates, garbler.num_rows, garbler.ciphertext_bytes);
+        log::debug!("garbled a circuit of {} gates ({} rows) with {} inputs", num_gates, num_rows, n);
+
+        Ok(StreamedCircuit {
             input_wires,
+            outs,
+            output_wires,
             n,
-        }
+            num_gates,
+            num_rows,
+            ciphertext_bytes,
+        })
     }
 
-    pub fn input_keys(&self) -> HashMap<usize, GarbledWire> {
+    /// The output decoding table (see `GarbledCircuit::decoding_table`)
+    pub fn decoding_table(&self) -> Vec<([u8; 32], [u8; 32])> {
+        decoding_entries(self.output_wires.iter())
+    }
+
+    pub fn input_keys(&self) -> HashMap<usize, GarbledWire<N>> {
         self.input_wires.clone()
     }
 
-    pub fn out(&self) -> GarbledNode {
-        self.out.clone()
+    /// The wire of input `idx`, without cloning the rest of the input wires (like `input_keys`
+    /// does). Returns `None` if the circuit has no input `idx`
+    pub fn input_wire(&self, idx: usize) -> Option<&GarbledWire<N>> {
+        self.input_wires.get(&idx)
+    }
+
+    pub fn outs(&self) -> Vec<GarbledNode> {
+        self.outs.clone()
+    }
+
+    /// The wires coming out of the circuit's outputs (in order)
+    pub fn output_wires(&self) -> Vec<GarbledWire<N>> {
+        self.output_wires.clone()
     }
 
     pub fn n(&self) -> usize {
         self.n
     }
+
+    /// The number of gates that were emitted
+    pub fn num_gates(&self) -> usize {
+        self.num_gates
+    }
+
+    /// The number of (non-empty) rows of the gates that were emitted
+    pub fn num_rows(&self) -> usize {
+        self.num_rows
+    }
+
+    /// The total size of the rows of the gates that were emitted, in bytes
+    pub fn ciphertext_bytes(&self) -> usize {
+        self.ciphertext_bytes
+    }
+}
+
+impl<const N: usize> GarbledKeys<N> for StreamedCircuit<N> {
+    fn input_keys(&self) -> HashMap<usize, GarbledWire<N>> {
+        StreamedCircuit::input_keys(self)
+    }
+
+    fn input_wire(&self, idx: usize) -> Option<&GarbledWire<N>> {
+        StreamedCircuit::input_wire(self, idx)
+    }
+
+    fn n(&self) -> usize {
+        StreamedCircuit::n(self)
+    }
+
+    fn decoding_table(&self) -> Vec<([u8; 32], [u8; 32])> {
+        StreamedCircuit::decoding_table(self)
+    }
+
+    fn output_wires(&self) -> Vec<GarbledWire<N>> {
+        StreamedCircuit::output_wires(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use num_bigint::BigUint;
+
+    use crate::{
+        backend::receiver_backend::GarbledCircuitRecv,
+        circuit::{Circuit, Node},
+        crypto::{aes_ctr::AesCtr, fixed_key::FixedKeyAes},
+        gates::{AND, OR, XOR},
+    };
+
+    use super::GarbledCircuit;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let bytes = BigUint::parse_bytes(s.as_bytes(), 16).unwrap().to_bytes_be();
+        // Restore the leading zeros dropped by BigUint
+        let mut padded = vec![0u8; s.len() / 2 - bytes.len()];
+        padded.extend(bytes);
+
+        padded
+    }
+
+    /// Garble (x0 ^ x1) & x2 with `N`-byte keys and evaluate it on every input
+    fn garbled_eval_test<const N: usize>() {
+        let circuit = Circuit::new(Node::Gate(
+            AND,
+            Rc::new(Node::Gate(XOR, Rc::new(Node::Input(0)), Rc::new(Node::Input(1)))),
+            Rc::new(Node::Input(2)),
+        ))
+        .unwrap();
+        let garbled: GarbledCircuit<N> = circuit.into();
+        let input_keys = garbled.input_keys();
+        let recv_circuit: GarbledCircuitRecv = garbled.into();
+
+        assert_eq!(recv_circuit.key_size(), N);
+
+        for x in 0..8 {
+            let bits: Vec<bool> = (0..3).map(|i| x & (1 << i) != 0).collect();
+            let keys: Vec<[u8; N]> = bits
+                .iter()
+                .enumerate()
+                .map(|(i, bit)| {
+                    let wire = input_keys.get(&i).unwrap();
+
+                    if *bit { wire.on_key() } else { wire.off_key() }
+                })
+                .collect();
+            let expected = (bits[0] ^ bits[1]) && bits[2];
+
+            assert_eq!(recv_circuit.eval_bool(&keys).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn input_wire_test() {
+        use crate::backend::garbler_backend::comparison_circuit;
+
+        use super::StreamedCircuit;
+
+        let circuit: GarbledCircuit = comparison_circuit(2).into();
+        let streamed: StreamedCircuit = StreamedCircuit::garble(comparison_circuit(2), |_| Ok(())).unwrap();
+
+        for i in 0..4 {
+            assert_eq!(circuit.input_wire(i).unwrap().off_key(), circuit.input_keys()[&i].off_key());
+            assert_eq!(streamed.input_wire(i).unwrap().on_key(), streamed.input_keys()[&i].on_key());
+        }
+
+        assert!(circuit.input_wire(4).is_none());
+        assert!(streamed.input_wire(4).is_none());
+    }
+
+    #[test]
+    fn fresh_keys_per_garbling_test() {
+        let circuit = || Circuit::new(Node::Gate(AND, Rc::new(Node::Input(0)), Rc::new(Node::Input(1)))).unwrap();
+        let first: GarbledCircuit = circuit().into();
+        let second: GarbledCircuit = circuit().into();
+
+        // Garbling the same circuit twice yields independent wire keys
+        for i in 0..2 {
+            let (first_wire, second_wire) = (&first.input_keys()[&i], &second.input_keys()[&i]);
+
+            assert_ne!(first_wire.on_key(), second_wire.on_key());
+            assert_ne!(first_wire.off_key(), second_wire.off_key());
+        }
+    }
+
+    #[test]
+    fn wire_zeroized_on_drop_test() {
+        use std::{mem::ManuallyDrop, ptr};
+
+        use super::GarbledWire;
+
+        let mut wire = ManuallyDrop::new(GarbledWire::<32>::new());
+        let clone = (*wire).clone();
+        let keys = (wire.on_key(), wire.off_key());
+        assert_ne!(keys, ([0u8; 32], [0u8; 32]));
+
+        // Drop the wire in place, so that its memory is still ours to read afterwards
+        unsafe { ManuallyDrop::drop(&mut wire) };
+        let (on_key, off_key) = unsafe { (ptr::read(ptr::addr_of!(wire.on_key)), ptr::read(ptr::addr_of!(wire.off_key))) };
+
+        assert_eq!((on_key, off_key), ([0u8; 32], [0u8; 32]));
+        // The clone's keys are its own, so they weren't zeroed along with the wire's
+        assert_eq!((clone.on_key(), clone.off_key()), keys);
+    }
+
+    #[test]
+    fn seeded_garbling_test() {
+        use protobuf::Message;
+
+        use crate::backend::garbler_backend::{comparison_circuit, garbled_circuit_msg};
+
+        let serialize = |seed: [u8; 32]| {
+            let garbled: GarbledCircuit = GarbledCircuit::from_seeded(comparison_circuit(4), seed);
+            let keys: Vec<_> = (0..8).map(|i| garbled.input_keys()[&i].off_key()).collect();
+
+            (garbled_circuit_msg(garbled).write_to_bytes().unwrap(), keys)
+        };
+
+        // The same seed yields the same circuit, and a different seed yields a different one
+        assert_eq!(serialize([1u8; 32]), serialize([1u8; 32]));
+        assert_ne!(serialize([1u8; 32]), serialize([2u8; 32]));
+    }
+
+    #[test]
+    fn and_gate_vectors_test() {
+        use sha2::{Digest, Sha256};
+
+        let circuit = Circuit::new(Node::Gate(AND, Rc::new(Node::Input(0)), Rc::new(Node::Input(1)))).unwrap();
+        let garbled: GarbledCircuit = GarbledCircuit::from_seeded(circuit, [3u8; 32]);
+        let (input_keys, out_wire) = (garbled.input_keys(), garbled.gates()[0].out_wire());
+        // Pins the gate's ciphertexts, so that a change to the garbling scheme (or to how it uses
+        // the RNG) doesn't go unnoticed
+        let rows_hash = Sha256::digest(garbled.gates()[0].rows().concat()).to_vec();
+
+        assert_eq!(rows_hash, hex("57dd4ee9cba7eb851d8a8fb18106753c46be6c013b0544553a4998d67b64b918"));
+
+        let recv_circuit: GarbledCircuitRecv = garbled.into();
+
+        // Every combination of input keys decrypts to the key of the gate's output value
+        for (left, right) in [(false, false), (false, true), (true, false), (true, true)] {
+            let key = |i: usize, bit: bool| if bit { input_keys[&i].on_key() } else { input_keys[&i].off_key() };
+            let expected = if left && right { out_wire.on_key() } else { out_wire.off_key() };
+
+            assert_eq!(recv_circuit.eval(&[key(0, left), key(1, right)]), vec![expected]);
+        }
+    }
+
+    #[test]
+    fn parallel_garbling_matches_sequential_test() {
+        use protobuf::Message;
+        use rand::SeedableRng;
+        use rand_chacha::ChaCha20Rng;
+
+        use crate::backend::garbler_backend::{comparison_circuit, garbled_circuit_msg};
+
+        // Garbling from the same seed yields the same circuit, whether the gates are encrypted
+        // concurrently or not
+        let garble = |parallel: bool| {
+            let mut rng = ChaCha20Rng::from_seed([7u8; 32]);
+            let garbled: GarbledCircuit = GarbledCircuit::garble_with_rng::<FixedKeyAes, _>(comparison_circuit(8), &mut rng, parallel);
+            let keys: Vec<_> = (0..16).map(|i| garbled.input_keys()[&i].on_key()).collect();
+
+            (garbled_circuit_msg(garbled).write_to_bytes().unwrap(), keys)
+        };
+
+        assert_eq!(garble(true), garble(false));
+    }
+
+    #[test]
+    fn streamed_garbling_test() {
+        use rand::SeedableRng;
+        use rand_chacha::ChaCha20Rng;
+
+        use crate::backend::garbler_backend::comparison_circuit;
+
+        use super::StreamedCircuit;
+
+        // Streaming from the same seed emits exactly the gates of the arena, shared nodes included
+        let circuit = || comparison_circuit(8).dedup_subexpressions();
+        let rng = || ChaCha20Rng::from_seed([7u8; 32]);
+        let garbled: GarbledCircuit = GarbledCircuit::garble_with_rng::<FixedKeyAes, _>(circuit(), &mut rng(), false);
+        let mut emitted = vec![];
+        let streamed: StreamedCircuit = StreamedCircuit::garble_with_rng::<FixedKeyAes, _>(circuit(), &mut rng(), |gate| {
+            emitted.push((gate.rows().to_vec(), gate.inputs.clone()));
+
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(emitted.len(), circuit().num_gates());
+        assert_eq!(streamed.num_gates(), emitted.len());
+        assert_eq!(streamed.ciphertext_bytes(), emitted.iter().flat_map(|(rows, _)| rows).map(|row| row.len()).sum::<usize>());
+        assert_eq!(streamed.num_rows(), emitted.iter().flat_map(|(rows, _)| rows).filter(|row| !row.is_empty()).count());
+        for (gate, (rows, inputs)) in garbled.gates().iter().zip(&emitted) {
+            assert_eq!(gate.rows(), rows.as_slice());
+            assert_eq!(&gate.inputs, inputs);
+        }
+        assert_eq!(streamed.outs(), garbled.outs());
+        assert_eq!(streamed.decoding_table(), garbled.decoding_table());
+        // The receiver counts the same rows
+        let recv_circuit: GarbledCircuitRecv = garbled.into();
+        assert_eq!(recv_circuit.num_rows(), streamed.num_rows());
+
+        // An error from `emit` stops the garbling
+        let mut calls = 0;
+        let result: Result<StreamedCircuit, _> = StreamedCircuit::garble(circuit(), |_| {
+            calls += 1;
+
+            Err(std::io::Error::other("the receiver went away"))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+
+        // Streaming with another cipher emits the gates of the arena garbled with that cipher
+        let garbled: GarbledCircuit = GarbledCircuit::garble_with_rng::<AesCtr, _>(circuit(), &mut rng(), false);
+        let mut rows = vec![];
+        StreamedCircuit::<32>::garble_with_rng::<AesCtr, _>(circuit(), &mut rng(), |gate| {
+            rows.push(gate.rows().to_vec());
+
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(rows, garbled.gates().iter().map(|gate| gate.rows().to_vec()).collect::<Vec<_>>());
+        assert_ne!(rows, emitted.into_iter().map(|(rows, _)| rows).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn dedup_subexpressions_test() {
+        use crate::backend::garbler_backend::{comparison_circuit, construct_circuit};
+
+        let tree: GarbledCircuit = comparison_circuit(8).into();
+        let dag: GarbledCircuit = construct_circuit(8).into();
+
+        // The XNOR of each pair of bits is only garbled once, not once per gate that uses it
+        assert_eq!(tree.gates().len(), 86);
+        assert_eq!(dag.gates().len(), 58);
+        // The circuit's metrics agree with the number of garbled gates
+        assert_eq!(comparison_circuit(8).num_gates(), 86);
+        assert_eq!(comparison_circuit(8).dedup_subexpressions().num_gates(), 58);
+
+        let input_keys = dag.input_keys();
+        let recv_circuit: GarbledCircuitRecv = dag.into();
+
+        for (a, b) in [(0u8, 0u8), (200, 13), (13, 200), (77, 77), (255, 254)] {
+            let bits = (a as usize) | ((b as usize) << 8);
+            let keys: Vec<[u8; 32]> = (0..16)
+                .map(|i| {
+                    let wire = &input_keys[&i];
+
+                    if bits & (1 << i) != 0 { wire.on_key() } else { wire.off_key() }
+                })
+                .collect();
+            assert_eq!(recv_circuit.eval_bits(&keys).unwrap(), vec![a > b, a == b]);
+        }
+    }
+
+    #[test]
+    fn row_reduction_test() {
+        use crate::{backend::garbler_backend::construct_circuit, garbling::TAG_SIZE};
+
+        use super::GarbledNode;
+
+        // The first row of every table gate is reduced to its tag
+        let majority = Node::GateN(
+            vec![false, false, false, true, false, true, true, true],
+            (0..3).map(|i| Rc::new(Node::Input(i))).collect(),
+        );
+        let garbled: GarbledCircuit = Circuit::new(majority).unwrap().into();
+
+        for gate in garbled.gates() {
+            assert_eq!(gate.rows()[0].len(), TAG_SIZE);
+            assert!(gate.rows()[1..].iter().all(|row| row.len() == 32 + TAG_SIZE));
+        }
+
+        // Output keys are random rather than fixed
+        let garbled: GarbledCircuit = construct_circuit(4).into();
+
+        for out in garbled.outs() {
+            let GarbledNode::Gate(idx) = out else {
+                panic!("the outputs should be gates");
+            };
+            let out_wire = garbled.gates()[idx].out_wire();
+
+            assert_ne!(out_wire.on_key(), [1u8; 32]);
+            assert_ne!(out_wire.off_key(), [0u8; 32]);
+        }
+    }
+
+    #[test]
+    fn half_gates_test() {
+        use crate::backend::garbler_backend::construct_circuit;
+
+        use super::GateKind;
+
+        let garbled: GarbledCircuit = construct_circuit(4).into();
+
+        // The XNORs are free, and every other gate has two rows of a single key each
+        for gate in garbled.gates() {
+            match gate.kind() {
+                GateKind::FreeXor => assert!(gate.rows().is_empty()),
+                GateKind::HalfGates => {
+                    assert_eq!(gate.rows().len(), 2);
+                    assert!(gate.rows().iter().all(|row| row.len() == 32));
+                }
+                GateKind::Table => panic!("two-input gates shouldn't be garbled as tables"),
+            }
+        }
+        assert!(garbled.gates().iter().any(|gate| gate.kind() == GateKind::FreeXor));
+
+        let input_keys = garbled.input_keys();
+        let recv_circuit: GarbledCircuitRecv = garbled.into();
+
+        for a in 0..16usize {
+            for b in 0..16usize {
+                let bits = a | (b << 4);
+                let keys: Vec<[u8; 32]> = (0..8)
+                    .map(|i| {
+                        let wire = &input_keys[&i];
+
+                        if bits & (1 << i) != 0 { wire.on_key() } else { wire.off_key() }
+                    })
+                    .collect();
+
+                assert_eq!(recv_circuit.eval_bits(&keys).unwrap(), vec![a > b, a == b]);
+            }
+        }
+    }
+
+    #[test]
+    fn multi_input_gate_test() {
+        // The sum bit of a full adder (the XOR of its three inputs), and the majority of the sum
+        // and two other inputs
+        let sum = Rc::new(Node::GateN(
+            vec![false, true, true, false, true, false, false, true],
+            (0..3).map(|i| Rc::new(Node::Input(i))).collect(),
+        ));
+        let majority = Node::GateN(
+            vec![false, false, false, true, false, true, true, true],
+            vec![sum.clone(), Rc::new(Node::Input(3)), Rc::new(Node::Input(4))],
+        );
+        let circuit = Circuit::with_outputs(vec![Rc::unwrap_or_clone(sum), majority]).unwrap();
+        let garbled: GarbledCircuit = circuit.into();
+        let input_keys = garbled.input_keys();
+
+        // Each gate has a row per combination of its inputs
+        assert!(garbled.gates().iter().all(|gate| gate.rows().len() == 8));
+
+        let recv_circuit: GarbledCircuitRecv = garbled.into();
+
+        for bits in 0..32usize {
+            let keys: Vec<[u8; 32]> = (0..5)
+                .map(|i| {
+                    let wire = &input_keys[&i];
+
+                    if bits & (1 << i) != 0 { wire.on_key() } else { wire.off_key() }
+                })
+                .collect();
+            let sum = (bits & 0b111).count_ones() % 2 == 1;
+            let majority = sum as u32 + (bits >> 3).count_ones() >= 2;
+
+            assert_eq!(recv_circuit.eval_bits(&keys).unwrap(), vec![sum, majority]);
+        }
+    }
+
+    #[test]
+    fn shared_input_test() {
+        use crate::{
+            garbling::GarbledNode,
+            ot::simplest::{SimplestOtReceiver, SimplestOtSender},
+        };
+
+        // (x0 & x1) ^ (x0 | x2), where the two occurrences of x0 are separate nodes
+        let input = |idx| Rc::new(Node::Input(idx));
+        let circuit = || {
+            Circuit::new(Node::Gate(
+                XOR,
+                Rc::new(Node::Gate(AND, input(0), input(1))),
+                Rc::new(Node::Gate(OR, input(0), input(2))),
+            ))
+            .unwrap()
+        };
+        let garbled: GarbledCircuit = circuit().into();
+        let input_keys = garbled.input_keys();
+
+        // x0 has a single wire, which both of the gates that use it read
+        assert_eq!(input_keys.len(), 3);
+        assert_eq!(
+            garbled.gates().iter().filter(|gate| gate.inputs.contains(&GarbledNode::Input(0))).count(),
+            2
+        );
+
+        let recv_circuit: GarbledCircuitRecv = garbled.into();
+
+        for bits in 0..8usize {
+            let bits: Vec<bool> = (0..3).map(|i| bits & (1 << i) != 0).collect();
+            let mut keys: Vec<[u8; 32]> = (0..3)
+                .map(|i| if bits[i] { input_keys[&i].on_key() } else { input_keys[&i].off_key() })
+                .collect();
+            // The receiver gets x0's key using OT, and that one key is fed to both of its gates
+            let sender = SimplestOtSender::new((input_keys[&0].off_key(), input_keys[&0].on_key()));
+            let receiver = SimplestOtReceiver::new(sender.public()).unwrap();
+            let m_primes = sender.gen_combined(receiver.blind_idx(bits[0] as usize)).unwrap();
+            keys[0] = receiver.derive_msg(m_primes, bits[0] as usize);
+
+            assert_eq!(recv_circuit.eval_bool(&keys).unwrap(), circuit().eval(&bits));
+        }
+    }
+
+    #[test]
+    fn progress_test() {
+        // Two gates that are garbled during the traversal, and a table gate whose rows are
+        // encrypted at the end
+        let input = |idx| Rc::new(Node::Input(idx));
+        let and = Rc::new(Node::Gate(AND, input(0), input(1)));
+        let xor = Rc::new(Node::Gate(XOR, and.clone(), input(2)));
+        let table = vec![false, true, true, true, false, false, false, true];
+        let circuit = || Circuit::new(Node::GateN(table.clone(), vec![and.clone(), xor.clone(), input(3)])).unwrap();
+        let mut reports = vec![];
+        let garbled: GarbledCircuit = GarbledCircuit::from_with_progress(circuit(), |done, total| reports.push((done, total)));
+
+        assert_eq!(reports, vec![(1, 3), (2, 3), (3, 3)]);
+        assert_eq!(garbled.gates().len(), circuit().num_gates());
+    }
+
+    #[test]
+    fn gate_tweak_test() {
+        // Two identical gates over the same wires (which aren't deduplicated into one gate) share
+        // their input keys, but each gate's id is mixed into its hashes (see `gate_tweak`), so
+        // their rows are unrelated
+        let input = |idx| Rc::new(Node::Input(idx));
+        let and = || Node::Gate(AND, input(0), input(1));
+        let or = || Node::Gate(OR, input(0), input(1));
+
+        for circuit in [Circuit::with_outputs(vec![and(), and()]), Circuit::with_outputs(vec![or(), or()])] {
+            let garbled: GarbledCircuit = circuit.unwrap().into();
+            let gates = garbled.gates();
+
+            assert_eq!(gates.len(), 2);
+            assert!(!gates[0].rows().is_empty());
+            assert!(gates[0].rows().iter().all(|row| !gates[1].rows().contains(row)));
+        }
+    }
+
+    /// A random circuit of two-input gates (with any of the 16 operations) over `n` inputs, with
+    /// one or two outputs. Some gates feed several others, so the circuit is usually a DAG rather
+    /// than a tree
+    fn random_circuit(rng: &mut impl rand::Rng, n: usize) -> Circuit {
+        let mut pool: Vec<Rc<Node>> = (0..n).map(|i| Rc::new(Node::Input(i))).collect();
+        let outputs = rng.gen_range(1..=2);
+
+        while pool.len() > outputs {
+            let left = pool.swap_remove(rng.gen_range(0..pool.len()));
+            let right = pool.swap_remove(rng.gen_range(0..pool.len()));
+
+            // Sometimes keep an input of the gate around, so that it's shared with a later gate
+            if rng.gen_bool(0.3) {
+                pool.push(left.clone());
+            }
+
+            pool.push(Rc::new(Node::Gate(rng.gen_range(0..16), left, right)));
+        }
+
+        Circuit::with_outputs(pool.into_iter().map(|out| (*out).clone()).collect()).unwrap()
+    }
+
+    #[test]
+    fn random_circuits_test() {
+        use rand::{Rng, SeedableRng};
+        use rand_chacha::ChaCha20Rng;
+
+        // The receiver must decode the same outputs as evaluating the plain circuit, for any
+        // circuit, input and wire keys. On a mismatch, the seed and the circuit are printed, so the
+        // failing case can be reproduced
+        for seed in 0..2000u64 {
+            let mut rng = ChaCha20Rng::seed_from_u64(seed);
+            let n = rng.gen_range(2..=4);
+            let circuit = random_circuit(&mut rng, n);
+            let rendered = circuit.to_string();
+            let bits: Vec<bool> = (0..n).map(|_| rng.gen_bool(0.5)).collect();
+            let expected = circuit.eval_outputs(&bits);
+
+            let mut key_seed = [0u8; 32];
+            key_seed[..8].copy_from_slice(&seed.to_be_bytes());
+            let garbled: GarbledCircuit = GarbledCircuit::from_seeded(circuit, key_seed);
+            let input_keys = garbled.input_keys();
+            let keys: Vec<[u8; 32]> = bits
+                .iter()
+                .enumerate()
+                .map(|(i, bit)| if *bit { input_keys[&i].on_key() } else { input_keys[&i].off_key() })
+                .collect();
+            let recv_circuit: GarbledCircuitRecv = garbled.into();
+
+            assert_eq!(
+                recv_circuit.eval_bits(&keys).unwrap(),
+                expected,
+                "seed {} with inputs {:?}, circuit:\n{}",
+                seed,
+                bits,
+                rendered
+            );
+        }
+    }
+
+    #[test]
+    fn garbled_eval_key_sizes_test() {
+        garbled_eval_test::<16>();
+        garbled_eval_test::<32>();
+    }
+
+    #[test]
+    fn garble_cipher_test() {
+        // A table gate (the XOR of three inputs) feeding a half gate
+        let parity = Node::GateN(
+            vec![false, true, true, false, true, false, false, true],
+            (0..3).map(|i| Rc::new(Node::Input(i))).collect(),
+        );
+        let circuit = Circuit::new(Node::Gate(AND, Rc::new(parity), Rc::new(Node::Input(3)))).unwrap();
+        let garbled: GarbledCircuit = GarbledCircuit::from_with_cipher::<AesCtr>(circuit);
+        let input_keys = garbled.input_keys();
+        let recv_circuit: GarbledCircuitRecv = garbled.into();
+
+        for bits in 0..16usize {
+            let keys: Vec<[u8; 32]> = (0..4)
+                .map(|i| {
+                    let wire = &input_keys[&i];
+
+                    if bits & (1 << i) != 0 { wire.on_key() } else { wire.off_key() }
+                })
+                .collect();
+            let expected = (bits & 0b111).count_ones() % 2 == 1 && bits & 0b1000 != 0;
+            let outs = recv_circuit.try_eval_with::<AesCtr, 32>(&keys).unwrap();
+
+            assert_eq!(recv_circuit.decode_bits(&outs).unwrap(), vec![expected]);
+            // None of the table gate's rows authenticate under the default cipher
+            assert!(recv_circuit.try_eval(&keys).is_err());
+        }
+    }
 }
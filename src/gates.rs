@@ -0,0 +1,109 @@
+//! The operations of two-input gates (`Node::Gate`). An operation is the gate's 4-bit truth table: the output for the
+//! inputs (l, r) is bit 2l + r, so e.g. AND is 1000 (only the row l = r = 1 is set) and OR is 1110
+
+/// The operation whose outputs for the inputs (l, r) = (0, 0), (0, 1), (1, 0) and (1, 1) are `ff`, `ft`, `tf` and `tt`,
+/// e.g. `from_rows(false, false, false, true)` is `AND`. This builds operations without dealing with the bit layout
+pub const fn from_rows(ff: bool, ft: bool, tf: bool, tt: bool) -> u8 {
+    (ff as u8) | ((ft as u8) << 1) | ((tf as u8) << 2) | ((tt as u8) << 3)
+}
+
+/// The outputs of an operation in the order `from_rows` takes them, ignoring any bits set above the truth table
+pub const fn rows(op: u8) -> [bool; 4] {
+    [op & 1 != 0, op & 0b10 != 0, op & 0b100 != 0, op & 0b1000 != 0]
+}
+
+/// Always false, regardless of the inputs
+pub const FALSE: u8 = from_rows(false, false, false, false);
+/// Not (l or r)
+pub const NOR: u8 = from_rows(true, false, false, false);
+/// (Not l) and r, the negation of r implying l (named `CNIMPLY`)
+pub const NOT_LEFT_AND_RIGHT: u8 = from_rows(false, true, false, false);
+/// Not l, ignoring r
+pub const NOT_LEFT: u8 = from_rows(true, true, false, false);
+/// l and (not r), the negation of l implying r (named `NIMPLY`). The comparison circuit uses it to tell whether a bit of
+/// the first input is greater than the same bit of the second
+pub const LEFT_AND_NOT_RIGHT: u8 = from_rows(false, false, true, false);
+/// Not r, ignoring l
+pub const NOT_RIGHT: u8 = from_rows(true, false, true, false);
+pub const XOR: u8 = from_rows(false, true, true, false);
+/// Not (l and r)
+pub const NAND: u8 = from_rows(true, true, true, false);
+pub const AND: u8 = from_rows(false, false, false, true);
+/// l equals r
+pub const XNOR: u8 = from_rows(true, false, false, true);
+/// r, ignoring l
+pub const RIGHT: u8 = from_rows(false, true, false, true);
+/// l implies r, i.e. (not l) or r
+pub const IMPLY: u8 = from_rows(true, true, false, true);
+/// l, ignoring r
+pub const LEFT: u8 = from_rows(false, false, true, true);
+/// r implies l, i.e. l or (not r)
+pub const CIMPLY: u8 = from_rows(true, false, true, true);
+pub const OR: u8 = from_rows(false, true, true, true);
+/// Always true, regardless of the inputs
+pub const TRUE: u8 = from_rows(true, true, true, true);
+
+/// The name of every operation, indexed by the operation
+const NAMES: [&str; 16] = [
+    "FALSE", "NOR", "CNIMPLY", "NOTL", "NIMPLY", "NOTR", "XOR", "NAND", "AND", "XNOR", "RIGHT", "IMPLY", "LEFT", "CIMPLY", "OR",
+    "TRUE",
+];
+
+/// The name of a gate's operation, e.g. `AND` for 1000. Names are a single word, so they can be used as infix operators
+/// in expressions (see `Node::parse`). Operations with bits set above the truth table aren't valid and are named `INVALID`
+pub fn op_name(op: u8) -> &'static str {
+    NAMES.get(op as usize).copied().unwrap_or("INVALID")
+}
+
+/// The operation with the given name (see `op_name`), if there is one
+pub fn op_by_name(name: &str) -> Option<u8> {
+    NAMES.iter().position(|op_name| *op_name == name).map(|op| op as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_rows, op_by_name, op_name, rows, AND, FALSE, IMPLY, LEFT_AND_NOT_RIGHT, NOT_LEFT, OR, TRUE, XNOR, XOR};
+
+    #[test]
+    fn op_name_test() {
+        let implies = |l: bool, r: bool| !l || r;
+
+        // Each operation's truth table matches its name
+        for (l, r) in [(false, false), (false, true), (true, false), (true, true)] {
+            let row = 2 * l as usize + r as usize;
+
+            assert_eq!((AND >> row) & 1 == 1, l && r);
+            assert_eq!((OR >> row) & 1 == 1, l || r);
+            assert_eq!((IMPLY >> row) & 1 == 1, implies(l, r));
+            assert_eq!((LEFT_AND_NOT_RIGHT >> row) & 1 == 1, !implies(l, r));
+            assert_eq!((NOT_LEFT >> row) & 1 == 1, !l);
+        }
+
+        // Every operation has a distinct name, which maps back to it
+        for op in FALSE..=TRUE {
+            assert_eq!(op_by_name(op_name(op)), Some(op));
+        }
+        assert_eq!(op_name(LEFT_AND_NOT_RIGHT), "NIMPLY");
+        assert_eq!(op_name(0b10000), "INVALID");
+        assert_eq!(op_by_name("INVALID"), None);
+    }
+
+    #[test]
+    fn truth_table_test() {
+        // The constants are the operations with their truth tables, in the bit layout described above
+        assert_eq!((AND, OR, XOR, XNOR), (0b1000, 0b1110, 0b0110, 0b1001));
+        assert_eq!(from_rows(false, false, false, true), AND);
+        assert_eq!(from_rows(false, true, true, true), OR);
+        assert_eq!(from_rows(false, true, true, false), XOR);
+        assert_eq!(from_rows(true, false, false, true), XNOR);
+        assert_eq!(from_rows(false, false, true, false), LEFT_AND_NOT_RIGHT);
+
+        // Reading an operation's rows gives back the operation
+        for op in FALSE..=TRUE {
+            let [ff, ft, tf, tt] = rows(op);
+
+            assert_eq!(from_rows(ff, ft, tf, tt), op);
+        }
+        assert_eq!(rows(IMPLY), [true, true, false, true]);
+    }
+}
@@ -2,5 +2,9 @@ pub mod backend;
 pub mod circuit;
 pub mod crypto;
 pub mod garbling;
+pub mod gates;
+#[cfg(any(feature = "sync", feature = "async"))]
 pub mod message;
 pub mod ot;
+#[cfg(feature = "tls")]
+pub mod tls;
@@ -1,9 +1,16 @@
-use std::{
-    io::{self, Read, Write},
-    net::TcpStream,
-};
+use std::io;
+#[cfg(feature = "sync")]
+use std::io::{Read, Write};
+#[cfg(feature = "async")]
+use std::future::Future;
 
 use protobuf::Message as ProtobufMessage;
+#[cfg(feature = "async")]
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// The largest payload we accept, in bytes. Anything larger is treated as a framing violation,
+/// so that a malformed (or malicious) size prefix can't make us allocate arbitrary amounts of memory
+pub const MAX_MSG_SIZE: usize = 1 << 24;
 
 /// A message of a certain type
 pub struct TypedMessage {
@@ -12,7 +19,8 @@ pub struct TypedMessage {
 }
 
 /// This trait allows us to send and receive untyped messages over a stream
-/// We implement it for TcpStream
+/// We implement it for every stream that can be read from and written to (e.g. a TcpStream, or a TLS stream over one)
+#[cfg(feature = "sync")]
 pub trait MessageStream<T: ProtobufMessage> {
     // Receive a message of type T from the stream
     fn receive_msg(&mut self) -> Result<T, io::Error>;
@@ -23,44 +31,123 @@ pub trait MessageStream<T: ProtobufMessage> {
 /// Simialr to `MessageStream`. The main difference is that this trait
 /// sends **typed** messages, i.e. the type of the message is transmitted over the wire
 /// and the receiver can perform specific actions according to the type of the message received
+#[cfg(feature = "sync")]
 pub trait TypedMessageReader {
     // Receive a message
     fn receive_typed_msg(&mut self) -> Result<TypedMessage, io::Error>;
 }
 
+#[cfg(feature = "sync")]
 pub trait TypedMessageSender<T: ProtobufMessage> {
     // Send a message of type T over the stream. We also require
     // a u8 that indicates the type of the message
     fn send_typed_msg(&mut self, msg: T, msg_type: u8) -> Result<usize, io::Error>;
 }
 
-impl<T: ProtobufMessage> MessageStream<T> for TcpStream {
+/// The async counterpart of `MessageStream`, which we implement for tokio's TcpStream.
+/// Messages have the same format on the wire, so an async peer can talk to a sync one
+#[cfg(feature = "async")]
+pub trait AsyncMessageStream<T: ProtobufMessage> {
+    // Receive a message of type T from the stream
+    fn receive_msg(&mut self) -> impl Future<Output = Result<T, io::Error>> + Send;
+    // Send a message of type T over the stream
+    fn send_msg(&mut self, msg: T) -> impl Future<Output = Result<usize, io::Error>> + Send;
+}
+
+/// Attach the protocol step that failed to an error, so that an aborted run reports where it stopped
+pub trait ProtocolStep<T> {
+    fn step(self, step: &str) -> Result<T, io::Error>;
+}
+
+impl<T> ProtocolStep<T> for Result<T, io::Error> {
+    fn step(self, step: &str) -> Result<T, io::Error> {
+        self.map_err(|e| io::Error::new(e.kind(), format!("{}: {}", step, e)))
+    }
+}
+
+/// Serialize a message into the bytes we send over the wire
+fn to_wire_bytes<T: ProtobufMessage>(msg: &T) -> Result<Vec<u8>, io::Error> {
+    let mut msg_bytes = msg.write_to_bytes()?;
+    // The first 8 bytes of the message are its size (in big-endian)
+    // and the rest of the bytes are the proto itself
+    let mut wire_bytes = (payload_size(msg_bytes.len() as u64)? as u64).to_be_bytes().to_vec();
+    wire_bytes.append(&mut msg_bytes);
+
+    Ok(wire_bytes)
+}
+
+/// Validate the size prefix of a message
+fn payload_size(size: u64) -> Result<usize, io::Error> {
+    match usize::try_from(size) {
+        Ok(size) if size <= MAX_MSG_SIZE => Ok(size),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message of {} bytes exceeds the maximum of {} bytes", size, MAX_MSG_SIZE),
+        )),
+    }
+}
+
+/// Read a message of type T from any reader (see `MessageStream::receive_msg`)
+#[cfg(feature = "sync")]
+pub fn read_msg<T: ProtobufMessage>(reader: &mut impl Read) -> Result<T, io::Error> {
+    // Parse the size
+    let mut size_bytes = [0u8; 8];
+    reader.read_exact(&mut size_bytes)?;
+    // Read `size` bytes from the stream
+    let mut payload_bytes = vec![0u8; payload_size(u64::from_be_bytes(size_bytes))?];
+    reader.read_exact(&mut payload_bytes)?;
+    // Parse the payload and return it
+    let msg = T::parse_from_bytes(&payload_bytes)?;
+
+    Ok(msg)
+}
+
+/// Write a message of type T to any writer (see `MessageStream::send_msg`), returning the number of bytes written
+#[cfg(feature = "sync")]
+pub fn write_msg<T: ProtobufMessage>(writer: &mut impl Write, msg: &T) -> Result<usize, io::Error> {
+    // These are the bytes we send over the wire
+    let wire_bytes = to_wire_bytes(msg)?;
+    writer.write_all(&wire_bytes)?;
+
+    Ok(wire_bytes.len())
+}
+
+#[cfg(feature = "sync")]
+impl<T: ProtobufMessage, S: Read + Write> MessageStream<T> for S {
     fn receive_msg(&mut self) -> Result<T, io::Error> {
+        read_msg(self)
+    }
+
+    fn send_msg(&mut self, msg: T) -> Result<usize, io::Error> {
+        write_msg(self, &msg)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T: ProtobufMessage> AsyncMessageStream<T> for tokio::net::TcpStream {
+    async fn receive_msg(&mut self) -> Result<T, io::Error> {
         // Parse the size
         let mut size_bytes = [0u8; 8];
-        self.read_exact(&mut size_bytes)?;
+        self.read_exact(&mut size_bytes).await?;
         // Read `size` bytes from the stream
-        let mut payload_bytes = vec![0u8; u64::from_be_bytes(size_bytes).try_into().unwrap()];
-        self.read_exact(&mut payload_bytes)?;
+        let mut payload_bytes = vec![0u8; payload_size(u64::from_be_bytes(size_bytes))?];
+        self.read_exact(&mut payload_bytes).await?;
         // Parse the payload and return it
         let msg = T::parse_from_bytes(&payload_bytes)?;
 
         Ok(msg)
     }
 
-    fn send_msg(&mut self, msg: T) -> Result<usize, io::Error> {
-        // The first 8 bytes of the message are its size (in big-endian)
-        // and the rest of the bytes are the proto itself
-        let mut wire_bytes = msg.compute_size().to_be_bytes().to_vec();
-        let mut msg_bytes = msg.write_to_bytes()?;
-        // These are the bytes we send over the wire
-        wire_bytes.append(&mut msg_bytes);
+    async fn send_msg(&mut self, msg: T) -> Result<usize, io::Error> {
+        let wire_bytes = to_wire_bytes(&msg)?;
+        self.write_all(&wire_bytes).await?;
 
-        self.write(&wire_bytes)
+        Ok(wire_bytes.len())
     }
 }
 
-impl TypedMessageReader for TcpStream {
+#[cfg(feature = "sync")]
+impl<R: Read> TypedMessageReader for R {
     fn receive_typed_msg(&mut self) -> Result<TypedMessage, io::Error> {
         // Parse the size
         let mut size_bytes = [0u8; 8];
@@ -69,7 +156,7 @@ impl TypedMessageReader for TcpStream {
         let mut type_bytes = [0u8; 1];
         self.read_exact(&mut type_bytes)?;
         // Read `size` bytes from the stream
-        let mut payload_bytes = vec![0u8; u64::from_be_bytes(size_bytes).try_into().unwrap()];
+        let mut payload_bytes = vec![0u8; payload_size(u64::from_be_bytes(size_bytes))?];
         self.read_exact(&mut payload_bytes)?;
 
         Ok(TypedMessage {
@@ -79,17 +166,19 @@ impl TypedMessageReader for TcpStream {
     }
 }
 
-impl<T: ProtobufMessage> TypedMessageSender<T> for TcpStream {
+#[cfg(feature = "sync")]
+impl<T: ProtobufMessage, W: Write> TypedMessageSender<T> for W {
     fn send_typed_msg(&mut self, msg: T, msg_type: u8) -> Result<usize, io::Error> {
         // The first 8 bytes of the message are its size (in big-endian)
         // , after that we have one byte indicating the type, and the rest of the bytes are the proto itself
-        let mut wire_bytes = msg.compute_size().to_be_bytes().to_vec();
         let mut msg_bytes = msg.write_to_bytes()?;
+        let mut wire_bytes = (payload_size(msg_bytes.len() as u64)? as u64).to_be_bytes().to_vec();
         // These are the bytes we send over the wire
         wire_bytes.push(msg_type);
         wire_bytes.append(&mut msg_bytes);
+        self.write_all(&wire_bytes)?;
 
-        self.write(&wire_bytes)
+        Ok(wire_bytes.len())
     }
 }
 
@@ -103,3 +192,89 @@ impl TypedMessage {
         self.payload.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::garbler_backend::protos::GarblerKeys;
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn oversized_message_test() {
+        use std::{
+            io::{ErrorKind, Write},
+            net::{TcpListener, TcpStream},
+            thread,
+        };
+
+        use super::{MessageStream, MAX_MSG_SIZE};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let sender = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            // A size prefix that's larger than the maximum
+            stream.write_all(&(MAX_MSG_SIZE as u64 + 1).to_be_bytes()).unwrap();
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let result = MessageStream::<GarblerKeys>::receive_msg(&mut stream);
+        sender.join().unwrap();
+
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn in_memory_message_test() {
+        use std::io::Cursor;
+
+        use super::{read_msg, write_msg, MessageStream, TypedMessageReader, TypedMessageSender};
+
+        let mut msg = GarblerKeys::new();
+        msg.keys = vec![vec![1u8; 32], vec![2u8; 32]];
+
+        // Messages can be written to any writer and read back from any reader, without a socket
+        let mut bytes = vec![];
+        let written = write_msg(&mut bytes, &msg).unwrap();
+        assert_eq!(written, bytes.len());
+        assert_eq!(read_msg::<GarblerKeys>(&mut bytes.as_slice()).unwrap(), msg);
+        // A truncated message fails to read
+        assert!(read_msg::<GarblerKeys>(&mut &bytes[..written - 1]).is_err());
+
+        // A cursor is both, so it's a `MessageStream`
+        let mut stream = Cursor::new(vec![]);
+        MessageStream::<GarblerKeys>::send_msg(&mut stream, msg.clone()).unwrap();
+        stream.set_position(0);
+        assert_eq!(MessageStream::<GarblerKeys>::receive_msg(&mut stream).unwrap(), msg);
+
+        let mut bytes = vec![];
+        bytes.send_typed_msg(msg.clone(), 3).unwrap();
+        let typed = bytes.as_slice().receive_typed_msg().unwrap();
+        assert_eq!(typed.msg_type(), 3);
+        assert_eq!(typed.payload(), bytes[9..].to_vec());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_message_stream_test() {
+        use tokio::net::{TcpListener, TcpStream};
+
+        use super::AsyncMessageStream;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut msg = GarblerKeys::new();
+        msg.keys = vec![vec![1u8; 32], vec![2u8; 32]];
+        let sent = msg.clone();
+
+        let sender = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            AsyncMessageStream::<GarblerKeys>::send_msg(&mut stream, sent).await.unwrap();
+        });
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let received = AsyncMessageStream::<GarblerKeys>::receive_msg(&mut stream).await.unwrap();
+        sender.await.unwrap();
+
+        assert_eq!(received, msg);
+    }
+}
@@ -0,0 +1,289 @@
+use std::io;
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    crypto::aes_ctr::{AesCtr, NONCE_SIZE},
+    garbling::KEY_SIZE,
+};
+
+/// Number of base OTs, i.e. the computational security parameter
+pub const NUM_BASE_OTS: usize = 128;
+/// Size of a row of the extension matrix (one bit per base OT) in bytes
+const ROW_SIZE: usize = NUM_BASE_OTS / 8;
+
+/// A pair of messages, one of which the receiver gets
+pub type MsgPair = ([u8; KEY_SIZE], [u8; KEY_SIZE]);
+
+/// OT extension (Ishai, Kilian, Nissim and Petrank).
+/// Running a public-key OT per transfer is expensive. Instead, the parties run `NUM_BASE_OTS`
+/// base OTs with their roles reversed, and then extend them into any number of transfers using only a PRG and a hash function.
+///
+/// The receiver, with choice bits r, picks a pair of seeds for each base OT, and acts as the sender in the base OTs.
+/// The sender picks random bits s, and receives the seed of each pair selected by s. The receiver then sends
+/// u_j = G(seed_j^0) ^ G(seed_j^1) ^ r for each base OT j, which lets the sender compute the columns of
+/// a matrix Q whose i-th row is q_i = t_i ^ (r_i * s), where t_i is the i-th row of the receiver's matrix T (whose columns are G(seed_j^0)).
+/// The sender masks its messages as y_i^0 = x_i^0 ^ H(i, q_i) and y_i^1 = x_i^1 ^ H(i, q_i ^ s), and the receiver can only unmask
+/// y_i^{r_i} = x_i^{r_i} ^ H(i, t_i)
+pub struct OtExtensionSender {
+    /// The message pairs we want to transfer
+    msgs: Vec<MsgPair>,
+    /// Our choice bits in the base OTs
+    s: Vec<bool>,
+}
+
+/// OT extension from the receiver's POV
+pub struct OtExtensionReceiver {
+    /// Which message of each pair we want
+    choices: Vec<bool>,
+    /// The seed pairs we send as the sender of the base OTs
+    seeds: Vec<MsgPair>,
+}
+
+/// Expand a seed pseudorandomly (using AES-CTR as a PRG) into as many bytes as `src`, writing G(seed) ^ `src` into `dst`.
+/// Each seed only ever expands into its own column, so a fixed nonce is fine
+fn prg_xor(seed: &[u8; KEY_SIZE], dst: &mut [u8], src: &[u8]) {
+    AesCtr::new(seed).encrypt_into(dst, src, &[0u8; NONCE_SIZE], 0);
+}
+
+/// The hash used to mask the i-th message pair
+fn hash_row(i: usize, row: &[u8; ROW_SIZE]) -> [u8; KEY_SIZE] {
+    let mut hasher = Sha256::new();
+    hasher.update((i as u64).to_be_bytes());
+    hasher.update(row);
+
+    hasher.finalize().into()
+}
+
+fn get_bit(bytes: &[u8], i: usize) -> bool {
+    (bytes[i / 8] & (1 << (i % 8))) != 0
+}
+
+/// Pack a vector of bits into bytes (little-endian bit order)
+fn pack_bits(bits: &[bool]) -> Vec<u8> {
+    let mut bytes = vec![0u8; bits.len().div_ceil(8)];
+
+    for (i, _) in bits.iter().enumerate().filter(|(_, bit)| **bit) {
+        bytes[i / 8] |= 1 << (i % 8);
+    }
+
+    bytes
+}
+
+/// Extract the i-th row of a matrix stored as `NUM_BASE_OTS` columns
+fn row(columns: &[Vec<u8>], i: usize) -> [u8; ROW_SIZE] {
+    let mut row = [0u8; ROW_SIZE];
+
+    for (j, column) in columns.iter().enumerate() {
+        if get_bit(column, i) {
+            row[j / 8] |= 1 << (j % 8);
+        }
+    }
+
+    row
+}
+
+fn xor<const N: usize>(x: &[u8; N], y: &[u8; N]) -> [u8; N] {
+    let mut out = [0u8; N];
+
+    for (out_byte, (x_byte, y_byte)) in out.iter_mut().zip(x.iter().zip(y)) {
+        *out_byte = x_byte ^ y_byte;
+    }
+
+    out
+}
+
+impl OtExtensionSender {
+    /// Generate a new sender that wants to transfer one message out of each pair in `msgs`
+    pub fn new(msgs: Vec<MsgPair>) -> OtExtensionSender {
+        let mut rng = ChaCha20Rng::from_entropy();
+        let s = (0..NUM_BASE_OTS).map(|_| rng.gen()).collect();
+
+        OtExtensionSender { msgs, s }
+    }
+
+    /// Our choices in the base OTs, in which we act as the receiver
+    pub fn base_choices(&self) -> Vec<bool> {
+        self.s.clone()
+    }
+
+    /// Mask all message pairs, given the seeds we received in the base OTs and the receiver's u columns.
+    /// Returns an error if there isn't a seed and a column per base OT, or if a column isn't a bit per message pair long
+    pub fn gen_combined(&self, base_keys: Vec<[u8; KEY_SIZE]>, us: Vec<Vec<u8>>) -> Result<Vec<MsgPair>, io::Error> {
+        let m = self.msgs.len();
+        let col_size = m.div_ceil(8);
+
+        if base_keys.len() != NUM_BASE_OTS || us.len() != NUM_BASE_OTS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected {} base OT seeds and u columns, got {} and {}", NUM_BASE_OTS, base_keys.len(), us.len()),
+            ));
+        }
+
+        if let Some(u) = us.iter().find(|u| u.len() != col_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("a u column is {} bytes long, expected {}", u.len(), col_size),
+            ));
+        }
+
+        // q_j = G(seed_j^{s_j}) ^ (s_j * u_j) = t_j ^ (s_j * r)
+        let zeros = vec![0u8; col_size];
+        let qs: Vec<Vec<u8>> = base_keys
+            .iter()
+            .zip(us.iter().zip(&self.s))
+            .map(|(key, (u, s_j))| {
+                let mut q = vec![0u8; col_size];
+                prg_xor(key, &mut q, if *s_j { u } else { &zeros });
+
+                q
+            })
+            .collect();
+        let s_row: [u8; ROW_SIZE] = pack_bits(&self.s).try_into().unwrap();
+
+        Ok(self
+            .msgs
+            .iter()
+            .enumerate()
+            .map(|(i, (x_0, x_1))| {
+                let q_i = row(&qs, i);
+
+                (
+                    xor(x_0, &hash_row(i, &q_i)),
+                    xor(x_1, &hash_row(i, &xor(&q_i, &s_row))),
+                )
+            })
+            .collect())
+    }
+
+    pub fn msgs(&self) -> Vec<MsgPair> {
+        self.msgs.clone()
+    }
+}
+
+impl OtExtensionReceiver {
+    /// Generate a new receiver that wants message `choices[i]` out of the i-th pair
+    pub fn new(choices: Vec<bool>) -> OtExtensionReceiver {
+        let mut rng = ChaCha20Rng::from_entropy();
+        let seeds = (0..NUM_BASE_OTS).map(|_| (rng.gen(), rng.gen())).collect();
+
+        OtExtensionReceiver { choices, seeds }
+    }
+
+    /// The message pairs of the base OTs, in which we act as the sender
+    pub fn base_msgs(&self) -> Vec<MsgPair> {
+        self.seeds.clone()
+    }
+
+    /// Generate the u columns sent to the sender once the base OTs are complete
+    pub fn gen_us(&self) -> Vec<Vec<u8>> {
+        let col_size = self.choices.len().div_ceil(8);
+        let r = pack_bits(&self.choices);
+        let mut masked = vec![0u8; col_size];
+
+        self.seeds
+            .iter()
+            .map(|(seed_0, seed_1)| {
+                // u_j = t_j ^ G(seed_j^1) ^ r, where t_j = G(seed_j^0)
+                let mut u = vec![0u8; col_size];
+                prg_xor(seed_1, &mut masked, &r);
+                prg_xor(seed_0, &mut u, &masked);
+
+                u
+            })
+            .collect()
+    }
+
+    /// Derive the chosen messages from the sender's masked pairs
+    pub fn derive_msgs(&self, m_primes: Vec<MsgPair>) -> Vec<[u8; KEY_SIZE]> {
+        let zeros = vec![0u8; self.choices.len().div_ceil(8)];
+        let ts: Vec<Vec<u8>> = self
+            .seeds
+            .iter()
+            .map(|(seed_0, _)| {
+                let mut t = vec![0u8; zeros.len()];
+                prg_xor(seed_0, &mut t, &zeros);
+
+                t
+            })
+            .collect();
+
+        m_primes
+            .iter()
+            .zip(&self.choices)
+            .enumerate()
+            .map(|(i, ((y_0, y_1), r_i))| xor(if *r_i { y_1 } else { y_0 }, &hash_row(i, &row(&ts, i))))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha20Rng;
+
+    use crate::ot::simplest::{SimplestOtReceiver, SimplestOtSender};
+
+    use super::{OtExtensionReceiver, OtExtensionSender, NUM_BASE_OTS};
+
+    #[test]
+    fn ot_extension_test() {
+        let mut rng = ChaCha20Rng::from_entropy();
+        // The sender has 300 pairs of keys
+        let msgs: Vec<([u8; 32], [u8; 32])> = (0..300).map(|_| (rng.gen(), rng.gen())).collect();
+        let choices: Vec<bool> = (0..300).map(|_| rng.gen()).collect();
+        let sender = OtExtensionSender::new(msgs.clone());
+        let receiver = OtExtensionReceiver::new(choices.clone());
+        // Run the base OTs, with the roles reversed
+        let base_keys = receiver
+            .base_msgs()
+            .into_iter()
+            .zip(sender.base_choices())
+            .map(|(seeds, s_j)| {
+                let base_sender = SimplestOtSender::new(seeds);
+                let base_receiver = SimplestOtReceiver::new(base_sender.public()).unwrap();
+                let v = base_receiver.blind_idx(s_j as usize);
+                let m_primes = base_sender.gen_combined(v).unwrap();
+
+                base_receiver.derive_msg(m_primes, s_j as usize)
+            })
+            .collect();
+        // Extend the base OTs
+        let m_primes = sender.gen_combined(base_keys, receiver.gen_us()).unwrap();
+        let extracted_msgs = receiver.derive_msgs(m_primes.clone());
+
+        for (i, ((x_0, x_1), r_i)) in msgs.iter().zip(&choices).enumerate() {
+            assert_eq!(extracted_msgs[i], if *r_i { *x_1 } else { *x_0 });
+        }
+
+        // Unmasking with the flipped choice bits doesn't reveal the other messages
+        let cheater = OtExtensionReceiver {
+            choices: choices.iter().map(|r_i| !r_i).collect(),
+            seeds: receiver.base_msgs(),
+        };
+        let cheated_msgs = cheater.derive_msgs(m_primes);
+
+        for (i, ((x_0, x_1), r_i)) in msgs.iter().zip(&choices).enumerate() {
+            assert_ne!(cheated_msgs[i], if *r_i { *x_0 } else { *x_1 });
+        }
+    }
+
+    #[test]
+    fn gen_combined_lengths_test() {
+        // 20 pairs, so each u column is 3 bytes long
+        let sender = OtExtensionSender::new(vec![([1u8; 32], [2u8; 32]); 20]);
+        let base_keys = vec![[0u8; 32]; NUM_BASE_OTS];
+        let us = vec![vec![0u8; 3]; NUM_BASE_OTS];
+
+        assert_eq!(sender.gen_combined(base_keys.clone(), us.clone()).unwrap().len(), 20);
+
+        // A missing seed or column, or a column of the wrong size
+        assert!(sender.gen_combined(base_keys[1..].to_vec(), us.clone()).is_err());
+        assert!(sender.gen_combined(base_keys.clone(), us[1..].to_vec()).is_err());
+        let mut short = us;
+        short[5].pop();
+        assert!(sender.gen_combined(base_keys, short).is_err());
+    }
+}
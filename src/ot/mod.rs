@@ -0,0 +1,299 @@
+pub mod extension;
+pub mod simplest;
+
+use std::io;
+
+use num_bigint::{BigUint, RandBigInt};
+use rand::thread_rng;
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use crate::{
+    crypto::rsa::{Keypair, PublicKey},
+    garbling::KEY_SIZE,
+};
+
+/// A wire key moved through OT, whose messages are numbers. `BigUint` drops a key's leading zero bytes,
+/// so converting back with `from_biguint` pads the key to its full `N` bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WireKey<const N: usize = KEY_SIZE>(pub [u8; N]);
+
+impl<const N: usize> WireKey<N> {
+    /// The key as a big-endian number
+    pub fn to_biguint(&self) -> BigUint {
+        BigUint::from_bytes_be(&self.0)
+    }
+
+    /// The key whose big-endian value is `value`. Returns an error if it doesn't fit in `N` bytes
+    pub fn from_biguint(value: &BigUint) -> Result<WireKey<N>, io::Error> {
+        let bytes = Zeroizing::new(value.to_bytes_be());
+
+        if bytes.len() > N {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("an OT message of {} bytes doesn't fit in a {}-byte key", bytes.len(), N),
+            ));
+        }
+
+        let mut key = [0u8; N];
+        key[N - bytes.len()..].copy_from_slice(&bytes);
+
+        Ok(WireKey(key))
+    }
+}
+
+impl<const N: usize> From<[u8; N]> for WireKey<N> {
+    fn from(key: [u8; N]) -> WireKey<N> {
+        WireKey(key)
+    }
+}
+
+/// Oblivious transfer
+/// Alice (the Sender) has two messages m_0 and m_1. Bob (the Receiver) wants to receive
+/// message m_b, without Alice finding out which message he received.
+/// More generally, Alice can have n messages m_0, ..., m_{n - 1}, out of which Bob receives exactly one
+/// The protocol runs the simplest OT (see `simplest`) instead; this RSA-based OT is kept as a standalone primitive
+///
+/// This OT uses textbook RSA on purpose, rather than `encrypt_oaep`/`decrypt_oaep`: Alice decrypts v - x_i for *every* i,
+/// and only one of these is an actual encryption (of Bob's random k). With OAEP, all other decryptions would fail to
+/// unpad, which would tell Alice which message Bob chose. Textbook RSA isn't a problem here, since the values it
+/// encrypts (k) are uniformly random and only ever used as one-time pads.
+///
+/// The k_i aren't added to the messages directly, but through a hash (see `pad`): a malicious Bob who sends
+/// v = (x_0 + x_1) / 2 gets k_1 = (-(x_1 - x_0) / 2)^d = -k_0, so if the pads were the k_i themselves, adding
+/// m'_0 and m'_1 would give him m_0 + m_1. Hashing each k_i together with its index breaks any such relation
+pub struct ObTransferSender<'a> {
+    msgs: Vec<BigUint>,
+    /// RSA keypair, borrowed so that the many OTs of a session don't each copy the private key
+    keypair: &'a Keypair,
+    /// Random messages, one per message
+    xs: Vec<BigUint>,
+}
+
+/// OT from the receiver's POV
+pub struct ObTransferReceiver {
+    /// The xs sent by the sender
+    xs: Vec<BigUint>,
+    /// Used to blind the message index
+    k: BigUint,
+    /// Sender's pubkey
+    sender_pubkey: PublicKey,
+}
+
+/// The pad that hides message `idx` given its blinding value `k` (modulo the RSA modulus `n`): SHA-256 of the index,
+/// a block counter and `k` is expanded to 16 bytes more than `n`, so that reducing it modulo `n` is close to uniform
+fn pad(idx: usize, k: &BigUint, n: &BigUint) -> BigUint {
+    let len = n.to_bytes_be().len();
+    let mut k_bytes = vec![0u8; len];
+    let k_be = k.to_bytes_be();
+    k_bytes[len - k_be.len()..].copy_from_slice(&k_be);
+
+    let stream: Vec<u8> = (0..(len + 16).div_ceil(32) as u64)
+        .flat_map(|block| {
+            let mut hasher = Sha256::new();
+            hasher.update((idx as u64).to_be_bytes());
+            hasher.update(block.to_be_bytes());
+            hasher.update(&k_bytes);
+
+            hasher.finalize()
+        })
+        .collect();
+
+    BigUint::from_bytes_be(&stream) % n
+}
+
+impl<'a> ObTransferSender<'a> {
+    /// Generate a new sender for 1-out-of-2 OT
+    pub fn new(msgs: (BigUint, BigUint), keypair: &'a Keypair) -> ObTransferSender<'a> {
+        ObTransferSender::new_n(vec![msgs.0, msgs.1], keypair)
+    }
+
+    /// Generate a new sender for 1-out-of-n OT, where n is the number of messages
+    pub fn new_n(msgs: Vec<BigUint>, keypair: &'a Keypair) -> ObTransferSender<'a> {
+        // The x's are random messages smaller than the RSA modulus
+        let xs = msgs
+            .iter()
+            .map(|_| thread_rng().gen_biguint_below(&keypair.public.n))
+            .collect();
+
+        ObTransferSender {
+            msgs,
+            keypair,
+            xs,
+        }
+    }
+
+    /// Generate the combined messages that allow the receiver to derive the message they want
+    /// v is the blinded x the receiver wants
+    pub fn gen_combined(&self, v: BigUint) -> (BigUint, BigUint) {
+        let mut m_primes = self.gen_combined_n(v).into_iter();
+
+        (m_primes.next().unwrap(), m_primes.next().unwrap())
+    }
+
+    /// Same as `gen_combined`, but returns one combined message for each of the n messages
+    pub fn gen_combined_n(&self, v: BigUint) -> Vec<BigUint> {
+        let n = &self.keypair.public.n;
+
+        self.xs
+            .iter()
+            .zip(&self.msgs)
+            .enumerate()
+            .map(|(i, (x_i, m_i))| {
+                let k_i = self.keypair.private.decrypt(&((&v + (n - x_i)) % n));
+
+                // Combine with the secret message
+                (m_i + pad(i, &k_i, n)) % n
+            })
+            .collect()
+    }
+
+    pub fn msgs(&self) -> (BigUint, BigUint) {
+        (self.msgs[0].clone(), self.msgs[1].clone())
+    }
+
+    pub fn msgs_n(&self) -> Vec<BigUint> {
+        self.msgs.clone()
+    }
+
+    pub fn keypair(&self) -> &Keypair {
+        self.keypair
+    }
+
+    pub fn xs(&self) -> (BigUint, BigUint) {
+        (self.xs[0].clone(), self.xs[1].clone())
+    }
+
+    pub fn xs_n(&self) -> Vec<BigUint> {
+        self.xs.clone()
+    }
+}
+
+impl ObTransferReceiver {
+    pub fn new(sender_pubkey: PublicKey, xs: (BigUint, BigUint)) -> ObTransferReceiver {
+        ObTransferReceiver::new_n(sender_pubkey, vec![xs.0, xs.1])
+    }
+
+    /// Generate a receiver for 1-out-of-n OT, where the sender sent us n xs
+    pub fn new_n(sender_pubkey: PublicKey, xs: Vec<BigUint>) -> ObTransferReceiver {
+        let k = thread_rng().gen_biguint_below(&sender_pubkey.n);
+
+        ObTransferReceiver {
+            xs,
+            k,
+            sender_pubkey,
+        }
+    }
+
+    /// Generate the blinded x_b given the index b (0 <= b < n)
+    pub fn blind_idx(&self, b: usize) -> BigUint {
+        (&self.xs[b] + self.k.modpow(&self.sender_pubkey.e, &self.sender_pubkey.n))
+            % &self.sender_pubkey.n
+    }
+
+    /// Derive the selected message from the sender's reply
+    pub fn derive_msg(&self, m_primes: (BigUint, BigUint), b: usize) -> BigUint {
+        self.derive_msg_n(vec![m_primes.0, m_primes.1], b)
+    }
+
+    /// Derive the selected message from the sender's reply in 1-out-of-n OT
+    pub fn derive_msg_n(&self, m_primes: Vec<BigUint>, b: usize) -> BigUint {
+        let n = &self.sender_pubkey.n;
+
+        (&m_primes[b] + (n - pad(b, &self.k, n))) % n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigUint;
+
+    use crate::crypto::rsa::Keypair;
+
+    use super::{ObTransferReceiver, ObTransferSender, WireKey};
+
+    #[test]
+    fn oblivious_transfer_test() {
+        let sender_pubkey = Keypair::new(None, None);
+        // The sender has two messages
+        let sender = ObTransferSender::new((123u64.into(), 456u64.into()), &sender_pubkey);
+        // Receiver wants to get one of the messages, w/o the sender knowing which message
+        // was sent
+        // First of all, we need to get the sender's public parameters (in real usage, these would be sent over the network)
+        let xs = sender.xs();
+        let receiver = ObTransferReceiver::new(sender_pubkey.public.clone(), xs);
+        // In this case, the receiver wants to get message 0 (123), so he blinds x_0
+        let v = receiver.blind_idx(0);
+        // The receiver then sends v to the sender, and the sender responds with m_prime_0 and m_prime_1
+        // from which m_b can be derived
+        let m_primes = sender.gen_combined(v);
+        // The receiver then uses these to extract the desired message
+        let extracted_msg = receiver.derive_msg(m_primes, 0);
+        // The extracted message should be equal to the original one
+        assert_eq!(extracted_msg, sender.msgs().0);
+    }
+
+    #[test]
+    fn oblivious_transfer_n_test() {
+        let sender_pubkey = Keypair::new(None, None);
+        // The sender has five messages
+        let msgs: Vec<BigUint> = (0..5u64).map(|i| (100 * i + 7).into()).collect();
+        let sender = ObTransferSender::new_n(msgs, &sender_pubkey);
+        let receiver = ObTransferReceiver::new_n(sender_pubkey.public.clone(), sender.xs_n());
+        // The receiver wants message 3
+        let v = receiver.blind_idx(3);
+        let m_primes = sender.gen_combined_n(v);
+
+        assert_eq!(m_primes.len(), 5);
+        assert_eq!(receiver.derive_msg_n(m_primes, 3), sender.msgs_n()[3]);
+    }
+
+    #[test]
+    fn malicious_receiver_test() {
+        let keypair = Keypair::new(None, None);
+        let n = keypair.public.n.clone();
+        let sender = ObTransferSender::new((123u64.into(), 456u64.into()), &keypair);
+        let (x_0, x_1) = sender.xs();
+        // v = (x_0 + x_1) / 2, so that k_1 = -k_0 (2 is invertible since n is odd)
+        let v = (x_0 + x_1) * ((&n + 1u8) / 2u8) % &n;
+        let (m_prime_0, m_prime_1) = sender.gen_combined(v);
+
+        // Without hashing the pads, the sum of the combined messages would be the sum of the messages
+        assert_ne!((m_prime_0 + m_prime_1) % &n, BigUint::from(123u64 + 456));
+
+        // An honest receiver only gets the message it chose
+        let receiver = ObTransferReceiver::new(keypair.public.clone(), sender.xs());
+        let m_primes = sender.gen_combined(receiver.blind_idx(0));
+
+        assert_eq!(receiver.derive_msg(m_primes.clone(), 0), BigUint::from(123u64));
+        assert_ne!(receiver.derive_msg(m_primes, 1), BigUint::from(456u64));
+    }
+
+    #[test]
+    fn wire_key_test() {
+        let key = WireKey([0u8, 0, 7, 1]);
+
+        // BigUint drops the leading zeros, and converting back restores them
+        assert_eq!(key.to_biguint().to_bytes_be(), vec![7, 1]);
+        assert_eq!(WireKey::<4>::from_biguint(&key.to_biguint()).unwrap(), key);
+        assert_eq!(WireKey::<4>::from_biguint(&BigUint::from(0u8)).unwrap(), WireKey([0u8; 4]));
+        assert!(WireKey::<4>::from_biguint(&BigUint::from(1u64 << 32)).is_err());
+    }
+
+    #[test]
+    fn leading_zero_key_test() {
+        let keypair = Keypair::new(None, None);
+        // About one in 256 keys starts with a zero byte
+        let mut key = [0xabu8; 32];
+        key[0] = 0;
+        let msgs = (WireKey(key).to_biguint(), WireKey([1u8; 32]).to_biguint());
+        let sender = ObTransferSender::new(msgs, &keypair);
+        let receiver = ObTransferReceiver::new(keypair.public.clone(), sender.xs());
+        let m_primes = sender.gen_combined(receiver.blind_idx(0));
+
+        // The derived message is a byte short, and the key comes back whole
+        let derived = receiver.derive_msg(m_primes, 0);
+        assert_eq!(derived.to_bytes_be().len(), 31);
+        assert_eq!(WireKey::<32>::from_biguint(&derived).unwrap(), WireKey(key));
+    }
+}
@@ -0,0 +1,163 @@
+use num_bigint::BigUint;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    crypto::dh::{self, G, P},
+    garbling::KEY_SIZE,
+};
+
+/// The "simplest" oblivious transfer of Chou and Orlandi, over a Diffie-Hellman group.
+/// The messages are exactly `KEY_SIZE` bytes long, so they can be used directly as garbled wire keys:
+/// the garbler sends the receiver its keys with it (see `run_garbler`).
+///
+/// The sender publishes A = g^a. The receiver, who wants message c, replies with
+/// B = g^b (if c = 0) or B = A * g^b (if c = 1). The sender derives the pads
+/// k_0 = H(B^a) and k_1 = H((B / A)^a), while the receiver can only derive k_c = H(A^b)
+pub struct SimplestOtSender {
+    msgs: ([u8; KEY_SIZE], [u8; KEY_SIZE]),
+    /// Secret exponent
+    a: BigUint,
+    /// A = g^a
+    public: BigUint,
+    /// (A^a)^{-1}, used to compute (B / A)^a = B^a * (A^a)^{-1}
+    a_pow_a_inv: BigUint,
+}
+
+/// Simplest OT from the receiver's POV
+pub struct SimplestOtReceiver {
+    /// Secret exponent
+    b: BigUint,
+    /// The sender's public value A
+    sender_public: BigUint,
+}
+
+/// Derive the pad used to mask a message from the transcript and the shared group element
+fn derive_pad(a: &BigUint, b: &BigUint, shared: &BigUint) -> [u8; KEY_SIZE] {
+    let mut hasher = Sha256::new();
+    hasher.update(dh::to_fixed_bytes(a));
+    hasher.update(dh::to_fixed_bytes(b));
+    hasher.update(dh::to_fixed_bytes(shared));
+
+    hasher.finalize().into()
+}
+
+fn xor(x: &[u8; KEY_SIZE], y: &[u8; KEY_SIZE]) -> [u8; KEY_SIZE] {
+    let mut out = [0u8; KEY_SIZE];
+
+    for (out_byte, (x_byte, y_byte)) in out.iter_mut().zip(x.iter().zip(y)) {
+        *out_byte = x_byte ^ y_byte;
+    }
+
+    out
+}
+
+impl SimplestOtSender {
+    /// Generate a new sender
+    pub fn new(msgs: ([u8; KEY_SIZE], [u8; KEY_SIZE])) -> SimplestOtSender {
+        let a = dh::gen_secret();
+        let public = G.modpow(&a, &P);
+        let a_pow_a_inv = public.modpow(&a, &P).modinv(&P).unwrap();
+
+        SimplestOtSender {
+            msgs,
+            a,
+            public,
+            a_pow_a_inv,
+        }
+    }
+
+    /// Mask both messages given the receiver's blinded choice v (B in the notation above).
+    /// Returns `None` if v isn't a valid group element
+    pub fn gen_combined(&self, v: BigUint) -> Option<([u8; KEY_SIZE], [u8; KEY_SIZE])> {
+        if !dh::is_group_element(&v) {
+            return None;
+        }
+
+        let b_pow_a = v.modpow(&self.a, &P);
+        let k_0 = derive_pad(&self.public, &v, &b_pow_a);
+        let k_1 = derive_pad(&self.public, &v, &((&b_pow_a * &self.a_pow_a_inv) % &*P));
+        let (m_0, m_1) = &self.msgs;
+
+        Some((xor(m_0, &k_0), xor(m_1, &k_1)))
+    }
+
+    /// The public value A, which is sent to the receiver
+    pub fn public(&self) -> BigUint {
+        self.public.clone()
+    }
+
+    pub fn msgs(&self) -> ([u8; KEY_SIZE], [u8; KEY_SIZE]) {
+        self.msgs
+    }
+}
+
+impl SimplestOtReceiver {
+    /// Returns `None` if the sender's public value isn't a valid group element
+    pub fn new(sender_public: BigUint) -> Option<SimplestOtReceiver> {
+        if !dh::is_group_element(&sender_public) {
+            return None;
+        }
+
+        Some(SimplestOtReceiver {
+            b: dh::gen_secret(),
+            sender_public,
+        })
+    }
+
+    /// Generate the blinded choice B given the index b
+    pub fn blind_idx(&self, b: usize) -> BigUint {
+        let g_pow_b = G.modpow(&self.b, &P);
+
+        if b == 0 {
+            g_pow_b
+        } else {
+            (&self.sender_public * g_pow_b) % &*P
+        }
+    }
+
+    /// Derive the selected message from the sender's reply
+    pub fn derive_msg(&self, m_primes: ([u8; KEY_SIZE], [u8; KEY_SIZE]), b: usize) -> [u8; KEY_SIZE] {
+        let pad = derive_pad(
+            &self.sender_public,
+            &self.blind_idx(b),
+            &self.sender_public.modpow(&self.b, &P),
+        );
+
+        xor(if b == 0 { &m_primes.0 } else { &m_primes.1 }, &pad)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SimplestOtReceiver, SimplestOtSender};
+
+    #[test]
+    fn simplest_ot_test() {
+        // The sender has two messages
+        let sender = SimplestOtSender::new(([1u8; 32], [2u8; 32]));
+        // First of all, the receiver needs the sender's public value (in real usage, this would be sent over the network)
+        let receiver = SimplestOtReceiver::new(sender.public()).unwrap();
+
+        for b in 0..2 {
+            // The receiver blinds its choice and sends it to the sender, which responds with both masked messages
+            let v = receiver.blind_idx(b);
+            let m_primes = sender.gen_combined(v).unwrap();
+            // The receiver can only unmask the message it chose
+            let extracted_msg = receiver.derive_msg(m_primes, b);
+            let other_msg = receiver.derive_msg(m_primes, 1 - b);
+            let msgs = sender.msgs();
+            let (chosen, other) = if b == 0 { (msgs.0, msgs.1) } else { (msgs.1, msgs.0) };
+
+            assert_eq!(extracted_msg, chosen);
+            assert_ne!(other_msg, other);
+        }
+    }
+
+    #[test]
+    fn simplest_ot_rejects_invalid_elements_test() {
+        let sender = SimplestOtSender::new(([1u8; 32], [2u8; 32]));
+
+        assert!(SimplestOtReceiver::new(1u64.into()).is_none());
+        assert!(sender.gen_combined(0u64.into()).is_none());
+    }
+}
@@ -0,0 +1,253 @@
+use std::{io, net::TcpStream, sync::Arc};
+
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, ServerConfig, ServerConnection,
+    SignatureScheme, StreamOwned,
+};
+
+/// A TLS stream from the server's (the garbler's) POV. Like a TcpStream, it can be used as a `MessageStream`
+pub type ServerStream = StreamOwned<ServerConnection, TcpStream>;
+/// A TLS stream from the client's (the receiver's) POV
+pub type ClientStream = StreamOwned<ClientConnection, TcpStream>;
+
+fn tls_error(e: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn pem_error(e: rustls::pki_types::pem::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid PEM: {}", e))
+}
+
+/// The certificates in a PEM file
+fn certificates(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>, io::Error> {
+    let certs = CertificateDer::pem_slice_iter(pem).collect::<Result<Vec<_>, _>>().map_err(pem_error)?;
+
+    if certs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "no certificates in PEM"));
+    }
+
+    Ok(certs)
+}
+
+/// The server's configuration, given its certificate chain and private key (both PEM-encoded).
+/// Only TLS 1.2 and 1.3 with their safe default cipher suites are offered
+pub fn server_config(cert_pem: &[u8], key_pem: &[u8]) -> Result<Arc<ServerConfig>, io::Error> {
+    let key = PrivateKeyDer::from_pem_slice(key_pem).map_err(pem_error)?;
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_no_client_auth()
+        .with_single_cert(certificates(cert_pem)?, key)
+        .map_err(tls_error)?;
+
+    Ok(Arc::new(config))
+}
+
+/// The (PEM-encoded) CA certificates a client trusts
+fn roots(ca_pem: &[u8]) -> Result<RootCertStore, io::Error> {
+    let mut roots = RootCertStore::empty();
+
+    for cert in certificates(ca_pem)? {
+        roots.add(cert).map_err(tls_error)?;
+    }
+
+    Ok(roots)
+}
+
+/// The client's configuration, which only trusts servers whose certificate chains to one of the given (PEM-encoded)
+/// CA certificates. A self-signed server certificate (that isn't itself marked as a CA) can be trusted directly, which pins it
+pub fn client_config(ca_pem: &[u8]) -> Result<Arc<ClientConfig>, io::Error> {
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_root_certificates(roots(ca_pem)?)
+        .with_no_client_auth();
+
+    Ok(Arc::new(config))
+}
+
+/// Only trusts the server certificate it pins, whatever its name or issuer, on top of the checks of `ca` (if any)
+#[derive(Debug)]
+struct PinnedVerifier {
+    pinned: CertificateDer<'static>,
+    ca: Option<Arc<WebPkiServerVerifier>>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() != self.pinned.as_ref() {
+            return Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure));
+        }
+
+        match &self.ca {
+            Some(ca) => ca.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now),
+            None => Ok(ServerCertVerified::assertion()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// The client's configuration, which only trusts a server presenting the first certificate in `cert_pem`, e.g. the one the
+/// garbler serves with. This pins the server's identity across sessions. Given CA certificates, the server's certificate must
+/// also chain to one of them and be valid for the name we connect to; otherwise its name and issuer aren't checked
+pub fn pinned_client_config(cert_pem: &[u8], ca_pem: Option<&[u8]>) -> Result<Arc<ClientConfig>, io::Error> {
+    let provider = Arc::new(ring::default_provider());
+    let ca = match ca_pem {
+        Some(ca_pem) => Some(
+            WebPkiServerVerifier::builder_with_provider(Arc::new(roots(ca_pem)?), provider.clone())
+                .build()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        ),
+        None => None,
+    };
+    let verifier = PinnedVerifier {
+        pinned: certificates(cert_pem)?.swap_remove(0),
+        ca,
+        algorithms: provider.signature_verification_algorithms,
+    };
+
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+
+    Ok(Arc::new(config))
+}
+
+/// Run the server's side of the TLS handshake over an accepted connection. The handshake is completed before returning,
+/// so that a failed handshake is reported here rather than by the first message
+pub fn accept(config: Arc<ServerConfig>, stream: TcpStream) -> Result<ServerStream, io::Error> {
+    let mut tls = StreamOwned::new(ServerConnection::new(config).map_err(tls_error)?, stream);
+
+    while tls.conn.is_handshaking() {
+        tls.conn.complete_io(&mut tls.sock)?;
+    }
+
+    Ok(tls)
+}
+
+/// Run the client's side of the TLS handshake, checking that the server's certificate is valid for `server_name`
+/// (a DNS name or an IP address)
+pub fn connect(config: Arc<ClientConfig>, server_name: &str, stream: TcpStream) -> Result<ClientStream, io::Error> {
+    let name = ServerName::try_from(server_name.to_string())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid server name {:?}: {}", server_name, e)))?;
+    let mut tls = StreamOwned::new(ClientConnection::new(config, name).map_err(tls_error)?, stream);
+
+    while tls.conn.is_handshaking() {
+        tls.conn.complete_io(&mut tls.sock)?;
+    }
+
+    Ok(tls)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    use rcgen::{generate_simple_self_signed, CertifiedKey};
+
+    use crate::{backend::garbler_backend::protos::GarblerKeys, message::MessageStream};
+
+    use super::{accept, client_config, connect, pinned_client_config, server_config};
+
+    #[test]
+    fn tls_message_stream_test() {
+        let CertifiedKey { cert, key_pair } = generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let server = server_config(cert.pem().as_bytes(), key_pair.serialize_pem().as_bytes()).unwrap();
+        let other = generate_simple_self_signed(vec!["localhost".to_string()]).unwrap().cert;
+
+        // Messages go through to a client that trusts the server's certificate, and a client that doesn't aborts
+        for (ca, trusted) in [(cert.pem(), true), (other.pem(), false)] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = server.clone();
+            let garbler = thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                let mut keys_msg = GarblerKeys::new();
+                keys_msg.keys = vec![vec![7u8; 32]];
+
+                accept(server, stream).and_then(|mut tls| MessageStream::<GarblerKeys>::send_msg(&mut tls, keys_msg))
+            });
+            let client = client_config(ca.as_bytes()).unwrap();
+            let received = connect(client, "localhost", TcpStream::connect(addr).unwrap())
+                .and_then(|mut tls| MessageStream::<GarblerKeys>::receive_msg(&mut tls));
+
+            assert_eq!(garbler.join().unwrap().is_ok(), trusted);
+            assert_eq!(received.map(|msg| msg.keys).ok(), trusted.then(|| vec![vec![7u8; 32]]));
+        }
+
+        assert!(server_config(b"not a certificate", key_pair.serialize_pem().as_bytes()).is_err());
+    }
+
+    #[test]
+    fn pinned_client_test() {
+        let CertifiedKey { cert, key_pair } = generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let server = server_config(cert.pem().as_bytes(), key_pair.serialize_pem().as_bytes()).unwrap();
+        let other = generate_simple_self_signed(vec!["localhost".to_string()]).unwrap().cert;
+        let (pem, other_pem) = (cert.pem(), other.pem());
+
+        // The pinned certificate is trusted whatever name we connect to, unless a CA has to have issued it too
+        let cases = [
+            (&pem, None, "localhost", true),
+            (&pem, None, "garbler.example", true),
+            (&other_pem, None, "localhost", false),
+            (&pem, Some(&pem), "localhost", true),
+            (&pem, Some(&pem), "garbler.example", false),
+            (&pem, Some(&other_pem), "localhost", false),
+        ];
+
+        for (pinned, ca, name, trusted) in cases {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = server.clone();
+            let garbler = thread::spawn(move || accept(server, listener.accept().unwrap().0).map(|_| ()));
+            let client = pinned_client_config(pinned.as_bytes(), ca.map(|ca| ca.as_bytes())).unwrap();
+            let connected = connect(client, name, TcpStream::connect(addr).unwrap()).is_ok();
+
+            assert_eq!(connected, trusted, "CA: {}, name: {}", ca.is_some(), name);
+            assert_eq!(garbler.join().unwrap().is_ok(), trusted);
+        }
+
+        assert!(pinned_client_config(b"not a certificate", None).is_err());
+    }
+}
@@ -0,0 +1,226 @@
+//! Runs the garbler and receiver binaries against each other over a loopback socket, so that the protocol as a whole
+//! (message ordering, OT rounds, the result reporting modes) is exercised, rather than only its parts
+#![cfg(feature = "sync")]
+
+use std::{
+    cmp::Ordering,
+    fmt::Display,
+    io::{BufRead, BufReader, Read, Write},
+    process::{Child, Command, Stdio},
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::Duration,
+};
+
+/// How long we wait for the garbler to print something before giving up on it
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The line both parties print for a comparison of their net worths
+fn expected_result(garbler_worth: usize, receiver_worth: usize) -> &'static str {
+    match garbler_worth.cmp(&receiver_worth) {
+        Ordering::Equal => "You have the same amount!",
+        Ordering::Greater => "The garbler is richer!",
+        Ordering::Less => "The receiver is richer!",
+    }
+}
+
+/// Forward the lines of one of a child's outputs to `lines`
+fn forward_lines(output: impl Read + Send + 'static, lines: mpsc::Sender<String>) {
+    thread::spawn(move || {
+        for line in BufReader::new(output).lines().map_while(Result::ok) {
+            let _ = lines.send(line);
+        }
+    });
+}
+
+/// The next line the garbler printed (to either of its outputs) that `matches`
+fn next_line<T>(lines: &Receiver<String>, matches: impl Fn(&str) -> Option<T>) -> T {
+    loop {
+        let line = lines.recv_timeout(TIMEOUT).expect("the garbler stopped printing");
+
+        if let Some(found) = matches(&line) {
+            return found;
+        }
+    }
+}
+
+/// Start a garbler with the given net worth on a port picked by the OS, returning it along with its port and its output
+fn spawn_garbler(net_worth: impl Display, args: &[&str]) -> (Child, u16, Receiver<String>) {
+    let mut garbler = Command::new(env!("CARGO_BIN_EXE_garbler"))
+        .args(["--host", "127.0.0.1", "--port", "0"])
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    writeln!(garbler.stdin.take().unwrap(), "{}", net_worth).unwrap();
+
+    let (sender, lines) = mpsc::channel();
+    forward_lines(garbler.stdout.take().unwrap(), sender.clone());
+    forward_lines(garbler.stderr.take().unwrap(), sender);
+
+    // The garbler prints its address once it's listening (after the net worth prompt, on the same line)
+    let port = next_line(&lines, |line| {
+        line.split("Listening on ").nth(1).map(|addr| addr.rsplit(':').next().unwrap().parse().unwrap())
+    });
+
+    (garbler, port, lines)
+}
+
+/// Run a receiver with the given net worth against the garbler on `port`, returning what it printed
+fn run_receiver(port: u16, net_worth: impl Display, args: &[&str]) -> String {
+    let mut receiver = Command::new(env!("CARGO_BIN_EXE_receiver"))
+        .args(["--host", "127.0.0.1", "--port", &port.to_string()])
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    writeln!(receiver.stdin.take().unwrap(), "{}", net_worth).unwrap();
+
+    let output = receiver.wait_with_output().unwrap();
+    assert!(output.status.success(), "receiver failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn loopback_test() {
+    // The arguments each party is run with in every mode of learning the result
+    let modes: [(&[&str], &[&str]); 3] = [(&[], &[]), (&["--verify-result"], &[]), (&["--dual-exec"], &["--dual-exec"])];
+
+    for (garbler_args, receiver_args) in modes {
+        for garbler_worth in [0, 500] {
+            let (mut garbler, port, garbler_lines) = spawn_garbler(garbler_worth, garbler_args);
+
+            // A single garbler serves several receivers, one after the other
+            for receiver_worth in [0, 500, 1023] {
+                let expected = expected_result(garbler_worth, receiver_worth);
+                let receiver_output = run_receiver(port, receiver_worth, receiver_args);
+                // Either the result, or the reason the garbler aborted the session
+                let garbler_output =
+                    next_line(&garbler_lines, |line| (line.ends_with('!') || line.starts_with("Aborted")).then(|| line.to_string()));
+
+                assert!(receiver_output.trim_end().ends_with(expected), "{:?}: {}", receiver_args, receiver_output);
+                assert!(receiver_output.contains("Received our keys using 10 extended OTs"), "{}", receiver_output);
+                assert_eq!(garbler_output, expected, "{:?}", garbler_args);
+            }
+
+            garbler.kill().unwrap();
+            garbler.wait().unwrap();
+        }
+    }
+}
+
+#[test]
+fn wide_inputs_test() {
+    // Net worths wider than a usize are compared in full: comparing only their low 64 bits would make the receiver richer
+    let args = ["--bits", "70", "--peer-bits", "70"];
+    let (mut garbler, port, garbler_lines) = spawn_garbler("590295810358705651712", &args);
+    let receiver_output = run_receiver(port, 1, &args);
+    let garbler_output = next_line(&garbler_lines, |line| (line.ends_with('!') || line.starts_with("Aborted")).then(|| line.to_string()));
+
+    assert!(receiver_output.trim_end().ends_with("The garbler is richer!"), "{}", receiver_output);
+    assert_eq!(garbler_output, "The garbler is richer!");
+
+    garbler.kill().unwrap();
+    garbler.wait().unwrap();
+}
+
+#[test]
+fn signed_inputs_test() {
+    // With --signed, net worths are two's complement, so debts compare below any positive net worth
+    let args = ["--signed"];
+    let (mut garbler, port, garbler_lines) = spawn_garbler(-3, &args);
+    let receiver_output = run_receiver(port, 5, &args);
+    let garbler_output = next_line(&garbler_lines, |line| (line.ends_with('!') || line.starts_with("Aborted")).then(|| line.to_string()));
+
+    assert!(receiver_output.trim_end().ends_with("The receiver is richer!"), "{}", receiver_output);
+    assert_eq!(garbler_output, "The receiver is richer!");
+
+    garbler.kill().unwrap();
+    garbler.wait().unwrap();
+}
+
+#[test]
+fn receiver_only_test() {
+    // With --receiver-only, the receiver doesn't send the result back, so the garbler doesn't learn it
+    let args = ["--receiver-only"];
+    let (mut garbler, port, garbler_lines) = spawn_garbler(500, &args);
+    let receiver_output = run_receiver(port, 0, &args);
+    let garbler_output = next_line(&garbler_lines, |line| {
+        (line.ends_with('!') || line.starts_with("Aborted") || line.starts_with("Only")).then(|| line.to_string())
+    });
+
+    assert!(receiver_output.trim_end().ends_with("The garbler is richer!"), "{}", receiver_output);
+    assert_eq!(garbler_output, "Only the receiver learned the result");
+
+    // A receiver run in another mode aborts, rather than waiting for messages that never come
+    let mut receiver = Command::new(env!("CARGO_BIN_EXE_receiver"))
+        .args(["--host", "127.0.0.1", "--port", &port.to_string()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    writeln!(receiver.stdin.take().unwrap(), "0").unwrap();
+    let output = receiver.wait_with_output().unwrap();
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("where only the receiver learns the result, but we run"));
+
+    garbler.kill().unwrap();
+    garbler.wait().unwrap();
+}
+
+#[test]
+fn usage_error_test() {
+    // Missing, invalid or conflicting arguments are reported with a usage error, rather than a panic
+    for (binary, args) in [
+        (env!("CARGO_BIN_EXE_garbler"), &["--host", "127.0.0.1"][..]),
+        (env!("CARGO_BIN_EXE_receiver"), &["--port", "70000"][..]),
+        (env!("CARGO_BIN_EXE_receiver"), &["--port", "1", "--bits", "0"][..]),
+        (env!("CARGO_BIN_EXE_garbler"), &["--port", "1", "--dual-exec", "--verify-result"][..]),
+    ] {
+        let output = Command::new(binary).args(args).stdin(Stdio::null()).output().unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        assert_eq!(output.status.code(), Some(2), "{:?}: {}", args, stderr);
+        assert!(stderr.starts_with("error: ") && !stderr.contains("panicked"), "{:?}: {}", args, stderr);
+    }
+}
+
+#[test]
+fn offline_file_test() {
+    // The garbler writes its circuit to a file instead of listening, and the receiver evaluates the file instead of connecting
+    let path = std::env::temp_dir().join(format!("loopback-{}.gc", std::process::id()));
+    let path = path.to_str().unwrap();
+    let mut garbler = Command::new(env!("CARGO_BIN_EXE_garbler"))
+        .args(["--emit", path])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    writeln!(garbler.stdin.take().unwrap(), "500").unwrap();
+    assert!(garbler.wait_with_output().unwrap().status.success());
+
+    for receiver_worth in [0, 500, 1023] {
+        let mut receiver = Command::new(env!("CARGO_BIN_EXE_receiver"))
+            .args(["--eval", path])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        writeln!(receiver.stdin.take().unwrap(), "{}", receiver_worth).unwrap();
+        let output = receiver.wait_with_output().unwrap();
+
+        assert!(output.status.success());
+        assert!(String::from_utf8(output.stdout).unwrap().trim_end().ends_with(expected_result(500, receiver_worth)));
+    }
+
+    std::fs::remove_file(path).unwrap();
+}